@@ -0,0 +1,223 @@
+// MIPS of the interpreter on a CoreMark-like workload: linked list, matrix, state machine and
+// CRC kernels, written for the mini assembler since there is no M extension and no compiler.
+// Run with `cargo bench --bench coremark`, an argument selects the configurations by name.
+
+use std::time::Instant;
+
+use rv64_emu::{assemble, Exit, MachineBuilder, DRAM_BASE};
+
+// Instructions of a run, the best of `RUNS` runs is reported
+const INSTRUCTIONS: u64 = 20_000_000;
+const RUNS: usize = 5;
+
+// Input of the state machine kernel
+const TEXT: &str = "5012 -3.7e12 hello, world 0x1F 42 abc_def 9.81 T0 +100 ::end";
+
+const WORKLOAD: &str = "
+        li s11, 0
+    loop:
+        call list
+        call matrix
+        call state
+        call crc
+        j loop
+
+    # Build a list of 64 nodes, sum it, count the large values and reverse it
+    list:
+        la t0, nodes
+        li t1, 0
+        li t2, 64
+    1:  slli t3, t1, 4
+        add t3, t0, t3
+        addi t4, t3, 16
+        sd t4, 0(t3)
+        xor t5, t1, s11
+        andi t5, t5, 0xff
+        sd t5, 8(t3)
+        addi t1, t1, 1
+        blt t1, t2, 1b
+        sd zero, 0(t3)
+        mv t1, t0
+        li a0, 0
+        li a1, 0
+    2:  ld t4, 8(t1)
+        add a0, a0, t4
+        sltiu t5, t4, 0x80
+        xori t5, t5, 1
+        add a1, a1, t5
+        ld t1, 0(t1)
+        bnez t1, 2b
+        mv t1, t0
+        li t3, 0
+    3:  ld t4, 0(t1)
+        sd t3, 0(t1)
+        mv t3, t1
+        mv t1, t4
+        bnez t1, 3b
+        add s11, s11, a0
+        xor s11, s11, a1
+        ret
+
+    # Fill two 4x4 matrices and multiply them, with shift-and-add products
+    matrix:
+        la a2, mat_a
+        la a3, mat_b
+        li t5, 4
+        li t0, 0
+    1:  li t1, 0
+    2:  add t2, t0, t1
+        add t2, t2, s11
+        andi t2, t2, 0xff
+        slli t3, t0, 2
+        add t3, t3, t1
+        slli t3, t3, 2
+        add t4, a2, t3
+        sw t2, 0(t4)
+        xor t2, t0, t1
+        addi t2, t2, 1
+        add t4, a3, t3
+        sw t2, 0(t4)
+        addi t1, t1, 1
+        blt t1, t5, 2b
+        addi t0, t0, 1
+        blt t0, t5, 1b
+        la a4, mat_c
+        li t0, 0
+    3:  li t1, 0
+    4:  li a5, 0
+        li t2, 0
+    5:  slli t3, t0, 2
+        add t3, t3, t2
+        slli t3, t3, 2
+        add t3, a2, t3
+        lw a6, 0(t3)
+        slli t3, t2, 2
+        add t3, t3, t1
+        slli t3, t3, 2
+        add t3, a3, t3
+        lw a7, 0(t3)
+    6:  andi t4, a7, 1
+        beqz t4, 7f
+        add a5, a5, a6
+    7:  slli a6, a6, 1
+        srli a7, a7, 1
+        bnez a7, 6b
+        addi t2, t2, 1
+        blt t2, t5, 5b
+        slli t3, t0, 2
+        add t3, t3, t1
+        slli t3, t3, 2
+        add t3, a4, t3
+        sw a5, 0(t3)
+        add s11, s11, a5
+        addi t1, t1, 1
+        blt t1, t5, 4b
+        addi t0, t0, 1
+        blt t0, t5, 3b
+        ret
+
+    # Classify the characters of the text as digits, letters or others and count the changes
+    state:
+        la t0, text
+        li t1, 0
+        li a0, 0
+        li a1, 0
+    1:  lbu t2, 0(t0)
+        beqz t2, 5f
+        addi t0, t0, 1
+        addi t3, t2, -48
+        sltiu t3, t3, 10
+        bnez t3, 2f
+        ori t4, t2, 0x20
+        addi t4, t4, -97
+        sltiu t4, t4, 26
+        bnez t4, 3f
+        li t5, 0
+        j 4f
+    2:  li t5, 1
+        addi a1, a1, 1
+        j 4f
+    3:  li t5, 2
+    4:  beq t5, t1, 1b
+        addi a0, a0, 1
+        mv t1, t5
+        j 1b
+    5:  add s11, s11, a0
+        xor s11, s11, a1
+        ret
+
+    # CRC-16 of the product matrix
+    crc:
+        la t0, mat_c
+        li t1, 64
+        li a0, 0xffff
+        li a2, 0xa001
+    1:  lbu t2, 0(t0)
+        xor a0, a0, t2
+        li t3, 8
+    2:  andi t4, a0, 1
+        srli a0, a0, 1
+        beqz t4, 3f
+        xor a0, a0, a2
+    3:  addi t3, t3, -1
+        bnez t3, 2b
+        addi t0, t0, 1
+        addi t1, t1, -1
+        bnez t1, 1b
+        xor s11, s11, a0
+        ret
+
+        .align 3
+    nodes:
+        .zero 1024
+    mat_a:
+        .zero 64
+    mat_b:
+        .zero 64
+    mat_c:
+        .zero 64
+    text:
+";
+
+fn source() -> String {
+    let bytes: Vec<String> = TEXT.bytes().chain([0]).map(|b| b.to_string()).collect();
+    format!("{}        .byte {}\n", WORKLOAD, bytes.join(", "))
+}
+
+// Millions of instructions per second of one hart, with or without the decoded-instruction cache,
+// the translated blocks and their compilation
+fn mips(decode_cache: bool, blocks: bool, jit: bool) -> f64 {
+    (0..RUNS).map(|_| run(decode_cache, blocks, jit)).fold(0.0, f64::max)
+}
+
+fn run(decode_cache: bool, blocks: bool, jit: bool) -> f64 {
+    let code = assemble(&source(), DRAM_BASE).unwrap();
+    let mut machine = MachineBuilder::new().program(code).build().unwrap();
+    machine.harts[0].icache.enabled = decode_cache;
+    machine.harts[0].blocks.enabled = blocks;
+    machine.harts[0].blocks.jit = jit;
+
+    let start = Instant::now();
+    assert_eq!(machine.run(Some(INSTRUCTIONS)), Exit::Limit);
+    let elapsed = start.elapsed().as_secs_f64();
+    // The workload never traps, a trap means it went wrong
+    assert_eq!(machine.harts[0].trap, None);
+    INSTRUCTIONS as f64 / elapsed / 1e6
+}
+
+fn main() {
+    // cargo bench passes --bench
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with('-'));
+    let configurations = [
+        ("decode every instruction", false, false, false),
+        ("decoded-instruction cache", true, false, false),
+        ("translated blocks", true, true, false),
+        ("compiled blocks", true, true, true),
+    ];
+    for (name, decode_cache, blocks, jit) in configurations {
+        if filter.as_ref().is_some_and(|filter| !name.contains(filter.as_str())) {
+            continue;
+        }
+        println!("{:<26} {:>8.1} MIPS", name, mips(decode_cache, blocks, jit));
+    }
+}
@@ -0,0 +1,679 @@
+// Mini assembler for test programs: the implemented instructions in GNU syntax, labels, numeric
+// local labels (`1:` with `1b`/`1f`), the common pseudo-instructions, `%hi`/`%lo` and the
+// .byte/.half/.word/.dword/.zero/.align/.balign directives. Statements are separated by newlines
+// or `;`, comments start with `#` or `//`.
+//
+// Branch and jump operands that are plain numbers are offsets from the instruction, as in
+// llvm-mc; an operand naming a label or `.` is an address.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::csr::*;
+use crate::instruction::Instruction;
+use crate::instruction::Instruction::*;
+use crate::register::Register;
+use crate::register::Register::*;
+use crate::register::ABI_NAMES;
+
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+enum Statement<'a> {
+    Label(&'a str),
+    Instruction(&'a str, Vec<&'a str>),
+    Directive(&'a str, Vec<&'a str>),
+}
+
+// Split the source into statements with their line numbers
+fn parse(source: &str) -> Vec<(usize, Statement<'_>)> {
+    let mut statements = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let line = line.split("//").next().unwrap();
+        for mut text in line.split(';') {
+            // Any number of labels before the statement
+            while let Some((label, rest)) = text.split_once(':') {
+                let label = label.trim();
+                if label.is_empty() || !label.chars().all(|c| c.is_alphanumeric() || "_.$".contains(c)) {
+                    break;
+                }
+                statements.push((i + 1, Statement::Label(label)));
+                text = rest;
+            }
+
+            let text = text.trim();
+            if text.is_empty() {
+                continue;
+            }
+            let (name, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+            let operands: Vec<&str> = match operands.trim() {
+                "" => Vec::new(),
+                operands => operands.split(',').map(str::trim).collect(),
+            };
+            let statement = match name.strip_prefix('.') {
+                Some(_) => Statement::Directive(name, operands),
+                None => Statement::Instruction(name, operands),
+            };
+            statements.push((i + 1, statement));
+        }
+    }
+    statements
+}
+
+/// Assemble `source` for loading at `base`
+pub fn assemble(source: &str, base: u64) -> Result<Vec<u8>, AsmError> {
+    let statements = parse(source);
+    let mut assembler = Assembler {
+        labels: HashMap::new(),
+        local_labels: Vec::new(),
+        resolved: false,
+    };
+
+    // The first pass places the labels, unknown symbols stand for the current address. Sizes
+    // are checked against the second pass, where every symbol is known.
+    let mut sizes = Vec::new();
+    let mut addr = base;
+    for (line, statement) in &statements {
+        let error = |message| AsmError { line: *line, message };
+        if let Statement::Label(label) = statement {
+            if label.chars().all(|c| c.is_ascii_digit()) {
+                assembler.local_labels.push((label.to_string(), addr));
+            } else if assembler.labels.insert(label.to_string(), addr).is_some() {
+                return Err(error(format!("label {} is defined twice", label)));
+            }
+            continue;
+        }
+        let size = assembler.statement(statement, addr).map_err(error)?.len() as u64;
+        sizes.push(size);
+        addr += size;
+    }
+
+    assembler.resolved = true;
+    let mut output = Vec::new();
+    let mut sizes = sizes.into_iter();
+    for (line, statement) in &statements {
+        if let Statement::Label(_) = statement {
+            continue;
+        }
+        let error = |message| AsmError { line: *line, message };
+        let bytes = assembler.statement(statement, base + output.len() as u64).map_err(error)?;
+        if Some(bytes.len() as u64) != sizes.next() {
+            return Err(error("the size of the statement depends on a symbol".to_string()));
+        }
+        output.extend(bytes);
+    }
+    Ok(output)
+}
+
+struct Assembler {
+    labels: HashMap<String, u64>,
+    // Numeric labels in order, they can be defined several times
+    local_labels: Vec<(String, u64)>,
+    // Set for the second pass, all labels are known
+    resolved: bool,
+}
+
+fn register(name: &str) -> Result<Register, String> {
+    let index = match name {
+        "fp" => Some(8),
+        _ => ABI_NAMES.iter().position(|abi| *abi == name).or_else(|| {
+            name.strip_prefix('x').and_then(|i| i.parse().ok()).filter(|i| *i < 32)
+        }),
+    };
+    index.map(Register::from).ok_or_else(|| format!("unknown register: {}", name))
+}
+
+fn number(text: &str) -> Option<i64> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = text.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()? as i64
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        u64::from_str_radix(binary, 2).ok()? as i64
+    } else {
+        text.parse::<u64>().ok()? as i64
+    };
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+fn to_i32(value: i64) -> Result<i32, String> {
+    i32::try_from(value).map_err(|_| format!("{} does not fit in 32 bits", value))
+}
+
+// Sign-extend the low 12 bits, the part of a value addi adds
+fn low12(value: i64) -> i64 {
+    (value << 52) >> 52
+}
+
+// Instructions loading a constant, see RISCVMatInt.cpp in LLVM
+fn load_immediate(rd: Register, value: i64) -> Vec<Instruction> {
+    if i32::try_from(value).is_ok() {
+        let high = ((value + 0x800) >> 12) << 12;
+        let low = low12(value) as i32;
+        if high == 0 {
+            return vec![Addi { rd, rs1: X0, imm: low }];
+        }
+        let mut sequence = vec![Lui { rd, imm: high as i32 }];
+        if low != 0 {
+            sequence.push(Addiw { rd, rs1: rd, imm: low });
+        }
+        return sequence;
+    }
+
+    let low = low12(value);
+    let high = (value.wrapping_sub(low)) >> 12;
+    let shift = 12 + high.trailing_zeros();
+    let mut sequence = load_immediate(rd, (value.wrapping_sub(low)) >> shift);
+    sequence.push(Slli { rd, rs1: rd, shamt: shift });
+    if low != 0 {
+        sequence.push(Addi { rd, rs1: rd, imm: low as i32 });
+    }
+    sequence
+}
+
+fn csr_address(name: &str) -> Result<u16, String> {
+    if name == "time" {
+        return Ok(TIME);
+    }
+    if let Some((addr, _)) = CSR_NAMES.iter().find(|(_, csr)| *csr == name) {
+        return Ok(*addr);
+    }
+    number(name).and_then(|n| u16::try_from(n).ok()).ok_or_else(|| format!("unknown CSR: {}", name))
+}
+
+type RType = fn(Register, Register, Register) -> Instruction;
+type IType = fn(Register, Register, i32) -> Instruction;
+type Shift = fn(Register, Register, u32) -> Instruction;
+type SType = fn(Register, Register, i32) -> Instruction;
+type Csr = fn(Register, u32, u16) -> Instruction;
+
+fn r_type(mnemonic: &str) -> Option<RType> {
+    let op: RType = match mnemonic {
+        "add" => |rd, rs1, rs2| Add { rd, rs1, rs2 },
+        "sub" => |rd, rs1, rs2| Sub { rd, rs1, rs2 },
+        "sll" => |rd, rs1, rs2| Sll { rd, rs1, rs2 },
+        "slt" => |rd, rs1, rs2| Slt { rd, rs1, rs2 },
+        "sltu" => |rd, rs1, rs2| Sltu { rd, rs1, rs2 },
+        "xor" => |rd, rs1, rs2| Xor { rd, rs1, rs2 },
+        "srl" => |rd, rs1, rs2| Srl { rd, rs1, rs2 },
+        "sra" => |rd, rs1, rs2| Sra { rd, rs1, rs2 },
+        "or" => |rd, rs1, rs2| Or { rd, rs1, rs2 },
+        "and" => |rd, rs1, rs2| And { rd, rs1, rs2 },
+        "addw" => |rd, rs1, rs2| Addw { rd, rs1, rs2 },
+        "subw" => |rd, rs1, rs2| Subw { rd, rs1, rs2 },
+        "sllw" => |rd, rs1, rs2| Sllw { rd, rs1, rs2 },
+        "srlw" => |rd, rs1, rs2| Srlw { rd, rs1, rs2 },
+        "sraw" => |rd, rs1, rs2| Sraw { rd, rs1, rs2 },
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn i_type(mnemonic: &str) -> Option<IType> {
+    let op: IType = match mnemonic {
+        "addi" => |rd, rs1, imm| Addi { rd, rs1, imm },
+        "slti" => |rd, rs1, imm| Slti { rd, rs1, imm },
+        "sltiu" => |rd, rs1, imm| Sltiu { rd, rs1, imm },
+        "xori" => |rd, rs1, imm| Xori { rd, rs1, imm },
+        "ori" => |rd, rs1, imm| Ori { rd, rs1, imm },
+        "andi" => |rd, rs1, imm| Andi { rd, rs1, imm },
+        "addiw" => |rd, rs1, imm| Addiw { rd, rs1, imm },
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn shift(mnemonic: &str) -> Option<Shift> {
+    let op: Shift = match mnemonic {
+        "slli" => |rd, rs1, shamt| Slli { rd, rs1, shamt },
+        "srli" => |rd, rs1, shamt| Srli { rd, rs1, shamt },
+        "srai" => |rd, rs1, shamt| Srai { rd, rs1, shamt },
+        "slliw" => |rd, rs1, shamt| Slliw { rd, rs1, shamt },
+        "srliw" => |rd, rs1, shamt| Srliw { rd, rs1, shamt },
+        "sraiw" => |rd, rs1, shamt| Sraiw { rd, rs1, shamt },
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn load(mnemonic: &str) -> Option<IType> {
+    let op: IType = match mnemonic {
+        "lb" => |rd, rs1, imm| Lb { rd, rs1, imm },
+        "lh" => |rd, rs1, imm| Lh { rd, rs1, imm },
+        "lw" => |rd, rs1, imm| Lw { rd, rs1, imm },
+        "lbu" => |rd, rs1, imm| Lbu { rd, rs1, imm },
+        "lhu" => |rd, rs1, imm| Lhu { rd, rs1, imm },
+        "lwu" => |rd, rs1, imm| Lwu { rd, rs1, imm },
+        "ld" => |rd, rs1, imm| Ld { rd, rs1, imm },
+        _ => return None,
+    };
+    Some(op)
+}
+
+// Stores and branches, the registers are rs1 then rs2
+fn store(mnemonic: &str) -> Option<SType> {
+    let op: SType = match mnemonic {
+        "sb" => |rs1, rs2, imm| Sb { rs1, rs2, imm },
+        "sh" => |rs1, rs2, imm| Sh { rs1, rs2, imm },
+        "sw" => |rs1, rs2, imm| Sw { rs1, rs2, imm },
+        "sd" => |rs1, rs2, imm| Sd { rs1, rs2, imm },
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn branch(mnemonic: &str) -> Option<SType> {
+    let op: SType = match mnemonic {
+        "beq" => |rs1, rs2, imm| Beq { rs1, rs2, imm },
+        "bne" => |rs1, rs2, imm| Bne { rs1, rs2, imm },
+        "blt" => |rs1, rs2, imm| Blt { rs1, rs2, imm },
+        "bge" => |rs1, rs2, imm| Bge { rs1, rs2, imm },
+        "bltu" => |rs1, rs2, imm| Bltu { rs1, rs2, imm },
+        "bgeu" => |rs1, rs2, imm| Bgeu { rs1, rs2, imm },
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn amo(mnemonic: &str) -> Option<RType> {
+    let op: RType = match mnemonic {
+        "sc.w" => |rd, rs1, rs2| ScW { rd, rs1, rs2 },
+        "amoswap.w" => |rd, rs1, rs2| AmoswapW { rd, rs1, rs2 },
+        "amoadd.w" => |rd, rs1, rs2| AmoaddW { rd, rs1, rs2 },
+        "amoxor.w" => |rd, rs1, rs2| AmoxorW { rd, rs1, rs2 },
+        "amoand.w" => |rd, rs1, rs2| AmoandW { rd, rs1, rs2 },
+        "amoor.w" => |rd, rs1, rs2| AmoorW { rd, rs1, rs2 },
+        "amomin.w" => |rd, rs1, rs2| AmominW { rd, rs1, rs2 },
+        "amomax.w" => |rd, rs1, rs2| AmomaxW { rd, rs1, rs2 },
+        "amominu.w" => |rd, rs1, rs2| AmominuW { rd, rs1, rs2 },
+        "amomaxu.w" => |rd, rs1, rs2| AmomaxuW { rd, rs1, rs2 },
+        "sc.d" => |rd, rs1, rs2| ScD { rd, rs1, rs2 },
+        "amoswap.d" => |rd, rs1, rs2| AmoswapD { rd, rs1, rs2 },
+        "amoadd.d" => |rd, rs1, rs2| AmoaddD { rd, rs1, rs2 },
+        "amoxor.d" => |rd, rs1, rs2| AmoxorD { rd, rs1, rs2 },
+        "amoand.d" => |rd, rs1, rs2| AmoandD { rd, rs1, rs2 },
+        "amoor.d" => |rd, rs1, rs2| AmoorD { rd, rs1, rs2 },
+        "amomin.d" => |rd, rs1, rs2| AmominD { rd, rs1, rs2 },
+        "amomax.d" => |rd, rs1, rs2| AmomaxD { rd, rs1, rs2 },
+        "amominu.d" => |rd, rs1, rs2| AmominuD { rd, rs1, rs2 },
+        "amomaxu.d" => |rd, rs1, rs2| AmomaxuD { rd, rs1, rs2 },
+        _ => return None,
+    };
+    Some(op)
+}
+
+// CSR instructions, the source is a register index or a zimm
+fn csr(mnemonic: &str) -> Option<(Csr, bool)> {
+    let op: (Csr, bool) = match mnemonic {
+        "csrrw" => (|rd, rs1, csr| Csrrw { rd, rs1: Register::from(rs1 as usize), csr }, false),
+        "csrrs" => (|rd, rs1, csr| Csrrs { rd, rs1: Register::from(rs1 as usize), csr }, false),
+        "csrrc" => (|rd, rs1, csr| Csrrc { rd, rs1: Register::from(rs1 as usize), csr }, false),
+        "csrrwi" => (|rd, uimm, csr| Csrrwi { rd, uimm, csr }, true),
+        "csrrsi" => (|rd, uimm, csr| Csrrsi { rd, uimm, csr }, true),
+        "csrrci" => (|rd, uimm, csr| Csrrci { rd, uimm, csr }, true),
+        _ => return None,
+    };
+    Some(op)
+}
+
+impl Assembler {
+    // Value of an expression: a number, a label, a numeric label reference, `.`, %hi/%lo of
+    // an expression, or a sum or difference of those
+    fn eval(&self, expr: &str, pc: u64) -> Result<i64, String> {
+        let expr = expr.trim();
+        if let Some(inner) = expr.strip_prefix("%hi(").and_then(|e| e.strip_suffix(')')) {
+            let value = self.eval(inner, pc)?;
+            return Ok(((value + 0x800) >> 12) & 0xfffff);
+        }
+        if let Some(inner) = expr.strip_prefix("%lo(").and_then(|e| e.strip_suffix(')')) {
+            return Ok(low12(self.eval(inner, pc)?));
+        }
+        // The last operator outside of the leading sign splits the expression
+        if let Some(i) = expr.rfind(['+', '-']).filter(|i| *i > 0) {
+            let left = self.eval(&expr[..i], pc)?;
+            let right = self.eval(&expr[i + 1..], pc)?;
+            return Ok(if &expr[i..i + 1] == "+" { left.wrapping_add(right) } else { left.wrapping_sub(right) });
+        }
+
+        if let Some(value) = number(expr) {
+            return Ok(value);
+        }
+        if expr == "." {
+            return Ok(pc as i64);
+        }
+        if let Some(addr) = self.local_label(expr, pc) {
+            return Ok(addr as i64);
+        }
+        match self.labels.get(expr) {
+            Some(addr) => Ok(*addr as i64),
+            None if !self.resolved => Ok(pc as i64),
+            None => Err(format!("undefined symbol: {}", expr)),
+        }
+    }
+
+    // `1b` is the closest `1:` at or before pc, `1f` the closest after it
+    fn local_label(&self, reference: &str, pc: u64) -> Option<u64> {
+        let (name, backward) = match reference.strip_suffix('b') {
+            Some(name) => (name, true),
+            None => (reference.strip_suffix('f')?, false),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let mut labels = self.local_labels.iter().filter(|(label, _)| label == name);
+        let found = if backward {
+            labels.rfind(|(_, addr)| *addr <= pc)
+        } else {
+            labels.find(|(_, addr)| *addr > pc)
+        };
+        found.map(|(_, addr)| *addr).or(if self.resolved { None } else { Some(pc) })
+    }
+
+    fn imm(&self, expr: &str, pc: u64) -> Result<i32, String> {
+        to_i32(self.eval(expr, pc)?)
+    }
+
+    // Branch and jump targets, a plain number is an offset
+    fn offset(&self, expr: &str, pc: u64) -> Result<i32, String> {
+        match number(expr) {
+            Some(offset) => to_i32(offset),
+            None => to_i32(self.eval(expr, pc)?.wrapping_sub(pc as i64)),
+        }
+    }
+
+    // `offset(register)`, the offset is optional
+    fn memory(&self, operand: &str, pc: u64) -> Result<(i32, Register), String> {
+        let (offset, base) = operand
+            .strip_suffix(')')
+            .and_then(|operand| operand.rsplit_once('('))
+            .ok_or_else(|| format!("expected offset(register): {}", operand))?;
+        let offset = if offset.trim().is_empty() { 0 } else { self.imm(offset, pc)? };
+        Ok((offset, register(base.trim())?))
+    }
+
+    // The operands of an instruction, checking their number
+    fn operands<'a, const N: usize>(operands: &[&'a str]) -> Result<[&'a str; N], String> {
+        operands.try_into().map_err(|_| format!("expected {} operands, got {}", N, operands.len()))
+    }
+
+    // Instructions for a mnemonic, pseudo-instructions expand to one or more
+    fn expand(&self, mnemonic: &str, ops: &[&str], pc: u64) -> Result<Vec<Instruction>, String> {
+        if let Some(op) = r_type(mnemonic) {
+            let [rd, rs1, rs2] = Self::operands(ops)?;
+            return Ok(vec![op(register(rd)?, register(rs1)?, register(rs2)?)]);
+        }
+        if let Some(op) = i_type(mnemonic) {
+            let [rd, rs1, imm] = Self::operands(ops)?;
+            return Ok(vec![op(register(rd)?, register(rs1)?, self.imm(imm, pc)?)]);
+        }
+        if let Some(op) = shift(mnemonic) {
+            let [rd, rs1, shamt] = Self::operands(ops)?;
+            let shamt = u32::try_from(self.eval(shamt, pc)?).map_err(|_| format!("invalid shift amount: {}", shamt))?;
+            return Ok(vec![op(register(rd)?, register(rs1)?, shamt)]);
+        }
+        if let Some(op) = load(mnemonic) {
+            let [rd, address] = Self::operands(ops)?;
+            let (offset, rs1) = self.memory(address, pc)?;
+            return Ok(vec![op(register(rd)?, rs1, offset)]);
+        }
+        if let Some(op) = store(mnemonic) {
+            let [rs2, address] = Self::operands(ops)?;
+            let (offset, rs1) = self.memory(address, pc)?;
+            return Ok(vec![op(rs1, register(rs2)?, offset)]);
+        }
+        if let Some(op) = branch(mnemonic) {
+            let [rs1, rs2, target] = Self::operands(ops)?;
+            return Ok(vec![op(register(rs1)?, register(rs2)?, self.offset(target, pc)?)]);
+        }
+        if let Some(op) = amo(mnemonic) {
+            let [rd, rs2, address] = Self::operands(ops)?;
+            let (_, rs1) = self.memory(address, pc)?;
+            return Ok(vec![op(register(rd)?, rs1, register(rs2)?)]);
+        }
+        if let Some((op, immediate)) = csr(mnemonic) {
+            let [rd, csr, source] = Self::operands(ops)?;
+            let source = match immediate {
+                true => u32::try_from(self.eval(source, pc)?).map_err(|_| format!("invalid zimm: {}", source))?,
+                false => usize::from(register(source)?) as u32,
+            };
+            return Ok(vec![op(register(rd)?, source, csr_address(csr)?)]);
+        }
+
+        // Branches against zero and with swapped operands
+        let zero_branch = match mnemonic {
+            "beqz" => Some(("beq", false)),
+            "bnez" => Some(("bne", false)),
+            "bltz" => Some(("blt", false)),
+            "bgez" => Some(("bge", false)),
+            "bgtz" => Some(("blt", true)),
+            "blez" => Some(("bge", true)),
+            _ => None,
+        };
+        if let Some((name, swap)) = zero_branch {
+            let [rs, target] = Self::operands(ops)?;
+            let (rs1, rs2) = if swap { (X0, register(rs)?) } else { (register(rs)?, X0) };
+            return Ok(vec![branch(name).unwrap()(rs1, rs2, self.offset(target, pc)?)]);
+        }
+        let swapped = match mnemonic {
+            "bgt" => Some("blt"),
+            "ble" => Some("bge"),
+            "bgtu" => Some("bltu"),
+            "bleu" => Some("bgeu"),
+            _ => None,
+        };
+        if let Some(name) = swapped {
+            let [rs1, rs2, target] = Self::operands(ops)?;
+            return Ok(vec![branch(name).unwrap()(register(rs2)?, register(rs1)?, self.offset(target, pc)?)]);
+        }
+
+        let inst = match (mnemonic, ops) {
+            ("nop", []) => Addi { rd: X0, rs1: X0, imm: 0 },
+            ("li", [rd, value]) => return Ok(load_immediate(register(rd)?, self.eval(value, pc)?)),
+            ("la", [rd, symbol]) => {
+                // auipc+addi, pc-relative like the assembler's `la` without PIC
+                let rd = register(rd)?;
+                let offset = self.eval(symbol, pc)?.wrapping_sub(pc as i64);
+                let high = ((offset + 0x800) >> 12) << 12;
+                return Ok(vec![Auipc { rd, imm: to_i32(high)? }, Addi { rd, rs1: rd, imm: low12(offset) as i32 }]);
+            }
+            ("call" | "tail", [symbol]) => {
+                let (link, scratch) = if mnemonic == "call" { (X1, X1) } else { (X0, X6) };
+                let offset = self.eval(symbol, pc)?.wrapping_sub(pc as i64);
+                let high = ((offset + 0x800) >> 12) << 12;
+                return Ok(vec![
+                    Auipc { rd: scratch, imm: to_i32(high)? },
+                    Jalr { rd: link, rs1: scratch, imm: low12(offset) as i32 },
+                ]);
+            }
+            ("mv", [rd, rs]) => Addi { rd: register(rd)?, rs1: register(rs)?, imm: 0 },
+            ("not", [rd, rs]) => Xori { rd: register(rd)?, rs1: register(rs)?, imm: -1 },
+            ("neg", [rd, rs]) => Sub { rd: register(rd)?, rs1: X0, rs2: register(rs)? },
+            ("negw", [rd, rs]) => Subw { rd: register(rd)?, rs1: X0, rs2: register(rs)? },
+            ("sext.w", [rd, rs]) => Addiw { rd: register(rd)?, rs1: register(rs)?, imm: 0 },
+            ("seqz", [rd, rs]) => Sltiu { rd: register(rd)?, rs1: register(rs)?, imm: 1 },
+            ("snez", [rd, rs]) => Sltu { rd: register(rd)?, rs1: X0, rs2: register(rs)? },
+            ("sltz", [rd, rs]) => Slt { rd: register(rd)?, rs1: register(rs)?, rs2: X0 },
+            ("sgtz", [rd, rs]) => Slt { rd: register(rd)?, rs1: X0, rs2: register(rs)? },
+            ("lui", [rd, imm]) => Lui { rd: register(rd)?, imm: self.imm(imm, pc)?.wrapping_shl(12) },
+            ("auipc", [rd, imm]) => Auipc { rd: register(rd)?, imm: self.imm(imm, pc)?.wrapping_shl(12) },
+            ("j", [target]) => Jal { rd: X0, imm: self.offset(target, pc)? },
+            ("jal", [target]) => Jal { rd: X1, imm: self.offset(target, pc)? },
+            ("jal", [rd, target]) => Jal { rd: register(rd)?, imm: self.offset(target, pc)? },
+            ("jr", [rs]) => Jalr { rd: X0, rs1: register(rs)?, imm: 0 },
+            ("ret", []) => Jalr { rd: X0, rs1: X1, imm: 0 },
+            ("jalr", [rs]) => Jalr { rd: X1, rs1: register(rs)?, imm: 0 },
+            ("jalr", [rd, address]) => {
+                let (imm, rs1) = self.memory(address, pc)?;
+                Jalr { rd: register(rd)?, rs1, imm }
+            }
+            ("jalr", [rd, rs1, imm]) => Jalr { rd: register(rd)?, rs1: register(rs1)?, imm: self.imm(imm, pc)? },
+            ("fence", []) => Fence { rd: X0, rs1: X0, imm: 0xff },
+            ("fence", [pred, succ]) => {
+                let set = |set: &str| -> Result<i32, String> {
+                    set.chars().try_fold(0, |bits, c| match "iorw".find(c) {
+                        Some(i) => Ok(bits | (8 >> i)),
+                        None => Err(format!("invalid fence set: {}", set)),
+                    })
+                };
+                Fence { rd: X0, rs1: X0, imm: (set(pred)? << 4) | set(succ)? }
+            }
+            ("fence.i", []) => FenceI,
+            ("ecall", []) => Ecall,
+            ("ebreak", []) => Ebreak,
+            ("sret", []) => Sret,
+            ("mret", []) => Mret,
+            ("wfi", []) => Wfi,
+            ("sfence.vma", []) => SfenceVma { rs1: X0, rs2: X0 },
+            ("sfence.vma", [rs1]) => SfenceVma { rs1: register(rs1)?, rs2: X0 },
+            ("sfence.vma", [rs1, rs2]) => SfenceVma { rs1: register(rs1)?, rs2: register(rs2)? },
+            ("lr.w", [rd, address]) => LrW { rd: register(rd)?, rs1: self.memory(address, pc)?.1 },
+            ("lr.d", [rd, address]) => LrD { rd: register(rd)?, rs1: self.memory(address, pc)?.1 },
+            ("csrr", [rd, csr]) => Csrrs { rd: register(rd)?, rs1: X0, csr: csr_address(csr)? },
+            ("csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci", [csr, source]) => {
+                let full = mnemonic.replacen("csr", "csrr", 1);
+                return self.expand(&full, &["zero", csr, source], pc);
+            }
+            _ => return Err(format!("unknown instruction or operands: {} {}", mnemonic, ops.join(", "))),
+        };
+        Ok(vec![inst])
+    }
+
+    // Bytes of a statement at `pc`
+    fn statement(&self, statement: &Statement, pc: u64) -> Result<Vec<u8>, String> {
+        match statement {
+            Statement::Label(_) => Ok(Vec::new()),
+            Statement::Instruction(mnemonic, operands) => {
+                let mut bytes = Vec::new();
+                for (i, inst) in self.expand(mnemonic, operands, pc)?.iter().enumerate() {
+                    // Only the second pass checks the fields, the first one sees placeholder values
+                    let word = match (self.resolved, inst.encode()) {
+                        (_, Ok(word)) => word,
+                        (false, Err(_)) => 0,
+                        (true, Err(e)) => return Err(format!("cannot encode instruction {}: {:?}", i + 1, e)),
+                    };
+                    bytes.extend_from_slice(&word.to_le_bytes());
+                }
+                Ok(bytes)
+            }
+            Statement::Directive(name, args) => {
+                let size = match *name {
+                    ".byte" => 1,
+                    ".half" => 2,
+                    ".word" => 4,
+                    ".dword" => 8,
+                    ".zero" => {
+                        let [count] = Self::operands(args)?;
+                        return Ok(vec![0; self.eval(count, pc)? as usize]);
+                    }
+                    ".align" | ".balign" => {
+                        let [n] = Self::operands(args)?;
+                        let n = self.eval(n, pc)? as u64;
+                        let align = if *name == ".align" { 1u64.checked_shl(n as u32).unwrap_or(0) } else { n };
+                        if !align.is_power_of_two() {
+                            return Err(format!("invalid alignment: {}", n));
+                        }
+                        return Ok(vec![0; (pc.next_multiple_of(align) - pc) as usize]);
+                    }
+                    ".text" | ".data" | ".globl" | ".global" | ".option" | ".section" => return Ok(Vec::new()),
+                    _ => return Err(format!("unknown directive: {}", name)),
+                };
+                let mut bytes = Vec::new();
+                for arg in args {
+                    let value = self.eval(arg, pc + bytes.len() as u64)?;
+                    bytes.extend_from_slice(&value.to_le_bytes()[..size]);
+                }
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn words(bytes: &[u8]) -> Vec<u32> {
+    bytes.chunks_exact(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect()
+}
+
+#[test]
+fn test_assemble_encodings() {
+    // Encodings from llvm-mc -triple=riscv64 -mattr=+a
+    let program = "
+        start:  addi sp, sp, -16      # comment
+                sd ra, 8(sp); ld a0, 0(a1)
+                lui a0, 0x12345
+                csrrs a0, mstatus, zero
+                csrw mtvec, t0
+                amoadd.w a0, a1, (a2)
+                lr.d t0, (a0)
+        1:      bne a0, a1, 1b
+                beqz a0, start
+                j 1f
+                jal ra, start
+        1:      ret
+                fence
+                sfence.vma
+                srai a0, a0, 63
+    ";
+    let expected = [
+        0xff010113, 0x00113423, 0x0005b503, 0x12345537, 0x30002573, 0x30529073, 0x00b6252f, 0x100532af,
+        0x00b51063, 0xfc050ee3, 0x0080006f, 0xfd5ff0ef, 0x00008067, 0x0ff0000f, 0x12000073, 0x43f55513,
+    ];
+    assert_eq!(words(&assemble(program, 0x8000_0000).unwrap()), expected);
+}
+
+#[test]
+fn test_assemble_pseudo_instructions() {
+    assert_eq!(words(&assemble("li a0, 5", 0).unwrap()), [0x00500513]);
+    assert_eq!(words(&assemble("li a0, 0x12345678", 0).unwrap()), [0x12345537, 0x6785051b]);
+    assert_eq!(words(&assemble("li a0, -2048", 0).unwrap()), [0x80000513]);
+    assert_eq!(words(&assemble("li t0, 0x80000000", 0).unwrap()), [0x00100293, 0x01f29293]);
+
+    // %hi/%lo and la reach the same address
+    let source = "lui a0, %hi(data); addi a0, a0, %lo(data); la a1, data; call f; f: ret; .align 3; data: .dword 0x1122334455667788, data";
+    let code = assemble(source, 0x8000_0800).unwrap();
+    assert_eq!(words(&code)[..6], [0x80001537, 0x82050513, 0x00000597, 0x01858593, 0x00000097, 0x008080e7]);
+    assert_eq!(code.len(), 48);
+    assert_eq!(code[32..40], 0x1122334455667788u64.to_le_bytes());
+    assert_eq!(code[40..48], 0x8000_0820u64.to_le_bytes());
+
+    assert_eq!(
+        assemble("nop\nbeq a0, a1, nowhere", 0),
+        Err(AsmError { line: 2, message: "undefined symbol: nowhere".to_string() })
+    );
+    assert_eq!(
+        assemble("addi a0, a0, 4096", 0).unwrap_err().message,
+        "cannot encode instruction 1: OutOfRange(4096)"
+    );
+}
+
+#[test]
+fn test_assembled_program_runs() {
+    use crate::bus::DRAM_BASE;
+    use crate::machine::Machine;
+
+    // Sum 1..=10 in a loop, then jump to address 0 to stop
+    let source = "
+            li a0, 0
+            li t0, 10
+        1:  add a0, a0, t0
+            addi t0, t0, -1
+            bnez t0, 1b
+            jr zero
+    ";
+    let mut machine = Machine::new(assemble(source, DRAM_BASE).unwrap(), 1);
+    while machine.harts[0].pc != 0 {
+        machine.step().unwrap();
+    }
+    assert_eq!(machine.harts[0].regs[10], 55);
+}
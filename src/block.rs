@@ -0,0 +1,702 @@
+use std::collections::HashMap;
+
+use crate::bus::Bus;
+use crate::config::Misaligned;
+use crate::cpu::Cpu;
+use crate::dram::PAGE_SIZE;
+use crate::exception::Exception;
+use crate::instruction::{decode, Instruction};
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+use crate::jit::{self, Code, Context, Jit};
+use crate::register::Register;
+
+// Basic-block translation. Straight-line code is translated once into micro-ops with the
+// registers as plain indices and the immediates, branch targets and link addresses resolved,
+// then run by a dispatch loop that goes from block to block through their links. A block ends
+// at the first branch, jump or system instruction, the last one runs through the interpreter.
+// Blocks stay within a page and are checked against its generation, like the decoded-instruction
+// cache: a store to their code retranslates them, see `Bus::watch_code`. With the jit feature,
+// blocks that ran often are compiled to x86-64 code, see `jit`.
+
+// Longest block, the run of a block can also stop early at an instruction budget
+const MAX_OPS: usize = 64;
+
+// Blocks kept before they are all dropped, code is rarely that large
+const MAX_BLOCKS: usize = 1 << 14;
+
+// Link of a block to a successor it has not reached yet
+const NO_LINK: u32 = u32::MAX;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub(crate) enum Op {
+    // rd = constant, for lui and auipc
+    Li { rd: u8, imm: u64 },
+    Addi { rd: u8, rs1: u8, imm: u64 },
+    Slti { rd: u8, rs1: u8, imm: u64 },
+    Sltiu { rd: u8, rs1: u8, imm: u64 },
+    Xori { rd: u8, rs1: u8, imm: u64 },
+    Ori { rd: u8, rs1: u8, imm: u64 },
+    Andi { rd: u8, rs1: u8, imm: u64 },
+    Slli { rd: u8, rs1: u8, shamt: u32 },
+    Srli { rd: u8, rs1: u8, shamt: u32 },
+    Srai { rd: u8, rs1: u8, shamt: u32 },
+    Addiw { rd: u8, rs1: u8, imm: u64 },
+    Slliw { rd: u8, rs1: u8, shamt: u32 },
+    Srliw { rd: u8, rs1: u8, shamt: u32 },
+    Sraiw { rd: u8, rs1: u8, shamt: u32 },
+
+    Add { rd: u8, rs1: u8, rs2: u8 },
+    Sub { rd: u8, rs1: u8, rs2: u8 },
+    Sll { rd: u8, rs1: u8, rs2: u8 },
+    Slt { rd: u8, rs1: u8, rs2: u8 },
+    Sltu { rd: u8, rs1: u8, rs2: u8 },
+    Xor { rd: u8, rs1: u8, rs2: u8 },
+    Srl { rd: u8, rs1: u8, rs2: u8 },
+    Sra { rd: u8, rs1: u8, rs2: u8 },
+    Or { rd: u8, rs1: u8, rs2: u8 },
+    And { rd: u8, rs1: u8, rs2: u8 },
+    Addw { rd: u8, rs1: u8, rs2: u8 },
+    Subw { rd: u8, rs1: u8, rs2: u8 },
+    Sllw { rd: u8, rs1: u8, rs2: u8 },
+    Srlw { rd: u8, rs1: u8, rs2: u8 },
+    Sraw { rd: u8, rs1: u8, rs2: u8 },
+
+    // Loads may target x0, they still access memory
+    Lb { rd: u8, rs1: u8, imm: u64 },
+    Lh { rd: u8, rs1: u8, imm: u64 },
+    Lw { rd: u8, rs1: u8, imm: u64 },
+    Ld { rd: u8, rs1: u8, imm: u64 },
+    Lbu { rd: u8, rs1: u8, imm: u64 },
+    Lhu { rd: u8, rs1: u8, imm: u64 },
+    Lwu { rd: u8, rs1: u8, imm: u64 },
+    Store { rs1: u8, rs2: u8, imm: u64, size: u8 },
+
+    // An instruction writing x0 that has no other effect
+    Nop,
+
+    // The last op of a block
+    Beq { rs1: u8, rs2: u8, target: u64 },
+    Bne { rs1: u8, rs2: u8, target: u64 },
+    Blt { rs1: u8, rs2: u8, target: u64 },
+    Bge { rs1: u8, rs2: u8, target: u64 },
+    Bltu { rs1: u8, rs2: u8, target: u64 },
+    Bgeu { rs1: u8, rs2: u8, target: u64 },
+    Jal { rd: u8, link: u64, target: u64 },
+    Jalr { rd: u8, rs1: u8, imm: u64, link: u64 },
+    FenceI,
+    Interpret { inst: u32, instruction: Instruction },
+}
+
+// Index of a register, the micro-ops index the register file with it
+fn r(register: Register) -> u8 {
+    usize::from(register) as u8
+}
+
+impl Op {
+    // Micro-op of the instruction at `pc`, and whether it ends the block
+    fn translate(inst: u32, instruction: Instruction, pc: u64) -> (Op, bool) {
+        use Instruction as I;
+
+        // Instructions without side effects are dropped when they only write x0
+        let op = match instruction {
+            I::Lui { rd, .. } | I::Auipc { rd, .. } | I::Addi { rd, .. } | I::Slti { rd, .. } | I::Sltiu { rd, .. }
+            | I::Xori { rd, .. } | I::Ori { rd, .. } | I::Andi { rd, .. } | I::Slli { rd, .. } | I::Srli { rd, .. }
+            | I::Srai { rd, .. } | I::Addiw { rd, .. } | I::Slliw { rd, .. } | I::Srliw { rd, .. }
+            | I::Sraiw { rd, .. } | I::Add { rd, .. } | I::Sub { rd, .. } | I::Sll { rd, .. } | I::Slt { rd, .. }
+            | I::Sltu { rd, .. } | I::Xor { rd, .. } | I::Srl { rd, .. } | I::Sra { rd, .. } | I::Or { rd, .. }
+            | I::And { rd, .. } | I::Addw { rd, .. } | I::Subw { rd, .. } | I::Sllw { rd, .. }
+            | I::Srlw { rd, .. } | I::Sraw { rd, .. }
+                if rd == Register::X0 =>
+            {
+                Op::Nop
+            }
+
+            I::Lui { rd, imm } => Op::Li { rd: r(rd), imm: imm as i64 as u64 },
+            I::Auipc { rd, imm } => Op::Li { rd: r(rd), imm: pc.wrapping_add(imm as i64 as u64) },
+            I::Addi { rd, rs1, imm } => Op::Addi { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Slti { rd, rs1, imm } => Op::Slti { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Sltiu { rd, rs1, imm } => Op::Sltiu { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Xori { rd, rs1, imm } => Op::Xori { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Ori { rd, rs1, imm } => Op::Ori { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Andi { rd, rs1, imm } => Op::Andi { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Slli { rd, rs1, shamt } => Op::Slli { rd: r(rd), rs1: r(rs1), shamt },
+            I::Srli { rd, rs1, shamt } => Op::Srli { rd: r(rd), rs1: r(rs1), shamt },
+            I::Srai { rd, rs1, shamt } => Op::Srai { rd: r(rd), rs1: r(rs1), shamt },
+            I::Addiw { rd, rs1, imm } => Op::Addiw { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Slliw { rd, rs1, shamt } => Op::Slliw { rd: r(rd), rs1: r(rs1), shamt },
+            I::Srliw { rd, rs1, shamt } => Op::Srliw { rd: r(rd), rs1: r(rs1), shamt },
+            I::Sraiw { rd, rs1, shamt } => Op::Sraiw { rd: r(rd), rs1: r(rs1), shamt },
+
+            I::Add { rd, rs1, rs2 } => Op::Add { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Sub { rd, rs1, rs2 } => Op::Sub { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Sll { rd, rs1, rs2 } => Op::Sll { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Slt { rd, rs1, rs2 } => Op::Slt { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Sltu { rd, rs1, rs2 } => Op::Sltu { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Xor { rd, rs1, rs2 } => Op::Xor { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Srl { rd, rs1, rs2 } => Op::Srl { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Sra { rd, rs1, rs2 } => Op::Sra { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Or { rd, rs1, rs2 } => Op::Or { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::And { rd, rs1, rs2 } => Op::And { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Addw { rd, rs1, rs2 } => Op::Addw { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Subw { rd, rs1, rs2 } => Op::Subw { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Sllw { rd, rs1, rs2 } => Op::Sllw { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Srlw { rd, rs1, rs2 } => Op::Srlw { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+            I::Sraw { rd, rs1, rs2 } => Op::Sraw { rd: r(rd), rs1: r(rs1), rs2: r(rs2) },
+
+            I::Lb { rd, rs1, imm } => Op::Lb { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Lh { rd, rs1, imm } => Op::Lh { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Lw { rd, rs1, imm } => Op::Lw { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Ld { rd, rs1, imm } => Op::Ld { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Lbu { rd, rs1, imm } => Op::Lbu { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Lhu { rd, rs1, imm } => Op::Lhu { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Lwu { rd, rs1, imm } => Op::Lwu { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64 },
+            I::Sb { rs1, rs2, imm } => Op::Store { rs1: r(rs1), rs2: r(rs2), imm: imm as i64 as u64, size: 8 },
+            I::Sh { rs1, rs2, imm } => Op::Store { rs1: r(rs1), rs2: r(rs2), imm: imm as i64 as u64, size: 16 },
+            I::Sw { rs1, rs2, imm } => Op::Store { rs1: r(rs1), rs2: r(rs2), imm: imm as i64 as u64, size: 32 },
+            I::Sd { rs1, rs2, imm } => Op::Store { rs1: r(rs1), rs2: r(rs2), imm: imm as i64 as u64, size: 64 },
+
+            instruction => {
+                let target = |imm: i32| pc.wrapping_add(imm as i64 as u64);
+                let op = match instruction {
+                    // The interpreter raises the exception or jumps, as the policy says
+                    I::Beq { imm, .. } | I::Bne { imm, .. } | I::Blt { imm, .. } | I::Bge { imm, .. }
+                    | I::Bltu { imm, .. } | I::Bgeu { imm, .. } | I::Jal { imm, .. }
+                        if !target(imm).is_multiple_of(4) =>
+                    {
+                        Op::Interpret { inst, instruction }
+                    }
+                    I::Beq { rs1, rs2, imm } => Op::Beq { rs1: r(rs1), rs2: r(rs2), target: target(imm) },
+                    I::Bne { rs1, rs2, imm } => Op::Bne { rs1: r(rs1), rs2: r(rs2), target: target(imm) },
+                    I::Blt { rs1, rs2, imm } => Op::Blt { rs1: r(rs1), rs2: r(rs2), target: target(imm) },
+                    I::Bge { rs1, rs2, imm } => Op::Bge { rs1: r(rs1), rs2: r(rs2), target: target(imm) },
+                    I::Bltu { rs1, rs2, imm } => Op::Bltu { rs1: r(rs1), rs2: r(rs2), target: target(imm) },
+                    I::Bgeu { rs1, rs2, imm } => Op::Bgeu { rs1: r(rs1), rs2: r(rs2), target: target(imm) },
+                    I::Jal { rd, imm } => Op::Jal { rd: r(rd), link: pc + 4, target: target(imm) },
+                    I::Jalr { rd, rs1, imm } => Op::Jalr { rd: r(rd), rs1: r(rs1), imm: imm as i64 as u64, link: pc + 4 },
+                    I::FenceI => Op::FenceI,
+                    instruction => Op::Interpret { inst, instruction },
+                };
+                return (op, true);
+            }
+        };
+        (op, false)
+    }
+}
+
+// How the run of a block ended, pc is where the hart goes on
+enum End {
+    // Through the block's link, 0 for a jump or a branch taken and 1 for falling through
+    Link(usize),
+    // Through an indirect jump, or to the instruction after the limit
+    Jump,
+    // After an interpreted instruction, or a store that changed the code or stopped the machine:
+    // the caller checks what changed before going on
+    Leave,
+    // FENCE.I, the blocks are dropped
+    Flush,
+    // The instruction at the address raised the exception, pc is past it as after a fetch
+    Trap(u64, Exception),
+}
+
+pub(crate) struct Block {
+    pc: u64,
+    // Generation of the page when the block was translated
+    generation: u32,
+    ops: Box<[Op]>,
+    // The instructions the ops come from
+    insts: Box<[u32]>,
+    // Blocks at the end of the links, NO_LINK until the hart went there once
+    links: [u32; 2],
+    // Runs until the block is compiled, and its code once it is
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    runs: u32,
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    code: Option<Code>,
+}
+
+impl Block {
+    fn end(&self) -> u64 {
+        self.pc + 4 * self.ops.len() as u64
+    }
+
+    // Translate the instructions from `pc` until a block end, the end of the page or a fetch that
+    // fails. None when pc is not in DRAM or its instruction cannot be fetched.
+    fn translate(bus: &Bus, pc: u64) -> Option<Block> {
+        // Every instruction is watched before it is read, a later store bumps the generation
+        let generation = bus.watch_code(pc)?;
+        let (mut ops, mut insts) = (Vec::new(), Vec::new());
+        let mut addr = pc;
+        loop {
+            if bus.watch_code(addr).is_none() {
+                break;
+            }
+            let Ok(inst) = bus.fetch(addr) else { break };
+            let (op, last) = Op::translate(inst as u32, decode(inst as u32), addr);
+            ops.push(op);
+            insts.push(inst as u32);
+            addr += 4;
+            if last || addr.is_multiple_of(PAGE_SIZE) || ops.len() == MAX_OPS {
+                break;
+            }
+        }
+        if ops.is_empty() {
+            return None;
+        }
+        Some(Block {
+            pc,
+            generation,
+            ops: ops.into_boxed_slice(),
+            insts: insts.into_boxed_slice(),
+            links: [NO_LINK; 2],
+            #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+            runs: 0,
+            #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+            code: None,
+        })
+    }
+
+    // New generation of the page when the instructions of the block are still the same, they are
+    // watched again first
+    fn unchanged(&self, bus: &Bus) -> Option<u32> {
+        let generation = bus.watch_code(self.pc)?;
+        for (i, &inst) in self.insts.iter().enumerate() {
+            let addr = self.pc + 4 * i as u64;
+            bus.watch_code(addr)?;
+            if bus.fetch(addr).ok()? as u32 != inst {
+                return None;
+            }
+        }
+        Some(generation)
+    }
+
+    // Whether the hart has to leave the block after a store of `size` bits at `addr`: the store
+    // changed the code of the block, or stopped the machine
+    pub(crate) fn stored(&self, bus: &Bus, addr: u64, size: u64) -> bool {
+        (addr < self.end() && self.pc < addr + size / 8) || bus.guest_exit().is_some()
+    }
+
+    // Run the ops from `start` up to `limit`, returns how many ran from the first one and how
+    // the block ended
+    fn run(&self, cpu: &mut Cpu, bus: &Bus, start: usize, limit: usize) -> (usize, End) {
+        let load = |cpu: &Cpu, rs1: u8, imm: u64, size: u64| bus.load(cpu.regs[rs1 as usize].wrapping_add(imm), size);
+
+        for (i, op) in self.ops[..limit].iter().enumerate().skip(start) {
+            let pc = self.pc + 4 * i as u64;
+            let x = &mut cpu.regs;
+            match *op {
+                Op::Li { rd, imm } => x[rd as usize] = imm,
+                Op::Addi { rd, rs1, imm } => x[rd as usize] = x[rs1 as usize].wrapping_add(imm),
+                Op::Slti { rd, rs1, imm } => x[rd as usize] = ((x[rs1 as usize] as i64) < (imm as i64)) as u64,
+                Op::Sltiu { rd, rs1, imm } => x[rd as usize] = (x[rs1 as usize] < imm) as u64,
+                Op::Xori { rd, rs1, imm } => x[rd as usize] = x[rs1 as usize] ^ imm,
+                Op::Ori { rd, rs1, imm } => x[rd as usize] = x[rs1 as usize] | imm,
+                Op::Andi { rd, rs1, imm } => x[rd as usize] = x[rs1 as usize] & imm,
+                Op::Slli { rd, rs1, shamt } => x[rd as usize] = x[rs1 as usize].wrapping_shl(shamt),
+                Op::Srli { rd, rs1, shamt } => x[rd as usize] = x[rs1 as usize].wrapping_shr(shamt),
+                Op::Srai { rd, rs1, shamt } => x[rd as usize] = (x[rs1 as usize] as i64).wrapping_shr(shamt) as u64,
+                Op::Addiw { rd, rs1, imm } => x[rd as usize] = x[rs1 as usize].wrapping_add(imm) as i32 as i64 as u64,
+                Op::Slliw { rd, rs1, shamt } => x[rd as usize] = x[rs1 as usize].wrapping_shl(shamt) as i32 as i64 as u64,
+                Op::Srliw { rd, rs1, shamt } => {
+                    x[rd as usize] = (x[rs1 as usize] as u32).wrapping_shr(shamt) as i32 as i64 as u64
+                }
+                Op::Sraiw { rd, rs1, shamt } => x[rd as usize] = (x[rs1 as usize] as i32).wrapping_shr(shamt) as i64 as u64,
+
+                Op::Add { rd, rs1, rs2 } => x[rd as usize] = x[rs1 as usize].wrapping_add(x[rs2 as usize]),
+                Op::Sub { rd, rs1, rs2 } => x[rd as usize] = x[rs1 as usize].wrapping_sub(x[rs2 as usize]),
+                Op::Sll { rd, rs1, rs2 } => x[rd as usize] = x[rs1 as usize].wrapping_shl((x[rs2 as usize] & 0x3f) as u32),
+                Op::Slt { rd, rs1, rs2 } => x[rd as usize] = ((x[rs1 as usize] as i64) < (x[rs2 as usize] as i64)) as u64,
+                Op::Sltu { rd, rs1, rs2 } => x[rd as usize] = (x[rs1 as usize] < x[rs2 as usize]) as u64,
+                Op::Xor { rd, rs1, rs2 } => x[rd as usize] = x[rs1 as usize] ^ x[rs2 as usize],
+                Op::Srl { rd, rs1, rs2 } => x[rd as usize] = x[rs1 as usize].wrapping_shr((x[rs2 as usize] & 0x3f) as u32),
+                Op::Sra { rd, rs1, rs2 } => {
+                    x[rd as usize] = (x[rs1 as usize] as i64).wrapping_shr((x[rs2 as usize] & 0x3f) as u32) as u64
+                }
+                Op::Or { rd, rs1, rs2 } => x[rd as usize] = x[rs1 as usize] | x[rs2 as usize],
+                Op::And { rd, rs1, rs2 } => x[rd as usize] = x[rs1 as usize] & x[rs2 as usize],
+                Op::Addw { rd, rs1, rs2 } => {
+                    x[rd as usize] = x[rs1 as usize].wrapping_add(x[rs2 as usize]) as i32 as i64 as u64
+                }
+                Op::Subw { rd, rs1, rs2 } => x[rd as usize] = x[rs1 as usize].wrapping_sub(x[rs2 as usize]) as i32 as u64,
+                Op::Sllw { rd, rs1, rs2 } => {
+                    x[rd as usize] = (x[rs1 as usize] as u32).wrapping_shl((x[rs2 as usize] & 0x3f) as u32) as i32 as u64
+                }
+                Op::Srlw { rd, rs1, rs2 } => {
+                    x[rd as usize] = (x[rs1 as usize] as u32).wrapping_shr((x[rs2 as usize] & 0x3f) as u32) as i32 as u64
+                }
+                Op::Sraw { rd, rs1, rs2 } => x[rd as usize] = ((x[rs1 as usize] as i32) >> (x[rs2 as usize] & 0x1f)) as u64,
+
+                Op::Lb { rd, rs1, imm } | Op::Lh { rd, rs1, imm } | Op::Lw { rd, rs1, imm } | Op::Ld { rd, rs1, imm }
+                | Op::Lbu { rd, rs1, imm } | Op::Lhu { rd, rs1, imm } | Op::Lwu { rd, rs1, imm } => {
+                    let value = match *op {
+                        Op::Lb { .. } => load(cpu, rs1, imm, 8).map(|v| v as i8 as i64 as u64),
+                        Op::Lh { .. } => load(cpu, rs1, imm, 16).map(|v| v as i16 as i64 as u64),
+                        Op::Lw { .. } => load(cpu, rs1, imm, 32).map(|v| v as i32 as i64 as u64),
+                        Op::Ld { .. } => load(cpu, rs1, imm, 64),
+                        Op::Lbu { .. } => load(cpu, rs1, imm, 8),
+                        Op::Lhu { .. } => load(cpu, rs1, imm, 16),
+                        _ => load(cpu, rs1, imm, 32),
+                    };
+                    match value {
+                        Ok(value) => {
+                            cpu.regs[rd as usize] = value;
+                            cpu.regs[0] = 0;
+                        }
+                        Err(exception) => return trap(cpu, pc, i, exception),
+                    }
+                }
+                Op::Store { rs1, rs2, imm, size } => {
+                    let addr = x[rs1 as usize].wrapping_add(imm);
+                    if let Err(exception) = bus.store(addr, size as u64, x[rs2 as usize]) {
+                        return trap(cpu, pc, i, exception);
+                    }
+                    if self.stored(bus, addr, size as u64) {
+                        cpu.pc = pc + 4;
+                        return (i + 1, End::Leave);
+                    }
+                }
+                Op::Nop => {}
+
+                Op::Beq { rs1, rs2, target }
+                | Op::Bne { rs1, rs2, target }
+                | Op::Blt { rs1, rs2, target }
+                | Op::Bge { rs1, rs2, target }
+                | Op::Bltu { rs1, rs2, target }
+                | Op::Bgeu { rs1, rs2, target } => {
+                    let (a, b) = (x[rs1 as usize], x[rs2 as usize]);
+                    let taken = match *op {
+                        Op::Beq { .. } => a == b,
+                        Op::Bne { .. } => a != b,
+                        Op::Blt { .. } => (a as i64) < (b as i64),
+                        Op::Bge { .. } => (a as i64) >= (b as i64),
+                        Op::Bltu { .. } => a < b,
+                        _ => a >= b,
+                    };
+                    cpu.pc = if taken { target } else { self.end() };
+                    return (i + 1, End::Link(if taken { 0 } else { 1 }));
+                }
+                Op::Jal { rd, link, target } => {
+                    x[rd as usize] = link;
+                    x[0] = 0;
+                    cpu.pc = target;
+                    return (i + 1, End::Link(0));
+                }
+                Op::Jalr { rd, rs1, imm, link } => {
+                    let target = x[rs1 as usize].wrapping_add(imm) & !1;
+                    if bus.misaligned.fetch == Misaligned::Trap && !target.is_multiple_of(4) {
+                        return trap(cpu, pc, i, Exception::InstructionAddressMisaligned(target));
+                    }
+                    cpu.pc = target;
+                    x[rd as usize] = link;
+                    x[0] = 0;
+                    return (i + 1, End::Jump);
+                }
+                Op::FenceI => {
+                    cpu.pc = pc + 4;
+                    return (i + 1, End::Flush);
+                }
+                Op::Interpret { inst, instruction } => {
+                    cpu.pc = pc + 4;
+                    return match cpu.execute_decoded(bus, inst, instruction) {
+                        Ok(()) => (i + 1, End::Leave),
+                        Err(exception) => (i + 1, End::Trap(pc, exception)),
+                    };
+                }
+            }
+        }
+
+        // Out of ops: the block went past its last instruction, or stopped at the limit
+        if limit == self.ops.len() {
+            cpu.pc = self.end();
+            (limit, End::Link(1))
+        } else {
+            cpu.pc = self.pc + 4 * limit as u64;
+            (limit, End::Jump)
+        }
+    }
+}
+
+fn trap(cpu: &mut Cpu, pc: u64, i: usize, exception: Exception) -> (usize, End) {
+    cpu.pc = pc + 4;
+    (i + 1, End::Trap(pc, exception))
+}
+
+/// Translated blocks of a hart, keyed by physical address
+pub struct BlockCache {
+    /// Run every instruction through `Cpu::step` when cleared
+    pub enabled: bool,
+    /// Compile the blocks that run often to native code, without effect unless the jit feature
+    /// is enabled on an x86-64 Linux host
+    pub jit: bool,
+    blocks: Vec<Block>,
+    index: HashMap<u64, u32>,
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    code: Jit,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            jit: true,
+            blocks: Vec::new(),
+            index: HashMap::new(),
+            #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+            code: Jit::default(),
+        }
+    }
+}
+
+impl BlockCache {
+    /// Drop every block, for FENCE.I
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.index.clear();
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        self.code.reset();
+    }
+
+    // Whether the blocks have to be dropped to make room for a new one
+    fn full(&self) -> bool {
+        #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+        if self.code.full() {
+            return true;
+        }
+        self.blocks.len() == MAX_BLOCKS
+    }
+
+    // Index of an up to date block at pc, translated if needed. `from` is the block and link the
+    // hart came through, followed without a lookup when it was linked before.
+    fn block(&mut self, bus: &Bus, pc: u64, from: Option<(u32, usize)>) -> Option<u32> {
+        if let Some((block, link)) = from {
+            let index = self.blocks[block as usize].links[link];
+            if index != NO_LINK {
+                let target = &self.blocks[index as usize];
+                if target.generation == bus.code_generation(pc) {
+                    return Some(index);
+                }
+            }
+        }
+
+        let index = match self.index.get(&pc) {
+            Some(&index) if self.blocks[index as usize].generation == bus.code_generation(pc) => index,
+            // The page changed, often through data next to the code. A block whose own code
+            // changed is translated again in place, so that the links to it stay valid.
+            Some(&index) => {
+                let block = &mut self.blocks[index as usize];
+                match block.unchanged(bus) {
+                    Some(generation) => block.generation = generation,
+                    None => *block = Block::translate(bus, pc)?,
+                }
+                index
+            }
+            None => {
+                if !pc.is_multiple_of(4) {
+                    return None;
+                }
+                let block = Block::translate(bus, pc)?;
+                // The links of the block the hart came from go away with the others
+                if self.full() {
+                    self.flush();
+                    return Some(self.push(pc, block));
+                }
+                self.push(pc, block)
+            }
+        };
+        if let Some((block, link)) = from {
+            if let Some(block) = self.blocks.get_mut(block as usize) {
+                block.links[link] = index;
+            }
+        }
+        Some(index)
+    }
+
+    // Run a block, compiled once it is hot and only when it runs to its end
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    fn execute(&mut self, index: u32, cpu: &mut Cpu, bus: &Bus, limit: usize) -> (usize, End) {
+        let block = &mut self.blocks[index as usize];
+        if !self.jit || limit < block.ops.len() {
+            return block.run(cpu, bus, 0, limit);
+        }
+        if block.runs < jit::THRESHOLD {
+            block.runs += 1;
+            if block.runs == jit::THRESHOLD {
+                block.code = self.code.compile(block.pc, &block.ops);
+            }
+        }
+        let Some(code) = block.code else {
+            return block.run(cpu, bus, 0, limit);
+        };
+
+        let block = &self.blocks[index as usize];
+        let mut context = Context { pc: 0, bus, block, exception: None };
+        let (ran, end) = code.call(&mut cpu.regs, &mut context);
+        match end {
+            jit::TAKEN | jit::NEXT | jit::JUMP => {
+                cpu.pc = context.pc;
+                let end = match end {
+                    jit::TAKEN => End::Link(0),
+                    jit::NEXT => End::Link(1),
+                    _ => End::Jump,
+                };
+                (ran, end)
+            }
+            jit::LEAVE => {
+                cpu.pc = block.pc + 4 * ran as u64;
+                (ran, End::Leave)
+            }
+            jit::TRAP => trap(cpu, block.pc + 4 * (ran - 1) as u64, ran - 1, context.exception.unwrap()),
+            _ => block.run(cpu, bus, ran, limit),
+        }
+    }
+
+    #[cfg(not(all(feature = "jit", target_arch = "x86_64", target_os = "linux")))]
+    fn execute(&mut self, index: u32, cpu: &mut Cpu, bus: &Bus, limit: usize) -> (usize, End) {
+        self.blocks[index as usize].run(cpu, bus, 0, limit)
+    }
+
+    // Number of blocks that have code
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    #[cfg(test)]
+    pub(crate) fn compiled(&self) -> usize {
+        self.blocks.iter().filter(|block| block.code.is_some()).count()
+    }
+
+    fn push(&mut self, pc: u64, block: Block) -> u32 {
+        let index = self.blocks.len() as u32;
+        self.blocks.push(block);
+        self.index.insert(pc, index);
+        index
+    }
+
+    /// Run translated blocks from pc for at most `budget` instructions, see `Cpu::run_blocks`
+    pub(crate) fn run(&mut self, cpu: &mut Cpu, bus: &Bus, budget: u64) -> (u64, Result<(), Exception>) {
+        let mut count = 0;
+        let mut from = None;
+        while count < budget {
+            if cpu.take_interrupt(bus).is_some() {
+                from = None;
+            }
+            let Some(index) = self.block(bus, cpu.pc, from) else {
+                break;
+            };
+            let limit = self.blocks[index as usize].ops.len().min((budget - count) as usize);
+            let (ran, end) = self.execute(index, cpu, bus, limit);
+            count += ran as u64;
+            from = None;
+            match end {
+                End::Link(link) => from = Some((index, link)),
+                End::Jump => {}
+                End::Leave => break,
+                End::Flush => {
+                    self.flush();
+                    cpu.icache.flush();
+                }
+                End::Trap(pc, exception) => {
+                    if let Err(exception) = cpu.raise(bus, exception, pc) {
+                        return (count, Err(exception));
+                    }
+                }
+            }
+        }
+        (count, Ok(()))
+    }
+}
+
+#[test]
+fn test_blocks_match_interpreter() {
+    use crate::asm::assemble;
+    use crate::bus::DRAM_BASE;
+    use crate::machine::{Exit, Machine};
+
+    // Every kind of micro-op, calls, a trap handled by the guest and a store to the code
+    let source = "
+            la t0, trap
+            csrw mtvec, t0
+            li s0, 0
+            li s1, 40
+            la s2, data
+        loop:
+            addi s0, s0, 1
+            slli t1, s0, 61
+            srai t2, t1, 3
+            srli t3, t1, 7
+            sraiw t4, t2, 5
+            srliw t5, t1, 2
+            slliw t6, s0, 30
+            addiw a0, t6, -2048
+            sub a1, t2, s0
+            sll a2, s0, t5
+            srl a3, t2, s0
+            sra a4, t2, s0
+            slt a5, t2, s0
+            sltu a6, t2, s0
+            slti a7, a1, -5
+            sltiu s3, s0, 7
+            xori s4, a1, -1
+            ori s5, s0, 0x555
+            andi s6, a1, 0x7f0
+            xor s7, s4, s5
+            or s8, s6, s7
+            and s9, s8, a1
+            addw s10, t6, t6
+            subw s11, a0, t6
+            sllw t0, s0, s0
+            srlw t1, a4, s0
+            sraw t2, a4, s0
+            lui t3, 0xfffff
+            auipc t4, 0x10
+            sd a1, 0(s2)
+            sw s0, 8(s2)
+            sh a4, 12(s2)
+            sb a0, 14(s2)
+            ld t5, 0(s2)
+            lw t6, 4(s2)
+            lwu a0, 8(s2)
+            lh a2, 2(s2)
+            lhu a3, 12(s2)
+            lb a4, 14(s2)
+            lbu a5, 1(s2)
+            lw zero, 0(s2)
+            call square
+            ecall
+            la t0, patched
+            li t1, 0x00100593
+            sw t1, 0(t0)
+        patched:
+            li a1, 2
+            beq s0, a1, 1f
+            bge s0, s1, done
+            bltu s0, s1, loop
+        1:  bne s0, zero, loop
+        done:
+            j done
+
+        square:
+            mv t0, a0
+            li a0, 0
+        2:  add a0, a0, t0
+            addi t0, t0, -1
+            blt zero, t0, 2b
+            ret
+
+        trap:
+            csrr t0, mepc
+            addi t0, t0, 4
+            csrw mepc, t0
+            mret
+
+        data:
+            .dword 0
+            .dword 0
+    ";
+    let code = assemble(source, DRAM_BASE).unwrap();
+    for max in (1..3000).step_by(37).chain([8000]) {
+        let machine = || Machine::builder().memory(DRAM_BASE, 0x10000).program(code.clone()).build().unwrap();
+        let (mut blocks, mut steps) = (machine(), machine());
+        steps.harts[0].blocks.enabled = false;
+        assert_eq!(blocks.run(Some(max)), Exit::Limit);
+        assert_eq!(steps.run(Some(max)), Exit::Limit);
+        assert_eq!(blocks.harts[0].pc, steps.harts[0].pc, "after {} instructions", max);
+        assert_eq!(blocks.harts[0].regs, steps.harts[0].regs, "after {} instructions", max);
+    }
+}
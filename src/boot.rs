@@ -0,0 +1,182 @@
+use std::io;
+
+use crate::config::MachineConfig;
+use crate::cpu::*;
+use crate::csr::MHARTID;
+use crate::devicetree;
+use crate::elf::*;
+use crate::machine::Machine;
+use crate::sbi;
+
+// Header at the start of a RISC-V Linux `Image`, see Documentation/riscv/boot-image-header.rst
+const IMAGE_HEADER_SIZE: usize = 64;
+// "RSC\x05" at offset 56, the "RISCV" magic at offset 48 is deprecated
+const IMAGE_MAGIC2: u32 = 0x0543_5352;
+
+// Raw kernels are placed like an `Image` with the usual text_offset when there is a firmware
+// below them, that is where fw_jump jumps to
+const DEFAULT_TEXT_OFFSET: u64 = 0x20_0000;
+
+// The device tree goes to the top of DRAM, the fw_dynamic info and the initramfs below it
+const DTB_MAX_SIZE: u64 = 0x1_0000;
+const PAGE_SIZE: u64 = 0x1000;
+
+// struct fw_dynamic_info of OpenSBI, see include/sbi/fw_dynamic.h
+const FW_DYNAMIC_INFO_MAGIC: u64 = 0x4942_534f;
+const FW_DYNAMIC_INFO_VERSION: u64 = 2;
+const FW_DYNAMIC_INFO_NEXT_MODE_S: u64 = 1;
+
+pub struct ImageHeader {
+    pub text_offset: u64,
+    pub image_size: u64,
+}
+
+impl ImageHeader {
+    /// None when the file does not start with an `Image` header
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < IMAGE_HEADER_SIZE {
+            return None;
+        }
+        let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+        let magic2 = u32::from_le_bytes(data[56..60].try_into().unwrap());
+        if magic2 != IMAGE_MAGIC2 {
+            return None;
+        }
+        Some(Self {
+            text_offset: u64_at(8),
+            image_size: u64_at(16),
+        })
+    }
+}
+
+/// What to boot: a kernel (`Image`, ELF or raw binary), an optional M-mode firmware such as
+/// OpenSBI fw_jump or fw_dynamic, an optional initramfs and the kernel command line
+pub struct BootOptions {
+    pub kernel: Vec<u8>,
+    pub firmware: Option<Vec<u8>>,
+    pub initrd: Option<Vec<u8>>,
+    pub bootargs: Option<String>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+// Copy a buffer into guest memory
+fn write_memory(machine: &mut Machine, addr: u64, data: &[u8]) -> io::Result<()> {
+    machine.bus.write_bytes(addr, data)
+        .map_err(|exception| invalid(format!("{:#x} is outside of DRAM", exception.value())))
+}
+
+// Load an ELF or a raw binary at `base`, returns the entry and the end of the loaded image
+fn load_image(machine: &mut Machine, data: &[u8], base: u64) -> io::Result<(u64, u64)> {
+    if is_elf(data) {
+        let elf = Elf::parse(data)?;
+        machine.load_elf(&elf).map_err(|e| invalid(format!("cannot load ELF segment: {:?}", e)))?;
+        let end = elf.segments.iter().map(|s| s.addr + s.mem_size).max().unwrap_or(elf.entry);
+        Ok((elf.entry, end))
+    } else {
+        write_memory(machine, base, data)?;
+        Ok((base, base + data.len() as u64))
+    }
+}
+
+/// Set up memory and registers for the boot flow of QEMU virt: the firmware, if any, runs in
+/// M-mode from the start of DRAM on every hart with a0 = hartid, a1 = device tree and a2 =
+/// fw_dynamic info, fw_jump ignores a2 and jumps to the fixed kernel address. Without a firmware
+/// the kernel is entered in S-mode directly on hart 0 and its SBI calls are served natively.
+/// The memory layout and the harts come from `config`.
+pub fn boot(options: &BootOptions, config: &MachineConfig) -> io::Result<Machine> {
+    config.validate().map_err(invalid)?;
+    let mut machine = Machine::with_config(config, Vec::new())?;
+    let dram_base = config.memory_base;
+
+    let firmware_end = match &options.firmware {
+        Some(firmware) => Some(load_image(&mut machine, firmware, dram_base)?),
+        None => None,
+    };
+
+    let (kernel_entry, kernel_end) = match ImageHeader::parse(&options.kernel) {
+        Some(header) => {
+            let size = header.image_size.max(options.kernel.len() as u64);
+            let addr = dram_base.checked_add(header.text_offset)
+                .filter(|addr| addr.checked_add(size).is_some())
+                .ok_or_else(|| invalid(format!("Image text_offset {:#x} is outside of DRAM", header.text_offset)))?;
+            write_memory(&mut machine, addr, &options.kernel)?;
+            (addr, addr + size)
+        }
+        None => {
+            let base = if firmware_end.is_some() { dram_base + DEFAULT_TEXT_OFFSET } else { dram_base };
+            load_image(&mut machine, &options.kernel, base)?
+        }
+    };
+    if let Some((_, end)) = firmware_end {
+        if end > kernel_entry {
+            return Err(invalid(format!("firmware ends at {:#x}, above the kernel at {:#x}", end, kernel_entry)));
+        }
+    }
+
+    let info_addr = config.memory_size.checked_sub(DTB_MAX_SIZE + PAGE_SIZE)
+        .map(|offset| dram_base + offset)
+        .ok_or_else(|| invalid(format!("{} bytes of DRAM leave no room for the device tree", config.memory_size)))?;
+    let dtb_addr = info_addr + PAGE_SIZE;
+    if kernel_end > info_addr {
+        return Err(invalid(format!("kernel ends at {:#x}, above the device tree at {:#x}", kernel_end, info_addr)));
+    }
+
+    let initrd = match &options.initrd {
+        Some(initrd) => {
+            let start = info_addr.checked_sub(initrd.len() as u64)
+                .map(|start| start & !(PAGE_SIZE - 1))
+                .filter(|&start| start >= kernel_end)
+                .ok_or_else(|| invalid(format!("initramfs of {} bytes does not fit above the kernel", initrd.len())))?;
+            write_memory(&mut machine, start, initrd)?;
+            Some((start, start + initrd.len() as u64))
+        }
+        None => None,
+    };
+
+    let dtb = devicetree::machine_dtb(config, options.bootargs.as_deref(), initrd);
+    if dtb.len() as u64 > DTB_MAX_SIZE {
+        return Err(invalid(format!("device tree of {} bytes is too large", dtb.len())));
+    }
+    write_memory(&mut machine, dtb_addr, &dtb)?;
+
+    match firmware_end {
+        Some((firmware_entry, _)) => {
+            let info = [
+                FW_DYNAMIC_INFO_MAGIC,
+                FW_DYNAMIC_INFO_VERSION,
+                kernel_entry,
+                FW_DYNAMIC_INFO_NEXT_MODE_S,
+                0,
+                0,
+            ];
+            let info: Vec<u8> = info.iter().flat_map(|v| v.to_le_bytes()).collect();
+            write_memory(&mut machine, info_addr, &info)?;
+
+            for hart in &mut machine.harts {
+                hart.mode = Mode::Machine;
+                hart.pc = firmware_entry;
+                hart.regs[10] = hart.csr.load(MHARTID);
+                hart.regs[11] = dtb_addr;
+                hart.regs[12] = info_addr;
+            }
+        }
+        None => sbi::boot(&mut machine, kernel_entry, dtb_addr),
+    }
+    Ok(machine)
+}
+
+#[test]
+fn test_image_header() {
+    let mut image = vec![0; IMAGE_HEADER_SIZE];
+    image[8..16].copy_from_slice(&0x20_0000u64.to_le_bytes());
+    image[16..24].copy_from_slice(&0x1_0000u64.to_le_bytes());
+    assert!(ImageHeader::parse(&image).is_none());
+
+    image[56..60].copy_from_slice(b"RSC\x05");
+    let header = ImageHeader::parse(&image).unwrap();
+    assert_eq!(header.text_offset, 0x20_0000);
+    assert_eq!(header.image_size, 0x1_0000);
+}
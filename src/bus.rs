@@ -1,34 +1,507 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use crate::clint::*;
+use crate::config::{MachineConfig, Misaligned, MisalignedPolicy};
 use crate::dram::*;
+use crate::exception::Exception;
+use crate::finisher::*;
+use crate::htif::*;
+use crate::machine::Exit;
+use crate::uart::*;
 
-// Dram start address, same as QEMU
+/// Default DRAM start address, same as QEMU
 pub const DRAM_BASE: u64 = 0x8000_0000;
 
-// Bus
+// No reservation, LR reservations are 8-byte aligned
+const NO_RESERVATION: u64 = u64::MAX;
+
+/// Accesses a debugger watchpoint stops on
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub addr: u64,
+    pub len: u64,
+    pub kind: WatchKind,
+}
+
+/// A memory-mapped device. `addr` is the bus address of the access and `size` its width in
+/// bits; unsupported accesses return a load or store access fault for the guest. Harts may run
+/// on their own threads, devices keep their registers in atomics or behind a lock.
+pub trait Device: Send + Sync {
+    fn load(&self, addr: u64, size: u64) -> Result<u64, Exception>;
+    fn store(&self, addr: u64, size: u64, value: u64) -> Result<(), Exception>;
+}
+
+// A device added with `Bus::attach`
+struct Mapping {
+    base: u64,
+    size: u64,
+    device: Box<dyn Device>,
+}
+
+/// DRAM, the CLINT, the UART, the test finisher, HTIF and the devices attached by embedders.
+/// The bus is shared by harts running on other threads: all accesses go through `&self`.
 pub struct Bus {
-    dram: Dram,
+    pub(crate) dram: Dram,
+    htif: Option<Htif>,
+    pub clint: Clint,
+    pub uart: Uart,
+    pub finisher: Finisher,
+    // The first exit the guest asked for through the exit syscall or semihosting
+    exit: OnceLock<Exit>,
+    devices: Vec<Mapping>,
+    // Reservation set of each hart, the 8-byte word its last LR read
+    reservations: Vec<AtomicU64>,
+    /// Set by the debugger while the machine is stopped, the first load or store that touches
+    /// one of them is recorded for the debugger to report
+    pub watchpoints: Vec<Watchpoint>,
+    watch_hit: Mutex<Option<Watchpoint>>,
+    /// What loads, stores and atomics that are not naturally aligned do, the harts check their
+    /// jumps against `fetch`
+    pub misaligned: MisalignedPolicy,
+}
+
+// Whether an access of `size` bits at `addr` spans two 8-byte words, atomics cannot
+fn crosses_word(addr: u64, size: u64) -> bool {
+    addr % 8 + size / 8 > 8
+}
+
+// Raise `exception` for an access of `size` bits at `addr` that is not naturally aligned, when
+// `policy` traps
+fn check_alignment(addr: u64, size: u64, policy: Misaligned, exception: fn(u64) -> Exception) -> Result<(), Exception> {
+    if policy == Misaligned::Trap && size > 8 && !addr.is_multiple_of(size / 8) {
+        return Err(exception(addr));
+    }
+    Ok(())
 }
 
 impl Bus {
-    pub fn new(code: Vec<u8>) -> Self {
-        Self {
-            dram: Dram::new(code)
+    /// Memory and devices laid out as `config` says, `code` is loaded at the start of DRAM. An
+    /// error when the memory cannot be allocated or `code` does not fit in it.
+    pub fn new(config: &MachineConfig, code: Vec<u8>) -> io::Result<Self> {
+        let harts = config.harts;
+        Ok(Self {
+            dram: Dram::new(config.memory_base, config.memory_size, code)?,
+            htif: None,
+            clint: Clint::new(config.clint_base, harts),
+            uart: Uart::new(config.uart_base),
+            finisher: Finisher::new(config.finisher_base),
+            exit: OnceLock::new(),
+            devices: Vec::new(),
+            reservations: (0..harts).map(|_| AtomicU64::new(NO_RESERVATION)).collect(),
+            watchpoints: Vec::new(),
+            watch_hit: Mutex::new(None),
+            misaligned: config.misaligned,
+        })
+    }
+
+    /// Start address of DRAM
+    pub fn dram_base(&self) -> u64 {
+        self.dram.base()
+    }
+
+    /// DRAM size in bytes
+    pub fn dram_size(&self) -> u64 {
+        self.dram.size()
+    }
+
+    /// Map `device` at `base..base + size`, in front of DRAM for loads and stores; atomics only
+    /// reach DRAM. The CLINT, the UART and the test finisher come first.
+    pub fn attach(&mut self, base: u64, size: u64, device: Box<dyn Device>) {
+        self.devices.push(Mapping { base, size, device });
+    }
+
+    // Device attached at `addr`, if any
+    fn device(&self, addr: u64) -> Option<&dyn Device> {
+        let mapping = self.devices.iter().find(|m| addr.wrapping_sub(m.base) < m.size)?;
+        Some(mapping.device.as_ref())
+    }
+
+    /// Watch `tohost` for HTIF commands, the addresses come from the ELF symbols
+    pub fn attach_htif(&mut self, tohost: u64, fromhost: Option<u64>) {
+        self.htif = Some(Htif::new(tohost, fromhost));
+    }
+
+    /// Addresses of `tohost` and `fromhost` when HTIF is attached
+    pub fn htif_addresses(&self) -> Option<(u64, Option<u64>)> {
+        self.htif.as_ref().map(|htif| (htif.tohost, htif.fromhost))
+    }
+
+    /// Exit code the guest passed through HTIF, if it has exited
+    pub fn htif_exit_code(&self) -> Option<u64> {
+        self.htif.as_ref().and_then(|htif| htif.exit_code.get().copied())
+    }
+
+    /// Record that the guest exits, the first request wins
+    pub fn request_exit(&self, exit: Exit) {
+        let _ = self.exit.set(exit);
+    }
+
+    /// How the guest asked to stop through HTIF, the test finisher, the exit syscall or
+    /// semihosting, if it has
+    pub fn guest_exit(&self) -> Option<Exit> {
+        if let Some(code) = self.htif_exit_code() {
+            return Some(Exit::Htif(code));
+        }
+        if let Some(stop) = self.finisher.stop.get() {
+            return Some(Exit::Finisher(*stop));
+        }
+        self.exit.get().copied()
+    }
+
+    // Record the first access to a watched range
+    fn watch(&self, addr: u64, size: u64, write: bool) {
+        let hit = self.watchpoints.iter().find(|watchpoint| {
+            let kind = match watchpoint.kind {
+                WatchKind::Write => write,
+                WatchKind::Read => !write,
+                WatchKind::Access => true,
+            };
+            kind && addr < watchpoint.addr.saturating_add(watchpoint.len) && watchpoint.addr < addr.saturating_add(size / 8)
+        });
+        if let Some(watchpoint) = hit {
+            self.watch_hit.lock().unwrap().get_or_insert(*watchpoint);
+        }
+    }
+
+    /// Watchpoint hit since the last call, if any
+    pub fn take_watch_hit(&self) -> Option<Watchpoint> {
+        self.watch_hit.lock().unwrap().take()
+    }
+
+    /// Mark the instruction at `addr` as cached by a hart, see `ICache`. Returns the generation of
+    /// its page, None when the instructions there are not cached: outside of DRAM or behind a
+    /// device.
+    pub fn watch_code(&self, addr: u64) -> Option<u32> {
+        let dram = self.dram.base()..self.dram.base() + self.dram.size();
+        if !dram.contains(&addr) || self.device(addr).is_some() {
+            return None;
+        }
+        Some(self.dram.watch_code(addr))
+    }
+
+    /// Generation of a page `watch_code` accepted, stores to its cached instructions change it
+    #[inline]
+    pub fn code_generation(&self, addr: u64) -> u32 {
+        self.dram.code_generation(addr)
+    }
+
+    /// Instruction fetch, watchpoints only see data accesses
+    pub fn fetch(&self, addr: u64) -> Result<u64, Exception> {
+        self.read(addr, 32)
+    }
+
+    /// API for load memory
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        check_alignment(addr, size, self.misaligned.load, Exception::LoadAddressMisaligned)?;
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, size, false);
+        }
+        self.read(addr, size)
+    }
+
+    /// Load without the watchpoint check, the commit log reads back what an AMO stored
+    pub fn read(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if (self.clint.base..self.clint.base + CLINT_SIZE).contains(&addr) {
+            return self.clint.load(addr, size);
+        }
+        if (self.uart.base..self.uart.base + UART_SIZE).contains(&addr) {
+            return self.uart.load(addr, size);
+        }
+        if (self.finisher.base..self.finisher.base + FINISHER_SIZE).contains(&addr) {
+            return self.finisher.load(addr, size);
+        }
+        if let Some(device) = self.device(addr) {
+            return device.load(addr, size);
+        }
+
+        self.dram.load(addr, size).map_err(|_| Exception::LoadAccessFault(addr))
+    }
+
+    /// API for store memory
+    pub fn store(&self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        check_alignment(addr, size, self.misaligned.store, Exception::StoreAMOAddressMisaligned)?;
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, size, true);
         }
+        self.write(addr, size, value)
     }
 
-    // API for load memory
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, ()> {
-        if addr < DRAM_BASE {
-            return Err(());
+    // Store without the watchpoint check
+    fn write(&self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if (self.clint.base..self.clint.base + CLINT_SIZE).contains(&addr) {
+            return self.clint.store(addr, size, value);
+        }
+        if (self.uart.base..self.uart.base + UART_SIZE).contains(&addr) {
+            return self.uart.store(addr, size, value);
+        }
+        if (self.finisher.base..self.finisher.base + FINISHER_SIZE).contains(&addr) {
+            return self.finisher.store(addr, size, value);
         }
+        if let Some(device) = self.device(addr) {
+            return device.store(addr, size, value);
+        }
+
+        self.dram.store(addr, size, value).map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+        self.written(addr, size / 8)
+    }
 
-        return self.dram.load(addr, size);
+    // After a store to DRAM: drop the reservations on it and serve an HTIF command written to
+    // `tohost`
+    fn written(&self, addr: u64, len: u64) -> Result<(), Exception> {
+        if len == 0 {
+            return Ok(());
+        }
+        self.invalidate_reservations(addr, len);
+        if let Some(tohost) = self.htif.as_ref().map(|htif| htif.tohost) {
+            if addr < tohost + 8 && tohost < addr + len {
+                self.htif_command(tohost)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the `len` bytes at `addr` are all DRAM, with no device in front
+    fn plain_dram(&self, addr: u64, len: u64) -> bool {
+        let overlaps = |base: u64, size: u64| addr < base.saturating_add(size) && base < addr + len;
+        self.dram.contains(addr, len)
+            && !overlaps(self.clint.base, CLINT_SIZE)
+            && !overlaps(self.uart.base, UART_SIZE)
+            && !overlaps(self.finisher.base, FINISHER_SIZE)
+            && !self.devices.iter().any(|m| overlaps(m.base, m.size))
+    }
+
+    /// Fill `buf` with the bytes at `addr`, for loaders, devices and the services of the host.
+    /// Plain DRAM is copied a word at a time, other addresses are read byte by byte; an address
+    /// nothing answers to gives a load access fault. Watchpoints do not see it, like `read`.
+    pub fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        if self.plain_dram(addr, buf.len() as u64) {
+            return self.dram.read_bytes(addr, buf).map_err(|_| Exception::LoadAccessFault(addr));
+        }
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.read(addr.wrapping_add(i as u64), 8)? as u8;
+        }
+        Ok(())
+    }
+
+    /// Copy the DRAM at `addr` into `buf` for the debuggers without touching any device, reading
+    /// a device register can have side effects such as popping a received byte from the UART.
+    /// Anything but plain DRAM gives a load access fault.
+    pub fn peek_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<(), Exception> {
+        if !self.plain_dram(addr, buf.len() as u64) {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        self.read_bytes(addr, buf)
+    }
+
+    /// Write `data` at `addr`, the counterpart of `read_bytes` with store access faults. The
+    /// bytes before a fault are written.
+    pub fn write_bytes(&self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        if self.plain_dram(addr, data.len() as u64) {
+            self.dram.write_bytes(addr, data).map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+            return self.written(addr, data.len() as u64);
+        }
+        for (i, byte) in data.iter().enumerate() {
+            self.write(addr.wrapping_add(i as u64), 8, *byte as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Set the `len` bytes at `addr` to `value`, see `write_bytes`
+    pub fn memset(&self, addr: u64, value: u8, len: u64) -> Result<(), Exception> {
+        if self.plain_dram(addr, len) {
+            self.dram.fill(addr, value, len).map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+            return self.written(addr, len);
+        }
+        for i in 0..len {
+            self.write(addr.wrapping_add(i), 8, value as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Copy `len` bytes from `src` to `dst`, overlapping ranges are copied like memmove. See
+    /// `read_bytes` and `write_bytes` for the faults.
+    pub fn memcpy(&self, dst: u64, src: u64, len: u64) -> Result<(), Exception> {
+        let mut buffer = [0; PAGE_SIZE as usize];
+        let chunks = len.div_ceil(PAGE_SIZE);
+        for i in 0..chunks {
+            // Backwards when the destination is after the source
+            let i = if dst > src { chunks - 1 - i } else { i };
+            let start = i * PAGE_SIZE;
+            let chunk = &mut buffer[..(len - start).min(PAGE_SIZE) as usize];
+            self.read_bytes(src.wrapping_add(start), chunk)?;
+            self.write_bytes(dst.wrapping_add(start), chunk)?;
+        }
+        Ok(())
     }
 
-    // API for store memory
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), ()> {
-        if addr >= DRAM_BASE {
-            return self.dram.store(addr, size, value);
+    /// The NUL-terminated string at `addr`, without its NUL, at most `max` bytes of it. Only the
+    /// bytes up to the NUL are read, see `read_bytes` for the faults.
+    pub fn read_cstring(&self, addr: u64, max: u64) -> Result<Vec<u8>, Exception> {
+        let mut string = Vec::new();
+        let mut word = [0; 8];
+        while (string.len() as u64) < max {
+            let start = addr.wrapping_add(string.len() as u64);
+            // Up to the end of the word, so that nothing past the NUL is read
+            let len = (8 - start % 8).min(max - string.len() as u64) as usize;
+            let bytes = match self.read_bytes(start, &mut word[..len]) {
+                Ok(()) => &word[..len],
+                // A word partly outside of what the bus maps, the NUL may be before the hole
+                Err(_) => {
+                    word[0] = self.read(start, 8)? as u8;
+                    &word[..1]
+                }
+            };
+            if let Some(end) = bytes.iter().position(|&byte| byte == 0) {
+                string.extend_from_slice(&bytes[..end]);
+                return Ok(string);
+            }
+            string.extend_from_slice(bytes);
         }
-        Err(())
+        Ok(string)
     }
+
+    // Serve the command just written to `tohost`
+    fn htif_command(&self, tohost: u64) -> Result<(), Exception> {
+        let value = self.read(tohost, 64)?;
+        if value == 0 {
+            return Ok(());
+        }
+
+        match Htif::decode(value) {
+            HtifCommand::Exit(code) => {
+                if let Some(htif) = self.htif.as_ref() {
+                    let _ = htif.exit_code.set(code);
+                }
+            }
+            HtifCommand::Syscall(block) => {
+                let mut args = [0; 8];
+                for (i, arg) in args.iter_mut().enumerate() {
+                    *arg = self.read(block + 8 * i as u64, 64)?;
+                }
+                let ret = Htif::syscall(&args, |addr| self.read(addr, 8).ok().map(|b| b as u8));
+                self.dram.store(block, 64, ret).map_err(|_| Exception::StoreAMOAccessFault(block))?;
+                if let Some(fromhost) = self.htif.as_ref().and_then(|htif| htif.fromhost) {
+                    self.dram.store(fromhost, 64, 1).map_err(|_| Exception::StoreAMOAccessFault(fromhost))?;
+                }
+            }
+            HtifCommand::PutChar(c) => {
+                use std::io::Write;
+                let _ = std::io::stdout().write_all(&[c]);
+            }
+            HtifCommand::Unknown => {}
+        }
+
+        // Acknowledge the command so the guest can send the next one
+        self.dram.store(tohost, 64, 0).map_err(|_| Exception::StoreAMOAccessFault(tohost))
+    }
+
+    // A store to a reserved word makes the SC of the hart holding the reservation fail, `len`
+    // bytes were stored at `addr`
+    fn invalidate_reservations(&self, addr: u64, len: u64) {
+        let first = addr & !7;
+        let last = (addr + len - 1) & !7;
+        for reservation in &self.reservations {
+            let reserved = reservation.load(Ordering::Relaxed);
+            if (first..=last).contains(&reserved) {
+                let _ = reservation.compare_exchange(reserved, NO_RESERVATION, Ordering::AcqRel, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// LR: load a value within an 8-byte word of DRAM and register a reservation on it
+    pub fn load_reserved(&self, hart: usize, addr: u64, size: u64) -> Result<u64, Exception> {
+        check_alignment(addr, size, self.misaligned.amo, Exception::LoadAddressMisaligned)?;
+        if !matches!(size, 32 | 64) || crosses_word(addr, size) || !self.dram.contains(addr, size / 8) {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, size, false);
+        }
+        // The reservation is made before the load, a store between the two clears it
+        self.reservations[hart].store(addr & !7, Ordering::SeqCst);
+        self.dram.load(addr, size).map_err(|_| Exception::LoadAccessFault(addr))
+    }
+
+    /// SC: store if the hart still holds its reservation and memory still holds the value its LR
+    /// read, the comparison covers a store racing with this one. The reservation is dropped
+    /// either way. Returns whether the store happened.
+    pub fn store_conditional(&self, hart: usize, addr: u64, size: u64, expected: u64, value: u64) -> Result<bool, Exception> {
+        check_alignment(addr, size, self.misaligned.amo, Exception::StoreAMOAddressMisaligned)?;
+        if !matches!(size, 32 | 64) || crosses_word(addr, size) || !self.dram.contains(addr, size / 8) {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, size, true);
+        }
+        if self.reservations[hart].swap(NO_RESERVATION, Ordering::SeqCst) != addr & !7 {
+            return Ok(false);
+        }
+        let stored = self.dram
+            .compare_exchange(addr, size, expected, value)
+            .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+        if stored {
+            self.invalidate_reservations(addr, size / 8);
+        }
+        Ok(stored)
+    }
+
+    /// AMO: atomically replace the value at `addr` with `op(old)`, returns the old value. Only
+    /// DRAM supports atomics.
+    pub fn fetch_update(&self, addr: u64, size: u64, op: impl Fn(u64) -> u64) -> Result<u64, Exception> {
+        check_alignment(addr, size, self.misaligned.amo, Exception::StoreAMOAddressMisaligned)?;
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, size, true);
+        }
+        let old = self.dram.fetch_update(addr, size, op).map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+        self.invalidate_reservations(addr, size / 8);
+        Ok(old)
+    }
+}
+
+#[test]
+fn test_bulk_access() {
+    let config = MachineConfig { memory_size: 0x10000, ..MachineConfig::default() };
+    let bus = Bus::new(&config, Vec::new()).unwrap();
+    let end = DRAM_BASE + 0x10000;
+
+    // Across words and a page, the bytes around are untouched
+    let data: Vec<u8> = (1..=100).collect();
+    bus.store(DRAM_BASE + 0xfcc, 64, u64::MAX).unwrap();
+    bus.write_bytes(DRAM_BASE + 0xfcd, &data).unwrap();
+    let mut bytes = [0; 102];
+    bus.read_bytes(DRAM_BASE + 0xfcc, &mut bytes).unwrap();
+    assert_eq!((bytes[0], &bytes[1..101], bytes[101]), (0xff, &data[..], 0));
+    assert_eq!(bus.load(DRAM_BASE + 0xfcd, 32), Ok(0x0403_0201));
+
+    // memmove semantics both ways, over more than a chunk
+    bus.memset(DRAM_BASE, 0xaa, 0x3000).unwrap();
+    bus.write_bytes(DRAM_BASE + 0x2ffd, b"xyz").unwrap();
+    bus.memcpy(DRAM_BASE + 3, DRAM_BASE, 0x3000).unwrap();
+    assert_eq!(bus.load(DRAM_BASE + 0x3000, 32), Ok(u32::from_le_bytes(*b"xyz\0") as u64));
+    assert_eq!(bus.load(DRAM_BASE, 64), Ok(0xaaaa_aaaa_aaaa_aaaa));
+    bus.memcpy(DRAM_BASE, DRAM_BASE + 3, 0x3000).unwrap();
+    assert_eq!(bus.load(DRAM_BASE + 0x2ffc, 64), Ok(u64::from_le_bytes(*b"\xaaxyzxyz\0")));
+
+    // Strings stop at their NUL or at `max`, even right at the end of DRAM
+    bus.write_bytes(end - 6, b"hello\0").unwrap();
+    assert_eq!(bus.read_cstring(end - 6, 100), Ok(b"hello".to_vec()));
+    assert_eq!(bus.read_cstring(end - 6, 3), Ok(b"hel".to_vec()));
+    bus.write_bytes(end - 2, b"hi").unwrap();
+    assert_eq!(bus.read_cstring(end - 2, 100), Err(Exception::LoadAccessFault(end)));
+
+    // Ranges leaving DRAM fault instead of panicking, at the first byte outside for the stores
+    assert_eq!(bus.read_bytes(end - 4, &mut [0; 8]), Err(Exception::LoadAccessFault(end)));
+    assert_eq!(bus.write_bytes(end - 4, &[1; 8]), Err(Exception::StoreAMOAccessFault(end)));
+    assert_eq!(bus.load(end - 4, 32), Ok(0x0101_0101));
+    assert_eq!(bus.memset(u64::MAX - 1, 0, 4), Err(Exception::StoreAMOAccessFault(u64::MAX - 1)));
+    assert_eq!(bus.memcpy(DRAM_BASE, end - 8, 16), Err(Exception::LoadAccessFault(end)));
 }
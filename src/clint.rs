@@ -0,0 +1,147 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::bus::Device;
+use crate::exception::Exception;
+
+// Core-local interruptor, same default address and layout as QEMU virt and SiFive
+pub const CLINT_BASE: u64 = 0x200_0000;
+pub const CLINT_SIZE: u64 = 0x10000;
+
+// Register offsets
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+
+// Harts the register layout has room for, the last mtimecmp ends right below mtime
+pub const MAX_HARTS: u64 = (MTIME - MTIMECMP) / 8;
+
+// mtime ticks once per instruction, this is the frequency advertised in the device tree
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+// Clint, one msip and one mtimecmp register per hart. The registers are atomic, harts running
+// on other threads share the device.
+pub struct Clint {
+    pub base: u64,
+    pub msip: Vec<AtomicU64>,
+    pub mtimecmp: Vec<AtomicU64>,
+    pub mtime: AtomicU64,
+    // Supervisor software interrupts raised by the SBI on behalf of another hart, like the SSWI
+    // device of the ACLINT but not memory mapped. The target hart moves them to its sip.
+    pub ssip: Vec<AtomicBool>,
+}
+
+// Register selected by an offset
+#[derive(Copy, Clone)]
+enum Register {
+    Msip(usize),
+    Mtimecmp(usize),
+    Mtime,
+}
+
+impl Clint {
+    pub fn new(base: u64, harts: usize) -> Self {
+        Self {
+            base,
+            msip: (0..harts).map(|_| AtomicU64::new(0)).collect(),
+            mtimecmp: (0..harts).map(|_| AtomicU64::new(0)).collect(),
+            mtime: AtomicU64::new(0),
+            ssip: (0..harts).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    // Advance the timer
+    pub fn tick(&self, ticks: u64) {
+        self.mtime.fetch_add(ticks, Ordering::Relaxed);
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime.load(Ordering::Relaxed)
+    }
+
+    // Machine timer interrupt condition
+    pub fn timer_pending(&self, hart: usize) -> bool {
+        self.mtime() >= self.mtimecmp[hart].load(Ordering::Relaxed)
+    }
+
+    // Machine software interrupt condition
+    pub fn software_pending(&self, hart: usize) -> bool {
+        self.msip[hart].load(Ordering::Relaxed) & 1 == 1
+    }
+
+    // Take the supervisor software interrupt sent to a hart, if any
+    pub fn take_ssip(&self, hart: usize) -> bool {
+        self.ssip[hart].load(Ordering::Relaxed) && self.ssip[hart].swap(false, Ordering::AcqRel)
+    }
+
+    // msip is 4 bytes wide and mtimecmp 8 bytes wide per hart, returns the register and its offset
+    fn register(&self, offset: u64) -> Option<(Register, u64)> {
+        let harts = self.msip.len() as u64;
+        match offset {
+            MSIP..=0x3fff if offset < MSIP + 4 * harts => {
+                let hart = (offset - MSIP) / 4;
+                Some((Register::Msip(hart as usize), MSIP + 4 * hart))
+            }
+            MTIMECMP..=0xbff7 if offset < MTIMECMP + 8 * harts => {
+                let hart = (offset - MTIMECMP) / 8;
+                Some((Register::Mtimecmp(hart as usize), MTIMECMP + 8 * hart))
+            }
+            MTIME..=0xbfff => Some((Register::Mtime, MTIME)),
+            _ => None,
+        }
+    }
+
+    fn atomic(&self, register: Register) -> &AtomicU64 {
+        match register {
+            Register::Msip(hart) => &self.msip[hart],
+            Register::Mtimecmp(hart) => &self.mtimecmp[hart],
+            Register::Mtime => &self.mtime,
+        }
+    }
+}
+
+impl Device for Clint {
+    // Registers are 64-bit, 32-bit accesses read and write one half
+    fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let offset = addr - self.base;
+        let (register, base) = match self.register(offset) {
+            Some(register) => register,
+            None => return Ok(0),
+        };
+        let reg = self.atomic(register).load(Ordering::Relaxed);
+        let shift = (offset - base) * 8;
+        match size {
+            32 => Ok((reg >> shift) & 0xffff_ffff),
+            64 if shift == 0 => Ok(reg),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let offset = addr - self.base;
+        let (register, base) = match self.register(offset) {
+            Some(register) => register,
+            None => return Ok(()),
+        };
+        let reg = self.atomic(register);
+        let shift = (offset - base) * 8;
+        match size {
+            32 => {
+                let _ = reg.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reg| {
+                    Some((reg & !(0xffff_ffff << shift)) | ((value & 0xffff_ffff) << shift))
+                });
+                Ok(())
+            }
+            64 if shift == 0 => {
+                reg.store(value, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new(CLINT_BASE, 1)
+    }
+}
@@ -0,0 +1,515 @@
+// Machine configuration: memory layout, harts, ISA, reset vector, device addresses and boot mode.
+// The defaults are the QEMU virt layout. A configuration file uses a subset of TOML, tables of
+// `key = value` with integers, strings and booleans:
+//
+//     [memory]
+//     base = 0x8000_0000
+//     size = "256M"            # or a number of bytes
+//
+//     [cpu]
+//     harts = 2
+//     isa = "rv64iasu"         # misa letters, I is required
+//     reset_vector = 0x8000_0000
+//     quantum = 1000
+//     threads = false
+//     semihosting = false      # serve semihosting calls
+//
+//     [misaligned]             # trap or emulate accesses that are not naturally aligned
+//     fetch = "trap"
+//     load = "emulate"
+//     store = "emulate"
+//     amo = "trap"
+//
+//     [devices]
+//     clint = 0x0200_0000
+//     uart = 0x1000_0000
+//     finisher = 0x10_0000     # SiFive test finisher
+//
+//     [boot]
+//     mode = "kernel"          # bare, test or kernel
+//     firmware = "fw_jump.bin"
+//     initrd = "rootfs.cpio"
+//     bootargs = "console=ttyS0"
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+
+use crate::bus::DRAM_BASE;
+use crate::clint::{CLINT_BASE, CLINT_SIZE, MAX_HARTS};
+use crate::csr::{extension, MISA_VALUE};
+use crate::dram::{DRAM_SIZE, MAX_DRAM_SIZE};
+use crate::finisher::{FINISHER_BASE, FINISHER_SIZE};
+use crate::machine::DEFAULT_QUANTUM;
+use crate::uart::{UART_BASE, UART_SIZE};
+
+/// What the command line does with the program it is given
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum BootMode {
+    /// Run a raw binary, an ELF file or an assembly source until it exits
+    Bare,
+    /// Run a riscv-tests style ELF until it reports through HTIF
+    Test,
+    /// Boot a kernel, see `boot::boot`
+    Kernel,
+}
+
+/// What the harts do with an access that is not naturally aligned
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Misaligned {
+    /// Raise the address-misaligned exception of the access, with the address in xtval
+    Trap,
+    /// Do the access, as cores that handle misaligned accesses in hardware or firmware do
+    Emulate,
+}
+
+/// `Misaligned` for each kind of access. By default jumps and AMOs trap, and loads and stores
+/// are emulated: what a kernel sees on the cores it runs on, where the firmware emulates them.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MisalignedPolicy {
+    /// Jumps and branches to an address that is not a multiple of 4, and fetches from one
+    pub fetch: Misaligned,
+    pub load: Misaligned,
+    pub store: Misaligned,
+    /// AMOs and LR/SC. Emulated ones stay atomic within an aligned 8-byte word, those crossing
+    /// one raise an access fault.
+    pub amo: Misaligned,
+}
+
+impl Default for MisalignedPolicy {
+    fn default() -> Self {
+        Self { fetch: Misaligned::Trap, load: Misaligned::Emulate, store: Misaligned::Emulate, amo: Misaligned::Trap }
+    }
+}
+
+/// Layout and settings of a machine, see `MachineBuilder::config`
+#[derive(Clone, PartialEq, Debug)]
+pub struct MachineConfig {
+    pub memory_base: u64,
+    pub memory_size: u64,
+    pub harts: usize,
+    /// misa value: MXL and one bit per extension
+    pub misa: u64,
+    /// pc of the harts at reset, the start of memory when None
+    pub reset_vector: Option<u64>,
+    pub quantum: u64,
+    pub threaded: bool,
+    /// Serve the semihosting calls of the guest instead of taking breakpoint exceptions
+    pub semihosting: bool,
+    pub misaligned: MisalignedPolicy,
+    pub clint_base: u64,
+    pub uart_base: u64,
+    pub finisher_base: u64,
+    pub boot: BootMode,
+    /// Paths of the firmware and the initramfs, and the kernel command line, for `BootMode::Kernel`
+    pub firmware: Option<String>,
+    pub initrd: Option<String>,
+    pub bootargs: Option<String>,
+}
+
+/// Error in a configuration file, `line` is None for an inconsistent configuration
+#[derive(Clone, PartialEq, Debug)]
+pub struct ConfigError {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn error<T>(line: Option<usize>, message: String) -> Result<T, ConfigError> {
+    Err(ConfigError { line, message })
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Value {
+    Integer(u64),
+    String(String),
+    Boolean(bool),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Integer(_) => "an integer",
+            Value::String(_) => "a string",
+            Value::Boolean(_) => "a boolean",
+        }
+    }
+}
+
+// Decimal, 0x hexadecimal, 0o octal or 0b binary, with `_` separators
+fn parse_integer(text: &str) -> Option<u64> {
+    let text = text.replace('_', "");
+    let (digits, radix) = match text.get(..2) {
+        Some("0x") => (&text[2..], 16),
+        Some("0o") => (&text[2..], 8),
+        Some("0b") => (&text[2..], 2),
+        _ => (&text[..], 10),
+    };
+    u64::from_str_radix(digits, radix).ok()
+}
+
+// A value and what follows it on the line
+fn parse_value(text: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some(c @ ('"' | '\\')) => value.push(c),
+                    _ => return Err("invalid escape in string".to_string()),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".to_string());
+    }
+
+    let end = text.find(|c: char| c.is_whitespace() || c == '#').unwrap_or(text.len());
+    let (token, rest) = text.split_at(end);
+    let value = match token {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        "" => return Err("missing value".to_string()),
+        _ => Value::Integer(parse_integer(token).ok_or_else(|| format!("unsupported value `{}`", token))?),
+    };
+    Ok((value, rest))
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// Dotted key, `a.b` with optional spaces around the dots
+fn parse_key(text: &str) -> Option<String> {
+    let parts: Vec<&str> = text.split('.').map(str::trim).collect();
+    parts.iter().all(|part| is_bare_key(part)).then(|| parts.join("."))
+}
+
+// `key = value` lines with the table they are in, as full dotted keys with their line numbers
+fn parse_toml(text: &str) -> Result<Vec<(String, Value, usize)>, ConfigError> {
+    let mut entries = Vec::new();
+    let mut keys = HashSet::new();
+    let mut table = String::new();
+    for (i, line) in text.lines().enumerate() {
+        let number = Some(i + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix('[') {
+            let Some((name, rest)) = rest.split_once(']') else {
+                return error(number, "unterminated table header".to_string());
+            };
+            if name.starts_with('[') {
+                return error(number, "arrays of tables are not supported".to_string());
+            }
+            let rest = rest.trim();
+            if !(rest.is_empty() || rest.starts_with('#')) {
+                return error(number, format!("unexpected `{}` after the table header", rest));
+            }
+            table = parse_key(name).ok_or_else(|| ConfigError { line: number, message: format!("invalid table name `{}`", name) })?;
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            return error(number, "expected `key = value`".to_string());
+        };
+        let Some(key) = parse_key(key) else {
+            return error(number, format!("invalid key `{}`", key.trim()));
+        };
+        let key = if table.is_empty() { key } else { format!("{}.{}", table, key) };
+        let (value, rest) = parse_value(value.trim()).map_err(|message| ConfigError { line: number, message })?;
+        let rest = rest.trim();
+        if !(rest.is_empty() || rest.starts_with('#')) {
+            return error(number, format!("unexpected `{}` after the value", rest));
+        }
+        if !keys.insert(key.clone()) {
+            return error(number, format!("duplicate key `{}`", key));
+        }
+        entries.push((key, value, i + 1));
+    }
+    Ok(entries)
+}
+
+/// Size in bytes with an optional K, M or G suffix (or KiB, MiB, GiB), `128M` or `0x800_0000`
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let (number, shift) = [("K", 10), ("M", 20), ("G", 30)]
+        .iter()
+        .find_map(|(unit, shift)| {
+            let number = text.strip_suffix(&format!("{}iB", unit)).or_else(|| text.strip_suffix(unit))?;
+            Some((number, *shift))
+        })
+        .unwrap_or((text, 0));
+    parse_integer(number.trim())?.checked_mul(1 << shift)
+}
+
+/// misa value of an ISA string such as `rv64ia` or `rv64iasu`: the letters of misa, multi-letter
+/// extensions after `_` are accepted when the emulator always implements them
+pub fn parse_isa(isa: &str) -> Result<u64, String> {
+    let lower = isa.to_ascii_lowercase();
+    let Some(rest) = lower.strip_prefix("rv64") else {
+        return Err(format!("the ISA string `{}` does not start with rv64", isa));
+    };
+    let mut parts = rest.split('_');
+    let letters = parts.next().unwrap_or("");
+    for part in parts {
+        if !matches!(part, "zicsr" | "zifencei") {
+            return Err(format!("the emulator does not implement the {} extension", part));
+        }
+    }
+
+    let mut misa = MISA_VALUE & !((1 << 26) - 1);
+    for letter in letters.bytes().map(|c| c.to_ascii_uppercase()) {
+        if !letter.is_ascii_uppercase() || MISA_VALUE & extension(letter) == 0 {
+            return Err(format!("the emulator does not implement the {} extension", letter as char));
+        }
+        misa |= extension(letter);
+    }
+    if misa & extension(b'I') == 0 {
+        return Err(format!("the ISA string `{}` does not have the base integer ISA", isa));
+    }
+    if misa & extension(b'S') != 0 && misa & extension(b'U') == 0 {
+        return Err("S-mode requires U-mode".to_string());
+    }
+    Ok(misa)
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            memory_base: DRAM_BASE,
+            memory_size: DRAM_SIZE,
+            harts: 1,
+            misa: MISA_VALUE,
+            reset_vector: None,
+            quantum: DEFAULT_QUANTUM,
+            threaded: false,
+            semihosting: false,
+            misaligned: MisalignedPolicy::default(),
+            clint_base: CLINT_BASE,
+            uart_base: UART_BASE,
+            finisher_base: FINISHER_BASE,
+            boot: BootMode::Bare,
+            firmware: None,
+            initrd: None,
+            bootargs: None,
+        }
+    }
+}
+
+impl MachineConfig {
+    /// Defaults overridden by a configuration file
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        for (key, value, line) in parse_toml(text)? {
+            config.apply(&key, value).map_err(|message| ConfigError { line: Some(line), message })?;
+        }
+        config.validate().map_err(|message| ConfigError { line: None, message })?;
+        Ok(config)
+    }
+
+    /// Read and parse a configuration file
+    pub fn load(path: &str) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError { line: None, message: format!("{}: {}", path, e) })?;
+        Self::parse(&text).map_err(|e| ConfigError { message: format!("{}: {}", path, e.message), ..e })
+    }
+
+    /// Override one setting from a `key=value` pair, with the key and the value written as in a
+    /// configuration file: `cpu.harts=2`, `boot.mode="test"`. Strings may be left unquoted.
+    pub fn set(&mut self, setting: &str) -> Result<(), ConfigError> {
+        let invalid = |message: String| ConfigError { line: None, message: format!("{}: {}", setting, message) };
+        let (key, value) = setting.split_once('=').ok_or_else(|| invalid("expected `key=value`".to_string()))?;
+        let key = parse_key(key).ok_or_else(|| invalid("invalid key".to_string()))?;
+        let value = value.trim();
+        let value = match parse_value(value) {
+            Ok((value, rest)) if rest.trim().is_empty() => value,
+            _ => Value::String(value.to_string()),
+        };
+        self.apply(&key, value).map_err(invalid)?;
+        self.validate().map_err(invalid)
+    }
+
+    fn apply(&mut self, key: &str, value: Value) -> Result<(), String> {
+        let kind = value.kind();
+        let wrong_type = |expected: &str| Err(format!("`{}` must be {}, not {}", key, expected, kind));
+        match (key, value) {
+            ("memory.base", Value::Integer(base)) => self.memory_base = base,
+            ("memory.size", Value::Integer(size)) => self.memory_size = size,
+            ("memory.size", Value::String(size)) => {
+                self.memory_size = parse_size(&size).ok_or_else(|| format!("invalid memory size `{}`", size))?;
+            }
+            ("cpu.harts", Value::Integer(harts)) => {
+                if !(1..=MAX_HARTS).contains(&harts) {
+                    return Err(format!("the number of harts must be between 1 and {}", MAX_HARTS));
+                }
+                self.harts = harts as usize;
+            }
+            ("cpu.isa", Value::String(isa)) => self.misa = parse_isa(&isa)?,
+            ("cpu.reset_vector", Value::Integer(pc)) => self.reset_vector = Some(pc),
+            ("cpu.quantum", Value::Integer(quantum)) => self.quantum = quantum.max(1),
+            ("cpu.threads", Value::Boolean(threaded)) => self.threaded = threaded,
+            ("cpu.semihosting", Value::Boolean(semihosting)) => self.semihosting = semihosting,
+            ("misaligned.fetch" | "misaligned.load" | "misaligned.store" | "misaligned.amo", Value::String(policy)) => {
+                let policy = match policy.as_str() {
+                    "trap" => Misaligned::Trap,
+                    "emulate" => Misaligned::Emulate,
+                    _ => return Err(format!("unknown misaligned access policy `{}`, expected trap or emulate", policy)),
+                };
+                match key {
+                    "misaligned.fetch" => self.misaligned.fetch = policy,
+                    "misaligned.load" => self.misaligned.load = policy,
+                    "misaligned.store" => self.misaligned.store = policy,
+                    _ => self.misaligned.amo = policy,
+                }
+            }
+            ("devices.clint", Value::Integer(base)) => self.clint_base = base,
+            ("devices.uart", Value::Integer(base)) => self.uart_base = base,
+            ("devices.finisher", Value::Integer(base)) => self.finisher_base = base,
+            ("boot.mode", Value::String(mode)) => {
+                self.boot = match mode.as_str() {
+                    "bare" => BootMode::Bare,
+                    "test" => BootMode::Test,
+                    "kernel" => BootMode::Kernel,
+                    _ => return Err(format!("unknown boot mode `{}`, expected bare, test or kernel", mode)),
+                };
+            }
+            ("boot.firmware", Value::String(path)) => self.firmware = Some(path),
+            ("boot.initrd", Value::String(path)) => self.initrd = Some(path),
+            ("boot.bootargs", Value::String(bootargs)) => self.bootargs = Some(bootargs),
+            (
+                "memory.base" | "cpu.harts" | "cpu.reset_vector" | "cpu.quantum" | "devices.clint" | "devices.uart"
+                | "devices.finisher",
+                _,
+            ) => return wrong_type("an integer"),
+            ("memory.size", _) => return wrong_type("an integer or a size"),
+            ("cpu.threads" | "cpu.semihosting", _) => return wrong_type("a boolean"),
+            (
+                "cpu.isa" | "misaligned.fetch" | "misaligned.load" | "misaligned.store" | "misaligned.amo" | "boot.mode"
+                | "boot.firmware" | "boot.initrd" | "boot.bootargs",
+                _,
+            ) => {
+                return wrong_type("a string");
+            }
+            _ => return Err(format!("unknown key `{}`", key)),
+        }
+        Ok(())
+    }
+
+    /// Check the number of harts, and that memory and the devices fit in the address space
+    /// without overlapping
+    pub fn validate(&self) -> Result<(), String> {
+        if self.memory_size == 0 {
+            return Err("the memory size must not be zero".to_string());
+        }
+        if self.memory_size > MAX_DRAM_SIZE {
+            return Err(format!("the memory size must be at most {:#x} bytes on this host", MAX_DRAM_SIZE));
+        }
+        if !self.memory_size.is_multiple_of(8) || !self.memory_base.is_multiple_of(8) {
+            return Err("the memory base and size must be multiples of 8 bytes".to_string());
+        }
+        if !(1..=MAX_HARTS).contains(&(self.harts as u64)) {
+            return Err(format!("the number of harts must be between 1 and {}", MAX_HARTS));
+        }
+        let regions = [
+            ("memory", self.memory_base, self.memory_size),
+            ("the CLINT", self.clint_base, CLINT_SIZE),
+            ("the UART", self.uart_base, UART_SIZE),
+            ("the test finisher", self.finisher_base, FINISHER_SIZE),
+        ];
+        for (name, base, size) in regions {
+            if base.checked_add(size).is_none() {
+                return Err(format!("{} at {:#x} ends beyond the address space", name, base));
+            }
+        }
+        for (i, (name, base, size)) in regions.iter().enumerate() {
+            for (other, other_base, other_size) in &regions[i + 1..] {
+                if base < &(other_base + other_size) && other_base < &(base + size) {
+                    return Err(format!("{} at {:#x} overlaps {} at {:#x}", name, base, other, other_base));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// pc of the harts at reset
+    pub fn reset_pc(&self) -> u64 {
+        self.reset_vector.unwrap_or(self.memory_base)
+    }
+
+    /// ISA string of misa, for the device tree: the base ISA then the other extensions, S and U
+    /// are privilege modes
+    pub fn isa_string(&self) -> String {
+        let extensions: String = (b'A'..=b'Z')
+            .filter(|letter| !matches!(letter, b'I' | b'S' | b'U') && self.misa & extension(*letter) != 0)
+            .map(|letter| letter.to_ascii_lowercase() as char)
+            .collect();
+        format!("rv64i{}", extensions)
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    let text = r#"
+        # Two harts and more memory
+        [memory]
+        base = 0x4000_0000
+        size = "256M"
+
+        [cpu]
+        harts = 2
+        isa = "rv64i_zicsr"     # no atomics, no S or U
+        reset_vector = 0x4000_1000
+
+        [devices]
+        uart = 0x1000_1000
+
+        [boot]
+        mode = "kernel"
+        bootargs = "console=ttyS0 # not a comment"
+    "#;
+    let config = MachineConfig::parse(text).unwrap();
+    assert_eq!(config.memory_base, 0x4000_0000);
+    assert_eq!(config.memory_size, 256 << 20);
+    assert_eq!(config.harts, 2);
+    assert_eq!(config.misa, (2 << 62) | extension(b'I'));
+    assert_eq!(config.reset_pc(), 0x4000_1000);
+    assert_eq!(config.clint_base, CLINT_BASE);
+    assert_eq!(config.uart_base, 0x1000_1000);
+    assert_eq!(config.boot, BootMode::Kernel);
+    assert_eq!(config.bootargs.as_deref(), Some("console=ttyS0 # not a comment"));
+    assert_eq!(config.isa_string(), "rv64i");
+
+    let mut config = MachineConfig::default();
+    config.set("cpu.harts=4").unwrap();
+    config.set("boot.mode=test").unwrap();
+    config.set("memory.size = 0x100_0000").unwrap();
+    config.set("cpu.semihosting=true").unwrap();
+    assert_eq!((config.harts, config.boot, config.memory_size), (4, BootMode::Test, 0x100_0000));
+    assert!(config.semihosting);
+    assert_eq!(config.isa_string(), "rv64ia");
+
+    let fails = |text: &str| MachineConfig::parse(text).unwrap_err().to_string();
+    assert_eq!(fails("[cpu]\nharts = \"two\""), "line 2: `cpu.harts` must be an integer, not a string");
+    assert_eq!(fails("[cpu]\nisa = \"rv64imac\""), "line 2: the emulator does not implement the M extension");
+    assert_eq!(fails("memory.sise = 1"), "line 1: unknown key `memory.sise`");
+    assert_eq!(fails("[cpu]\nharts = 1\nharts = 2"), "line 3: duplicate key `cpu.harts`");
+    assert_eq!(fails("[devices]\nuart = 0x8000_0100"), "memory at 0x80000000 overlaps the UART at 0x80000100");
+    assert_eq!(fails("[devices]\nfinisher = 0x200_8000"), "the CLINT at 0x2000000 overlaps the test finisher at 0x2008000");
+    assert!(config.set("cpu.threads=yes").is_err());
+}
@@ -1,18 +1,87 @@
-use std::ops::BitXor;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+
 use crate::instruction::{decode, Instruction};
+use crate::block::BlockCache;
 use crate::bus::*;
+use crate::config::Misaligned;
+use crate::csr::*;
 use crate::dram::*;
+use crate::exception::Exception;
+use crate::hooks::{Access, HookAction, Hooks};
+use crate::icache::ICache;
 use crate::instruction::Instruction::*;
+use crate::interrupt::*;
+use crate::register::*;
+use crate::semihosting;
+
+// Most significant bit of xcause, set when the trap is an interrupt
+const INTERRUPT_BIT: u64 = 1 << 63;
+
+/// Linux and newlib syscall numbers of exit and exit_group, in a7 with the status in a0
+pub const SYS_EXIT: u64 = 93;
+pub const SYS_EXIT_GROUP: u64 = 94;
+
+/// Privilege modes, the value is the encoding used in mstatus.MPP
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mode {
+    User = 0b00,
+    Supervisor = 0b01,
+    Machine = 0b11,
+}
+
+impl From<u64> for Mode {
+    fn from(value: u64) -> Self {
+        match value & 0b11 {
+            0b00 => Mode::User,
+            0b01 => Mode::Supervisor,
+            _ => Mode::Machine,
+        }
+    }
+}
 
-// CPU struct
+/// What the last step did, kept for the commit log
+#[derive(Clone)]
+pub struct Retired {
+    /// Cause and epc of an interrupt taken before the instruction
+    pub interrupt: Option<(u64, u64)>,
+    pub pc: u64,
+    pub mode: Mode,
+    /// None when the fetch failed
+    pub inst: Option<u32>,
+    /// Registers before the instruction, to find its operands
+    pub regs: [u64; 32],
+    pub exception: Option<Exception>,
+}
+
+/// CPU struct, one per hart, the bus is shared by all harts of the machine
 pub struct Cpu {
     pub regs: [u64; 32],
     pub pc: u64,
-    pub bus: Bus,
+    pub mode: Mode,
+    pub csr: Csr,
+    /// Value read by the last LR, SC only stores if memory still holds it
+    pub reserved_value: u64,
+    /// Set when the machine serves ecalls from S-mode with the native SBI, booting a kernel
+    /// without firmware
+    pub sbi: bool,
+    /// Set when the machine serves semihosting calls, see `semihosting`
+    pub semihosting: bool,
+    /// Cause of the last trap taken, the monitor takes it to stop on traps
+    pub trap: Option<u64>,
+    /// Set to record every step, see `Retired`
+    pub retired: Option<Retired>,
+    pub hooks: Option<Arc<Hooks>>,
+    /// Decoded instructions, see `ICache`
+    pub icache: ICache,
+    /// Translated blocks, see `run_blocks`
+    pub blocks: BlockCache,
+    // Set when a hook asked to stop the machine
+    hook_stop: bool,
 }
 
 impl Cpu {
-    pub fn new(binary: Vec<u8>) -> Self {
+    pub fn new(hartid: u64) -> Self {
         let mut regs = [0; 32];
         // SP(Stack pointer) must be at the end of memory, so we set it first
         regs[2] = DRAM_BASE + DRAM_SIZE;
@@ -20,58 +89,353 @@ impl Cpu {
         Self {
             regs,
             pc: DRAM_BASE,
-            bus: Bus::new(binary),
+            mode: Mode::Machine,
+            csr: Csr::new(hartid),
+            reserved_value: 0,
+            sbi: false,
+            semihosting: false,
+            trap: None,
+            retired: None,
+            hooks: None,
+            icache: ICache::default(),
+            blocks: BlockCache::default(),
+            hook_stop: false,
         }
     }
 
-    // Dump register values
+    pub fn hartid(&self) -> usize {
+        self.csr.load(MHARTID) as usize
+    }
+
+    /// Dump register values
     pub fn dump_registers(&self) {
         let mut output = String::from("");
-        let abi = [
-            "zero", " ra ", " sp ", " gp ", " tp ", " t0 ", " t1 ", " t2 ", " s0 ", " s1 ", " a0 ",
-            " a1 ", " a2 ", " a3 ", " a4 ", " a5 ", " a6 ", " a7 ", " s2 ", " s3 ", " s4 ", " s5 ",
-            " s6 ", " s7 ", " s8 ", " s9 ", " s10", " s11", " t3 ", " t4 ", " t5 ", " t6 ",
-        ];
+        // Names are padded to four characters to keep the columns aligned
+        let abi = ABI_NAMES.map(|name| match name.len() {
+            2 => format!(" {} ", name),
+            3 => format!(" {}", name),
+            _ => name.to_string(),
+        });
         for i in (0..32).step_by(4) {
             output = format!(
-                "{}\n{}",
+                "{}\nx{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x}",
                 output,
-                format!(
-                    "x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x}",
-                    i, abi[i], self.regs[i],
-                    i + 1, abi[i + 1], self.regs[i + 1],
-                    i + 2, abi[i + 2], self.regs[i + 2],
-                    i + 3, abi[i + 3], self.regs[i + 3],
-                )
+                i, abi[i], self.regs[i],
+                i + 1, abi[i + 1], self.regs[i + 1],
+                i + 2, abi[i + 2], self.regs[i + 2],
+                i + 3, abi[i + 3], self.regs[i + 3],
             );
         }
         println!("{}", output);
     }
 
-    // Load value from memory
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, ()> {
-        self.bus.load(addr, size)
+    /// Get an instruction
+    pub fn fetch(&self, bus: &Bus) -> Result<u32, Exception> {
+        match bus.fetch(self.pc) {
+            Ok(inst) => {
+                let inst = inst as u32;
+                Ok(inst)
+            }
+            Err(_) => Err(Exception::InstructionAccessFault(self.pc))
+        }
     }
 
-    // Store value to memory
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), ()> {
-        self.bus.store(addr, size, value)
+    // Fetch and decode the instruction at pc, through the decoded-instruction cache
+    fn fetch_decoded(&mut self, bus: &Bus) -> Result<(u32, Instruction), Exception> {
+        // Jumps check their target, pc can still be misaligned after an xRET or at reset
+        if bus.misaligned.fetch == Misaligned::Trap && !self.pc.is_multiple_of(4) {
+            return Err(Exception::InstructionAddressMisaligned(self.pc));
+        }
+        if let Some(cached) = self.icache.fetch(bus, self.pc) {
+            return Ok(cached);
+        }
+        let inst = self.fetch(bus)?;
+        Ok((inst, decode(inst)))
     }
 
-    // Get an instruction
-    pub fn fetch(&mut self) -> Result<u32, ()> {
-        match self.bus.load(self.pc, 32) {
-            Ok(inst) => {
-                let inst = inst as u32;
-                Ok(inst)
+    /// Run a single instruction: fetch, add 4 to the program counter, decode and execute.
+    /// Pending interrupts are taken first and exceptions are handed to the guest trap handler,
+    /// only fatal ones are returned, and those the machine serves, see `served_by_host`.
+    pub fn step(&mut self, bus: &Bus) -> Result<(), Exception> {
+        let epc = self.pc;
+        let interrupt = self.take_interrupt(bus).map(|cause| (cause, epc));
+
+        let pc = self.pc;
+        if let Some(retired) = &mut self.retired {
+            *retired = Retired { interrupt, pc, mode: self.mode, inst: None, regs: self.regs, exception: None };
+        }
+        let result = self.fetch_decoded(bus).and_then(|(inst, instruction)| {
+            let hooks = self.hooks.clone();
+            if let Some(hooks) = &hooks {
+                // A hook stopping the machine or moving pc skips the instruction
+                let action = hooks.run_before(self, bus, &instruction);
+                self.hook_action(action);
+                if action == HookAction::Stop || self.pc != pc {
+                    return Ok(());
+                }
+            }
+
+            if let Some(retired) = &mut self.retired {
+                retired.inst = Some(inst);
+            }
+            self.pc = self.pc.wrapping_add(4);
+            self.execute_decoded(bus, inst, instruction)?;
+
+            if let Some(hooks) = &hooks {
+                let action = hooks.run_after(self, bus, &instruction);
+                self.hook_action(action);
+            }
+            Ok(())
+        });
+
+        if let Err(exception) = result {
+            if let Some(retired) = &mut self.retired {
+                retired.exception = Some(exception);
             }
-            Err(_) => Err(())
+            return self.raise(bus, exception, pc);
         }
+        Ok(())
     }
 
-    // Execute an instruction
-    pub fn execute(&mut self, inst: u32) -> Result<(), ()> {
-        let instruction = decode(inst);
+    /// Run translated blocks from pc, at most `budget` instructions, while nothing needs to see
+    /// every step: without hooks and without recording them, see `block`. Pending interrupts
+    /// are taken between blocks and exceptions are handled as `step` does. Returns the number
+    /// of instructions run, 0 when the one at pc cannot be translated, `step` runs it then.
+    pub fn run_blocks(&mut self, bus: &Bus, budget: u64) -> (u64, Result<(), Exception>) {
+        if !self.blocks.enabled || self.hooks.is_some() || self.retired.is_some() {
+            return (0, Ok(()));
+        }
+        let mut blocks = std::mem::take(&mut self.blocks);
+        let result = blocks.run(self, bus, budget);
+        self.blocks = blocks;
+        result
+    }
+
+    // Take the pending interrupt with the highest priority, if any, returns its cause
+    pub(crate) fn take_interrupt(&mut self, bus: &Bus) -> Option<u64> {
+        self.update_pending_interrupts(bus);
+        let interrupt = self.pending_interrupt()?;
+        let cause = interrupt.code() | INTERRUPT_BIT;
+        self.take_trap(cause, 0, self.pc, Some(interrupt));
+        self.trap_hooks(bus, cause, 0);
+        Some(cause)
+    }
+
+    // Handle an exception of the instruction at `pc`, pc is past it. Returns the fatal ones and
+    // those the machine serves.
+    pub(crate) fn raise(&mut self, bus: &Bus, exception: Exception, pc: u64) -> Result<(), Exception> {
+        if exception.is_fatal() && bus.fetch(self.exception_vector(exception.code())).is_err() {
+            self.pc = pc;
+            return Err(exception);
+        }
+        if self.served_by_host(bus, exception) {
+            return Err(exception);
+        }
+        self.handle_exception(exception, pc);
+        self.trap_hooks(bus, exception.code(), exception.value());
+        Ok(())
+    }
+
+    /// Whether the machine serves the exception instead of the guest, `step` returns it then:
+    /// ecalls from S-mode to the native SBI, semihosting calls, and the exit syscall of a program
+    /// without a trap handler, that would otherwise trap to address 0
+    fn served_by_host(&self, bus: &Bus, exception: Exception) -> bool {
+        match exception {
+            Exception::EnvironmentCallFromSMode => self.sbi,
+            Exception::EnvironmentCallFromUMode | Exception::EnvironmentCallFromMMode => {
+                !self.sbi && self.csr.load(MTVEC) == 0 && matches!(self.regs[17], SYS_EXIT | SYS_EXIT_GROUP)
+            }
+            Exception::Breakpoint(addr) => self.semihosting && semihosting::is_call(bus, addr),
+            _ => false,
+        }
+    }
+
+    fn hook_action(&mut self, action: HookAction) {
+        if action == HookAction::Stop {
+            self.hook_stop = true;
+        }
+    }
+
+    /// Whether a hook asked to stop the machine since the last call
+    pub fn take_hook_stop(&mut self) -> bool {
+        std::mem::take(&mut self.hook_stop)
+    }
+
+    fn trap_hooks(&mut self, bus: &Bus, cause: u64, tval: u64) {
+        if let Some(hooks) = self.hooks.clone() {
+            let action = hooks.run_trap(self, bus, cause, tval);
+            self.hook_action(action);
+        }
+    }
+
+    fn memory_hooks(&mut self, bus: &Bus, access: Access) {
+        if let Some(hooks) = self.hooks.clone() {
+            let action = hooks.run_memory(self, bus, access);
+            self.hook_action(action);
+        }
+    }
+
+    // Data accesses of instructions, memory hooks see them
+    fn load(&mut self, bus: &Bus, addr: u64, size: u64) -> Result<u64, Exception> {
+        let value = bus.load(addr, size)?;
+        self.memory_hooks(bus, Access { addr, size, value, write: false });
+        Ok(value)
+    }
+
+    fn store(&mut self, bus: &Bus, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        bus.store(addr, size, value)?;
+        let value = if size == 64 { value } else { value & ((1 << size) - 1) };
+        self.memory_hooks(bus, Access { addr, size, value, write: true });
+        Ok(())
+    }
+
+    // Reflect the interrupt sources of the devices in mip. Without an M-mode firmware to
+    // forward the machine timer interrupt, the timer raises the supervisor one directly.
+    fn update_pending_interrupts(&mut self, bus: &Bus) {
+        let hart = self.hartid();
+        let timer = bus.clint.timer_pending(hart);
+        if self.sbi {
+            self.csr.set_pending(Interrupt::SupervisorTimer.mask(), timer);
+        } else {
+            self.csr.set_pending(Interrupt::MachineTimer.mask(), timer);
+        }
+        self.csr.set_pending(Interrupt::MachineSoftware.mask(), bus.clint.software_pending(hart));
+        if bus.clint.take_ssip(hart) {
+            self.csr.set_pending(Interrupt::SupervisorSoftware.mask(), true);
+        }
+    }
+
+    // Highest priority interrupt that is pending, enabled and not masked by the current mode,
+    // see privileged specification chapter 3.1.9
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = self.csr.load(MIP) & self.csr.load(MIE);
+        if pending == 0 {
+            return None;
+        }
+
+        let status = self.csr.load(MSTATUS);
+        let mideleg = self.csr.load(MIDELEG);
+        let machine_enabled = self.mode != Mode::Machine || status & MSTATUS_MIE != 0;
+        let supervisor_enabled = self.mode == Mode::User
+            || (self.mode == Mode::Supervisor && status & MSTATUS_SIE != 0);
+
+        let enabled = (if machine_enabled { pending & !mideleg } else { 0 })
+            | (if supervisor_enabled { pending & mideleg } else { 0 });
+        PRIORITY.iter().copied().find(|interrupt| enabled & interrupt.mask() != 0)
+    }
+
+    /// Take the trap for an exception raised by the instruction at `pc`
+    pub fn handle_exception(&mut self, exception: Exception, pc: u64) {
+        self.take_trap(exception.code(), exception.value(), pc, None);
+    }
+
+    // Jump or branch to `target`. Without the C extension, a target that is not a multiple of 4
+    // raises the exception on the jump when the policy traps, rd is left alone.
+    fn jump(&mut self, bus: &Bus, target: u64) -> Result<(), Exception> {
+        if bus.misaligned.fetch == Misaligned::Trap && !target.is_multiple_of(4) {
+            return Err(Exception::InstructionAddressMisaligned(target));
+        }
+        self.pc = target;
+        Ok(())
+    }
+
+    // Handler an exception of `code` would enter, see `take_trap`
+    fn exception_vector(&self, code: u64) -> u64 {
+        let to_supervisor = self.mode != Mode::Machine && (self.csr.load(MEDELEG) >> code) & 1 == 1;
+        self.csr.load(if to_supervisor { STVEC } else { MTVEC }) & !0b11
+    }
+
+    // Enter the trap handler, see privileged specification chapter 3.1.6.1. Traps from U/S-mode
+    // go to S-mode when delegated through medeleg/mideleg, everything else goes to M-mode.
+    fn take_trap(&mut self, cause: u64, tval: u64, epc: u64, interrupt: Option<Interrupt>) {
+        let deleg = if interrupt.is_some() { self.csr.load(MIDELEG) } else { self.csr.load(MEDELEG) };
+        let code = cause & !INTERRUPT_BIT;
+        let to_supervisor = self.mode != Mode::Machine && (deleg >> code) & 1 == 1;
+        self.trap = Some(cause);
+
+        let (tvec, status) = if to_supervisor {
+            self.csr.store(SEPC, epc);
+            self.csr.store(SCAUSE, cause);
+            self.csr.store(STVAL, tval);
+
+            // Save the interrupt enable bit and the previous privilege mode
+            let mut status = self.csr.load(MSTATUS);
+            let sie = (status & MSTATUS_SIE) >> 1;
+            status = (status & !MSTATUS_SPIE) | (sie << 5);
+            status &= !MSTATUS_SIE;
+            status = (status & !MSTATUS_SPP) | (((self.mode as u64) & 1) << 8);
+            self.mode = Mode::Supervisor;
+            (self.csr.load(STVEC), status)
+        } else {
+            self.csr.store(MEPC, epc);
+            self.csr.store(MCAUSE, cause);
+            self.csr.store(MTVAL, tval);
+
+            let mut status = self.csr.load(MSTATUS);
+            let mie = (status & MSTATUS_MIE) >> 3;
+            status = (status & !MSTATUS_MPIE) | (mie << 7);
+            status &= !MSTATUS_MIE;
+            status = (status & !MSTATUS_MPP) | ((self.mode as u64) << 11);
+            self.mode = Mode::Machine;
+            (self.csr.load(MTVEC), status)
+        };
+        self.csr.store(MSTATUS, status);
+
+        // Exceptions always jump to the base address, interrupts are vectored when mode is 1
+        let base = tvec & !0b11;
+        self.pc = match interrupt {
+            Some(_) if tvec & 0b11 == 1 => base + 4 * code,
+            _ => base,
+        };
+    }
+
+    // Read-modify-write of a CSR, `value` is None for the read-only forms of csrrs/csrrc
+    fn update_csr(&mut self, bus: &Bus, inst: u32, csr: u16, value: Option<u64>, op: fn(u64, u64) -> u64) -> Result<u64, Exception> {
+        if Csr::privilege(csr) > self.mode as u64 {
+            return Err(Exception::IllegalInstruction(inst as u64));
+        }
+        let old = match csr {
+            TIME => bus.clint.mtime(),
+            _ => self.csr.load(csr),
+        };
+        if let Some(value) = value {
+            if Csr::is_read_only(csr) {
+                return Err(Exception::IllegalInstruction(inst as u64));
+            }
+            self.csr.store(csr, op(old, value));
+            if let Some(hooks) = self.hooks.clone() {
+                let action = hooks.run_csr(self, bus, csr, self.csr.load(csr));
+                self.hook_action(action);
+            }
+        }
+        Ok(old)
+    }
+
+    // Atomic memory operation: rd gets the old value, sign-extended, memory gets op(old, rs2)
+    fn amo(&mut self, bus: &Bus, rd: Register, rs1: Register, rs2: Register, size: u64, op: fn(u64, u64) -> u64) -> Result<(), Exception> {
+        let src = self.regs[usize::from(rs2)];
+        let addr = self.regs[usize::from(rs1)];
+        let old = bus.fetch_update(addr, size, |old| op(old, src))?;
+        self.regs[usize::from(rd)] = if size == 32 { old as i32 as i64 as u64 } else { old };
+        if self.hooks.is_some() {
+            let new = if size == 32 { op(old, src) & 0xffff_ffff } else { op(old, src) };
+            self.memory_hooks(bus, Access { addr, size, value: old, write: false });
+            self.memory_hooks(bus, Access { addr, size, value: new, write: true });
+        }
+        Ok(())
+    }
+
+    /// Execute an instruction
+    pub fn execute(&mut self, bus: &Bus, inst: u32) -> Result<(), Exception> {
+        self.execute_decoded(bus, inst, decode(inst))
+    }
+
+    // Execute an instruction `decode` has already seen
+    pub(crate) fn execute_decoded(&mut self, bus: &Bus, inst: u32, instruction: Instruction) -> Result<(), Exception> {
+        // The A extension can be left out of misa, its instructions are then illegal
+        if is_atomic(&instruction) && !self.csr.implements(b'A') {
+            return Err(Exception::IllegalInstruction(inst as u64));
+        }
 
         match instruction {
             Lb { rd, rs1, imm } => {
@@ -79,7 +443,7 @@ impl Cpu {
                 let rs1 = usize::from(rs1);
                 let addr = self.regs[rs1].wrapping_add(imm as i64 as u64);
 
-                let val = self.load(addr, 8)?;
+                let val = self.load(bus, addr, 8)?;
                 self.regs[rd] = val as i8 as i64 as u64;
             }
             Lh { rd, rs1, imm } => {
@@ -87,7 +451,7 @@ impl Cpu {
                 let rs1 = usize::from(rs1);
                 let addr = self.regs[rs1].wrapping_add(imm as i64 as u64);
 
-                let val = self.load(addr, 16)?;
+                let val = self.load(bus, addr, 16)?;
                 self.regs[rd] = val as i16 as i64 as u64;
             }
             Lw { rd, rs1, imm } => {
@@ -95,7 +459,7 @@ impl Cpu {
                 let rs1 = usize::from(rs1);
                 let addr = self.regs[rs1].wrapping_add(imm as i64 as u64);
 
-                let val = self.load(addr, 32)?;
+                let val = self.load(bus, addr, 32)?;
                 self.regs[rd] = val as i32 as i64 as u64;
             }
             Ld { rd, rs1, imm } => {
@@ -103,7 +467,7 @@ impl Cpu {
                 let rs1 = usize::from(rs1);
                 let addr = self.regs[rs1].wrapping_add(imm as i64 as u64);
 
-                let val = self.load(addr, 64)?;
+                let val = self.load(bus, addr, 64)?;
                 self.regs[rd] = val as i64 as u64;
             }
             Lbu { rd, rs1, imm } => {
@@ -111,7 +475,7 @@ impl Cpu {
                 let rs1 = usize::from(rs1);
                 let addr = self.regs[rs1].wrapping_add(imm as i64 as u64);
 
-                let val = self.load(addr, 8)?;
+                let val = self.load(bus, addr, 8)?;
                 self.regs[rd] = val;
             }
             Lhu { rd, rs1, imm } => {
@@ -119,7 +483,7 @@ impl Cpu {
                 let rs1 = usize::from(rs1);
                 let addr = self.regs[rs1].wrapping_add(imm as i64 as u64);
 
-                let val = self.load(addr, 16)?;
+                let val = self.load(bus, addr, 16)?;
                 self.regs[rd] = val;
             }
             Lwu { rd, rs1, imm } => {
@@ -127,7 +491,7 @@ impl Cpu {
                 let rs1 = usize::from(rs1);
                 let addr = self.regs[rs1].wrapping_add(imm as i64 as u64);
 
-                let val = self.load(addr, 32)?;
+                let val = self.load(bus, addr, 32)?;
                 self.regs[rd] = val;
             }
             Addi { rd, rs1, imm } => {
@@ -202,36 +566,36 @@ impl Cpu {
             Srliw { rd, rs1, shamt } => {
                 let rd = usize::from(rd);
                 let rs1 = usize::from(rs1);
-                self.regs[rd] = self.regs[rs1].wrapping_shr(shamt) as i32 as i64 as u64;
+                self.regs[rd] = (self.regs[rs1] as u32).wrapping_shr(shamt) as i32 as i64 as u64;
             }
             Sraiw { rd, rs1, shamt } => {
                 let rd = usize::from(rd);
                 let rs1 = usize::from(rs1);
-                self.regs[rd] = self.regs[rs1].wrapping_shr(shamt) as i64 as u64;
+                self.regs[rd] = (self.regs[rs1] as i32).wrapping_shr(shamt) as i64 as u64;
             }
             Sb { rs1, rs2, imm } => {
                 let rs1 = usize::from(rs1);
                 let rs2 = usize::from(rs2);
                 let addr = self.regs[rs1].wrapping_add(imm as u64);
-                self.store(addr, 8, self.regs[rs2])?;
+                self.store(bus, addr, 8, self.regs[rs2])?;
             }
             Sh { rs1, rs2, imm } => {
                 let rs1 = usize::from(rs1);
                 let rs2 = usize::from(rs2);
                 let addr = self.regs[rs1].wrapping_add(imm as u64);
-                self.store(addr, 16, self.regs[rs2])?;
+                self.store(bus, addr, 16, self.regs[rs2])?;
             }
             Sw { rs1, rs2, imm } => {
                 let rs1 = usize::from(rs1);
                 let rs2 = usize::from(rs2);
                 let addr = self.regs[rs1].wrapping_add(imm as u64);
-                self.store(addr, 32, self.regs[rs2])?;
+                self.store(bus, addr, 32, self.regs[rs2])?;
             }
             Sd { rs1, rs2, imm } => {
                 let rs1 = usize::from(rs1);
                 let rs2 = usize::from(rs2);
                 let addr = self.regs[rs1].wrapping_add(imm as u64);
-                self.store(addr, 64, self.regs[rs2])?;
+                self.store(bus, addr, 64, self.regs[rs2])?;
             }
             Add { rd, rs1, rs2 } => {
                 let rd = usize::from(rd);
@@ -249,7 +613,7 @@ impl Cpu {
                 let rd = usize::from(rd);
                 let rs1 = usize::from(rs1);
                 let rs2 = usize::from(rs2);
-                let shamt = (self.regs[rs2] & 0x3f) as u64 as u32;
+                let shamt = (self.regs[rs2] & 0x3f) as u32;
                 self.regs[rd] = self.regs[rs1].wrapping_shl(shamt);
             }
             Slt { rd, rs1, rs2 } => {
@@ -274,14 +638,14 @@ impl Cpu {
                 let rd = usize::from(rd);
                 let rs1 = usize::from(rs1);
                 let rs2 = usize::from(rs2);
-                let shamt = (self.regs[rs2] & 0x3f) as u64 as u32;
+                let shamt = (self.regs[rs2] & 0x3f) as u32;
                 self.regs[rd] = self.regs[rs1].wrapping_shr(shamt);
             }
             Sra { rd, rs1, rs2 } => {
                 let rd = usize::from(rd);
                 let rs1 = usize::from(rs1);
                 let rs2 = usize::from(rs2);
-                let shamt = (self.regs[rs2] & 0x3f) as u64 as u32;
+                let shamt = (self.regs[rs2] & 0x3f) as u32;
                 self.regs[rd] = (self.regs[rs1] as i64).wrapping_shr(shamt) as u64;
             }
             Or { rd, rs1, rs2 } => {
@@ -316,29 +680,30 @@ impl Cpu {
                 let rd = usize::from(rd);
                 let rs1 = usize::from(rs1);
                 let rs2 = usize::from(rs2);
-                let shamt = (self.regs[rs2] & 0x3f) as u64 as u32;
+                let shamt = (self.regs[rs2] & 0x3f) as u32;
                 self.regs[rd] = (self.regs[rs1] as u32).wrapping_shl(shamt) as i32 as u64;
             }
             Srlw { rd, rs1, rs2 } => {
                 let rd = usize::from(rd);
                 let rs1 = usize::from(rs1);
                 let rs2 = usize::from(rs2);
-                let shamt = (self.regs[rs2] & 0x3f) as u64 as u32;
+                let shamt = (self.regs[rs2] & 0x3f) as u32;
                 self.regs[rd] = (self.regs[rs1] as u32).wrapping_shr(shamt) as i32 as u64;
             }
             Sraw { rd, rs1, rs2 } => {
                 let rd = usize::from(rd);
                 let rs1 = usize::from(rs1);
                 let rs2 = usize::from(rs2);
-                let shamt = (self.regs[rs2] & 0x3f) as u64 as u32;
-                self.regs[rd] = ((self.regs[rs1] as i32) >> (shamt as i32)) as u64;
+                // Only the low 5 bits of rs2 count for 32-bit shifts
+                let shamt = (self.regs[rs2] & 0x1f) as u32;
+                self.regs[rd] = ((self.regs[rs1] as i32) >> shamt) as u64;
             }
             Beq { rs1, rs2, imm } => {
                 let rs1 = usize::from(rs1);
                 let rs2 = usize::from(rs2);
                 let imm = imm as u64;
                 if self.regs[rs1] == self.regs[rs2] {
-                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                    self.jump(bus, self.pc.wrapping_add(imm).wrapping_sub(4))?;
                 }
             }
             Bne { rs1, rs2, imm } => {
@@ -346,7 +711,7 @@ impl Cpu {
                 let rs2 = usize::from(rs2);
                 let imm = imm as u64;
                 if self.regs[rs1] != self.regs[rs2] {
-                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                    self.jump(bus, self.pc.wrapping_add(imm).wrapping_sub(4))?;
                 }
             }
             Blt { rs1, rs2, imm } => {
@@ -354,7 +719,7 @@ impl Cpu {
                 let rs2 = usize::from(rs2);
                 let imm = imm as u64;
                 if (self.regs[rs1] as i64) < (self.regs[rs2] as i64) {
-                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                    self.jump(bus, self.pc.wrapping_add(imm).wrapping_sub(4))?;
                 }
             }
             Bge { rs1, rs2, imm } => {
@@ -362,7 +727,7 @@ impl Cpu {
                 let rs2 = usize::from(rs2);
                 let imm = imm as u64;
                 if (self.regs[rs1] as i64) >= (self.regs[rs2] as i64) {
-                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                    self.jump(bus, self.pc.wrapping_add(imm).wrapping_sub(4))?;
                 }
             }
             Bltu { rs1, rs2, imm } => {
@@ -370,7 +735,7 @@ impl Cpu {
                 let rs2 = usize::from(rs2);
                 let imm = imm as u64;
                 if self.regs[rs1] < self.regs[rs2] {
-                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                    self.jump(bus, self.pc.wrapping_add(imm).wrapping_sub(4))?;
                 }
             }
             Bgeu { rs1, rs2, imm } => {
@@ -378,7 +743,7 @@ impl Cpu {
                 let rs2 = usize::from(rs2);
                 let imm = imm as u64;
                 if self.regs[rs1] >= self.regs[rs2] {
-                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                    self.jump(bus, self.pc.wrapping_add(imm).wrapping_sub(4))?;
                 }
             }
             Jalr { rd, rs1, imm } => {
@@ -388,21 +753,177 @@ impl Cpu {
                 let t = self.pc;
                 let imm = imm as i64 as u64;
                 // Don't add 4 because pc already moved on.
-                self.pc = (self.regs[rs1].wrapping_add(imm)) & !1;
+                self.jump(bus, (self.regs[rs1].wrapping_add(imm)) & !1)?;
                 self.regs[rd] = t;
             }
             Jal { rd, imm } => {
                 let rd = usize::from(rd);
-                self.regs[rd] = self.pc;
+                let t = self.pc;
                 let imm = imm as i64 as u64;
-                self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
+                self.jump(bus, self.pc.wrapping_add(imm).wrapping_sub(4))?;
+                self.regs[rd] = t;
+            }
+            Fence { .. } => {
+                // Order the relaxed loads and stores against harts running on other threads
+                fence(Ordering::SeqCst);
+            }
+            FenceI => {
+                // Stores already invalidate the decoded instructions of every hart, the flush
+                // only costs a refill
+                self.icache.flush();
+                self.blocks.flush();
+            }
+            Ecall => {
+                return Err(match self.mode {
+                    Mode::User => Exception::EnvironmentCallFromUMode,
+                    Mode::Supervisor => Exception::EnvironmentCallFromSMode,
+                    Mode::Machine => Exception::EnvironmentCallFromMMode,
+                });
+            }
+            Ebreak => {
+                return Err(Exception::Breakpoint(self.pc.wrapping_sub(4)));
+            }
+            Sret => {
+                if self.mode == Mode::User || !self.csr.implements(b'S') {
+                    return Err(Exception::IllegalInstruction(inst as u64));
+                }
+                self.pc = self.csr.load(SEPC);
+
+                // Restore the privilege mode and the interrupt enable bit, SPP goes back to user
+                let mut status = self.csr.load(MSTATUS);
+                self.mode = Mode::from((status & MSTATUS_SPP) >> 8);
+                let spie = (status & MSTATUS_SPIE) >> 5;
+                status = (status & !MSTATUS_SIE) | (spie << 1);
+                status |= MSTATUS_SPIE;
+                status &= !MSTATUS_SPP;
+                self.csr.store(MSTATUS, status);
+            }
+            Mret => {
+                if self.mode != Mode::Machine {
+                    return Err(Exception::IllegalInstruction(inst as u64));
+                }
+                self.pc = self.csr.load(MEPC);
+
+                // Restore the privilege mode and the interrupt enable bit, MPP goes back to user
+                let mut status = self.csr.load(MSTATUS);
+                self.mode = Mode::from((status & MSTATUS_MPP) >> 11);
+                let mpie = (status & MSTATUS_MPIE) >> 7;
+                status = (status & !MSTATUS_MIE) | (mpie << 3);
+                status |= MSTATUS_MPIE;
+                status &= !MSTATUS_MPP;
+                self.csr.store(MSTATUS, status);
+            }
+            Wfi => {
+                // Interrupts are checked before every instruction, waiting is just a nop.
+            }
+            SfenceVma { .. } => {
+                // There is no TLB, only Bare translation is implemented.
+                if self.mode == Mode::User {
+                    return Err(Exception::IllegalInstruction(inst as u64));
+                }
+            }
+            Csrrw { rd, rs1, csr } => {
+                let rd = usize::from(rd);
+                let rs1 = usize::from(rs1);
+                self.regs[rd] = self.update_csr(bus, inst, csr, Some(self.regs[rs1]), |_, new| new)?;
+            }
+            Csrrs { rd, rs1, csr } => {
+                let value = if rs1 == Register::X0 { None } else { Some(self.regs[usize::from(rs1)]) };
+                let rd = usize::from(rd);
+                self.regs[rd] = self.update_csr(bus, inst, csr, value, |old, new| old | new)?;
+            }
+            Csrrc { rd, rs1, csr } => {
+                let value = if rs1 == Register::X0 { None } else { Some(self.regs[usize::from(rs1)]) };
+                let rd = usize::from(rd);
+                self.regs[rd] = self.update_csr(bus, inst, csr, value, |old, new| old & !new)?;
+            }
+            Csrrwi { rd, uimm, csr } => {
+                let rd = usize::from(rd);
+                self.regs[rd] = self.update_csr(bus, inst, csr, Some(uimm as u64), |_, new| new)?;
+            }
+            Csrrsi { rd, uimm, csr } => {
+                let value = if uimm == 0 { None } else { Some(uimm as u64) };
+                let rd = usize::from(rd);
+                self.regs[rd] = self.update_csr(bus, inst, csr, value, |old, new| old | new)?;
+            }
+            Csrrci { rd, uimm, csr } => {
+                let value = if uimm == 0 { None } else { Some(uimm as u64) };
+                let rd = usize::from(rd);
+                self.regs[rd] = self.update_csr(bus, inst, csr, value, |old, new| old & !new)?;
             }
-            _ => {
-                dbg!(format!("Invalid instruction: {:?}.", inst));
-                return Err(());
+            LrW { rd, rs1 } => {
+                let rd = usize::from(rd);
+                let rs1 = usize::from(rs1);
+                let val = bus.load_reserved(self.hartid(), self.regs[rs1], 32)?;
+                self.memory_hooks(bus, Access { addr: self.regs[rs1], size: 32, value: val, write: false });
+                self.reserved_value = val;
+                self.regs[rd] = val as i32 as i64 as u64;
+            }
+            ScW { rd, rs1, rs2 } => {
+                let rd = usize::from(rd);
+                let rs1 = usize::from(rs1);
+                let rs2 = usize::from(rs2);
+                let stored = bus.store_conditional(self.hartid(), self.regs[rs1], 32, self.reserved_value, self.regs[rs2])?;
+                if stored {
+                    self.memory_hooks(bus, Access { addr: self.regs[rs1], size: 32, value: self.regs[rs2] & 0xffff_ffff, write: true });
+                }
+                self.regs[rd] = if stored { 0 } else { 1 };
+            }
+            AmoswapW { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 32, |_, src| src)?,
+            AmoaddW { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 32, |old, src| old.wrapping_add(src))?,
+            AmoxorW { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 32, |old, src| old ^ src)?,
+            AmoandW { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 32, |old, src| old & src)?,
+            AmoorW { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 32, |old, src| old | src)?,
+            AmominW { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 32, |old, src| (old as i32).min(src as i32) as u64)?,
+            AmomaxW { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 32, |old, src| (old as i32).max(src as i32) as u64)?,
+            AmominuW { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 32, |old, src| (old as u32).min(src as u32) as u64)?,
+            AmomaxuW { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 32, |old, src| (old as u32).max(src as u32) as u64)?,
+            LrD { rd, rs1 } => {
+                let rd = usize::from(rd);
+                let rs1 = usize::from(rs1);
+                let val = bus.load_reserved(self.hartid(), self.regs[rs1], 64)?;
+                self.memory_hooks(bus, Access { addr: self.regs[rs1], size: 64, value: val, write: false });
+                self.reserved_value = val;
+                self.regs[rd] = val;
+            }
+            ScD { rd, rs1, rs2 } => {
+                let rd = usize::from(rd);
+                let rs1 = usize::from(rs1);
+                let rs2 = usize::from(rs2);
+                let stored = bus.store_conditional(self.hartid(), self.regs[rs1], 64, self.reserved_value, self.regs[rs2])?;
+                if stored {
+                    self.memory_hooks(bus, Access { addr: self.regs[rs1], size: 64, value: self.regs[rs2], write: true });
+                }
+                self.regs[rd] = if stored { 0 } else { 1 };
+            }
+            AmoswapD { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 64, |_, src| src)?,
+            AmoaddD { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 64, |old, src| old.wrapping_add(src))?,
+            AmoxorD { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 64, |old, src| old ^ src)?,
+            AmoandD { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 64, |old, src| old & src)?,
+            AmoorD { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 64, |old, src| old | src)?,
+            AmominD { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 64, |old, src| (old as i64).min(src as i64) as u64)?,
+            AmomaxD { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 64, |old, src| (old as i64).max(src as i64) as u64)?,
+            AmominuD { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 64, |old, src| old.min(src))?,
+            AmomaxuD { rd, rs1, rs2 } => self.amo(bus, rd, rs1, rs2, 64, |old, src| old.max(src))?,
+            Undefined => {
+                return Err(Exception::IllegalInstruction(inst as u64));
             }
         }
 
+        // x0 is hardwired to zero, undo any write to it
+        self.regs[0] = 0;
+
         Ok(())
     }
 }
+
+// LR, SC and the AMOs, the instructions of the A extension
+fn is_atomic(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        LrW { .. } | ScW { .. } | AmoswapW { .. } | AmoaddW { .. } | AmoxorW { .. } | AmoandW { .. }
+        | AmoorW { .. } | AmominW { .. } | AmomaxW { .. } | AmominuW { .. } | AmomaxuW { .. }
+        | LrD { .. } | ScD { .. } | AmoswapD { .. } | AmoaddD { .. } | AmoxorD { .. } | AmoandD { .. }
+        | AmoorD { .. } | AmominD { .. } | AmomaxD { .. } | AmominuD { .. } | AmomaxuD { .. }
+    )
+}
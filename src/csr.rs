@@ -0,0 +1,227 @@
+// Control and status registers, see privileged specification chapter 2.2: CSR Listing
+
+// Number of CSRs, addresses are 12-bit
+pub const NUM_CSRS: usize = 4096;
+
+// Unprivileged counters
+pub const TIME: u16 = 0xc01;
+
+// Supervisor trap setup
+pub const SSTATUS: u16 = 0x100;
+pub const SIE: u16 = 0x104;
+pub const STVEC: u16 = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+
+// Supervisor trap handling
+pub const SSCRATCH: u16 = 0x140;
+pub const SEPC: u16 = 0x141;
+pub const SCAUSE: u16 = 0x142;
+pub const STVAL: u16 = 0x143;
+pub const SIP: u16 = 0x144;
+
+// Supervisor address translation and protection
+pub const SATP: u16 = 0x180;
+
+// Machine information registers
+pub const MVENDORID: u16 = 0xf11;
+pub const MARCHID: u16 = 0xf12;
+pub const MIMPID: u16 = 0xf13;
+pub const MHARTID: u16 = 0xf14;
+
+// Machine trap setup
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MEDELEG: u16 = 0x302;
+pub const MIDELEG: u16 = 0x303;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+
+// Machine trap handling
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+
+// Names of the CSRs kept in the CSR file, for debuggers and the monitor. time is served by
+// the CLINT and is not listed.
+pub const CSR_NAMES: [(u16, &str); 26] = [
+    (SSTATUS, "sstatus"),
+    (SIE, "sie"),
+    (STVEC, "stvec"),
+    (SCOUNTEREN, "scounteren"),
+    (SSCRATCH, "sscratch"),
+    (SEPC, "sepc"),
+    (SCAUSE, "scause"),
+    (STVAL, "stval"),
+    (SIP, "sip"),
+    (SATP, "satp"),
+    (MVENDORID, "mvendorid"),
+    (MARCHID, "marchid"),
+    (MIMPID, "mimpid"),
+    (MHARTID, "mhartid"),
+    (MSTATUS, "mstatus"),
+    (MISA, "misa"),
+    (MEDELEG, "medeleg"),
+    (MIDELEG, "mideleg"),
+    (MIE, "mie"),
+    (MTVEC, "mtvec"),
+    (MCOUNTEREN, "mcounteren"),
+    (MSCRATCH, "mscratch"),
+    (MEPC, "mepc"),
+    (MCAUSE, "mcause"),
+    (MTVAL, "mtval"),
+    (MIP, "mip"),
+];
+
+// mstatus fields
+pub const MSTATUS_SIE: u64 = 1 << 1;
+pub const MSTATUS_MIE: u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP: u64 = 1 << 8;
+pub const MSTATUS_MPP: u64 = 0b11 << 11;
+
+// UXL and SXL are fixed to 64-bit
+const MSTATUS_XLEN: u64 = (2 << 32) | (2 << 34);
+
+// Fields of mstatus visible through sstatus: SIE, SPIE, UBE, SPP, VS, FS, XS, SUM, MXR, UXL and SD
+const SSTATUS_MASK: u64 = 0x8000_0003_000d_e762;
+
+// Supervisor interrupts, the only bits of mip/mie visible through sip/sie
+const SUPERVISOR_INTERRUPTS: u64 = (1 << 1) | (1 << 5) | (1 << 9);
+
+// Bits of mip writable by software, the others mirror the interrupt sources
+const MIP_WRITABLE: u64 = SUPERVISOR_INTERRUPTS;
+
+// Only the supervisor software interrupt is writable through sip
+const SIP_WRITABLE: u64 = 1 << 1;
+
+// misa: MXL=2 (64-bit) and the implemented extensions, one bit per letter
+pub const MISA_VALUE: u64 = (2 << 62) | extension(b'A') | extension(b'I') | extension(b'S') | extension(b'U');
+
+/// misa bit of an extension letter
+pub const fn extension(letter: u8) -> u64 {
+    1 << (letter - b'A')
+}
+
+// CSR file
+pub struct Csr {
+    csrs: [u64; NUM_CSRS],
+}
+
+impl Csr {
+    pub fn new(hartid: u64) -> Self {
+        let mut csrs = [0; NUM_CSRS];
+        csrs[MISA as usize] = MISA_VALUE;
+        csrs[MSTATUS as usize] = MSTATUS_XLEN;
+        csrs[MHARTID as usize] = hartid;
+        Self { csrs }
+    }
+
+    /// Set the implemented extensions, misa is read-only for the guest
+    pub fn set_misa(&mut self, misa: u64) {
+        self.csrs[MISA as usize] = misa;
+        if !self.implements_mode((self.csrs[MSTATUS as usize] & MSTATUS_MPP) >> 11) {
+            self.csrs[MSTATUS as usize] |= MSTATUS_MPP;
+        }
+    }
+
+    /// Whether misa has the extension `letter`
+    pub fn implements(&self, letter: u8) -> bool {
+        self.csrs[MISA as usize] & extension(letter) != 0
+    }
+
+    // Privilege modes MPP can hold, M is always implemented
+    fn implements_mode(&self, mode: u64) -> bool {
+        match mode {
+            0b00 => self.implements(b'U'),
+            0b01 => self.implements(b'S'),
+            _ => mode == 0b11,
+        }
+    }
+
+    // Read a CSR, the supervisor registers are views of the machine ones
+    pub fn load(&self, addr: u16) -> u64 {
+        match addr {
+            SSTATUS => self.csrs[MSTATUS as usize] & SSTATUS_MASK,
+            SIE => self.csrs[MIE as usize] & self.csrs[MIDELEG as usize],
+            SIP => self.csrs[MIP as usize] & self.csrs[MIDELEG as usize],
+            _ => self.csrs[addr as usize],
+        }
+    }
+
+    // Write a CSR, writes to read-only and WARL fields are dropped here
+    pub fn store(&mut self, addr: u16, value: u64) {
+        match addr {
+            MISA | MVENDORID | MARCHID | MIMPID | MHARTID => {}
+            MSTATUS => {
+                let mut value = (value & !MSTATUS_XLEN) | MSTATUS_XLEN;
+                // MPP is WARL, a mode the hart does not implement keeps the previous one
+                if !self.implements_mode((value & MSTATUS_MPP) >> 11) {
+                    value = (value & !MSTATUS_MPP) | (self.csrs[addr as usize] & MSTATUS_MPP);
+                }
+                self.csrs[addr as usize] = value;
+            }
+            // Traps cannot be delegated without S-mode
+            MEDELEG | MIDELEG if !self.implements(b'S') => {}
+            SSTATUS => {
+                let mstatus = self.csrs[MSTATUS as usize];
+                self.store(MSTATUS, (mstatus & !SSTATUS_MASK) | (value & SSTATUS_MASK));
+            }
+            MIDELEG => self.csrs[addr as usize] = value & SUPERVISOR_INTERRUPTS,
+            MIP => {
+                let mip = self.csrs[addr as usize];
+                self.csrs[addr as usize] = (mip & !MIP_WRITABLE) | (value & MIP_WRITABLE);
+            }
+            SIE => {
+                let mask = self.csrs[MIDELEG as usize];
+                let mie = self.csrs[MIE as usize];
+                self.csrs[MIE as usize] = (mie & !mask) | (value & mask);
+            }
+            SIP => {
+                let mask = self.csrs[MIDELEG as usize] & SIP_WRITABLE;
+                let mip = self.csrs[MIP as usize];
+                self.csrs[MIP as usize] = (mip & !mask) | (value & mask);
+            }
+            // Only Bare translation is implemented, writing another mode has no effect
+            SATP if value >> 60 != 0 => {}
+            _ => self.csrs[addr as usize] = value,
+        }
+    }
+
+    // Every CSR as stored, without the views and the checks of the guest accesses, for snapshots
+    pub(crate) fn raw(&self) -> &[u64; NUM_CSRS] {
+        &self.csrs
+    }
+
+    pub(crate) fn raw_mut(&mut self) -> &mut [u64; NUM_CSRS] {
+        &mut self.csrs
+    }
+
+    // Set or clear an interrupt pending bit on behalf of a device
+    pub fn set_pending(&mut self, mask: u64, pending: bool) {
+        if pending {
+            self.csrs[MIP as usize] |= mask;
+        } else {
+            self.csrs[MIP as usize] &= !mask;
+        }
+    }
+
+    // CSR address bits [11:10] == 0b11 mark a read-only register
+    pub fn is_read_only(addr: u16) -> bool {
+        (addr >> 10) & 0b11 == 0b11
+    }
+
+    // CSR address bits [9:8] encode the lowest privilege level that can access it
+    pub fn privilege(addr: u16) -> u64 {
+        ((addr >> 8) & 0b11) as u64
+    }
+}
+
+impl Default for Csr {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
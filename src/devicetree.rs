@@ -0,0 +1,266 @@
+use crate::clint::*;
+use crate::config::MachineConfig;
+use crate::finisher::*;
+use crate::uart::*;
+
+// Flattened device tree blob, see the Devicetree Specification chapter 5: Flattened Devicetree Format
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+// Structure block tokens
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+// Phandle of the interrupt controller of the first hart, the others follow
+const CPU0_INTC_PHANDLE: u32 = 1;
+// Phandle of the test finisher, after the interrupt controllers of all harts
+const FINISHER_PHANDLE: u32 = CPU0_INTC_PHANDLE + MAX_HARTS as u32;
+
+// Writer for the structure and strings blocks
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl Fdt {
+    pub fn new() -> Self {
+        Self {
+            structure: Vec::new(),
+            strings: Vec::new(),
+        }
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    // Structure block entries are padded to 4 bytes
+    fn push_padded(&mut self, bytes: &[u8]) {
+        self.structure.extend_from_slice(bytes);
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    // Offset of a property name in the strings block, names are shared between nodes
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        for s in self.strings.split(|&c| c == 0) {
+            if s == name.as_bytes() {
+                return offset as u32;
+            }
+            offset += s.len() + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        let mut bytes = name.as_bytes().to_vec();
+        bytes.push(0);
+        self.push_padded(&bytes);
+    }
+
+    pub fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name);
+        self.push_padded(value);
+    }
+
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &bytes);
+    }
+
+    // A 64-bit value as two cells, used for addresses and sizes with #address-cells = <2>
+    pub fn property_u64s(&mut self, name: &str, values: &[u64]) {
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        self.property(name, &bytes);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut bytes = Vec::new();
+        for value in values {
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+
+    // Assemble the header, an empty memory reservation map, the structure and the strings
+    pub fn finish(mut self) -> Vec<u8> {
+        self.push_u32(FDT_END);
+
+        // The reservation map must be 8-byte aligned and is terminated by a zero entry
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(total_size);
+        for field in header {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; 16]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+impl Default for Fdt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Describe the emulated machine: memory, the harts and the devices on the bus. The kernel
+// command line and the initramfs location, if any, go to /chosen.
+pub fn machine_dtb(config: &MachineConfig, bootargs: Option<&str>, initrd: Option<(u64, u64)>) -> Vec<u8> {
+    let (dram_base, dram_size, harts) = (config.memory_base, config.memory_size, config.harts);
+    let mut fdt = Fdt::new();
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "rv64_emu");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", config.uart_base));
+    if let Some(bootargs) = bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    if let Some((start, end)) = initrd {
+        fdt.property_u64s("linux,initrd-start", &[start]);
+        fdt.property_u64s("linux,initrd-end", &[end]);
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", dram_base));
+    fdt.property_string("device_type", "memory");
+    fdt.property_u64s("reg", &[dram_base, dram_size]);
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQUENCY as u32);
+    for hart in 0..harts as u32 {
+        fdt.begin_node(&format!("cpu@{}", hart));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hart);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &config.isa_string());
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", CPU0_INTC_PHANDLE + hart);
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    // Power off and reboot through the test finisher
+    let power = [("poweroff", "syscon-poweroff", FINISHER_PASS), ("reboot", "syscon-reboot", FINISHER_RESET)];
+    for (name, compatible, value) in power {
+        fdt.begin_node(name);
+        fdt.property_string("compatible", compatible);
+        fdt.property_u32("regmap", FINISHER_PHANDLE);
+        fdt.property_u32("offset", 0);
+        fdt.property_u32("value", value as u32);
+        fdt.end_node();
+    }
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+
+    fdt.begin_node(&format!("clint@{:x}", config.clint_base));
+    fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+    fdt.property_u64s("reg", &[config.clint_base, CLINT_SIZE]);
+    // Machine software and machine timer interrupts of each hart
+    let interrupts: Vec<u32> = (0..harts as u32)
+        .flat_map(|hart| [CPU0_INTC_PHANDLE + hart, 3, CPU0_INTC_PHANDLE + hart, 7])
+        .collect();
+    fdt.property_cells("interrupts-extended", &interrupts);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("serial@{:x}", config.uart_base));
+    fdt.property_string("compatible", "ns16550a");
+    fdt.property_u64s("reg", &[config.uart_base, UART_SIZE]);
+    fdt.property_u32("clock-frequency", 3_686_400);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("test@{:x}", config.finisher_base));
+    fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+    fdt.property_u64s("reg", &[config.finisher_base, FINISHER_SIZE]);
+    fdt.property_u32("phandle", FINISHER_PHANDLE);
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.end_node();
+    fdt.finish()
+}
+
+#[test]
+fn test_machine_dtb() {
+    let config = MachineConfig { harts: 2, ..Default::default() };
+    let dtb = machine_dtb(&config, Some("console=ttyS0"), Some((0x8700_0000, 0x8710_0000)));
+    let word = |offset: usize| u32::from_be_bytes(dtb[offset..offset + 4].try_into().unwrap());
+
+    assert_eq!(word(0), FDT_MAGIC);
+    assert_eq!(word(4) as usize, dtb.len());
+    // The structure block opens the root node and ends with FDT_END
+    assert_eq!(word(word(8) as usize), FDT_BEGIN_NODE);
+    assert_eq!(word((word(8) + word(36)) as usize - 4), FDT_END);
+    // Property names are stored once
+    let strings = &dtb[word(12) as usize..];
+    assert_eq!(strings.split(|&c| c == 0).filter(|s| *s == b"compatible").count(), 1);
+    assert!(strings.windows(18).any(|s| s == b"linux,initrd-start"));
+    assert!(dtb.windows(14).any(|s| s == b"console=ttyS0\0"));
+    assert!(dtb.windows(6).any(|s| s == b"cpu@1\0"));
+    assert!(dtb.windows(12).any(|s| s == b"test@100000\0"));
+}
@@ -0,0 +1,299 @@
+// Disassembler producing GNU-style assembly with ABI register names, e.g. `addi sp,sp,-16`.
+// Common pseudo-instructions are recognized, as objdump does without `-M no-aliases`.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::csr::*;
+use crate::instruction::Instruction::*;
+use crate::instruction::*;
+use crate::register::Register;
+use crate::register::Register::*;
+
+// Symbols sorted by address, to name the code around an address
+pub struct Symbols {
+    sorted: Vec<(u64, String)>,
+}
+
+impl Symbols {
+    pub fn new(symbols: &HashMap<String, u64>) -> Self {
+        let mut sorted: Vec<(u64, String)> = symbols.iter().map(|(name, addr)| (*addr, name.clone())).collect();
+        sorted.sort();
+        Self { sorted }
+    }
+
+    // Symbol at exactly this address
+    pub fn at(&self, addr: u64) -> Option<&str> {
+        let i = self.sorted.partition_point(|(a, _)| *a < addr);
+        self.sorted.get(i).filter(|(a, _)| *a == addr).map(|(_, name)| name.as_str())
+    }
+
+    // `name` or `name+0x10` for the closest symbol at or below the address
+    pub fn describe(&self, addr: u64) -> Option<String> {
+        let i = self.sorted.partition_point(|(a, _)| *a <= addr);
+        let (base, name) = self.sorted.get(i.checked_sub(1)?)?;
+        Some(match addr - base {
+            0 => name.clone(),
+            offset => format!("{}+{:#x}", name, offset),
+        })
+    }
+}
+
+fn csr_name(csr: u16) -> String {
+    if csr == TIME {
+        return "time".to_string();
+    }
+    match CSR_NAMES.iter().find(|(addr, _)| *addr == csr) {
+        Some((_, name)) => name.to_string(),
+        None => format!("{:#x}", csr),
+    }
+}
+
+// A CSR instruction, csrw/csrs/csrc and their immediate forms when the old value is dropped
+fn csr_text(name: &str, rd: &Register, src: String, csr: u16) -> String {
+    match rd {
+        X0 => format!("{} {},{}", name.replacen("csrr", "csr", 1), csr_name(csr), src),
+        _ => format!("{} {},{},{}", name, rd, csr_name(csr), src),
+    }
+}
+
+// Predecessor and successor sets of a fence
+fn fence_set(bits: i32) -> String {
+    "iorw".chars().enumerate().filter(|(i, _)| bits & (8 >> i) != 0).map(|(_, c)| c).collect()
+}
+
+// Assembly text, `target` formats the destination of a branch or a jump from its offset
+fn text(inst: &Instruction, target: &dyn Fn(i32) -> String) -> String {
+    let r = |name: &str, rd: &Register, rs1: &Register, rs2: &Register| format!("{} {},{},{}", name, rd, rs1, rs2);
+    let i = |name: &str, rd: &Register, rs1: &Register, imm: i64| format!("{} {},{},{}", name, rd, rs1, imm);
+    let load = |name: &str, rd: &Register, rs1: &Register, imm: &i32| format!("{} {},{}({})", name, rd, imm, rs1);
+    let store = |name: &str, rs2: &Register, rs1: &Register, imm: &i32| format!("{} {},{}({})", name, rs2, imm, rs1);
+    let branch = |name: &str, rs1: &Register, rs2: &Register, imm: &i32| {
+        match (rs1, rs2) {
+            (_, X0) => format!("{}z {},{}", name, rs1, target(*imm)),
+            // bgt/ble-style swaps only read better against zero
+            (X0, _) if name == "blt" => format!("bgtz {},{}", rs2, target(*imm)),
+            (X0, _) if name == "bge" => format!("blez {},{}", rs2, target(*imm)),
+            _ => format!("{} {},{},{}", name, rs1, rs2, target(*imm)),
+        }
+    };
+    let lr = |name: &str, rd: &Register, rs1: &Register| format!("{} {},({})", name, rd, rs1);
+    let amo = |name: &str, rd: &Register, rs1: &Register, rs2: &Register| format!("{} {},{},({})", name, rd, rs2, rs1);
+
+    match inst {
+        Undefined => "unknown".to_string(),
+
+        Addi { rd: X0, rs1: X0, imm: 0 } => "nop".to_string(),
+        Addi { rd, rs1: X0, imm } => format!("li {},{}", rd, imm),
+        Addi { rd, rs1, imm: 0 } => format!("mv {},{}", rd, rs1),
+        Addi { rd, rs1, imm } => i("addi", rd, rs1, *imm as i64),
+        Slti { rd, rs1, imm } => i("slti", rd, rs1, *imm as i64),
+        Sltiu { rd, rs1, imm: 1 } => format!("seqz {},{}", rd, rs1),
+        Sltiu { rd, rs1, imm } => i("sltiu", rd, rs1, *imm as i64),
+        Xori { rd, rs1, imm: -1 } => format!("not {},{}", rd, rs1),
+        Xori { rd, rs1, imm } => i("xori", rd, rs1, *imm as i64),
+        Ori { rd, rs1, imm } => i("ori", rd, rs1, *imm as i64),
+        Andi { rd, rs1, imm } => i("andi", rd, rs1, *imm as i64),
+
+        Slli { rd, rs1, shamt } => i("slli", rd, rs1, *shamt as i64),
+        Srli { rd, rs1, shamt } => i("srli", rd, rs1, *shamt as i64),
+        Srai { rd, rs1, shamt } => i("srai", rd, rs1, *shamt as i64),
+
+        Lb { rd, rs1, imm } => load("lb", rd, rs1, imm),
+        Lh { rd, rs1, imm } => load("lh", rd, rs1, imm),
+        Lw { rd, rs1, imm } => load("lw", rd, rs1, imm),
+        Lbu { rd, rs1, imm } => load("lbu", rd, rs1, imm),
+        Lhu { rd, rs1, imm } => load("lhu", rd, rs1, imm),
+        Lwu { rd, rs1, imm } => load("lwu", rd, rs1, imm),
+        Ld { rd, rs1, imm } => load("ld", rd, rs1, imm),
+
+        Fence { imm, .. } => {
+            let (pred, succ) = ((imm >> 4) & 0xf, imm & 0xf);
+            if pred == 0xf && succ == 0xf {
+                "fence".to_string()
+            } else {
+                format!("fence {},{}", fence_set(pred), fence_set(succ))
+            }
+        }
+        FenceI => "fence.i".to_string(),
+
+        Jalr { rd: X0, rs1: X1, imm: 0 } => "ret".to_string(),
+        Jalr { rd: X0, rs1, imm: 0 } => format!("jr {}", rs1),
+        Jalr { rd: X1, rs1, imm: 0 } => format!("jalr {}", rs1),
+        Jalr { rd, rs1, imm } => format!("jalr {},{}({})", rd, imm, rs1),
+
+        Ecall => "ecall".to_string(),
+        Ebreak => "ebreak".to_string(),
+        Sret => "sret".to_string(),
+        Mret => "mret".to_string(),
+        Wfi => "wfi".to_string(),
+        SfenceVma { rs1: X0, rs2: X0 } => "sfence.vma".to_string(),
+        SfenceVma { rs1, rs2: X0 } => format!("sfence.vma {}", rs1),
+        SfenceVma { rs1, rs2 } => format!("sfence.vma {},{}", rs1, rs2),
+
+        Csrrs { rd, rs1: X0, csr } => format!("csrr {},{}", rd, csr_name(*csr)),
+        Csrrw { rd, rs1, csr } => csr_text("csrrw", rd, rs1.to_string(), *csr),
+        Csrrs { rd, rs1, csr } => csr_text("csrrs", rd, rs1.to_string(), *csr),
+        Csrrc { rd, rs1, csr } => csr_text("csrrc", rd, rs1.to_string(), *csr),
+        Csrrwi { rd, uimm, csr } => csr_text("csrrwi", rd, uimm.to_string(), *csr),
+        Csrrsi { rd, uimm, csr } => csr_text("csrrsi", rd, uimm.to_string(), *csr),
+        Csrrci { rd, uimm, csr } => csr_text("csrrci", rd, uimm.to_string(), *csr),
+
+        Add { rd, rs1, rs2 } => r("add", rd, rs1, rs2),
+        Sub { rd, rs1: X0, rs2 } => format!("neg {},{}", rd, rs2),
+        Sub { rd, rs1, rs2 } => r("sub", rd, rs1, rs2),
+        Sll { rd, rs1, rs2 } => r("sll", rd, rs1, rs2),
+        Slt { rd, rs1, rs2: X0 } => format!("sltz {},{}", rd, rs1),
+        Slt { rd, rs1: X0, rs2 } => format!("sgtz {},{}", rd, rs2),
+        Slt { rd, rs1, rs2 } => r("slt", rd, rs1, rs2),
+        Sltu { rd, rs1: X0, rs2 } => format!("snez {},{}", rd, rs2),
+        Sltu { rd, rs1, rs2 } => r("sltu", rd, rs1, rs2),
+        Xor { rd, rs1, rs2 } => r("xor", rd, rs1, rs2),
+        Srl { rd, rs1, rs2 } => r("srl", rd, rs1, rs2),
+        Sra { rd, rs1, rs2 } => r("sra", rd, rs1, rs2),
+        Or { rd, rs1, rs2 } => r("or", rd, rs1, rs2),
+        And { rd, rs1, rs2 } => r("and", rd, rs1, rs2),
+
+        Auipc { rd, imm } => format!("auipc {},{:#x}", rd, (*imm as u32) >> 12),
+        Lui { rd, imm } => format!("lui {},{:#x}", rd, (*imm as u32) >> 12),
+
+        Sb { rs1, rs2, imm } => store("sb", rs2, rs1, imm),
+        Sh { rs1, rs2, imm } => store("sh", rs2, rs1, imm),
+        Sw { rs1, rs2, imm } => store("sw", rs2, rs1, imm),
+        Sd { rs1, rs2, imm } => store("sd", rs2, rs1, imm),
+
+        Beq { rs1, rs2, imm } => branch("beq", rs1, rs2, imm),
+        Bne { rs1, rs2, imm } => branch("bne", rs1, rs2, imm),
+        Blt { rs1, rs2, imm } => branch("blt", rs1, rs2, imm),
+        Bge { rs1, rs2, imm } => branch("bge", rs1, rs2, imm),
+        Bltu { rs1, rs2, imm } => format!("bltu {},{},{}", rs1, rs2, target(*imm)),
+        Bgeu { rs1, rs2, imm } => format!("bgeu {},{},{}", rs1, rs2, target(*imm)),
+
+        Jal { rd: X0, imm } => format!("j {}", target(*imm)),
+        Jal { rd: X1, imm } => format!("jal {}", target(*imm)),
+        Jal { rd, imm } => format!("jal {},{}", rd, target(*imm)),
+
+        Addiw { rd, rs1, imm: 0 } => format!("sext.w {},{}", rd, rs1),
+        Addiw { rd, rs1, imm } => i("addiw", rd, rs1, *imm as i64),
+        Slliw { rd, rs1, shamt } => i("slliw", rd, rs1, *shamt as i64),
+        Srliw { rd, rs1, shamt } => i("srliw", rd, rs1, *shamt as i64),
+        Sraiw { rd, rs1, shamt } => i("sraiw", rd, rs1, *shamt as i64),
+
+        Addw { rd, rs1, rs2 } => r("addw", rd, rs1, rs2),
+        Subw { rd, rs1: X0, rs2 } => format!("negw {},{}", rd, rs2),
+        Subw { rd, rs1, rs2 } => r("subw", rd, rs1, rs2),
+        Sllw { rd, rs1, rs2 } => r("sllw", rd, rs1, rs2),
+        Srlw { rd, rs1, rs2 } => r("srlw", rd, rs1, rs2),
+        Sraw { rd, rs1, rs2 } => r("sraw", rd, rs1, rs2),
+
+        LrW { rd, rs1 } => lr("lr.w", rd, rs1),
+        ScW { rd, rs1, rs2 } => amo("sc.w", rd, rs1, rs2),
+        AmoswapW { rd, rs1, rs2 } => amo("amoswap.w", rd, rs1, rs2),
+        AmoaddW { rd, rs1, rs2 } => amo("amoadd.w", rd, rs1, rs2),
+        AmoxorW { rd, rs1, rs2 } => amo("amoxor.w", rd, rs1, rs2),
+        AmoandW { rd, rs1, rs2 } => amo("amoand.w", rd, rs1, rs2),
+        AmoorW { rd, rs1, rs2 } => amo("amoor.w", rd, rs1, rs2),
+        AmominW { rd, rs1, rs2 } => amo("amomin.w", rd, rs1, rs2),
+        AmomaxW { rd, rs1, rs2 } => amo("amomax.w", rd, rs1, rs2),
+        AmominuW { rd, rs1, rs2 } => amo("amominu.w", rd, rs1, rs2),
+        AmomaxuW { rd, rs1, rs2 } => amo("amomaxu.w", rd, rs1, rs2),
+
+        LrD { rd, rs1 } => lr("lr.d", rd, rs1),
+        ScD { rd, rs1, rs2 } => amo("sc.d", rd, rs1, rs2),
+        AmoswapD { rd, rs1, rs2 } => amo("amoswap.d", rd, rs1, rs2),
+        AmoaddD { rd, rs1, rs2 } => amo("amoadd.d", rd, rs1, rs2),
+        AmoxorD { rd, rs1, rs2 } => amo("amoxor.d", rd, rs1, rs2),
+        AmoandD { rd, rs1, rs2 } => amo("amoand.d", rd, rs1, rs2),
+        AmoorD { rd, rs1, rs2 } => amo("amoor.d", rd, rs1, rs2),
+        AmominD { rd, rs1, rs2 } => amo("amomin.d", rd, rs1, rs2),
+        AmomaxD { rd, rs1, rs2 } => amo("amomax.d", rd, rs1, rs2),
+        AmominuD { rd, rs1, rs2 } => amo("amominu.d", rd, rs1, rs2),
+        AmomaxuD { rd, rs1, rs2 } => amo("amomaxu.d", rd, rs1, rs2),
+    }
+}
+
+// Without the address of the instruction, targets are offsets as the assembler accepts them:
+// `j .+16`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&text(self, &|offset| format!(".{:+}", offset)))
+    }
+}
+
+fn target(addr: u64, symbols: Option<&Symbols>) -> String {
+    match symbols.and_then(|symbols| symbols.describe(addr)) {
+        Some(name) => format!("{:#x} <{}>", addr, name),
+        None => format!("{:#x}", addr),
+    }
+}
+
+// Disassemble the instruction at `pc`, branch and jump targets are absolute and named after the
+// closest symbol
+pub fn disassemble(inst: u32, pc: u64, symbols: Option<&Symbols>) -> String {
+    match decode(inst) {
+        Undefined => format!(".word {:#010x}", inst),
+        instruction => text(&instruction, &|offset| target(pc.wrapping_add(offset as i64 as u64), symbols)),
+    }
+}
+
+// objdump-like listing of the code at `base`: a label before each symbol, then the address, the
+// raw bits and the text of each instruction. auipc+jalr pairs are shown as one call or tail.
+pub fn listing(code: &[u8], base: u64, symbols: Option<&Symbols>) -> String {
+    let words: Vec<u32> = code.chunks_exact(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect();
+    let mut output = String::new();
+    let mut i = 0;
+    while i < words.len() {
+        let pc = base + 4 * i as u64;
+        if let Some(name) = symbols.and_then(|symbols| symbols.at(pc)) {
+            output += &format!("\n{:016x} <{}>:\n", pc, name);
+        }
+
+        let call = match (decode(words[i]), words.get(i + 1).map(|w| decode(*w))) {
+            (Auipc { rd: X1, imm: high }, Some(Jalr { rd: X1, rs1: X1, imm: low })) => Some(("call", high, low)),
+            (Auipc { rd: X6, imm: high }, Some(Jalr { rd: X0, rs1: X6, imm: low })) => Some(("tail", high, low)),
+            _ => None,
+        };
+        let (raw, text) = match call {
+            Some((name, high, low)) => {
+                let addr = pc.wrapping_add(high as i64 as u64).wrapping_add(low as i64 as u64);
+                i += 1;
+                (format!("{:08x} {:08x}", words[i - 1], words[i]), format!("{} {}", name, target(addr, symbols)))
+            }
+            None => (format!("{:08x}", words[i]), disassemble(words[i], pc, symbols)),
+        };
+        output += &format!("{:8x}: {:<17}  {}\n", pc, raw, text);
+        i += 1;
+    }
+    output
+}
+
+#[test]
+fn test_disassemble() {
+    let symbols = Symbols::new(&HashMap::from([("reset".to_string(), 0x8000_0030), ("fail".to_string(), 0x8000_0094)]));
+    let cases: [(u32, u64, &str); 16] = [
+        (0x0300006f, 0x8000_0000, "j 0x80000030 <reset>"),
+        (0xfd428293, 0x8000_0034, "addi t0,t0,-44"),
+        (0xff010113, 0x8000_0000, "addi sp,sp,-16"),
+        (0x30529073, 0x8000_0038, "csrw mtvec,t0"),
+        (0xf1402573, 0x8000_003c, "csrr a0,mhartid"),
+        (0x3002b073, 0x8000_005c, "csrc mstatus,t0"),
+        (0x00000013, 0x8000_0000, "nop"),
+        (0x00500513, 0x8000_0064, "li a0,5"),
+        (0x02b51263, 0x8000_0070, "bne a0,a1,0x80000094 <fail>"),
+        (0x00050463, 0x8000_0070, "beqz a0,0x80000078 <reset+0x48>"),
+        (0x00a2b023, 0x8000_007c, "sd a0,0(t0)"),
+        (0x00001f17, 0x8000_0020, "auipc t5,0x1"),
+        (0x00008067, 0x8000_0000, "ret"),
+        (0x4000202f, 0x8000_0000, "amoor.w zero,zero,(zero)"),
+        (0x1005252f, 0x8000_0000, "lr.w a0,(a0)"),
+        (0xffffffff, 0x8000_0000, ".word 0xffffffff"),
+    ];
+    for (inst, pc, expected) in cases {
+        assert_eq!(disassemble(inst, pc, Some(&symbols)), expected, "{:08x}", inst);
+    }
+    assert_eq!(decode(0xfe000ee3).to_string(), "beqz zero,.-4");
+
+    // auipc ra,0x0; jalr ra,16(ra)
+    let code: Vec<u8> = [0x00000097u32, 0x010080e7].iter().flat_map(|w| w.to_le_bytes()).collect();
+    assert_eq!(listing(&code, 0x8000_0020, Some(&symbols)), "80000020: 00000097 010080e7  call 0x80000030 <reset>\n");
+}
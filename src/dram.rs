@@ -1,104 +1,389 @@
-use crate::bus::*;
+use std::alloc::Layout;
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
-// Init memory as 128MB
+// Default memory size, 128MB
 pub const DRAM_SIZE: u64 = 1024 * 1024 * 128;
 
-// Dram
+// Largest memory size, half of the 47-bit user address space of a 64-bit Linux host, a quarter of
+// the address space of smaller hosts. Mapping it can still fail.
+pub const MAX_DRAM_SIZE: u64 = if usize::BITS >= 64 { 1 << 46 } else { 1 << (usize::BITS - 2) };
+
+// Granule of the decoded-instruction caches, pages are watched for stores by lines of 64 bytes
+pub const PAGE_SIZE: u64 = 4096;
+const LINE_SIZE: u64 = PAGE_SIZE / 64;
+
+// Invalidation state of a page for the decoded-instruction caches of the harts: a store to one of
+// the lines a hart has cached instructions from bumps the generation and clears the lines
+#[derive(Debug)]
+struct CodePage {
+    generation: AtomicU32,
+    lines: AtomicU64,
+}
+
+// Dram, kept as 64-bit words so that harts on other threads can access it: every access within
+// a word is a single atomic operation, accesses crossing words are done byte by byte.
+// Plain loads and stores are relaxed, FENCE and the AMOs order them.
 #[derive(Debug)]
 pub struct Dram {
-    pub dram: Vec<u8>,
+    base: u64,
+    dram: Zeroed<AtomicU64>,
+    code_pages: Zeroed<CodePage>,
+}
+
+// Types whose all-zero value is valid, the initial value of the memory: only implement it for
+// those
+trait Zeroable {}
+
+impl Zeroable for AtomicU64 {}
+
+impl Zeroable for CodePage {}
+
+// `len` zeroed values, whose pages the OS only backs with memory once they are touched: DRAM
+// costs what the guest uses of it, not its size
+struct Zeroed<T> {
+    values: *mut T,
+    len: usize,
+}
+
+// The values are only shared through `&[T]`
+unsafe impl<T: Sync> Send for Zeroed<T> {}
+unsafe impl<T: Sync> Sync for Zeroed<T> {}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod map {
+    use std::ffi::c_void;
+
+    pub const PROT_READ: i32 = 1;
+    pub const PROT_WRITE: i32 = 2;
+    pub const MAP_PRIVATE: i32 = 0x02;
+    pub const MAP_ANONYMOUS: i32 = 0x20;
+    pub const MAP_NORESERVE: i32 = 0x4000;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        pub fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> i32;
+    }
+}
+
+// Layout of `len` values, an error when it does not fit in the address space of the host
+fn layout<T>(len: u64) -> io::Result<Layout> {
+    usize::try_from(len).ok().and_then(|len| Layout::array::<T>(len).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::OutOfMemory, "too large for the host"))
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+impl<T: Zeroable> Zeroed<T> {
+    fn new(len: u64) -> io::Result<Self> {
+        use map::*;
+
+        let layout = layout::<T>(len)?;
+        // Not committed up front, a guest can have more memory than the host as long as it does
+        // not use it all
+        let flags = MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE;
+        let values = unsafe { mmap(ptr::null_mut(), layout.size(), PROT_READ | PROT_WRITE, flags, -1, 0) };
+        if values == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { values: values as *mut T, len: len as usize })
+    }
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+impl<T> Drop for Zeroed<T> {
+    fn drop(&mut self) {
+        unsafe {
+            map::munmap(self.values as *mut _, self.len * size_of::<T>());
+        }
+    }
+}
+
+// Elsewhere zeroed allocations this large are fresh mappings too, but the host may refuse more
+// than it has
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+impl<T: Zeroable> Zeroed<T> {
+    fn new(len: u64) -> io::Result<Self> {
+        let layout = layout::<T>(len)?;
+        // The allocation is freed as a boxed slice, which does not allocate when it is empty
+        let values = match layout.size() {
+            0 => ptr::NonNull::dangling().as_ptr(),
+            _ => unsafe { std::alloc::alloc_zeroed(layout) as *mut T },
+        };
+        if values.is_null() {
+            return Err(io::ErrorKind::OutOfMemory.into());
+        }
+        Ok(Self { values, len: len as usize })
+    }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+impl<T> Drop for Zeroed<T> {
+    fn drop(&mut self) {
+        unsafe {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(self.values, self.len)));
+        }
+    }
+}
+
+impl<T> Deref for Zeroed<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.values, self.len) }
+    }
+}
+
+impl<T> fmt::Debug for Zeroed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} zeroed values", self.len)
+    }
 }
 
 impl Dram {
-    pub fn new(code: Vec<u8>) -> Self {
-        let mut dram = vec![0; DRAM_SIZE as usize];
-        dram.splice(..code.len(), code.iter().cloned());
-        Self { dram }
+    // `size` bytes at `base`, starting with `code`. An error when the host cannot map that much
+    // or `code` does not fit.
+    pub fn new(base: u64, size: u64, code: Vec<u8>) -> io::Result<Self> {
+        if code.len() as u64 > size {
+            let message = format!("a program of {} bytes does not fit in {} bytes of DRAM", code.len(), size);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        let allocate = |e: io::Error| {
+            io::Error::new(e.kind(), format!("cannot allocate {} bytes of DRAM: {}", size, e))
+        };
+        let dram = Zeroed::<AtomicU64>::new(size / 8).map_err(allocate)?;
+        for (i, chunk) in code.chunks(8).enumerate() {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            dram[i].store(u64::from_le_bytes(word), Ordering::Relaxed);
+        }
+        let code_pages = Zeroed::new(size.div_ceil(PAGE_SIZE)).map_err(allocate)?;
+        Ok(Self { base, dram, code_pages })
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    // Size in bytes
+    pub fn size(&self) -> u64 {
+        self.dram.len() as u64 * 8
+    }
+
+    // Whether the `len` bytes at `addr` are all in DRAM
+    pub fn contains(&self, addr: u64, len: u64) -> bool {
+        addr >= self.base && addr - self.base <= self.size() && len <= self.size() - (addr - self.base)
+    }
+
+    // Pieces of the `len` bytes at `addr` that each stay within a word: their address, offset from
+    // `addr` and length
+    fn pieces(&self, addr: u64, len: u64) -> impl Iterator<Item = (u64, usize, usize)> {
+        let base = self.base;
+        let mut done = 0;
+        std::iter::from_fn(move || {
+            let piece = addr + done;
+            let n = (8 - (piece - base) % 8).min(len - done);
+            done += n;
+            (n > 0).then_some((piece, (done - n) as usize, n as usize))
+        })
+    }
+
+    // Word index, bit offset in the word and mask of an access that does not cross words
+    fn locate(&self, addr: u64, size: u64) -> Option<(usize, u64, u64)> {
+        let offset = addr - self.base;
+        let shift = (offset % 8) * 8;
+        if shift + size > 64 {
+            return None;
+        }
+        let mask = if size == 64 { u64::MAX } else { (1 << size) - 1 };
+        Some(((offset / 8) as usize, shift, mask))
+    }
+
+    #[inline]
+    fn code_page(&self, addr: u64) -> &CodePage {
+        &self.code_pages[((addr - self.base) / PAGE_SIZE) as usize]
+    }
+
+    // Generation of the page holding `addr`, see `CodePage`
+    #[inline]
+    pub fn code_generation(&self, addr: u64) -> u32 {
+        self.code_page(addr).generation.load(Ordering::Acquire)
+    }
+
+    // Mark the line holding `addr` as cached by a hart, returns the generation of its page
+    pub fn watch_code(&self, addr: u64) -> u32 {
+        let page = self.code_page(addr);
+        page.lines.fetch_or(1 << (addr % PAGE_SIZE / LINE_SIZE), Ordering::AcqRel);
+        page.generation.load(Ordering::Acquire)
+    }
+
+    // Invalidate the cached instructions of the pages a store of `size` bits at `addr` touched,
+    // after the store so that a hart refilling its cache sees the new value. A hart running the
+    // code on another thread is only guaranteed to see the store after its FENCE.I.
+    fn written(&self, addr: u64, size: u64) {
+        self.written_line(addr);
+        self.written_line(addr + size / 8 - 1);
+    }
+
+    // Same for `len` bytes at `addr`, every line they cover
+    fn written_range(&self, addr: u64, len: u64) {
+        let end = addr + len;
+        let lines = std::iter::successors(Some(addr), |addr| Some((addr | (LINE_SIZE - 1)) + 1));
+        for addr in lines.take_while(|&addr| addr < end) {
+            self.written_line(addr);
+        }
+    }
+
+    fn written_line(&self, addr: u64) {
+        let page = self.code_page(addr);
+        let line = 1 << (addr % PAGE_SIZE / LINE_SIZE);
+        // The lines are cleared first, a hart watching a line again sees the new generation
+        if page.lines.load(Ordering::Relaxed) & line != 0 {
+            page.lines.store(0, Ordering::Release);
+            page.generation.fetch_add(1, Ordering::AcqRel);
+        }
     }
 
     // API for load memory, little endian
     pub fn load(&self, addr: u64, size: u64) -> Result<u64, ()> {
-        match size {
-            8 => Ok(self.load8(addr)),
-            16 => Ok(self.load16(addr)),
-            32 => Ok(self.load32(addr)),
-            64 => Ok(self.load64(addr)),
-            _ => Err(())
+        if !matches!(size, 8 | 16 | 32 | 64) || !self.contains(addr, size / 8) {
+            return Err(());
+        }
+        match self.locate(addr, size) {
+            Some((index, shift, mask)) => Ok((self.dram[index].load(Ordering::Relaxed) >> shift) & mask),
+            None => Ok((0..size / 8).fold(0, |value, i| value | (self.load8(addr + i) << (8 * i)))),
         }
     }
 
     // API for store memory, little endian
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), ()> {
-        match size {
-            8 => Ok(self.store8(addr, value)),
-            16 => Ok(self.store16(addr, value)),
-            32 => Ok(self.store32(addr, value)),
-            64 => Ok(self.store64(addr, value)),
-            _ => Err(())
+    pub fn store(&self, addr: u64, size: u64, value: u64) -> Result<(), ()> {
+        if !matches!(size, 8 | 16 | 32 | 64) || !self.contains(addr, size / 8) {
+            return Err(());
+        }
+        match self.locate(addr, size) {
+            Some((index, shift, mask)) => self.store_word(index, shift, mask, value),
+            None => {
+                for i in 0..size / 8 {
+                    self.store(addr + i, 8, value >> (8 * i))?;
+                }
+            }
         }
+        self.written(addr, size);
+        Ok(())
     }
 
-    // Internal methods for load memory
-    fn load8(&self, addr: u64) -> u64 {
-        let addr = (addr - DRAM_BASE) as usize;
-        self.dram[addr] as u64
+    // Copy the bytes at `addr` into `buf`, a word at a time
+    pub fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<(), ()> {
+        if !self.contains(addr, buf.len() as u64) {
+            return Err(());
+        }
+        for (addr, start, len) in self.pieces(addr, buf.len() as u64) {
+            let (index, shift, mask) = self.locate(addr, len as u64 * 8).unwrap();
+            let value = (self.dram[index].load(Ordering::Relaxed) >> shift) & mask;
+            buf[start..start + len].copy_from_slice(&value.to_le_bytes()[..len]);
+        }
+        Ok(())
     }
 
-    fn load16(&self, addr: u64) -> u64 {
-        let addr = (addr - DRAM_BASE) as usize;
-        self.dram[addr] as u64
-            | ((self.dram[addr + 1] as u64) << 8)
+    // Copy `data` to `addr`, a word at a time
+    pub fn write_bytes(&self, addr: u64, data: &[u8]) -> Result<(), ()> {
+        self.write_with(addr, data.len() as u64, |start, len| {
+            let mut bytes = [0; 8];
+            bytes[..len].copy_from_slice(&data[start..start + len]);
+            u64::from_le_bytes(bytes)
+        })
     }
 
-    fn load32(&self, addr: u64) -> u64 {
-        let addr = (addr - DRAM_BASE) as usize;
-        return self.dram[addr] as u64
-            | ((self.dram[addr + 1] as u64) << 8)
-            | ((self.dram[addr + 2] as u64) << 16)
-            | ((self.dram[addr + 3] as u64) << 24);
+    // Set the `len` bytes at `addr` to `value`
+    pub fn fill(&self, addr: u64, value: u8, len: u64) -> Result<(), ()> {
+        self.write_with(addr, len, |_, _| u64::from_le_bytes([value; 8]))
     }
 
-    fn load64(&self, addr: u64) -> u64 {
-        let addr = (addr - DRAM_BASE) as usize;
-        self.dram[addr] as u64
-            | ((self.dram[addr + 1] as u64) << 8)
-            | ((self.dram[addr + 2] as u64) << 16)
-            | ((self.dram[addr + 3] as u64) << 24)
-            | ((self.dram[addr + 4] as u64) << 32)
-            | ((self.dram[addr + 5] as u64) << 40)
-            | ((self.dram[addr + 6] as u64) << 48)
-            | ((self.dram[addr + 7] as u64) << 56)
+    // Store the `len` bytes at `addr` piece by piece, `piece` gives the value of the bytes at an
+    // offset and of a length
+    fn write_with(&self, addr: u64, len: u64, piece: impl Fn(usize, usize) -> u64) -> Result<(), ()> {
+        if !self.contains(addr, len) {
+            return Err(());
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        for (addr, start, len) in self.pieces(addr, len) {
+            let (index, shift, mask) = self.locate(addr, len as u64 * 8).unwrap();
+            self.store_word(index, shift, mask, piece(start, len));
+        }
+        self.written_range(addr, len);
+        Ok(())
     }
 
-    // Internal methods for store memory
-    fn store8(&mut self, addr: u64, value: u64) {
-        let addr = (addr - DRAM_BASE) as usize;
-        self.dram[addr] = (value & 0xff) as u8
+    // Store the bits of `value` under `mask` at `shift` in a word
+    fn store_word(&self, index: usize, shift: u64, mask: u64, value: u64) {
+        if mask == u64::MAX {
+            self.dram[index].store(value, Ordering::Relaxed);
+            return;
+        }
+        let _ = self.dram[index].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |word| {
+            Some((word & !(mask << shift)) | ((value & mask) << shift))
+        });
     }
 
-    fn store16(&mut self, addr: u64, value: u64) {
-        let addr = (addr - DRAM_BASE) as usize;
-        self.dram[addr] = (value & 0xff) as u8;
-        self.dram[addr + 1] = ((value >> 8) & 0xff) as u8;
+    fn load8(&self, addr: u64) -> u64 {
+        let (index, shift, _) = self.locate(addr, 8).unwrap();
+        (self.dram[index].load(Ordering::Relaxed) >> shift) & 0xff
     }
 
-    fn store32(&mut self, addr: u64, value: u64) {
-        let addr = (addr - DRAM_BASE) as usize;
-        self.dram[addr] = (value & 0xff) as u8;
-        self.dram[addr + 1] = ((value >> 8) & 0xff) as u8;
-        self.dram[addr + 2] = ((value >> 16) & 0xff) as u8;
-        self.dram[addr + 3] = ((value >> 24) & 0xff) as u8;
+    // Atomic read-modify-write of a value within a word, returns the old value
+    pub fn fetch_update(&self, addr: u64, size: u64, op: impl Fn(u64) -> u64) -> Result<u64, ()> {
+        if !matches!(size, 32 | 64) || !self.contains(addr, size / 8) {
+            return Err(());
+        }
+        let (index, shift, mask) = self.locate(addr, size).ok_or(())?;
+        let word = self.dram[index]
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |word| {
+                let new = op((word >> shift) & mask);
+                Some((word & !(mask << shift)) | ((new & mask) << shift))
+            })
+            .unwrap();
+        self.written(addr, size);
+        Ok((word >> shift) & mask)
     }
 
-    fn store64(&mut self, addr: u64, value: u64) {
-        let addr = (addr - DRAM_BASE) as usize;
-        self.dram[addr] = (value & 0xff) as u8;
-        self.dram[addr + 1] = ((value >> 8) & 0xff) as u8;
-        self.dram[addr + 2] = ((value >> 16) & 0xff) as u8;
-        self.dram[addr + 3] = ((value >> 24) & 0xff) as u8;
-        self.dram[addr + 4] = ((value >> 32) & 0xff) as u8;
-        self.dram[addr + 5] = ((value >> 40) & 0xff) as u8;
-        self.dram[addr + 6] = ((value >> 48) & 0xff) as u8;
-        self.dram[addr + 7] = ((value >> 56) & 0xff) as u8;
+    // Atomic compare-and-swap of a value within a word, returns whether `new` was written
+    pub fn compare_exchange(&self, addr: u64, size: u64, current: u64, new: u64) -> Result<bool, ()> {
+        if !matches!(size, 32 | 64) || !self.contains(addr, size / 8) {
+            return Err(());
+        }
+        let (index, shift, mask) = self.locate(addr, size).ok_or(())?;
+        let result = self.dram[index].fetch_update(Ordering::SeqCst, Ordering::SeqCst, |word| {
+            if (word >> shift) & mask != current & mask {
+                return None;
+            }
+            Some((word & !(mask << shift)) | ((new & mask) << shift))
+        });
+        if result.is_ok() {
+            self.written(addr, size);
+        }
+        Ok(result.is_ok())
     }
 }
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+#[test]
+fn test_sparse_memory() {
+    // More than most hosts have, only the touched pages are backed
+    let size = 64 << 30;
+    let dram = Dram::new(0x8000_0000, size, vec![0x13, 0, 0, 0, 0x6f]).unwrap();
+    assert!(Dram::new(0x8000_0000, 8, vec![0x13; 16]).is_err());
+    assert_eq!(dram.size(), size);
+    assert_eq!(dram.load(0x8000_0000, 64), Ok(0x6f_0000_0013));
+    let last = 0x8000_0000 + size - 8;
+    assert_eq!(dram.load(last, 64), Ok(0));
+    dram.store(last + 4, 32, 0x1234_5678).unwrap();
+    assert_eq!(dram.load(last, 64), Ok(0x1234_5678_0000_0000));
+    assert_eq!(dram.fetch_update(last + 4, 32, |value| value + 1), Ok(0x1234_5678));
+    assert_eq!(dram.load(last + 4, 32), Ok(0x1234_5679));
+}
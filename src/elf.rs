@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::io;
+
+// ELF identification, see System V ABI chapter 4: Object Files
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+// Program header and section header types
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+
+/// Loadable segment, `data` is shorter than `mem_size` when the segment has a .bss part
+#[derive(Clone)]
+pub struct Segment {
+    pub addr: u64,
+    pub data: Vec<u8>,
+    pub mem_size: u64,
+    pub executable: bool,
+}
+
+/// RV64 little endian ELF executable
+#[derive(Clone)]
+pub struct Elf {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    pub symbols: HashMap<String, u64>,
+}
+
+/// Check the magic number without parsing the rest of the file
+pub fn is_elf(data: &[u8]) -> bool {
+    data.len() >= 4 && data[..4] == ELF_MAGIC
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> io::Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("truncated ELF file"))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> io::Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("truncated ELF file"))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> io::Result<u64> {
+    data.get(offset..offset + 8)
+        .map(|b| u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+        .ok_or_else(|| invalid("truncated ELF file"))
+}
+
+fn read_slice(data: &[u8], offset: u64, size: u64) -> io::Result<&[u8]> {
+    let start = offset as usize;
+    let end = offset.checked_add(size).ok_or_else(|| invalid("truncated ELF file"))? as usize;
+    data.get(start..end).ok_or_else(|| invalid("truncated ELF file"))
+}
+
+impl Elf {
+    pub fn parse(data: &[u8]) -> io::Result<Self> {
+        if !is_elf(data) {
+            return Err(invalid("not an ELF file"));
+        }
+        if data.len() < 64 || data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+            return Err(invalid("not a 64-bit little endian ELF file"));
+        }
+        if read_u16(data, 18)? != EM_RISCV {
+            return Err(invalid("not a RISC-V ELF file"));
+        }
+
+        let entry = read_u64(data, 24)?;
+        let phoff = read_u64(data, 32)? as usize;
+        let shoff = read_u64(data, 40)? as usize;
+        let phentsize = read_u16(data, 54)? as usize;
+        let phnum = read_u16(data, 56)? as usize;
+        let shentsize = read_u16(data, 58)? as usize;
+        let shnum = read_u16(data, 60)? as usize;
+
+        // Program headers: collect the loadable segments at their physical addresses
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = phoff + i * phentsize;
+            if read_u32(data, ph)? != PT_LOAD {
+                continue;
+            }
+            let flags = read_u32(data, ph + 4)?;
+            let offset = read_u64(data, ph + 8)?;
+            let addr = read_u64(data, ph + 24)?;
+            let file_size = read_u64(data, ph + 32)?;
+            let mem_size = read_u64(data, ph + 40)?;
+            segments.push(Segment {
+                addr,
+                data: read_slice(data, offset, file_size)?.to_vec(),
+                mem_size,
+                executable: flags & PF_X != 0,
+            });
+        }
+
+        // Section headers: the symbol table is optional, stripped files have none
+        let mut symbols = HashMap::new();
+        for i in 0..shnum {
+            let sh = shoff + i * shentsize;
+            if read_u32(data, sh + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let offset = read_u64(data, sh + 24)?;
+            let size = read_u64(data, sh + 32)?;
+            let link = read_u32(data, sh + 40)? as usize;
+            let entsize = read_u64(data, sh + 56)?;
+
+            // The linked section holds the symbol names
+            let strtab = shoff + link * shentsize;
+            let strtab = read_slice(data, read_u64(data, strtab + 24)?, read_u64(data, strtab + 32)?)?;
+
+            let table = read_slice(data, offset, size)?;
+            for sym in table.chunks_exact(entsize.max(24) as usize) {
+                let name = read_u32(sym, 0)? as usize;
+                let value = read_u64(sym, 8)?;
+                let name = match strtab.get(name..) {
+                    Some(s) => s.split(|&c| c == 0).next().unwrap_or(&[]),
+                    None => continue,
+                };
+                if !name.is_empty() {
+                    symbols.insert(String::from_utf8_lossy(name).into_owned(), value);
+                }
+            }
+        }
+
+        Ok(Self { entry, segments, symbols })
+    }
+
+    /// Look up the address of a symbol
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols.get(name).copied()
+    }
+}
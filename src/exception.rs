@@ -0,0 +1,59 @@
+/// Exceptions in RISC-V, see privileged specification chapter 3.1.15: Machine Cause Register
+/// The value carried by each exception is written to xtval when the trap is taken.
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[non_exhaustive]
+pub enum Exception {
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(u64),
+    Breakpoint(u64),
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAMOAddressMisaligned(u64),
+    StoreAMOAccessFault(u64),
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode,
+}
+
+impl Exception {
+    /// Exception code written to xcause
+    pub fn code(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
+            Exception::Breakpoint(_) => 3,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAMOAddressMisaligned(_) => 6,
+            Exception::StoreAMOAccessFault(_) => 7,
+            Exception::EnvironmentCallFromUMode => 8,
+            Exception::EnvironmentCallFromSMode => 9,
+            Exception::EnvironmentCallFromMMode => 11,
+        }
+    }
+
+    /// Value written to xtval
+    pub fn value(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(value)
+            | Exception::InstructionAccessFault(value)
+            | Exception::IllegalInstruction(value)
+            | Exception::Breakpoint(value)
+            | Exception::LoadAddressMisaligned(value)
+            | Exception::LoadAccessFault(value)
+            | Exception::StoreAMOAddressMisaligned(value)
+            | Exception::StoreAMOAccessFault(value) => *value,
+            Exception::EnvironmentCallFromUMode
+            | Exception::EnvironmentCallFromSMode
+            | Exception::EnvironmentCallFromMMode => 0,
+        }
+    }
+
+    /// A fatal exception stops the emulator instead of being handed to the guest when its trap
+    /// handler cannot be fetched either, there is no sensible way to continue then.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Exception::InstructionAccessFault(_))
+    }
+}
@@ -0,0 +1,58 @@
+use std::sync::OnceLock;
+
+use crate::bus::Device;
+use crate::exception::Exception;
+
+// SiFive test finisher, same default address as QEMU virt. Bare programs write their status to
+// it, Linux drives it through the syscon-poweroff and syscon-reboot drivers.
+pub const FINISHER_BASE: u64 = 0x10_0000;
+pub const FINISHER_SIZE: u64 = 0x1000;
+
+// Low 16 bits of a write to the status register, a failure has its exit code in the upper 16 bits
+pub const FINISHER_FAIL: u64 = 0x3333;
+pub const FINISHER_PASS: u64 = 0x5555;
+pub const FINISHER_RESET: u64 = 0x7777;
+
+/// How the guest stopped the machine through the test finisher
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FinisherStop {
+    Pass,
+    Fail(u64),
+    Reset,
+}
+
+pub struct Finisher {
+    pub base: u64,
+    // Set by the first write of a known status
+    pub stop: OnceLock<FinisherStop>,
+}
+
+impl Finisher {
+    pub fn new(base: u64) -> Self {
+        Self {
+            base,
+            stop: OnceLock::new(),
+        }
+    }
+}
+
+impl Device for Finisher {
+    fn load(&self, _addr: u64, _size: u64) -> Result<u64, Exception> {
+        Ok(0)
+    }
+
+    fn store(&self, addr: u64, _size: u64, value: u64) -> Result<(), Exception> {
+        // Other offsets and unknown values are ignored, like QEMU does
+        if addr != self.base {
+            return Ok(());
+        }
+        let stop = match value & 0xffff {
+            FINISHER_FAIL => FinisherStop::Fail((value >> 16) & 0xffff),
+            FINISHER_PASS => FinisherStop::Pass,
+            FINISHER_RESET => FinisherStop::Reset,
+            _ => return Ok(()),
+        };
+        let _ = self.stop.set(stop);
+        Ok(())
+    }
+}
@@ -0,0 +1,564 @@
+// GDB remote serial protocol stub, see the GDB manual appendix E: GDB Remote Serial Protocol.
+// Each hart is a thread, thread ids are hart ids plus one since 0 means "any thread". The
+// debugger runs the harts in round-robin order so that it stops them all at the same point.
+
+use std::fs;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use crate::bus::*;
+use crate::cpu::Mode;
+use crate::csr::*;
+use crate::machine::*;
+use crate::register::ABI_NAMES;
+
+// Register numbers of the RISC-V target description, the CSRs follow the GPRs and pc from 65
+const REG_PC: usize = 32;
+const REG_CSR_BASE: usize = 65;
+const REG_PRIV: usize = REG_CSR_BASE + NUM_CSRS;
+
+// Largest packet we accept, advertised in qSupported
+const PACKET_SIZE: usize = 0x4000;
+
+// Signals in stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// Data sent by the debugger
+enum Incoming {
+    Packet(Vec<u8>),
+    // The checksum does not match, the debugger sends it again after a NAK
+    Corrupt,
+}
+
+// Why the harts stopped, answered to `?` and after a resume
+enum Stop {
+    Signal { signal: u8, hart: usize },
+    Breakpoint { hart: usize, hardware: bool },
+    Watchpoint { hart: usize, watchpoint: Watchpoint },
+}
+
+impl Stop {
+    fn reply(&self) -> String {
+        match self {
+            Stop::Signal { signal, hart } => format!("T{:02x}thread:{:x};", signal, hart + 1),
+            Stop::Breakpoint { hart, hardware } => {
+                let kind = if *hardware { "hwbreak" } else { "swbreak" };
+                format!("T{:02x}thread:{:x};{}:;", SIGTRAP, hart + 1, kind)
+            }
+            Stop::Watchpoint { hart, watchpoint } => {
+                let kind = match watchpoint.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{:02x}thread:{:x};{}:{:x};", SIGTRAP, hart + 1, kind, watchpoint.addr)
+            }
+        }
+    }
+}
+
+// Wait for a debugger on `address`, a TCP port on localhost or the path of a Unix socket, and
+// serve it. Returns why the guest exited, None when the debugger killed it or went away.
+pub fn serve(machine: &mut Machine, address: &str) -> io::Result<Option<Exit>> {
+    if let Ok(port) = address.parse::<u16>() {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        eprintln!("waiting for GDB on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        Stub::new(machine, stream, reader).run()
+    } else {
+        let listener = UnixListener::bind(address)?;
+        eprintln!("waiting for GDB on {}", address);
+        let accepted = listener.accept();
+        fs::remove_file(address)?;
+        let (stream, _) = accepted?;
+        let reader = stream.try_clone()?;
+        Stub::new(machine, stream, reader).run()
+    }
+}
+
+// Split the byte stream into packets. Ctrl-C comes outside of packets and pauses the machine
+// right away, the stub is busy running it.
+fn read_packets(reader: impl Read, control: Arc<Control>, packets: Sender<Incoming>) {
+    let mut bytes = BufReader::new(reader).bytes().map_while(Result::ok);
+    while let Some(byte) = bytes.next() {
+        match byte {
+            0x03 => control.pause(),
+            b'$' => {
+                let mut data = Vec::new();
+                let mut sum = 0u8;
+                let mut escaped = false;
+                for byte in bytes.by_ref() {
+                    if byte == b'#' {
+                        break;
+                    }
+                    sum = sum.wrapping_add(byte);
+                    match (escaped, byte) {
+                        (false, b'}') => escaped = true,
+                        (true, _) => {
+                            data.push(byte ^ 0x20);
+                            escaped = false;
+                        }
+                        _ => data.push(byte),
+                    }
+                }
+                let checksum: Vec<u8> = bytes.by_ref().take(2).collect();
+                let valid = std::str::from_utf8(&checksum)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .is_some_and(|checksum| checksum == sum);
+                let incoming = if valid { Incoming::Packet(data) } else { Incoming::Corrupt };
+                if packets.send(incoming).is_err() {
+                    return;
+                }
+            }
+            // Acknowledgments, the transports are reliable and replies are never resent
+            _ => {}
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn number(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+// A register value in target byte order
+fn unhex_u64(hex: &str) -> Option<u64> {
+    let bytes: [u8; 8] = unhex(hex)?.try_into().ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+// CSR address of a register number, None past the last CSR
+fn csr_address(register: usize) -> Option<u16> {
+    register.checked_sub(REG_CSR_BASE).filter(|&addr| addr < NUM_CSRS).map(|addr| addr as u16)
+}
+
+// "addr,length" of memory and breakpoint packets
+fn address_length(args: &str) -> Option<(u64, u64)> {
+    let (addr, length) = args.split_once(',')?;
+    Some((number(addr)?, number(length)?))
+}
+
+// Target description: the GPRs and pc, the CSRs the hart implements and the privilege mode
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n\
+         <architecture>riscv:rv64</architecture>\n<feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    );
+    for (i, name) in ABI_NAMES.iter().enumerate() {
+        let kind = match *name {
+            "ra" => "code_ptr",
+            "sp" | "gp" | "tp" | "s0" => "data_ptr",
+            _ => "int",
+        };
+        xml += &format!("<reg name=\"{}\" bitsize=\"64\" type=\"{}\" regnum=\"{}\"/>\n", name, kind, i);
+    }
+    xml += &format!("<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/>\n", REG_PC);
+    xml += "</feature>\n<feature name=\"org.gnu.gdb.riscv.csr\">\n";
+    let time = (TIME, "time");
+    for (addr, name) in CSR_NAMES.iter().chain([&time]) {
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\" group=\"csr\"/>\n",
+            name,
+            REG_CSR_BASE + *addr as usize
+        );
+    }
+    xml += "</feature>\n<feature name=\"org.gnu.gdb.riscv.virtual\">\n";
+    xml += &format!("<reg name=\"priv\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>\n", REG_PRIV);
+    xml += "</feature>\n</target>\n";
+    xml
+}
+
+struct Stub<'a, W: Write> {
+    machine: &'a mut Machine,
+    writer: W,
+    packets: Receiver<Incoming>,
+    no_ack: bool,
+    // Breakpoint addresses, the hardware ones only differ in how hits are reported
+    breakpoints: Vec<u64>,
+    hw_breakpoints: Vec<u64>,
+    // Hart selected for register and memory accesses by `Hg`, and for stepping by `Hc`
+    general: usize,
+    resume: Option<usize>,
+    stop: Stop,
+}
+
+impl<'a, W: Write> Stub<'a, W> {
+    fn new(machine: &'a mut Machine, writer: W, reader: impl Read + Send + 'static) -> Self {
+        let (sender, packets) = channel();
+        let control = machine.control();
+        thread::spawn(move || read_packets(reader, control, sender));
+
+        machine.threaded = false;
+        Self {
+            machine,
+            writer,
+            packets,
+            no_ack: false,
+            breakpoints: Vec::new(),
+            hw_breakpoints: Vec::new(),
+            general: 0,
+            resume: None,
+            stop: Stop::Signal { signal: SIGTRAP, hart: 0 },
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.writer, "${}#{:02x}", data, sum)?;
+        self.writer.flush()
+    }
+
+    fn run(mut self) -> io::Result<Option<Exit>> {
+        while let Ok(incoming) = self.packets.recv() {
+            let packet = match incoming {
+                Incoming::Packet(packet) => packet,
+                Incoming::Corrupt => {
+                    self.writer.write_all(b"-")?;
+                    continue;
+                }
+            };
+            if !self.no_ack {
+                self.writer.write_all(b"+")?;
+            }
+
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            match packet.as_bytes().first() {
+                Some(b'k') => return Ok(None),
+                Some(b'D') => {
+                    self.send("OK")?;
+                    self.machine.bus.watchpoints.clear();
+                    return Ok(Some(self.machine.run(None)));
+                }
+                _ => {}
+            }
+
+            let reply = match self.resume_request(&packet) {
+                Some(step) => match self.resume(step) {
+                    Ok(stop) => {
+                        self.stop = stop;
+                        self.stop.reply()
+                    }
+                    Err(exit) => {
+                        self.send(&format!("W{:02x}", exit_status(exit)))?;
+                        return Ok(Some(exit));
+                    }
+                },
+                None => self.handle(&packet).unwrap_or_else(|| "E01".to_string()),
+            };
+            self.send(&reply)?;
+        }
+        Ok(None)
+    }
+
+    // Continue and step requests, Some(hart) to step it, Some(None) to continue all harts
+    fn resume_request(&self, packet: &str) -> Option<Option<usize>> {
+        let step = |hart: Option<usize>| Some(hart.unwrap_or(self.general));
+        match packet.as_bytes().first()? {
+            b'c' | b'C' => Some(None),
+            b's' | b'S' => Some(step(self.resume)),
+            _ => {
+                // vCont;action[:thread]... the first action that steps wins, c resumes all
+                let actions = packet.strip_prefix("vCont;")?;
+                for action in actions.split(';') {
+                    let (action, thread) = action.split_once(':').unwrap_or((action, ""));
+                    if action.starts_with('s') || action.starts_with('S') {
+                        return Some(step(self.thread(thread)));
+                    }
+                }
+                Some(None)
+            }
+        }
+    }
+
+    // Hart of a thread id, None for "any" and "all"
+    fn thread(&self, id: &str) -> Option<usize> {
+        match i64::from_str_radix(id, 16) {
+            Ok(id) if id > 0 && (id as usize) <= self.machine.harts.len() => Some(id as usize - 1),
+            _ => None,
+        }
+    }
+
+    // Run until a breakpoint, a watchpoint, Ctrl-C or a fatal exception, or step one hart.
+    // Err when the guest exits.
+    fn resume(&mut self, step: Option<usize>) -> Result<Stop, Exit> {
+        let _ = self.machine.bus.take_watch_hit();
+        if let Some(hart) = step {
+            return self.step(hart);
+        }
+
+        // The instruction at a breakpoint the harts stopped on runs on resume
+        let mut first = true;
+        loop {
+            let hart = self.machine.next_hart();
+            if let Some(hart) = hart.filter(|_| !first) {
+                let pc = self.machine.harts[hart].pc;
+                if self.breakpoints.contains(&pc) || self.hw_breakpoints.contains(&pc) {
+                    let hardware = !self.breakpoints.contains(&pc);
+                    return Ok(self.stopped(Stop::Breakpoint { hart, hardware }));
+                }
+            }
+            first = false;
+
+            match self.machine.run(Some(1)) {
+                Exit::Limit => {}
+                Exit::Paused => {
+                    let hart = hart.unwrap_or(self.general);
+                    return Ok(self.stopped(Stop::Signal { signal: SIGINT, hart }));
+                }
+                Exit::Fatal { hart, .. } => return Ok(self.stopped(Stop::Signal { signal: SIGSEGV, hart })),
+                exit => return Err(exit),
+            }
+            if let (Some(hart), Some(watchpoint)) = (hart, self.machine.bus.take_watch_hit()) {
+                return Ok(self.stopped(Stop::Watchpoint { hart, watchpoint }));
+            }
+        }
+    }
+
+    fn step(&mut self, hart: usize) -> Result<Stop, Exit> {
+        if self.machine.runnable(hart) && self.machine.step_hart(hart).is_err() {
+            return Ok(self.stopped(Stop::Signal { signal: SIGSEGV, hart }));
+        }
+        if let Some(exit) = self.machine.exit_condition() {
+            return Err(exit);
+        }
+        Ok(match self.machine.bus.take_watch_hit() {
+            Some(watchpoint) => Stop::Watchpoint { hart, watchpoint },
+            None => Stop::Signal { signal: SIGTRAP, hart },
+        })
+    }
+
+    // The hart that stopped the others becomes the selected thread
+    fn stopped(&mut self, stop: Stop) -> Stop {
+        let (Stop::Signal { hart, .. } | Stop::Breakpoint { hart, .. } | Stop::Watchpoint { hart, .. }) = stop;
+        self.general = hart;
+        stop
+    }
+
+    // Reply to a packet that does not resume the guest, None for an error
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let command = packet.get(..1)?;
+        let args = &packet[1..];
+        let reply = match command {
+            "?" => self.stop.reply(),
+            "g" => {
+                let hart = &self.machine.harts[self.general];
+                let mut bytes = Vec::new();
+                for value in hart.regs.iter().chain([&hart.pc]) {
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+                hex(&bytes)
+            }
+            "G" => {
+                let bytes = unhex(args)?;
+                let hart = &mut self.machine.harts[self.general];
+                for (i, value) in bytes.chunks_exact(8).enumerate().skip(1).take(REG_PC) {
+                    let value = u64::from_le_bytes(value.try_into().unwrap());
+                    match i {
+                        REG_PC => hart.pc = value,
+                        _ => hart.regs[i] = value,
+                    }
+                }
+                "OK".to_string()
+            }
+            "p" => match self.read_register(number(args)? as usize) {
+                Some(value) => hex(&value.to_le_bytes()),
+                None => "xxxxxxxxxxxxxxxx".to_string(),
+            },
+            "P" => {
+                let (register, value) = args.split_once('=')?;
+                self.write_register(number(register)? as usize, unhex_u64(value)?)?;
+                "OK".to_string()
+            }
+            "m" => {
+                let (addr, length) = address_length(args)?;
+                let mut bytes = Vec::new();
+                for i in 0..length.min(PACKET_SIZE as u64 / 2) {
+                    let mut byte = [0];
+                    match self.machine.bus.peek_bytes(addr.wrapping_add(i), &mut byte) {
+                        Ok(()) => bytes.push(byte[0]),
+                        Err(_) => break,
+                    }
+                }
+                if bytes.is_empty() && length > 0 {
+                    return None;
+                }
+                hex(&bytes)
+            }
+            "M" => {
+                let (location, data) = args.split_once(':')?;
+                let (addr, _) = address_length(location)?;
+                let written = unhex(data)?.iter().enumerate().all(|(i, byte)| {
+                    self.machine.bus.store(addr.wrapping_add(i as u64), 8, *byte as u64).is_ok()
+                });
+                let _ = self.machine.bus.take_watch_hit();
+                if !written {
+                    return None;
+                }
+                "OK".to_string()
+            }
+            "Z" | "z" => {
+                let insert = command == "Z";
+                let (kind, location) = args.split_once(',')?;
+                let (addr, length) = address_length(location.split(';').next()?)?;
+                self.breakpoint(insert, kind, addr, length)?
+            }
+            "H" => {
+                let op = args.get(..1)?;
+                let id = &args[1..];
+                let hart = match id {
+                    "0" | "-1" => None,
+                    id => Some(self.thread(id)?),
+                };
+                match op {
+                    "g" => self.general = hart.unwrap_or(self.general),
+                    "c" => self.resume = hart,
+                    _ => return None,
+                }
+                "OK".to_string()
+            }
+            "T" => {
+                self.thread(args)?;
+                "OK".to_string()
+            }
+            _ => self.query(packet),
+        };
+        Some(reply)
+    }
+
+    fn read_register(&mut self, register: usize) -> Option<u64> {
+        let hart = &self.machine.harts[self.general];
+        match register {
+            0..REG_PC => Some(hart.regs[register]),
+            REG_PC => Some(hart.pc),
+            REG_PRIV => Some(hart.mode as u64),
+            _ => {
+                let addr = csr_address(register)?;
+                if addr == TIME {
+                    return Some(self.machine.bus.clint.mtime());
+                }
+                CSR_NAMES.iter().any(|(csr, _)| *csr == addr).then(|| hart.csr.load(addr))
+            }
+        }
+    }
+
+    fn write_register(&mut self, register: usize, value: u64) -> Option<()> {
+        let hart = &mut self.machine.harts[self.general];
+        match register {
+            0 => {}
+            1..REG_PC => hart.regs[register] = value,
+            REG_PC => hart.pc = value,
+            REG_PRIV => hart.mode = Mode::from(value),
+            _ => {
+                let addr = csr_address(register)?;
+                if !CSR_NAMES.iter().any(|(csr, _)| *csr == addr) {
+                    return None;
+                }
+                hart.csr.store(addr, value);
+            }
+        }
+        Some(())
+    }
+
+    // Z0/z0 software and Z1/z1 hardware breakpoints, Z2-Z4/z2-z4 write, read and access
+    // watchpoints
+    fn breakpoint(&mut self, insert: bool, kind: &str, addr: u64, length: u64) -> Option<String> {
+        let watch = match kind {
+            "0" | "1" => {
+                let breakpoints = if kind == "0" { &mut self.breakpoints } else { &mut self.hw_breakpoints };
+                breakpoints.retain(|a| *a != addr);
+                if insert {
+                    breakpoints.push(addr);
+                }
+                return Some("OK".to_string());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Some(String::new()),
+        };
+        let watchpoint = Watchpoint { addr, len: length, kind: watch };
+        self.machine.bus.watchpoints.retain(|w| *w != watchpoint);
+        if insert {
+            self.machine.bus.watchpoints.push(watchpoint);
+        }
+        Some("OK".to_string())
+    }
+
+    // General query packets, an empty reply for the ones we do not support
+    fn query(&mut self, packet: &str) -> String {
+        let harts = self.machine.harts.len();
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;vContSupported+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = address_length(args) else {
+                return "E01".to_string();
+            };
+            let xml = target_xml();
+            let start = (offset as usize).min(xml.len());
+            let end = start.saturating_add(length as usize).min(xml.len());
+            let more = if end < xml.len() { "m" } else { "l" };
+            return format!("{}{}", more, &xml[start..end]);
+        }
+        if let Some(id) = packet.strip_prefix("qThreadExtraInfo,") {
+            return match self.thread(id) {
+                Some(hart) => {
+                    let state = if self.machine.runnable(hart) { "running" } else { "stopped" };
+                    hex(format!("hart {} {}", hart, state).as_bytes())
+                }
+                None => "E01".to_string(),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qfThreadInfo" => {
+                let ids: Vec<String> = (1..=harts).map(|id| format!("{:x}", id)).collect();
+                format!("m{}", ids.join(","))
+            }
+            "qsThreadInfo" => "l".to_string(),
+            "qC" => format!("QC{:x}", self.general + 1),
+            "qAttached" => "1".to_string(),
+            "vCont?" => "vCont;c;C;s;S".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+// Exit status reported in a W reply
+fn exit_status(exit: Exit) -> u8 {
+    exit.status().unwrap_or(0) as u8
+}
+
+#[test]
+fn test_target_xml() {
+    let xml = target_xml();
+    assert!(xml.contains("<reg name=\"a0\" bitsize=\"64\" type=\"int\" regnum=\"10\"/>"));
+    assert!(xml.contains(&format!("name=\"mstatus\" bitsize=\"64\" type=\"int\" regnum=\"{}\"", 65 + 0x300)));
+    assert!(xml.contains("name=\"priv\" bitsize=\"64\" type=\"int\" regnum=\"4161\""));
+}
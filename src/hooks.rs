@@ -0,0 +1,186 @@
+// Callbacks observing the execution of the harts, for tools embedding the emulator. Hooks get
+// the hart and the bus, they can change registers, CSRs or memory, and stop the machine by
+// returning `HookAction::Stop`: `Machine::run` then returns `Exit::Hook`.
+//
+// Harts may run on their own threads, hooks must be Send + Sync and keep their own state behind
+// a lock or atomics.
+
+use std::ops::Range;
+
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::instruction::Instruction;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HookAction {
+    Continue,
+    /// Stop the machine. A hook before an instruction also keeps it from running, the others
+    /// stop after the instruction.
+    Stop,
+}
+
+/// A data access of an instruction, `value` is the value read or written
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Access {
+    pub addr: u64,
+    pub size: u64,
+    pub value: u64,
+    pub write: bool,
+}
+
+pub type InstructionHook = Box<dyn Fn(&mut Cpu, &Bus, &Instruction) -> HookAction + Send + Sync>;
+pub type MemoryHook = Box<dyn Fn(&mut Cpu, &Bus, Access) -> HookAction + Send + Sync>;
+/// Called with xcause and xtval once the trap is taken, pc is at the handler
+pub type TrapHook = Box<dyn Fn(&mut Cpu, &Bus, u64, u64) -> HookAction + Send + Sync>;
+/// Called with the CSR address and its new value after an instruction wrote it
+pub type CsrHook = Box<dyn Fn(&mut Cpu, &Bus, u16, u64) -> HookAction + Send + Sync>;
+
+/// Hooks of a machine, shared by all its harts, see `Machine::set_hooks`
+#[derive(Default)]
+pub struct Hooks {
+    // Before hooks only run for the pcs in their range, if they have one
+    before: Vec<(Option<Range<u64>>, InstructionHook)>,
+    after: Vec<InstructionHook>,
+    memory: Vec<MemoryHook>,
+    trap: Vec<TrapHook>,
+    csr: Vec<CsrHook>,
+}
+
+// Run hooks in order, all of them run even when one asks to stop
+fn run_all<H>(hooks: &[H], mut call: impl FnMut(&H) -> HookAction) -> HookAction {
+    hooks.iter().fold(HookAction::Continue, |action, hook| match call(hook) {
+        HookAction::Stop => HookAction::Stop,
+        HookAction::Continue => action,
+    })
+}
+
+impl Hooks {
+    pub fn before_instruction(&mut self, hook: impl Fn(&mut Cpu, &Bus, &Instruction) -> HookAction + Send + Sync + 'static) {
+        self.before.push((None, Box::new(hook)));
+    }
+
+    /// Before the instructions at pcs in `range`
+    pub fn pc_range(&mut self, range: Range<u64>, hook: impl Fn(&mut Cpu, &Bus, &Instruction) -> HookAction + Send + Sync + 'static) {
+        self.before.push((Some(range), Box::new(hook)));
+    }
+
+    /// After an instruction that did not trap
+    pub fn after_instruction(&mut self, hook: impl Fn(&mut Cpu, &Bus, &Instruction) -> HookAction + Send + Sync + 'static) {
+        self.after.push(Box::new(hook));
+    }
+
+    pub fn memory(&mut self, hook: impl Fn(&mut Cpu, &Bus, Access) -> HookAction + Send + Sync + 'static) {
+        self.memory.push(Box::new(hook));
+    }
+
+    pub fn trap(&mut self, hook: impl Fn(&mut Cpu, &Bus, u64, u64) -> HookAction + Send + Sync + 'static) {
+        self.trap.push(Box::new(hook));
+    }
+
+    pub fn csr_write(&mut self, hook: impl Fn(&mut Cpu, &Bus, u16, u64) -> HookAction + Send + Sync + 'static) {
+        self.csr.push(Box::new(hook));
+    }
+}
+
+impl Hooks {
+    pub(crate) fn run_before(&self, cpu: &mut Cpu, bus: &Bus, inst: &Instruction) -> HookAction {
+        let pc = cpu.pc;
+        run_all(&self.before, |(range, hook)| match range {
+            Some(range) if !range.contains(&pc) => HookAction::Continue,
+            _ => hook(cpu, bus, inst),
+        })
+    }
+
+    pub(crate) fn run_after(&self, cpu: &mut Cpu, bus: &Bus, inst: &Instruction) -> HookAction {
+        run_all(&self.after, |hook| hook(cpu, bus, inst))
+    }
+
+    pub(crate) fn run_memory(&self, cpu: &mut Cpu, bus: &Bus, access: Access) -> HookAction {
+        run_all(&self.memory, |hook| hook(cpu, bus, access))
+    }
+
+    pub(crate) fn run_trap(&self, cpu: &mut Cpu, bus: &Bus, cause: u64, tval: u64) -> HookAction {
+        run_all(&self.trap, |hook| hook(cpu, bus, cause, tval))
+    }
+
+    pub(crate) fn run_csr(&self, cpu: &mut Cpu, bus: &Bus, csr: u16, value: u64) -> HookAction {
+        run_all(&self.csr, |hook| hook(cpu, bus, csr, value))
+    }
+}
+
+#[test]
+fn test_hooks() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::asm::assemble;
+    use crate::bus::DRAM_BASE;
+    use crate::csr::{MSCRATCH, MTVEC};
+    use crate::machine::{Exit, Machine};
+
+    let source = "
+            la t0, data
+            li a0, 5
+            sd a0, 0(t0)
+            ld a1, 0(t0)
+            csrw mscratch, a0
+            la t1, handler
+            csrw mtvec, t1
+            ecall
+        handler:
+            li a2, 7
+            j handler
+        data:
+            .dword 0
+    ";
+    let code = assemble(source, DRAM_BASE).unwrap();
+    let handler = DRAM_BASE + 40;
+    let events = Arc::new(Mutex::new(Vec::new()));
+
+    let mut hooks = Hooks::default();
+    let log = events.clone();
+    hooks.before_instruction(move |cpu, _, inst| {
+        log.lock().unwrap().push(format!("before {:#x} {:?}", cpu.pc, inst));
+        HookAction::Continue
+    });
+    let log = events.clone();
+    hooks.memory(move |_, _, access| {
+        log.lock().unwrap().push(format!("{:?}", access));
+        HookAction::Continue
+    });
+    let log = events.clone();
+    hooks.trap(move |cpu, _, cause, _| {
+        log.lock().unwrap().push(format!("trap {} to {:#x}", cause, cpu.pc));
+        HookAction::Continue
+    });
+    let log = events.clone();
+    hooks.csr_write(move |_, _, csr, value| {
+        log.lock().unwrap().push(format!("csr {:#x} = {:#x}", csr, value));
+        HookAction::Continue
+    });
+    // Change the state after the load, then stop before the handler runs
+    hooks.after_instruction(|cpu, _, inst| {
+        if let Instruction::Ld { .. } = inst {
+            cpu.regs[11] += 1;
+        }
+        HookAction::Continue
+    });
+    hooks.pc_range(handler..handler + 4, |_, _, _| HookAction::Stop);
+
+    let mut machine = Machine::new(code, 1);
+    machine.set_hooks(hooks);
+    assert_eq!(machine.run(Some(100)), Exit::Hook { hart: 0 });
+    assert_eq!(machine.harts[0].pc, handler);
+    assert_eq!(machine.harts[0].regs[11], 6);
+    // li a2, 7 did not run
+    assert_eq!(machine.harts[0].regs[12], 0);
+
+    let events = events.lock().unwrap();
+    let data = DRAM_BASE + 48;
+    assert_eq!(events.iter().filter(|e| e.starts_with("before")).count(), 11);
+    assert!(events.contains(&format!("before {:#x} Ecall", DRAM_BASE + 36)));
+    assert!(events.contains(&format!("{:?}", Access { addr: data, size: 64, value: 5, write: true })));
+    assert!(events.contains(&format!("{:?}", Access { addr: data, size: 64, value: 5, write: false })));
+    assert!(events.contains(&format!("csr {:#x} = 0x5", MSCRATCH)));
+    assert!(events.contains(&format!("csr {:#x} = {:#x}", MTVEC, handler)));
+    assert!(events.contains(&format!("trap 11 to {:#x}", handler)));
+}
@@ -0,0 +1,65 @@
+use std::sync::OnceLock;
+
+// Host-target interface used by riscv-tests and riscv-arch-test to talk to the simulator.
+// The guest writes a command to `tohost`: bits [63:56] are the device, [55:48] the command
+// and [47:0] the payload. Device 0 with an odd payload means exit with code payload >> 1,
+// device 0 with an even payload points to a syscall block, device 1 command 1 prints a char.
+pub struct Htif {
+    pub tohost: u64,
+    pub fromhost: Option<u64>,
+    // Set by the first exit command
+    pub exit_code: OnceLock<u64>,
+}
+
+// Syscall numbers of the proxy kernel, only write is served
+const SYS_WRITE: u64 = 64;
+
+// Decoded `tohost` command
+pub enum HtifCommand {
+    Exit(u64),
+    Syscall(u64),
+    PutChar(u8),
+    Unknown,
+}
+
+impl Htif {
+    pub fn new(tohost: u64, fromhost: Option<u64>) -> Self {
+        Self {
+            tohost,
+            fromhost,
+            exit_code: OnceLock::new(),
+        }
+    }
+
+    pub fn decode(value: u64) -> HtifCommand {
+        let device = value >> 56;
+        let command = (value >> 48) & 0xff;
+        let payload = value & 0xffff_ffff_ffff;
+        match (device, command) {
+            (0, 0) if payload & 1 == 1 => HtifCommand::Exit(payload >> 1),
+            (0, 0) => HtifCommand::Syscall(payload),
+            (1, 1) => HtifCommand::PutChar(payload as u8),
+            _ => HtifCommand::Unknown,
+        }
+    }
+
+    // Serve a proxy kernel syscall, `args` are the eight words of the syscall block.
+    // Returns the value the guest sees in the first word of the block.
+    pub fn syscall(args: &[u64; 8], read: impl Fn(u64) -> Option<u8>) -> u64 {
+        use std::io::Write;
+
+        match args[0] {
+            SYS_WRITE if args[1] == 1 || args[1] == 2 => {
+                let bytes: Vec<u8> = (0..args[3]).map_while(|i| read(args[2] + i)).collect();
+                if args[1] == 1 {
+                    let _ = std::io::stdout().write_all(&bytes);
+                } else {
+                    let _ = std::io::stderr().write_all(&bytes);
+                }
+                bytes.len() as u64
+            }
+            // -ENOSYS
+            _ => (-38i64) as u64,
+        }
+    }
+}
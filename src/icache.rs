@@ -0,0 +1,105 @@
+use crate::bus::Bus;
+use crate::dram::PAGE_SIZE;
+use crate::instruction::{decode, Instruction};
+
+// Decoded-instruction cache of a hart, keyed by physical address. Pages of DRAM are filled one
+// instruction at a time as the hart fetches them, and emptied when their generation changes:
+// a store from any hart to a cached instruction bumps it, see `Bus::watch_code`. FENCE.I flushes
+// the cache.
+
+// Instructions in a page
+const PAGE_INSTS: usize = (PAGE_SIZE / 4) as usize;
+
+// Pages kept at a time, direct-mapped on the page number
+const SLOTS: usize = 64;
+
+struct Page {
+    addr: u64,
+    generation: u32,
+    // Raw and decoded instructions, None until fetched
+    insts: Box<[Option<(u32, Instruction)>]>,
+}
+
+pub struct ICache {
+    /// Decode every instruction again when cleared
+    pub enabled: bool,
+    slots: Vec<Option<Page>>,
+}
+
+impl Default for ICache {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            slots: (0..SLOTS).map(|_| None).collect(),
+        }
+    }
+}
+
+impl ICache {
+    /// Raw and decoded instruction at `pc`. None when it is not cached and cannot be: the cache
+    /// is disabled, pc is outside of DRAM or the fetch fails, the caller fetches it then.
+    #[inline]
+    pub fn fetch(&mut self, bus: &Bus, pc: u64) -> Option<(u32, Instruction)> {
+        if !self.enabled || !pc.is_multiple_of(4) {
+            return None;
+        }
+        let addr = pc & !(PAGE_SIZE - 1);
+        let index = ((pc - addr) / 4) as usize;
+        let slot = &mut self.slots[(pc / PAGE_SIZE) as usize % SLOTS];
+        if let Some(page) = slot {
+            if page.addr == addr && page.generation == bus.code_generation(addr) {
+                if let Some(cached) = page.insts[index] {
+                    return Some(cached);
+                }
+            }
+        }
+
+        // Watch the instruction before reading it, a later store bumps the generation
+        let generation = bus.watch_code(pc)?;
+        let inst = bus.fetch(pc).ok()? as u32;
+        let page = match slot {
+            Some(page) if page.addr == addr && page.generation == generation => page,
+            Some(page) => {
+                page.insts.fill(None);
+                page.addr = addr;
+                page.generation = generation;
+                page
+            }
+            None => slot.insert(Page { addr, generation, insts: vec![None; PAGE_INSTS].into_boxed_slice() }),
+        };
+        page.insts[index] = Some((inst, decode(inst)));
+        page.insts[index]
+    }
+
+    /// Drop every cached instruction, for FENCE.I
+    pub fn flush(&mut self) {
+        self.slots.iter_mut().for_each(|slot| *slot = None);
+    }
+}
+
+#[test]
+fn test_self_modifying_code() {
+    use crate::asm::assemble;
+    use crate::bus::DRAM_BASE;
+    use crate::machine::{Exit, Machine};
+
+    // The first pass runs `li a0, 1` and replaces it with `li a0, 2` for the second one
+    let source = "
+            li t0, 2
+        patched:
+            li a0, 1
+            addi t0, t0, -1
+            beqz t0, 1f
+            la t1, patched
+            li t2, 0x00200513
+            sw t2, 0(t1)
+            j patched
+        1:  j 1b
+    ";
+    let mut machine = Machine::new(assemble(source, DRAM_BASE).unwrap(), 1);
+    // Translated blocks would run the code instead, see `block`
+    machine.harts[0].blocks.enabled = false;
+    assert_eq!(machine.run(Some(100)), Exit::Limit);
+    assert_eq!(machine.harts[0].regs[10], 2);
+    assert!(machine.harts[0].icache.slots.iter().any(|slot| slot.is_some()));
+}
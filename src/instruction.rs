@@ -1,6 +1,10 @@
+// The decoder keeps its field masks grouped the way the specification draws them
+#![allow(clippy::unusual_byte_groupings)]
+
 use crate::register::Register;
 
-#[derive(Debug)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[non_exhaustive]
 pub enum Instruction {
     Undefined,
 
@@ -23,12 +27,28 @@ pub enum Instruction {
     Lwu { rd: Register, rs1: Register, imm: i32 },
     Ld { rd: Register, rs1: Register, imm: i32 },
 
+    // The ordering bits are decoded but unused, every fence orders all memory accesses
+    #[allow(dead_code)]
     Fence { rd: Register, rs1: Register, imm: i32 },
+    FenceI,
 
     Jalr { rd: Register, rs1: Register, imm: i32 },
 
     Ecall,
     Ebreak,
+    Sret,
+    Mret,
+    Wfi,
+    // There is no TLB, the address and address space are decoded but unused
+    #[allow(dead_code)]
+    SfenceVma { rs1: Register, rs2: Register },
+
+    Csrrw { rd: Register, rs1: Register, csr: u16 },
+    Csrrs { rd: Register, rs1: Register, csr: u16 },
+    Csrrc { rd: Register, rs1: Register, csr: u16 },
+    Csrrwi { rd: Register, uimm: u32, csr: u16 },
+    Csrrsi { rd: Register, uimm: u32, csr: u16 },
+    Csrrci { rd: Register, uimm: u32, csr: u16 },
 
     Add { rd: Register, rs1: Register, rs2: Register },
     Sub { rd: Register, rs1: Register, rs2: Register },
@@ -68,9 +88,34 @@ pub enum Instruction {
     Sllw { rd: Register, rs1: Register, rs2: Register },
     Srlw { rd: Register, rs1: Register, rs2: Register },
     Sraw { rd: Register, rs1: Register, rs2: Register },
+
+    // The aq/rl ordering bits are not decoded, every atomic is sequentially consistent
+    LrW { rd: Register, rs1: Register },
+    ScW { rd: Register, rs1: Register, rs2: Register },
+    AmoswapW { rd: Register, rs1: Register, rs2: Register },
+    AmoaddW { rd: Register, rs1: Register, rs2: Register },
+    AmoxorW { rd: Register, rs1: Register, rs2: Register },
+    AmoandW { rd: Register, rs1: Register, rs2: Register },
+    AmoorW { rd: Register, rs1: Register, rs2: Register },
+    AmominW { rd: Register, rs1: Register, rs2: Register },
+    AmomaxW { rd: Register, rs1: Register, rs2: Register },
+    AmominuW { rd: Register, rs1: Register, rs2: Register },
+    AmomaxuW { rd: Register, rs1: Register, rs2: Register },
+
+    LrD { rd: Register, rs1: Register },
+    ScD { rd: Register, rs1: Register, rs2: Register },
+    AmoswapD { rd: Register, rs1: Register, rs2: Register },
+    AmoaddD { rd: Register, rs1: Register, rs2: Register },
+    AmoxorD { rd: Register, rs1: Register, rs2: Register },
+    AmoandD { rd: Register, rs1: Register, rs2: Register },
+    AmoorD { rd: Register, rs1: Register, rs2: Register },
+    AmominD { rd: Register, rs1: Register, rs2: Register },
+    AmomaxD { rd: Register, rs1: Register, rs2: Register },
+    AmominuD { rd: Register, rs1: Register, rs2: Register },
+    AmomaxuD { rd: Register, rs1: Register, rs2: Register },
 }
 
-// Instruction type, see specification chapter 27: RV32/64G Instruction Set Listings
+/// Instruction type, see specification chapter 27: RV32/64G Instruction Set Listings
 #[derive(Copy, Clone)]
 pub enum InstType {
     // Type R: func7, rs2, rs1, func3, rd, opcode
@@ -88,16 +133,16 @@ pub enum InstType {
 }
 
 impl InstType {
-    // Decode instruction with known instruction type
+    /// Decode instruction with known instruction type
     pub fn decode(&self, inst: u32) -> Instruction {
-        return match self {
+        match self {
             InstType::I => InstType::decode_type_i(inst),
             InstType::R => InstType::decode_type_r(inst),
             InstType::S => InstType::decode_type_s(inst),
             InstType::U => InstType::decode_type_u(inst),
             InstType::B => InstType::decode_type_b(inst),
             InstType::J => InstType::decode_type_j(inst),
-        };
+        }
     }
 
     fn decode_type_i(inst: u32) -> Instruction {
@@ -113,7 +158,7 @@ impl InstType {
         // Sign extend the immediate
         let imm = ((imm as i32) << 20) >> 20;
 
-        return match opcode {
+        match opcode {
             0b0000011 => {
                 match func3 {
                     0b000 => Instruction::Lb { rd, rs1, imm },
@@ -147,6 +192,7 @@ impl InstType {
             0b0001111 => {
                 match func3 {
                     0b000 => Instruction::Fence { rd, rs1, imm },
+                    0b001 => Instruction::FenceI,
                     _ => Instruction::Undefined
                 }
             }
@@ -157,11 +203,32 @@ impl InstType {
                 }
             }
             0b1110011 => {
+                // CSR address is the unsigned immediate, the zimm form reuses the rs1 field
+                let csr = ((inst >> 20) & 0xfff) as u16;
+                let uimm = (inst >> 15) & 0b1111_1;
+
                 match func3 {
                     0b000 if imm == 0 && rs1 == Register::X0
                         && rd == Register::X0 => Instruction::Ecall,
                     0b000 if imm == 1 && rs1 == Register::X0
                         && rd == Register::X0 => Instruction::Ebreak,
+                    0b000 if imm == 0x102 && rs1 == Register::X0
+                        && rd == Register::X0 => Instruction::Sret,
+                    0b000 if imm == 0x302 && rs1 == Register::X0
+                        && rd == Register::X0 => Instruction::Mret,
+                    0b000 if imm == 0x105 && rs1 == Register::X0
+                        && rd == Register::X0 => Instruction::Wfi,
+                    0b000 if (imm >> 5) & 0b111_1111 == 0b000_1001
+                        && rd == Register::X0 => {
+                        let rs2: Register = ((imm & 0b1111_1) as usize).into();
+                        Instruction::SfenceVma { rs1, rs2 }
+                    }
+                    0b001 => Instruction::Csrrw { rd, rs1, csr },
+                    0b010 => Instruction::Csrrs { rd, rs1, csr },
+                    0b011 => Instruction::Csrrc { rd, rs1, csr },
+                    0b101 => Instruction::Csrrwi { rd, uimm, csr },
+                    0b110 => Instruction::Csrrsi { rd, uimm, csr },
+                    0b111 => Instruction::Csrrci { rd, uimm, csr },
                     _ => Instruction::Undefined
                 }
             }
@@ -179,7 +246,7 @@ impl InstType {
                 }
             }
             _ => { Instruction::Undefined }
-        };
+        }
     }
 
     fn decode_type_r(inst: u32) -> Instruction {
@@ -193,7 +260,7 @@ impl InstType {
         let func3 = (inst >> 12) & 0b111;
         let rd: Register = (((inst >> 7) & 0b1111_1) as usize).into();
 
-        return match opcode {
+        match opcode {
             0b0110011 => {
                 match (func3, func7) {
                     (0b000, 0b000_0000) => Instruction::Add { rd, rs1, rs2 },
@@ -219,8 +286,36 @@ impl InstType {
                     (_, _) => Instruction::Undefined
                 }
             }
+            0b0101111 => {
+                // func7 is funct5, aq, rl
+                match (func3, func7 >> 2) {
+                    (0b010, 0b00010) if rs2 == Register::X0 => Instruction::LrW { rd, rs1 },
+                    (0b010, 0b00011) => Instruction::ScW { rd, rs1, rs2 },
+                    (0b010, 0b00001) => Instruction::AmoswapW { rd, rs1, rs2 },
+                    (0b010, 0b00000) => Instruction::AmoaddW { rd, rs1, rs2 },
+                    (0b010, 0b00100) => Instruction::AmoxorW { rd, rs1, rs2 },
+                    (0b010, 0b01100) => Instruction::AmoandW { rd, rs1, rs2 },
+                    (0b010, 0b01000) => Instruction::AmoorW { rd, rs1, rs2 },
+                    (0b010, 0b10000) => Instruction::AmominW { rd, rs1, rs2 },
+                    (0b010, 0b10100) => Instruction::AmomaxW { rd, rs1, rs2 },
+                    (0b010, 0b11000) => Instruction::AmominuW { rd, rs1, rs2 },
+                    (0b010, 0b11100) => Instruction::AmomaxuW { rd, rs1, rs2 },
+                    (0b011, 0b00010) if rs2 == Register::X0 => Instruction::LrD { rd, rs1 },
+                    (0b011, 0b00011) => Instruction::ScD { rd, rs1, rs2 },
+                    (0b011, 0b00001) => Instruction::AmoswapD { rd, rs1, rs2 },
+                    (0b011, 0b00000) => Instruction::AmoaddD { rd, rs1, rs2 },
+                    (0b011, 0b00100) => Instruction::AmoxorD { rd, rs1, rs2 },
+                    (0b011, 0b01100) => Instruction::AmoandD { rd, rs1, rs2 },
+                    (0b011, 0b01000) => Instruction::AmoorD { rd, rs1, rs2 },
+                    (0b011, 0b10000) => Instruction::AmominD { rd, rs1, rs2 },
+                    (0b011, 0b10100) => Instruction::AmomaxD { rd, rs1, rs2 },
+                    (0b011, 0b11000) => Instruction::AmominuD { rd, rs1, rs2 },
+                    (0b011, 0b11100) => Instruction::AmomaxuD { rd, rs1, rs2 },
+                    (_, _) => Instruction::Undefined
+                }
+            }
             _ => Instruction::Undefined
-        };
+        }
     }
 
     fn decode_type_s(inst: u32) -> Instruction {
@@ -238,7 +333,7 @@ impl InstType {
         let imm = (imm115 << 5) | imm40;
         let imm = ((imm as i32) << 20) >> 20;
 
-        return match opcode {
+        match opcode {
             0b0100011 => {
                 match func3 {
                     0b000 => Instruction::Sb { rs1, rs2, imm },
@@ -249,25 +344,22 @@ impl InstType {
                 }
             }
             _ => Instruction::Undefined,
-        };
+        }
     }
 
     fn decode_type_u(inst: u32) -> Instruction {
         // Get opcode
         let opcode = inst & 0b1111111;
 
-        // Decode type fields
-        let imm = (inst >> 12) & 0xfffff;
+        // Decode type fields, the immediate keeps its place in bits [31:12]
+        let imm = (inst & 0xfffff_000) as i32;
         let rd: Register = (((inst >> 7) & 0b1111_1) as usize).into();
 
-        // Sign extend the immediate
-        let imm = ((imm as i32) << 12) >> 12;
-
-        return match opcode {
+        match opcode {
             0b0010111 => Instruction::Auipc { rd, imm },
             0b0110111 => Instruction::Lui { rd, imm },
             _ => Instruction::Undefined
-        };
+        }
     }
 
     fn decode_type_b(inst: u32) -> Instruction {
@@ -291,7 +383,7 @@ impl InstType {
         let imm = (imm12 << 12) | (imm11 << 11) | (imm105 << 5) | (imm41 << 1);
         let imm = (imm as i32) << 19 >> 19;
 
-        return match opcode {
+        match opcode {
             0b1100011 => {
                 match func3 {
                     0b000 => Instruction::Beq { rs1, rs2, imm },
@@ -304,7 +396,7 @@ impl InstType {
                 }
             }
             _ => Instruction::Undefined
-        };
+        }
     }
 
     fn decode_type_j(inst: u32) -> Instruction {
@@ -319,16 +411,16 @@ impl InstType {
         let imm20 = (imm >> 19) & 0b1;
         let imm101 = (imm >> 9) & 0b11_1111_1111;
         let imm11 = (imm >> 8) & 0b1;
-        let imm1912 = (imm >> 0) & 0b1111_1111;
+        let imm1912 = imm & 0b1111_1111;
         let imm = (imm20 << 20) | (imm1912 << 12) | (imm11 << 11) | (imm101 << 1);
 
         // Sign extend the immediate
         let imm = ((imm as i32) << 11) >> 11;
 
-        return match opcode {
+        match opcode {
             0b1101111 => Instruction::Jal { rd, imm },
             _ => Instruction::Undefined
-        };
+        }
     }
 }
 
@@ -337,10 +429,13 @@ fn test() {
     let inst = 0x0105053b;
     let decoded = decode(inst);
     println!("Instruction {:#010x} is {:?}.\n", inst, decoded);
-    panic!("Failed")
+    assert!(matches!(
+        decoded,
+        Instruction::Addw { rd: Register::X10, rs1: Register::X10, rs2: Register::X16 }
+    ));
 }
 
-// Decode a 32-bit instruction to enum Instruction
+/// Decode a 32-bit instruction to enum Instruction
 pub fn decode(inst: u32) -> Instruction {
     let opcode = inst & 0b1111111;
 
@@ -400,7 +495,7 @@ const OPCODE_TYPE: [Option<InstType>; 128] = [
     /* 0b0101100 */ None,
     /* 0b0101101 */ None,
     /* 0b0101110 */ None,
-    /* 0b0101111 */ None,
+    /* 0b0101111 */ Some(InstType::R),
     /* 0b0110000 */ None,
     /* 0b0110001 */ None,
     /* 0b0110010 */ None,
@@ -482,3 +577,271 @@ const OPCODE_TYPE: [Option<InstType>; 128] = [
     /* 0b1111110 */ None,
     /* 0b1111111 */ None,
 ];
+
+/// Why an instruction cannot be encoded
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[non_exhaustive]
+pub enum EncodeError {
+    // `Undefined` has no encoding
+    Undefined,
+    // An immediate, shift amount or CSR address does not fit its field
+    OutOfRange(i64),
+    // A branch or jump offset is odd, or the low 12 bits of a U-type immediate are set
+    Misaligned(i64),
+}
+
+fn reg(register: Register) -> u32 {
+    usize::from(register) as u32
+}
+
+// Check that a signed immediate fits in `bits` bits and is a multiple of `align`
+fn check_imm(imm: i32, bits: u32, align: i32) -> Result<u32, EncodeError> {
+    let limit = 1i64 << (bits - 1);
+    if !(-limit..limit).contains(&(imm as i64)) {
+        return Err(EncodeError::OutOfRange(imm as i64));
+    }
+    if imm % align != 0 {
+        return Err(EncodeError::Misaligned(imm as i64));
+    }
+    Ok(imm as u32)
+}
+
+// Check an unsigned field: shift amounts, CSR zimm and CSR addresses
+fn check_uimm(value: u32, bits: u32) -> Result<u32, EncodeError> {
+    if value >> bits != 0 {
+        return Err(EncodeError::OutOfRange(value as i64));
+    }
+    Ok(value)
+}
+
+fn encode_r(opcode: u32, func3: u32, func7: u32, rd: Register, rs1: Register, rs2: Register) -> u32 {
+    (func7 << 25) | (reg(rs2) << 20) | (reg(rs1) << 15) | (func3 << 12) | (reg(rd) << 7) | opcode
+}
+
+fn encode_i(opcode: u32, func3: u32, rd: Register, rs1: Register, imm: i32) -> Result<u32, EncodeError> {
+    let imm = check_imm(imm, 12, 1)?;
+    Ok(((imm & 0xfff) << 20) | (reg(rs1) << 15) | (func3 << 12) | (reg(rd) << 7) | opcode)
+}
+
+// Shifts by an immediate, `func6` goes above the shift amount of `bits` bits
+fn encode_shift(opcode: u32, func3: u32, func6: u32, rd: Register, rs1: Register, shamt: u32, bits: u32) -> Result<u32, EncodeError> {
+    let shamt = check_uimm(shamt, bits)?;
+    Ok((func6 << 26) | (shamt << 20) | (reg(rs1) << 15) | (func3 << 12) | (reg(rd) << 7) | opcode)
+}
+
+fn encode_s(func3: u32, rs1: Register, rs2: Register, imm: i32) -> Result<u32, EncodeError> {
+    let imm = check_imm(imm, 12, 1)?;
+    Ok((((imm >> 5) & 0x7f) << 25) | (reg(rs2) << 20) | (reg(rs1) << 15) | (func3 << 12) | ((imm & 0x1f) << 7) | 0b0100011)
+}
+
+fn encode_b(func3: u32, rs1: Register, rs2: Register, imm: i32) -> Result<u32, EncodeError> {
+    let imm = check_imm(imm, 13, 2)?;
+    let high = (((imm >> 12) & 1) << 6) | ((imm >> 5) & 0x3f);
+    let low = (((imm >> 1) & 0xf) << 1) | ((imm >> 11) & 1);
+    Ok((high << 25) | (reg(rs2) << 20) | (reg(rs1) << 15) | (func3 << 12) | (low << 7) | 0b1100011)
+}
+
+fn encode_u(opcode: u32, rd: Register, imm: i32) -> Result<u32, EncodeError> {
+    if imm & 0xfff != 0 {
+        return Err(EncodeError::Misaligned(imm as i64));
+    }
+    Ok((imm as u32) | (reg(rd) << 7) | opcode)
+}
+
+fn encode_j(rd: Register, imm: i32) -> Result<u32, EncodeError> {
+    let imm = check_imm(imm, 21, 2)?;
+    let field = (((imm >> 20) & 1) << 19) | (((imm >> 1) & 0x3ff) << 9) | (((imm >> 11) & 1) << 8) | ((imm >> 12) & 0xff);
+    Ok((field << 12) | (reg(rd) << 7) | 0b1101111)
+}
+
+fn encode_csr(func3: u32, rd: Register, source: u32, csr: u16) -> Result<u32, EncodeError> {
+    let csr = check_uimm(csr as u32, 12)?;
+    Ok((csr << 20) | (source << 15) | (func3 << 12) | (reg(rd) << 7) | 0b1110011)
+}
+
+// System instructions with a fixed encoding, `imm` is the func12 field
+fn encode_system(imm: u32) -> u32 {
+    (imm << 20) | 0b1110011
+}
+
+// A-extension instruction, the aq/rl bits are left clear
+fn encode_amo(func3: u32, func5: u32, rd: Register, rs1: Register, rs2: Register) -> u32 {
+    encode_r(0b0101111, func3, func5 << 2, rd, rs1, rs2)
+}
+
+impl Instruction {
+    /// Encode to the 32-bit form, the inverse of `decode`. There are no compressed instructions
+    /// in the enum, every instruction has a 32-bit encoding.
+    pub fn encode(&self) -> Result<u32, EncodeError> {
+        use Instruction::*;
+
+        const OP_IMM: u32 = 0b0010011;
+        const OP_IMM_32: u32 = 0b0011011;
+        const OP: u32 = 0b0110011;
+        const OP_32: u32 = 0b0111011;
+        const LOAD: u32 = 0b0000011;
+
+        let word = match *self {
+            Undefined => return Err(EncodeError::Undefined),
+
+            Addi { rd, rs1, imm } => encode_i(OP_IMM, 0b000, rd, rs1, imm)?,
+            Slti { rd, rs1, imm } => encode_i(OP_IMM, 0b010, rd, rs1, imm)?,
+            Sltiu { rd, rs1, imm } => encode_i(OP_IMM, 0b011, rd, rs1, imm)?,
+            Xori { rd, rs1, imm } => encode_i(OP_IMM, 0b100, rd, rs1, imm)?,
+            Ori { rd, rs1, imm } => encode_i(OP_IMM, 0b110, rd, rs1, imm)?,
+            Andi { rd, rs1, imm } => encode_i(OP_IMM, 0b111, rd, rs1, imm)?,
+
+            Slli { rd, rs1, shamt } => encode_shift(OP_IMM, 0b001, 0b000_000, rd, rs1, shamt, 6)?,
+            Srli { rd, rs1, shamt } => encode_shift(OP_IMM, 0b101, 0b000_000, rd, rs1, shamt, 6)?,
+            Srai { rd, rs1, shamt } => encode_shift(OP_IMM, 0b101, 0b010_000, rd, rs1, shamt, 6)?,
+
+            Lb { rd, rs1, imm } => encode_i(LOAD, 0b000, rd, rs1, imm)?,
+            Lh { rd, rs1, imm } => encode_i(LOAD, 0b001, rd, rs1, imm)?,
+            Lw { rd, rs1, imm } => encode_i(LOAD, 0b010, rd, rs1, imm)?,
+            Lbu { rd, rs1, imm } => encode_i(LOAD, 0b100, rd, rs1, imm)?,
+            Lhu { rd, rs1, imm } => encode_i(LOAD, 0b101, rd, rs1, imm)?,
+            Lwu { rd, rs1, imm } => encode_i(LOAD, 0b110, rd, rs1, imm)?,
+            Ld { rd, rs1, imm } => encode_i(LOAD, 0b011, rd, rs1, imm)?,
+
+            Fence { rd, rs1, imm } => encode_i(0b0001111, 0b000, rd, rs1, imm)?,
+            FenceI => encode_i(0b0001111, 0b001, Register::X0, Register::X0, 0)?,
+
+            Jalr { rd, rs1, imm } => encode_i(0b1100111, 0b000, rd, rs1, imm)?,
+
+            Ecall => encode_system(0x000),
+            Ebreak => encode_system(0x001),
+            Sret => encode_system(0x102),
+            Mret => encode_system(0x302),
+            Wfi => encode_system(0x105),
+            SfenceVma { rs1, rs2 } => encode_r(0b1110011, 0b000, 0b000_1001, Register::X0, rs1, rs2),
+
+            Csrrw { rd, rs1, csr } => encode_csr(0b001, rd, reg(rs1), csr)?,
+            Csrrs { rd, rs1, csr } => encode_csr(0b010, rd, reg(rs1), csr)?,
+            Csrrc { rd, rs1, csr } => encode_csr(0b011, rd, reg(rs1), csr)?,
+            Csrrwi { rd, uimm, csr } => encode_csr(0b101, rd, check_uimm(uimm, 5)?, csr)?,
+            Csrrsi { rd, uimm, csr } => encode_csr(0b110, rd, check_uimm(uimm, 5)?, csr)?,
+            Csrrci { rd, uimm, csr } => encode_csr(0b111, rd, check_uimm(uimm, 5)?, csr)?,
+
+            Add { rd, rs1, rs2 } => encode_r(OP, 0b000, 0b000_0000, rd, rs1, rs2),
+            Sub { rd, rs1, rs2 } => encode_r(OP, 0b000, 0b010_0000, rd, rs1, rs2),
+            Sll { rd, rs1, rs2 } => encode_r(OP, 0b001, 0b000_0000, rd, rs1, rs2),
+            Slt { rd, rs1, rs2 } => encode_r(OP, 0b010, 0b000_0000, rd, rs1, rs2),
+            Sltu { rd, rs1, rs2 } => encode_r(OP, 0b011, 0b000_0000, rd, rs1, rs2),
+            Xor { rd, rs1, rs2 } => encode_r(OP, 0b100, 0b000_0000, rd, rs1, rs2),
+            Srl { rd, rs1, rs2 } => encode_r(OP, 0b101, 0b000_0000, rd, rs1, rs2),
+            Sra { rd, rs1, rs2 } => encode_r(OP, 0b101, 0b010_0000, rd, rs1, rs2),
+            Or { rd, rs1, rs2 } => encode_r(OP, 0b110, 0b000_0000, rd, rs1, rs2),
+            And { rd, rs1, rs2 } => encode_r(OP, 0b111, 0b000_0000, rd, rs1, rs2),
+
+            Auipc { rd, imm } => encode_u(0b0010111, rd, imm)?,
+            Lui { rd, imm } => encode_u(0b0110111, rd, imm)?,
+
+            Sb { rs1, rs2, imm } => encode_s(0b000, rs1, rs2, imm)?,
+            Sh { rs1, rs2, imm } => encode_s(0b001, rs1, rs2, imm)?,
+            Sw { rs1, rs2, imm } => encode_s(0b010, rs1, rs2, imm)?,
+            Sd { rs1, rs2, imm } => encode_s(0b011, rs1, rs2, imm)?,
+
+            Beq { rs1, rs2, imm } => encode_b(0b000, rs1, rs2, imm)?,
+            Bne { rs1, rs2, imm } => encode_b(0b001, rs1, rs2, imm)?,
+            Blt { rs1, rs2, imm } => encode_b(0b100, rs1, rs2, imm)?,
+            Bge { rs1, rs2, imm } => encode_b(0b101, rs1, rs2, imm)?,
+            Bltu { rs1, rs2, imm } => encode_b(0b110, rs1, rs2, imm)?,
+            Bgeu { rs1, rs2, imm } => encode_b(0b111, rs1, rs2, imm)?,
+
+            Jal { rd, imm } => encode_j(rd, imm)?,
+
+            Addiw { rd, rs1, imm } => encode_i(OP_IMM_32, 0b000, rd, rs1, imm)?,
+            Slliw { rd, rs1, shamt } => encode_shift(OP_IMM_32, 0b001, 0b000_000, rd, rs1, shamt, 5)?,
+            Srliw { rd, rs1, shamt } => encode_shift(OP_IMM_32, 0b101, 0b000_000, rd, rs1, shamt, 5)?,
+            Sraiw { rd, rs1, shamt } => encode_shift(OP_IMM_32, 0b101, 0b010_000, rd, rs1, shamt, 5)?,
+
+            Addw { rd, rs1, rs2 } => encode_r(OP_32, 0b000, 0b000_0000, rd, rs1, rs2),
+            Subw { rd, rs1, rs2 } => encode_r(OP_32, 0b000, 0b010_0000, rd, rs1, rs2),
+            Sllw { rd, rs1, rs2 } => encode_r(OP_32, 0b001, 0b000_0000, rd, rs1, rs2),
+            Srlw { rd, rs1, rs2 } => encode_r(OP_32, 0b101, 0b000_0000, rd, rs1, rs2),
+            Sraw { rd, rs1, rs2 } => encode_r(OP_32, 0b101, 0b010_0000, rd, rs1, rs2),
+
+            LrW { rd, rs1 } => encode_amo(0b010, 0b00010, rd, rs1, Register::X0),
+            ScW { rd, rs1, rs2 } => encode_amo(0b010, 0b00011, rd, rs1, rs2),
+            AmoswapW { rd, rs1, rs2 } => encode_amo(0b010, 0b00001, rd, rs1, rs2),
+            AmoaddW { rd, rs1, rs2 } => encode_amo(0b010, 0b00000, rd, rs1, rs2),
+            AmoxorW { rd, rs1, rs2 } => encode_amo(0b010, 0b00100, rd, rs1, rs2),
+            AmoandW { rd, rs1, rs2 } => encode_amo(0b010, 0b01100, rd, rs1, rs2),
+            AmoorW { rd, rs1, rs2 } => encode_amo(0b010, 0b01000, rd, rs1, rs2),
+            AmominW { rd, rs1, rs2 } => encode_amo(0b010, 0b10000, rd, rs1, rs2),
+            AmomaxW { rd, rs1, rs2 } => encode_amo(0b010, 0b10100, rd, rs1, rs2),
+            AmominuW { rd, rs1, rs2 } => encode_amo(0b010, 0b11000, rd, rs1, rs2),
+            AmomaxuW { rd, rs1, rs2 } => encode_amo(0b010, 0b11100, rd, rs1, rs2),
+
+            LrD { rd, rs1 } => encode_amo(0b011, 0b00010, rd, rs1, Register::X0),
+            ScD { rd, rs1, rs2 } => encode_amo(0b011, 0b00011, rd, rs1, rs2),
+            AmoswapD { rd, rs1, rs2 } => encode_amo(0b011, 0b00001, rd, rs1, rs2),
+            AmoaddD { rd, rs1, rs2 } => encode_amo(0b011, 0b00000, rd, rs1, rs2),
+            AmoxorD { rd, rs1, rs2 } => encode_amo(0b011, 0b00100, rd, rs1, rs2),
+            AmoandD { rd, rs1, rs2 } => encode_amo(0b011, 0b01100, rd, rs1, rs2),
+            AmoorD { rd, rs1, rs2 } => encode_amo(0b011, 0b01000, rd, rs1, rs2),
+            AmominD { rd, rs1, rs2 } => encode_amo(0b011, 0b10000, rd, rs1, rs2),
+            AmomaxD { rd, rs1, rs2 } => encode_amo(0b011, 0b10100, rd, rs1, rs2),
+            AmominuD { rd, rs1, rs2 } => encode_amo(0b011, 0b11000, rd, rs1, rs2),
+            AmomaxuD { rd, rs1, rs2 } => encode_amo(0b011, 0b11100, rd, rs1, rs2),
+        };
+        Ok(word)
+    }
+}
+
+#[test]
+fn test_encode_round_trip() {
+    use std::collections::HashSet;
+    use std::mem::discriminant;
+
+    // Every opcode, func3 and func7 with register fields and immediates from a xorshift
+    // generator, plus all-zero fields for the system instructions
+    let mut state = 0x2545_f491u32;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+    let mut variants = HashSet::new();
+    for opcode in 0..128u32 {
+        for func3 in 0..8u32 {
+            for func7 in 0..128u32 {
+                for random in [false, true] {
+                    // rs2, rs1 and rd
+                    let fields = if random { (next() & (0x3ff << 15)) | ((next() & 0x1f) << 7) } else { 0 };
+                    let word = (func7 << 25) | fields | (func3 << 12) | opcode;
+                    let decoded = decode(word);
+                    if decoded == Instruction::Undefined {
+                        continue;
+                    }
+                    variants.insert(discriminant(&decoded));
+                    let encoded = decoded.encode().unwrap_or_else(|e| panic!("{:08x} {:?}: {:?}", word, decoded, e));
+                    assert_eq!(decode(encoded), decoded, "{:08x} encoded as {:08x}", word, encoded);
+                }
+            }
+        }
+    }
+    for word in [0x0000_0073, 0x0010_0073, 0x1020_0073, 0x3020_0073, 0x1050_0073] {
+        variants.insert(discriminant(&decode(word)));
+        assert_eq!(decode(word).encode(), Ok(word));
+    }
+    // All variants but Undefined
+    assert_eq!(variants.len(), 85);
+}
+
+#[test]
+fn test_encode_validation() {
+    use Register::*;
+
+    assert_eq!(Instruction::Addi { rd: X2, rs1: X2, imm: -16 }.encode(), Ok(0xff010113));
+    assert_eq!(Instruction::Jal { rd: X0, imm: 48 }.encode(), Ok(0x0300006f));
+    assert_eq!(Instruction::Bne { rs1: X10, rs2: X11, imm: 36 }.encode(), Ok(0x02b51263));
+    assert_eq!(Instruction::Addi { rd: X1, rs1: X1, imm: 2048 }.encode(), Err(EncodeError::OutOfRange(2048)));
+    assert_eq!(Instruction::Beq { rs1: X1, rs2: X1, imm: 3 }.encode(), Err(EncodeError::Misaligned(3)));
+    assert_eq!(Instruction::Jal { rd: X1, imm: 1 << 20 }.encode(), Err(EncodeError::OutOfRange(1 << 20)));
+    assert_eq!(Instruction::Lui { rd: X1, imm: 0x1001 }.encode(), Err(EncodeError::Misaligned(0x1001)));
+    assert_eq!(Instruction::Slliw { rd: X1, rs1: X1, shamt: 32 }.encode(), Err(EncodeError::OutOfRange(32)));
+    assert_eq!(Instruction::Csrrwi { rd: X1, uimm: 0, csr: 0x1000 }.encode(), Err(EncodeError::OutOfRange(0x1000)));
+    assert_eq!(Instruction::Undefined.encode(), Err(EncodeError::Undefined));
+}
@@ -0,0 +1,33 @@
+// Interrupts in RISC-V, see privileged specification chapter 3.1.9: Machine Interrupt Registers
+// The value of each interrupt is its bit in mip/mie and its exception code in xcause.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    MachineTimer = 7,
+    SupervisorExternal = 9,
+    MachineExternal = 11,
+}
+
+// Interrupts pending at the same time are taken in this order
+pub const PRIORITY: [Interrupt; 6] = [
+    Interrupt::MachineExternal,
+    Interrupt::MachineSoftware,
+    Interrupt::MachineTimer,
+    Interrupt::SupervisorExternal,
+    Interrupt::SupervisorSoftware,
+    Interrupt::SupervisorTimer,
+];
+
+impl Interrupt {
+    // Exception code written to xcause, the interrupt bit is added by the trap entry
+    pub fn code(&self) -> u64 {
+        *self as u64
+    }
+
+    // Bit in mip and mie
+    pub fn mask(&self) -> u64 {
+        1 << self.code()
+    }
+}
@@ -0,0 +1,533 @@
+use std::ffi::c_void;
+
+use crate::block::{Block, Op};
+use crate::bus::Bus;
+use crate::exception::Exception;
+
+// x86-64 backend of the translated blocks: blocks that ran often are compiled to native code.
+// The code keeps no guest state in host registers: every op reads its operands from the register
+// file and writes its result back, so that the state is precise when a load or a store, done by
+// calling back into the emulator, raises an exception. The compiled code returns the number of
+// ops it ran and how it left the block, see `BlockCache::execute`.
+//
+// Code goes to a buffer of each hart, writable while it is being filled and executable after. It
+// is dropped with the blocks: a block translated again after a store to its code loses its code,
+// the buffer is reset when every block is dropped.
+
+// Runs of a block before it is compiled
+pub const THRESHOLD: u32 = 16;
+
+// Size of the code buffer of a hart, and the most code a block can take
+const BUFFER_SIZE: usize = 8 << 20;
+const MAX_CODE: usize = 64 * 1024;
+
+// How the compiled code left the block, in the low byte of its return value, the number of ops
+// it ran is above
+pub const TAKEN: u64 = 0;
+pub const NEXT: u64 = 1;
+pub const JUMP: u64 = 2;
+pub const LEAVE: u64 = 3;
+pub const TRAP: u64 = 4;
+// The next op is not compiled, or jumps to a misaligned target: the interpreter goes on from it
+pub const CONTINUE: u64 = 5;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+const HOST_PAGE: usize = 4096;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+/// What the compiled code of a block works with besides the registers
+#[repr(C)]
+pub struct Context<'a> {
+    // Where the block went, written by the code at its end
+    pub pc: u64,
+    pub bus: &'a Bus,
+    pub block: &'a Block,
+    // Exception of the op that trapped
+    pub exception: Option<Exception>,
+}
+
+type Entry = extern "sysv64" fn(*mut u64, *mut Context) -> u64;
+
+/// Compiled code of a block, valid until the buffer it is in is reset
+#[derive(Copy, Clone)]
+pub struct Code {
+    entry: Entry,
+}
+
+impl Code {
+    // Run the code on the registers, returns the number of ops it ran and how it ended
+    pub fn call(&self, regs: &mut [u64; 32], context: &mut Context) -> (usize, u64) {
+        let result = (self.entry)(regs.as_mut_ptr(), context);
+        ((result >> 8) as usize, result & 0xff)
+    }
+}
+
+/// Executable memory of a hart
+pub struct Jit {
+    buffer: *mut u8,
+    used: usize,
+}
+
+// The buffer belongs to the hart, which only moves to its own thread
+unsafe impl Send for Jit {}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Self { buffer: std::ptr::null_mut(), used: 0 }
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        if !self.buffer.is_null() {
+            unsafe {
+                munmap(self.buffer as *mut c_void, BUFFER_SIZE);
+            }
+        }
+    }
+}
+
+impl Jit {
+    /// Forget all the code, the blocks that had some are gone
+    pub fn reset(&mut self) {
+        self.used = 0;
+    }
+
+    /// Whether the buffer has no room for another block, the blocks have to be dropped first
+    pub fn full(&self) -> bool {
+        self.used + MAX_CODE > BUFFER_SIZE
+    }
+
+    /// Compile the ops of a block at `pc`, None when none of them can be or there is no
+    /// executable memory
+    pub fn compile(&mut self, pc: u64, ops: &[Op]) -> Option<Code> {
+        if self.buffer.is_null() {
+            let buffer = unsafe {
+                mmap(std::ptr::null_mut(), BUFFER_SIZE, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+            };
+            if buffer == MAP_FAILED {
+                return None;
+            }
+            self.buffer = buffer as *mut u8;
+        }
+        let code = emit(pc, ops)?;
+        if code.len() > MAX_CODE || self.full() {
+            return None;
+        }
+
+        // Only the pages of the new code change, the code of other blocks stays executable
+        let start = self.used / HOST_PAGE * HOST_PAGE;
+        let end = (self.used + code.len()).div_ceil(HOST_PAGE) * HOST_PAGE;
+        unsafe {
+            let pages = self.buffer.add(start) as *mut c_void;
+            if mprotect(pages, end - start, PROT_READ | PROT_WRITE) != 0 {
+                return None;
+            }
+            std::ptr::copy_nonoverlapping(code.as_ptr(), self.buffer.add(self.used), code.len());
+            if mprotect(pages, end - start, PROT_READ | PROT_EXEC) != 0 {
+                return None;
+            }
+        }
+        let entry = unsafe { std::mem::transmute::<*mut u8, Entry>(self.buffer.add(self.used)) };
+        // Keep the entry points aligned
+        self.used = (self.used + code.len()).next_multiple_of(16);
+        Some(Code { entry })
+    }
+}
+
+#[repr(C)]
+struct Loaded {
+    value: u64,
+    result: u64,
+}
+
+// Loads and stores of the compiled code, `result` is 0 to go on, or the return value of the code
+// when the access trapped or the block has to be left
+extern "sysv64" fn load(context: &mut Context, addr: u64, size: u64, index: u64) -> Loaded {
+    match context.bus.load(addr, size) {
+        Ok(value) => Loaded { value, result: 0 },
+        Err(exception) => {
+            context.exception = Some(exception);
+            Loaded { value: 0, result: ((index + 1) << 8) | TRAP }
+        }
+    }
+}
+
+extern "sysv64" fn store(context: &mut Context, addr: u64, value: u64, size: u64, index: u64) -> u64 {
+    if let Err(exception) = context.bus.store(addr, size, value) {
+        context.exception = Some(exception);
+        return ((index + 1) << 8) | TRAP;
+    }
+    if context.block.stored(context.bus, addr, size) {
+        return ((index + 1) << 8) | LEAVE;
+    }
+    0
+}
+
+// Host registers, by their encoding
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RSI: u8 = 6;
+
+// Machine code of a function taking the register file in rdi and the context in rsi, rbx and
+// rbp keep them
+struct Emitter {
+    code: Vec<u8>,
+    // Offsets of the rel32 jumps to the epilogue
+    exits: Vec<usize>,
+}
+
+impl Emitter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, imm: u32) {
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    // mov reg, [rbx + 8 * r]
+    fn load(&mut self, reg: u8, r: u8) {
+        self.bytes(&[0x48, 0x8b, 0x83 | (reg << 3)]);
+        self.imm32(8 * r as u32);
+    }
+
+    // mov [rbx + 8 * r], rax, nothing for x0
+    fn store(&mut self, r: u8) {
+        if r != 0 {
+            self.bytes(&[0x48, 0x89, 0x83]);
+            self.imm32(8 * r as u32);
+        }
+    }
+
+    // mov reg, imm64
+    fn mov(&mut self, reg: u8, imm: u64) {
+        self.bytes(&[0x48, 0xb8 + reg]);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    // Leave with the value in eax: mov eax, imm32 unless it is already there, jmp epilogue
+    fn exit(&mut self, result: Option<u64>) {
+        if let Some(result) = result {
+            self.code.push(0xb8);
+            self.imm32(result as u32);
+        }
+        self.code.push(0xe9);
+        self.exits.push(self.code.len());
+        self.imm32(0);
+    }
+
+    // Set pc in the context to rax and leave
+    fn leave_to(&mut self, result: u64) {
+        // mov [rbp], rax
+        self.bytes(&[0x48, 0x89, 0x45, 0x00]);
+        self.exit(Some(result));
+    }
+
+    // rax = rs1 op imm32, with the short rax form of the ALU instruction
+    fn alu_imm(&mut self, rd: u8, rs1: u8, opcode: u8, imm: u64) {
+        self.load(RAX, rs1);
+        self.bytes(&[0x48, opcode]);
+        self.imm32(imm as u32);
+        self.store(rd);
+    }
+
+    // rax = rs1 op rcx(rs2), `wide` for 64-bit operands, words are sign-extended
+    fn alu(&mut self, rd: u8, rs1: u8, rs2: u8, op: &[u8], wide: bool) {
+        self.load(RAX, rs1);
+        self.load(RCX, rs2);
+        if wide {
+            self.code.push(0x48);
+        }
+        self.bytes(op);
+        if !wide {
+            // movsxd rax, eax
+            self.bytes(&[0x48, 0x63, 0xc0]);
+        }
+        self.store(rd);
+    }
+
+    // rax = rs1 shifted by shamt, `ext` selects the shift in the modrm byte
+    fn shift_imm(&mut self, rd: u8, rs1: u8, ext: u8, shamt: u32, wide: bool) {
+        self.load(RAX, rs1);
+        if wide {
+            self.code.push(0x48);
+        }
+        self.bytes(&[0xc1, 0xc0 | (ext << 3), shamt as u8]);
+        if !wide {
+            self.bytes(&[0x48, 0x63, 0xc0]);
+        }
+        self.store(rd);
+    }
+
+    // rax = rs1 compared to rcx, 1 when `setcc` holds
+    fn set(&mut self, rd: u8, setcc: u8) {
+        self.bytes(&[0x0f, setcc, 0xc0, 0x0f, 0xb6, 0xc0]);
+        self.store(rd);
+    }
+
+    // Call a helper at `f`, the arguments are set up
+    fn call(&mut self, f: u64) {
+        self.mov(RAX, f);
+        self.bytes(&[0xff, 0xd0]);
+    }
+
+    // Address of a load or store in rsi, the context in rdi
+    fn address(&mut self, rs1: u8, imm: u64) {
+        self.bytes(&[0x48, 0x89, 0xef]);
+        self.load(RSI, rs1);
+        self.mov(RAX, imm);
+        self.bytes(&[0x48, 0x01, 0xc6]);
+    }
+}
+
+// Code of the ops of a block at `pc`, up to the first one that runs through the interpreter
+fn emit(pc: u64, ops: &[Op]) -> Option<Vec<u8>> {
+    let compiled = ops.iter().take_while(|op| !matches!(op, Op::FenceI | Op::Interpret { .. })).count();
+    if compiled == 0 {
+        return None;
+    }
+    let mut e = Emitter { code: Vec::new(), exits: Vec::new() };
+    let result = |ran: usize, end: u64| ((ran as u64) << 8) | end;
+
+    // push rbx; push rbp; sub rsp, 8 to align the stack for calls; mov rbx, rdi; mov rbp, rsi
+    e.bytes(&[0x53, 0x55, 0x48, 0x83, 0xec, 0x08, 0x48, 0x89, 0xfb, 0x48, 0x89, 0xf5]);
+
+    for (i, op) in ops[..compiled].iter().enumerate() {
+        let end = pc + 4 * ops.len() as u64;
+        match *op {
+            Op::Li { rd, imm } => {
+                e.mov(RAX, imm);
+                e.store(rd);
+            }
+            Op::Addi { rd, rs1, imm } => e.alu_imm(rd, rs1, 0x05, imm),
+            Op::Xori { rd, rs1, imm } => e.alu_imm(rd, rs1, 0x35, imm),
+            Op::Ori { rd, rs1, imm } => e.alu_imm(rd, rs1, 0x0d, imm),
+            Op::Andi { rd, rs1, imm } => e.alu_imm(rd, rs1, 0x25, imm),
+            Op::Slti { rd, rs1, imm } | Op::Sltiu { rd, rs1, imm } => {
+                // cmp rax, imm32; setl or setb
+                e.load(RAX, rs1);
+                e.bytes(&[0x48, 0x3d]);
+                e.imm32(imm as u32);
+                e.set(rd, if matches!(op, Op::Slti { .. }) { 0x9c } else { 0x92 });
+            }
+            Op::Slli { rd, rs1, shamt } => e.shift_imm(rd, rs1, 4, shamt, true),
+            Op::Srli { rd, rs1, shamt } => e.shift_imm(rd, rs1, 5, shamt, true),
+            Op::Srai { rd, rs1, shamt } => e.shift_imm(rd, rs1, 7, shamt, true),
+            Op::Addiw { rd, rs1, imm } => {
+                // add eax, imm32; movsxd rax, eax
+                e.load(RAX, rs1);
+                e.code.push(0x05);
+                e.imm32(imm as u32);
+                e.bytes(&[0x48, 0x63, 0xc0]);
+                e.store(rd);
+            }
+            Op::Slliw { rd, rs1, shamt } => e.shift_imm(rd, rs1, 4, shamt, false),
+            Op::Srliw { rd, rs1, shamt } => e.shift_imm(rd, rs1, 5, shamt, false),
+            Op::Sraiw { rd, rs1, shamt } => e.shift_imm(rd, rs1, 7, shamt, false),
+
+            // x86 masks shift counts in cl to 6 bits, 5 bits for 32-bit operands, as RISC-V does
+            Op::Add { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0x01, 0xc8], true),
+            Op::Sub { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0x29, 0xc8], true),
+            Op::Sll { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0xd3, 0xe0], true),
+            Op::Xor { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0x31, 0xc8], true),
+            Op::Srl { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0xd3, 0xe8], true),
+            Op::Sra { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0xd3, 0xf8], true),
+            Op::Or { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0x09, 0xc8], true),
+            Op::And { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0x21, 0xc8], true),
+            Op::Slt { rd, rs1, rs2 } | Op::Sltu { rd, rs1, rs2 } => {
+                // cmp rax, rcx; setl or setb
+                e.load(RAX, rs1);
+                e.load(RCX, rs2);
+                e.bytes(&[0x48, 0x39, 0xc8]);
+                e.set(rd, if matches!(op, Op::Slt { .. }) { 0x9c } else { 0x92 });
+            }
+            Op::Addw { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0x01, 0xc8], false),
+            Op::Subw { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0x29, 0xc8], false),
+            Op::Sllw { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0xd3, 0xe0], false),
+            Op::Srlw { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0xd3, 0xe8], false),
+            Op::Sraw { rd, rs1, rs2 } => e.alu(rd, rs1, rs2, &[0xd3, 0xf8], false),
+
+            Op::Lb { rd, rs1, imm }
+            | Op::Lh { rd, rs1, imm }
+            | Op::Lw { rd, rs1, imm }
+            | Op::Ld { rd, rs1, imm }
+            | Op::Lbu { rd, rs1, imm }
+            | Op::Lhu { rd, rs1, imm }
+            | Op::Lwu { rd, rs1, imm } => {
+                let (size, extend): (u32, &[u8]) = match *op {
+                    // movsx rax, al / ax, movsxd rax, eax
+                    Op::Lb { .. } => (8, &[0x48, 0x0f, 0xbe, 0xc0]),
+                    Op::Lh { .. } => (16, &[0x48, 0x0f, 0xbf, 0xc0]),
+                    Op::Lw { .. } => (32, &[0x48, 0x63, 0xc0]),
+                    Op::Ld { .. } => (64, &[]),
+                    Op::Lbu { .. } => (8, &[]),
+                    Op::Lhu { .. } => (16, &[]),
+                    _ => (32, &[]),
+                };
+                e.address(rs1, imm);
+                // mov edx, size; mov ecx, index
+                e.code.push(0xba);
+                e.imm32(size);
+                e.code.push(0xb9);
+                e.imm32(i as u32);
+                e.call(load as *const () as u64);
+                // test rdx, rdx; jz over the exit; mov rax, rdx; jmp epilogue
+                e.bytes(&[0x48, 0x85, 0xd2, 0x74, 0x08, 0x48, 0x89, 0xd0]);
+                e.exit(None);
+                e.bytes(extend);
+                e.store(rd);
+            }
+            Op::Store { rs1, rs2, imm, size } => {
+                e.address(rs1, imm);
+                e.load(RDX, rs2);
+                // mov ecx, size; mov r8d, index
+                e.code.push(0xb9);
+                e.imm32(size as u32);
+                e.bytes(&[0x41, 0xb8]);
+                e.imm32(i as u32);
+                e.call(store as *const () as u64);
+                // test rax, rax; jz over the exit; jmp epilogue
+                e.bytes(&[0x48, 0x85, 0xc0, 0x74, 0x05]);
+                e.exit(None);
+            }
+            Op::Nop => {}
+
+            Op::Beq { rs1, rs2, target }
+            | Op::Bne { rs1, rs2, target }
+            | Op::Blt { rs1, rs2, target }
+            | Op::Bge { rs1, rs2, target }
+            | Op::Bltu { rs1, rs2, target }
+            | Op::Bgeu { rs1, rs2, target } => {
+                let jcc = match *op {
+                    Op::Beq { .. } => 0x84,
+                    Op::Bne { .. } => 0x85,
+                    Op::Blt { .. } => 0x8c,
+                    Op::Bge { .. } => 0x8d,
+                    Op::Bltu { .. } => 0x82,
+                    _ => 0x83,
+                };
+                // cmp rax, [rbx + 8 * rs2]; jcc over the fall-through exit, 24 bytes
+                e.load(RAX, rs1);
+                e.bytes(&[0x48, 0x3b, 0x83]);
+                e.imm32(8 * rs2 as u32);
+                e.bytes(&[0x0f, jcc]);
+                e.imm32(24);
+                e.mov(RAX, end);
+                e.leave_to(result(i + 1, NEXT));
+                e.mov(RAX, target);
+                e.leave_to(result(i + 1, TAKEN));
+            }
+            Op::Jal { rd, link, target } => {
+                e.mov(RAX, link);
+                e.store(rd);
+                e.mov(RAX, target);
+                e.leave_to(result(i + 1, TAKEN));
+            }
+            Op::Jalr { rd, rs1, imm, link } => {
+                // The target is computed before rd is written, rd may be rs1
+                e.load(RCX, rs1);
+                // add rcx, imm32; and rcx, -2
+                e.bytes(&[0x48, 0x81, 0xc1]);
+                e.imm32(imm as u32);
+                e.bytes(&[0x48, 0x83, 0xe1, 0xfe]);
+                // test cl, 2; jz over the exit: the interpreter handles a misaligned target
+                e.bytes(&[0xf6, 0xc1, 0x02, 0x74, 0x0a]);
+                e.exit(Some(result(i, CONTINUE)));
+                e.mov(RAX, link);
+                e.store(rd);
+                // mov rax, rcx
+                e.bytes(&[0x48, 0x89, 0xc8]);
+                e.leave_to(result(i + 1, JUMP));
+            }
+            Op::FenceI | Op::Interpret { .. } => unreachable!(),
+        }
+    }
+
+    // The block ran out of ops without a jump, or the next one is interpreted
+    if !matches!(ops[compiled - 1], Op::Beq { .. } | Op::Bne { .. } | Op::Blt { .. } | Op::Bge { .. }
+        | Op::Bltu { .. } | Op::Bgeu { .. } | Op::Jal { .. } | Op::Jalr { .. })
+    {
+        if compiled == ops.len() {
+            e.mov(RAX, pc + 4 * ops.len() as u64);
+            e.leave_to(result(compiled, NEXT));
+        } else {
+            e.exit(Some(result(compiled, CONTINUE)));
+        }
+    }
+
+    // Epilogue: add rsp, 8; pop rbp; pop rbx; ret
+    let epilogue = e.code.len();
+    e.bytes(&[0x48, 0x83, 0xc4, 0x08, 0x5d, 0x5b, 0xc3]);
+    for &at in &e.exits {
+        let rel = (epilogue - (at + 4)) as u32;
+        e.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+    Some(e.code)
+}
+
+#[test]
+fn test_precise_traps() {
+    use crate::asm::assemble;
+    use crate::bus::DRAM_BASE;
+    use crate::machine::{Exit, Machine};
+
+    // The loop is compiled long before the 30th pointer sends its second load outside of memory
+    let pointers = "            .dword data\n".repeat(29);
+    let source = format!("
+            la t0, trap
+            csrw mtvec, t0
+            la s0, pointers
+            li s1, 0
+        loop:
+            slli t0, s1, 3
+            add t0, s0, t0
+            ld t1, 0(t0)
+            addi s2, s2, 3
+            ld t2, 0(t1)
+            add s3, s3, t2
+            sd s3, 8(t1)
+            addi s1, s1, 1
+            j loop
+
+        trap:
+            csrr a0, mcause
+            csrr a1, mtval
+            csrr a2, mepc
+        1:  j 1b
+
+        pointers:
+{}            .dword 0x1000
+        data:
+            .dword 7
+            .dword 0
+    ", pointers);
+    let code = assemble(&source, DRAM_BASE).unwrap();
+    for max in [100, 200, 267, 268, 269, 270, 271, 272, 1000] {
+        let machine = || Machine::builder().memory(DRAM_BASE, 0x10000).program(code.clone()).build().unwrap();
+        let (mut compiled, mut steps) = (machine(), machine());
+        steps.harts[0].blocks.enabled = false;
+        assert_eq!(compiled.run(Some(max)), Exit::Limit);
+        assert_eq!(steps.run(Some(max)), Exit::Limit);
+        assert_eq!(compiled.harts[0].pc, steps.harts[0].pc, "after {} instructions", max);
+        assert_eq!(compiled.harts[0].regs, steps.harts[0].regs, "after {} instructions", max);
+        assert_eq!(compiled.harts[0].blocks.compiled() > 0, max >= 200);
+    }
+
+    let mut machine = Machine::builder().memory(DRAM_BASE, 0x10000).program(code).build().unwrap();
+    assert_eq!(machine.run(Some(1000)), Exit::Limit);
+    let regs = &machine.harts[0].regs;
+    // Load access fault at the second load of the 30th pass, after its first ALU op
+    assert_eq!((regs[10], regs[11], regs[18]), (5, 0x1000, 3 * 30));
+}
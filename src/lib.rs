@@ -0,0 +1,76 @@
+//! RV64 emulator: RV64IA harts with M, S and U modes, a CLINT, a UART, a test finisher and HTIF,
+//! able to run riscv-tests style programs and boot a Linux kernel.
+//!
+//! The main types:
+//!
+//! - [`Machine`]: harts sharing a [`Bus`], built with [`MachineBuilder`] and driven by
+//!   [`Machine::run`] or [`Machine::step`].
+//! - [`MachineConfig`]: memory layout, harts, ISA, device addresses and boot mode, from code or
+//!   a configuration file.
+//! - [`Cpu`]: one hart, its registers, CSRs and privilege mode.
+//! - [`Bus`]: DRAM and the memory-mapped devices, [`Device`] adds new ones.
+//! - [`Instruction`]: the decoded form of an instruction, see [`decode`] and
+//!   [`Instruction::encode`].
+//! - [`Hooks`]: callbacks observing or changing the execution.
+//! - Loaders: [`Elf`] with [`Machine::load_elf`], [`asm::assemble`] for assembly source, and
+//!   [`boot::boot`] for a kernel.
+//! - Snapshots: [`Machine::save_snapshot`] and [`Machine::restore_snapshot`] save a machine to
+//!   a file and resume it, see [`snapshot`].
+//!
+//! ```
+//! use rv64_emu::{assemble, Exit, MachineBuilder, DRAM_BASE};
+//!
+//! let code = assemble("li a0, 40; addi a0, a0, 2; 1: j 1b", DRAM_BASE).unwrap();
+//! let mut machine = MachineBuilder::new().program(code).build().unwrap();
+//! assert_eq!(machine.run(Some(3)), Exit::Limit);
+//! assert_eq!(machine.harts[0].regs[10], 42);
+//! ```
+//!
+//! Harts run translated blocks of micro-ops, see [`block`]. The `jit` feature, on by default,
+//! compiles the hot ones to native code on x86-64 Linux hosts.
+//!
+//! Enums that will grow with the emulator, like [`Instruction`], [`Exception`] and [`Exit`], are
+//! `#[non_exhaustive]`.
+
+pub mod asm;
+pub mod block;
+pub mod boot;
+pub mod bus;
+pub mod clint;
+pub mod config;
+pub mod cpu;
+pub mod csr;
+pub mod devicetree;
+pub mod disasm;
+mod dram;
+pub mod elf;
+pub mod exception;
+pub mod finisher;
+pub mod gdb;
+pub mod hooks;
+pub mod htif;
+pub mod icache;
+pub mod instruction;
+pub mod interrupt;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+mod jit;
+pub mod lockstep;
+pub mod machine;
+pub mod monitor;
+pub mod register;
+pub mod sbi;
+pub mod semihosting;
+pub mod snapshot;
+pub mod trace;
+pub mod uart;
+
+pub use crate::asm::assemble;
+pub use crate::bus::{Bus, Device, DRAM_BASE};
+pub use crate::config::{BootMode, MachineConfig, Misaligned, MisalignedPolicy};
+pub use crate::cpu::{Cpu, Mode};
+pub use crate::elf::Elf;
+pub use crate::exception::Exception;
+pub use crate::hooks::{Access, HookAction, Hooks};
+pub use crate::instruction::{decode, EncodeError, Instruction};
+pub use crate::machine::{BuildError, Exit, Machine, MachineBuilder};
+pub use crate::register::Register;
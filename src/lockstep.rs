@@ -0,0 +1,226 @@
+// Lockstep run against the commit log of a reference simulator, spike (--log-commits) or Sail
+// (--trace). Each reference instruction is compared with the next one the emulator retires on
+// the same hart, the run stops at the first difference in pc, instruction, destination register
+// value or memory write. Traps retire nothing in either log, CSR writes are not compared.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+use crate::disasm::disassemble;
+use crate::exception::Exception;
+use crate::machine::Machine;
+use crate::trace::writes;
+
+// What a retired instruction did
+#[derive(PartialEq, Debug)]
+struct Commit {
+    hart: usize,
+    pc: u64,
+    inst: u32,
+    // Write to a register other than x0
+    rd: Option<(usize, u64)>,
+    // Address and value of a store
+    store: Option<(u64, u64)>,
+}
+
+fn hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text.strip_prefix("0x")?, 16).ok()
+}
+
+fn xreg(text: &str) -> Option<usize> {
+    text.strip_prefix('x')?.parse().ok().filter(|i| *i < 32)
+}
+
+// `core   0: 3 0x0000000080000030 (0x00000297) x5  0x0000000080000030 mem 0x... 0x...`, the
+// disassembly lines of `-l` and the trap lines have no privilege level and are skipped
+fn parse_spike(line: &str) -> Option<Commit> {
+    let (core, rest) = line.strip_prefix("core")?.split_once(':')?;
+    let mut tokens = rest.split_whitespace().peekable();
+    let privilege = tokens.next()?;
+    if privilege.len() != 1 {
+        return None;
+    }
+    let pc = hex(tokens.next()?)?;
+    let inst = hex(tokens.next()?.strip_prefix('(')?.strip_suffix(')')?)? as u32;
+    let mut commit = Commit { hart: core.trim().parse().ok()?, pc, inst, rd: None, store: None };
+
+    while let Some(key) = tokens.next() {
+        if key == "mem" {
+            let addr = hex(tokens.next()?)?;
+            // A load has no value
+            if let Some(value) = tokens.next_if(|token| token.starts_with("0x")) {
+                commit.store = Some((addr, hex(value)?));
+            }
+            continue;
+        }
+        let value = hex(tokens.next()?)?;
+        if let Some(rd) = xreg(key).filter(|rd| *rd != 0) {
+            commit.rd = Some((rd, value));
+        }
+    }
+    Some(commit)
+}
+
+// `[12] [M]: 0x0000000080000030 (0x00000297) auipc t0, 0x0`, the hart is not printed
+fn parse_sail_instruction(line: &str) -> Option<Commit> {
+    let (_, rest) = line.strip_prefix('[')?.split_once("]: ")?;
+    let mut tokens = rest.split_whitespace();
+    let pc = hex(tokens.next()?)?;
+    let inst = hex(tokens.next()?.strip_prefix('(')?.strip_suffix(')')?)? as u32;
+    Some(Commit { hart: 0, pc, inst, rd: None, store: None })
+}
+
+// `x5 <- 0x...` or `mem[0x...] <- 0x...`, older Sail versions print `mem[X,0x...]`
+fn parse_sail_write(line: &str, commit: &mut Commit) {
+    let Some((target, value)) = line.split_once(" <- ") else {
+        return;
+    };
+    let Some(value) = hex(value.trim()) else {
+        return;
+    };
+    if let Some(rd) = xreg(target).filter(|rd| *rd != 0) {
+        commit.rd = Some((rd, value));
+    } else if let Some(addr) = target.strip_prefix("mem[").and_then(|t| t.strip_suffix(']')) {
+        if let Some(addr) = hex(addr.rsplit(',').next().unwrap()) {
+            commit.store = Some((addr, value));
+        }
+    }
+}
+
+// All the commits of a log, in order
+fn parse(reader: impl BufRead) -> io::Result<Vec<Commit>> {
+    let mut commits: Vec<Commit> = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with("core") {
+            commits.extend(parse_spike(&line));
+        } else if let Some(commit) = parse_sail_instruction(&line) {
+            commits.push(commit);
+        } else if let Some(commit) = commits.last_mut() {
+            parse_sail_write(&line, commit);
+        }
+    }
+    Ok(commits)
+}
+
+fn describe(commit: &Commit) -> String {
+    let mut text = format!("{:#018x} ({:#010x}) {}", commit.pc, commit.inst, disassemble(commit.inst, commit.pc, None));
+    if let Some((rd, value)) = commit.rd {
+        text += &format!(" x{} {:#018x}", rd, value);
+    }
+    if let Some((addr, value)) = commit.store {
+        text += &format!(" mem {:#018x} {:#x}", addr, value);
+    }
+    text
+}
+
+// Next instruction the hart retires, with the traps taken before it. Err when the machine
+// stops first.
+fn retire(machine: &mut Machine, hart: usize) -> Result<(Commit, Vec<Exception>), String> {
+    let mut traps = Vec::new();
+    loop {
+        if let Some(exit) = machine.exit_condition() {
+            return Err(format!("the guest stopped: {:?}", exit));
+        }
+        let step = machine.step_hart(hart);
+        let cpu = &machine.harts[hart];
+        let retired = cpu.retired.as_ref().unwrap();
+        if let Err(exception) = step {
+            return Err(format!("fatal {:?} at {:#x}", exception, retired.pc));
+        }
+        if let Some(exception) = retired.exception {
+            traps.push(exception);
+            continue;
+        }
+        let Some(inst) = retired.inst else {
+            continue;
+        };
+
+        let writes = writes(inst, &retired.regs, cpu, &machine.bus);
+        let commit = Commit {
+            hart,
+            pc: retired.pc,
+            inst,
+            rd: writes.rd.filter(|rd| *rd != 0).map(|rd| (rd, cpu.regs[rd])),
+            store: writes.store.map(|(addr, _, value)| (addr, value)),
+        };
+        return Ok((commit, traps));
+    }
+}
+
+// Run the reference log at `path` in lockstep, false when the emulator diverged. The machine is
+// left after the last instruction compared.
+pub fn run(machine: &mut Machine, path: &str) -> io::Result<bool> {
+    let reference = parse(BufReader::new(File::open(path)?))?;
+    machine.record_steps();
+
+    for (i, expected) in reference.iter().enumerate() {
+        if expected.hart >= machine.harts.len() {
+            eprintln!("lockstep: the reference runs hart {}, the machine has {}", expected.hart, machine.harts.len());
+            return Ok(false);
+        }
+        let (actual, traps) = match retire(machine, expected.hart) {
+            Ok(retired) => retired,
+            Err(reason) => {
+                eprintln!("lockstep: diverged at instruction {} on hart {}: {}", i, expected.hart, reason);
+                eprintln!("  reference: {}", describe(expected));
+                return Ok(false);
+            }
+        };
+        if actual == *expected {
+            continue;
+        }
+
+        eprintln!("lockstep: diverged at instruction {} on hart {}", i, expected.hart);
+        eprintln!("  reference: {}", describe(expected));
+        for trap in traps {
+            eprintln!("  emulator:  took {:?}", trap);
+        }
+        eprintln!("  emulator:  {}", describe(&actual));
+        machine.harts[expected.hart].dump_registers();
+        return Ok(false);
+    }
+
+    eprintln!("lockstep: {} instructions match the reference", reference.len());
+    Ok(true)
+}
+
+#[test]
+fn test_parse_logs() {
+    let spike = "\
+core   0: 0x0000000080000030 (0x00000297) auipc   t0, 0x0
+core   0: 3 0x0000000080000030 (0x00000297) x5  0x0000000080000030
+core   0: 3 0x0000000080000038 (0x30529073) c773_mtvec 0x0000000080000004
+core   0: exception trap_illegal_instruction, epc 0x0000000080000040
+core   0:           tval 0x00000000ffffffff
+core   1: 0 0x000000008000007c (0x00a2b023) mem 0x0000000080001010 0x0000000000000008
+core   0: 3 0x0000000080000084 (0x0002b503) x10 0x0000000000000001 mem 0x0000000080001000
+";
+    let sail = "\
+[0] [M]: 0x0000000080000030 (0x00000297) auipc t0, 0x0
+x5 <- 0x0000000080000030
+[1] [M]: 0x0000000080000038 (0x30529073) csrrw zero, mtvec, t0
+CSR mtvec <- 0x0000000080000004
+trapping from M to M to handle illegal-instruction
+[2] [U]: 0x000000008000007c (0x00A2B023) sd a0, 0(t0)
+mem[0x0000000080001010] <- 0x0000000000000008
+";
+    let commit = |hart, pc, inst, rd, store| Commit { hart, pc, inst, rd, store };
+    assert_eq!(
+        parse(spike.as_bytes()).unwrap(),
+        [
+            commit(0, 0x8000_0030, 0x00000297, Some((5, 0x8000_0030)), None),
+            commit(0, 0x8000_0038, 0x30529073, None, None),
+            commit(1, 0x8000_007c, 0x00a2b023, None, Some((0x8000_1010, 8))),
+            commit(0, 0x8000_0084, 0x0002b503, Some((10, 1)), None),
+        ]
+    );
+    assert_eq!(
+        parse(sail.as_bytes()).unwrap(),
+        [
+            commit(0, 0x8000_0030, 0x00000297, Some((5, 0x8000_0030)), None),
+            commit(0, 0x8000_0038, 0x30529073, None, None),
+            commit(0, 0x8000_007c, 0x00a2b023, None, Some((0x8000_1010, 8))),
+        ]
+    );
+}
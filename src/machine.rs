@@ -0,0 +1,607 @@
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::bus::*;
+use crate::config::MachineConfig;
+use crate::cpu::*;
+use crate::elf::Elf;
+use crate::exception::Exception;
+use crate::finisher::FinisherStop;
+use crate::hooks::Hooks;
+use crate::sbi;
+use crate::semihosting;
+use crate::sbi::{Sbi, SbiStop};
+use crate::trace::CommitLog;
+
+/// Instructions a hart runs before the next one is scheduled, and between two checks of the
+/// stop requests when harts run on their own threads
+pub const DEFAULT_QUANTUM: u64 = 100;
+
+// How long a stopped hart thread sleeps before it checks the stop requests again
+const STOPPED_HART_WAIT: Duration = Duration::from_millis(10);
+
+/// Why `Machine::run` returned
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[non_exhaustive]
+pub enum Exit {
+    /// The guest wrote an exit code through HTIF
+    Htif(u64),
+    /// The guest asked the SBI to shut down or reboot, or stopped its last hart
+    Sbi(SbiStop),
+    /// The guest wrote its status to the test finisher
+    Finisher(FinisherStop),
+    /// The guest made the exit syscall without a trap handler, with the status in a0
+    Syscall(u64),
+    /// The guest exited through semihosting
+    Semihosting(u64),
+    /// An exception the guest cannot handle
+    Fatal { hart: usize, exception: Exception },
+    /// The instruction limit was reached
+    Limit,
+    /// `Control::pause` was called, running again resumes the guest
+    Paused,
+    /// A hook asked to stop, see `Hooks`
+    Hook { hart: usize },
+}
+
+impl Exit {
+    /// Exit status of a guest that stopped by itself, None for the other stops. A shutdown or a
+    /// reset with a reason other than "no reason" is a failure.
+    pub fn status(&self) -> Option<u64> {
+        match *self {
+            Exit::Htif(code) | Exit::Syscall(code) | Exit::Semihosting(code) => Some(code),
+            Exit::Finisher(FinisherStop::Fail(code)) => Some(code),
+            Exit::Finisher(FinisherStop::Pass | FinisherStop::Reset) => Some(0),
+            Exit::Sbi(SbiStop::Shutdown { reason } | SbiStop::Reboot { reason }) => Some((reason != 0) as u64),
+            Exit::Sbi(SbiStop::HartStop) => Some(0),
+            _ => None,
+        }
+    }
+}
+
+/// Global stop of a running machine, shared by the hart threads. The first reason recorded
+/// stops every hart at its next check.
+#[derive(Default)]
+pub struct Control {
+    stopping: AtomicBool,
+    exit: Mutex<Option<Exit>>,
+}
+
+impl Control {
+    /// Pause the machine from another thread, `Machine::run` returns `Exit::Paused`
+    pub fn pause(&self) {
+        self.stop(Exit::Paused);
+    }
+
+    fn stop(&self, exit: Exit) {
+        let mut current = self.exit.lock().unwrap();
+        if current.is_none() {
+            *current = Some(exit);
+        }
+        self.stopping.store(true, Ordering::Release);
+    }
+
+    fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::Acquire)
+    }
+
+    // Take the reason and clear the request so that the machine can run again
+    fn take(&self) -> Option<Exit> {
+        self.stopping.store(false, Ordering::Release);
+        self.exit.lock().unwrap().take()
+    }
+}
+
+/// Why `MachineBuilder::build` failed
+#[derive(Clone, PartialEq, Debug)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// The configuration is inconsistent, see `MachineConfig::validate`
+    Config(String),
+    /// The program or an ELF segment is outside of DRAM
+    Load(Exception),
+    /// The host cannot allocate the memory
+    Memory(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::Config(message) => write!(f, "{}", message),
+            BuildError::Load(exception) => write!(f, "cannot load the program: {:?}", exception),
+            BuildError::Memory(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Machine settings and contents, see `Machine::builder`
+#[derive(Default)]
+pub struct MachineBuilder {
+    config: MachineConfig,
+    program: Vec<u8>,
+    load_address: Option<u64>,
+    elf: Option<Elf>,
+    devices: Vec<(u64, u64, Box<dyn Device>)>,
+    hooks: Option<Hooks>,
+}
+
+impl MachineBuilder {
+    /// The default configuration: one hart running in round-robin mode, empty DRAM
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from `config`, the other settings override it
+    pub fn config(mut self, config: MachineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn harts(mut self, harts: usize) -> Self {
+        self.config.harts = harts;
+        self
+    }
+
+    pub fn quantum(mut self, quantum: u64) -> Self {
+        self.config.quantum = quantum.max(1);
+        self
+    }
+
+    /// Run each hart on its own host thread
+    pub fn threaded(mut self, threaded: bool) -> Self {
+        self.config.threaded = threaded;
+        self
+    }
+
+    /// DRAM location and size in bytes
+    pub fn memory(mut self, base: u64, size: u64) -> Self {
+        self.config.memory_base = base;
+        self.config.memory_size = size;
+        self
+    }
+
+    /// Raw binary loaded at the start of DRAM
+    pub fn program(mut self, program: Vec<u8>) -> Self {
+        self.program = program;
+        self
+    }
+
+    /// Where `program` goes instead of the start of DRAM, the harts start there unless the
+    /// configuration has a reset vector
+    pub fn load_address(mut self, addr: u64) -> Self {
+        self.load_address = Some(addr);
+        self
+    }
+
+    /// ELF file loaded at its physical addresses, the harts start at its entry. `tohost` and
+    /// `fromhost` symbols attach HTIF.
+    pub fn elf(mut self, elf: Elf) -> Self {
+        self.elf = Some(elf);
+        self
+    }
+
+    /// See `Bus::attach`
+    pub fn device(mut self, base: u64, size: u64, device: Box<dyn Device>) -> Self {
+        self.devices.push((base, size, device));
+        self
+    }
+
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
+    pub fn build(self) -> Result<Machine, BuildError> {
+        self.config.validate().map_err(BuildError::Config)?;
+        let mut machine =
+            Machine::with_config(&self.config, Vec::new()).map_err(|e| BuildError::Memory(e.to_string()))?;
+        let addr = self.load_address.unwrap_or(self.config.memory_base);
+        machine.bus.write_bytes(addr, &self.program).map_err(BuildError::Load)?;
+        if self.load_address.is_some() && self.config.reset_vector.is_none() {
+            machine.harts.iter_mut().for_each(|hart| hart.pc = addr);
+        }
+        for (base, size, device) in self.devices {
+            machine.bus.attach(base, size, device);
+        }
+        if let Some(elf) = self.elf {
+            machine.load_elf(&elf).map_err(BuildError::Load)?;
+            if let Some(tohost) = elf.symbol("tohost") {
+                machine.bus.attach_htif(tohost, elf.symbol("fromhost"));
+            }
+        }
+        if let Some(hooks) = self.hooks {
+            machine.set_hooks(hooks);
+        }
+        Ok(machine)
+    }
+}
+
+/// Harts sharing one bus. They either run one at a time in round-robin order, or each on its own
+/// host thread.
+pub struct Machine {
+    pub harts: Vec<Cpu>,
+    pub bus: Bus,
+    /// Native SBI serving ecalls from S-mode, when booting a kernel without firmware
+    pub sbi: Option<Sbi>,
+    pub quantum: u64,
+    pub threaded: bool,
+    /// Log of every step, harts run in turn on the calling thread while it is set
+    pub commit_log: Option<CommitLog>,
+    control: Arc<Control>,
+    // Hart running now and the number of instructions it has run since it was scheduled
+    pub(crate) current: usize,
+    pub(crate) slice: u64,
+}
+
+impl Machine {
+    /// `harts` harts starting at the start of DRAM, where `binary` is loaded. Panics when
+    /// `binary` is larger than the default DRAM or the host cannot allocate it.
+    pub fn new(binary: Vec<u8>, harts: usize) -> Self {
+        Self::with_config(&MachineConfig { harts, ..Default::default() }, binary).expect("cannot create the machine")
+    }
+
+    /// Machine laid out as `config` says, with `binary` at the start of DRAM. The harts start at
+    /// the reset vector with sp at the end of DRAM. An error when the memory cannot be allocated
+    /// or `binary` is larger than it.
+    pub fn with_config(config: &MachineConfig, binary: Vec<u8>) -> io::Result<Self> {
+        let mut harts: Vec<Cpu> = (0..config.harts as u64).map(Cpu::new).collect();
+        for hart in &mut harts {
+            hart.pc = config.reset_pc();
+            hart.regs[2] = config.memory_base + config.memory_size;
+            hart.csr.set_misa(config.misa);
+            hart.semihosting = config.semihosting;
+        }
+        Ok(Self {
+            harts,
+            bus: Bus::new(config, binary)?,
+            sbi: None,
+            quantum: config.quantum,
+            threaded: config.threaded,
+            commit_log: None,
+            control: Arc::new(Control::default()),
+            current: 0,
+            slice: 0,
+        })
+    }
+
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::new()
+    }
+
+    /// Install the hooks on all harts, replacing the previous ones
+    pub fn set_hooks(&mut self, hooks: Hooks) {
+        let hooks = Arc::new(hooks);
+        for hart in &mut self.harts {
+            hart.hooks = Some(hooks.clone());
+        }
+    }
+
+    /// Make all harts record what their steps did, see `Cpu::retired`
+    pub fn record_steps(&mut self) {
+        for hart in &mut self.harts {
+            hart.retired = Some(Retired { interrupt: None, pc: 0, mode: hart.mode, inst: None, regs: [0; 32], exception: None });
+        }
+    }
+
+    /// Log the steps of all harts from now on
+    pub fn log_commits(&mut self, log: CommitLog) {
+        self.record_steps();
+        self.commit_log = Some(log);
+    }
+
+    /// Handle to stop the machine from another thread while it runs
+    pub fn control(&self) -> Arc<Control> {
+        self.control.clone()
+    }
+
+    /// Copy the loadable segments of an ELF file into memory, all harts start at its entry
+    pub fn load_elf(&mut self, elf: &Elf) -> Result<(), Exception> {
+        for segment in &elf.segments {
+            self.bus.write_bytes(segment.addr, &segment.data)?;
+            // The part of the segment beyond the file data is zero-filled
+            let data_end = segment.addr + segment.data.len() as u64;
+            self.bus.memset(data_end, 0, segment.mem_size.saturating_sub(segment.data.len() as u64))?;
+        }
+        for hart in &mut self.harts {
+            hart.pc = elf.entry;
+        }
+        Ok(())
+    }
+
+    /// Whether the hart runs, a stopped hart may have been started by another one
+    pub fn runnable(&mut self, id: usize) -> bool {
+        match self.sbi.as_ref() {
+            Some(sbi) => sbi.hart_running(&mut self.harts[id]),
+            None => true,
+        }
+    }
+
+    // Make the first running hart from the current one on current, false when all are stopped
+    fn schedule(&mut self) -> bool {
+        let count = self.harts.len();
+        for i in 0..count {
+            let id = (self.current + i) % count;
+            if self.runnable(id) {
+                self.current = id;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Hart that runs the next instruction, None when all harts are stopped
+    pub fn next_hart(&mut self) -> Option<usize> {
+        if self.slice == 0 && !self.schedule() {
+            return None;
+        }
+        Some(self.current)
+    }
+
+    /// Run one instruction on the current hart, the next running hart takes over when its
+    /// quantum is used up or it has stopped
+    pub fn step(&mut self) -> Result<(), Exception> {
+        let Some(id) = self.next_hart() else {
+            return Ok(());
+        };
+
+        let running = self.step_hart(id)?;
+        self.slice += 1;
+        if self.slice >= self.quantum || !running {
+            self.slice = 0;
+            self.current = (id + 1) % self.harts.len();
+        }
+        Ok(())
+    }
+
+    /// Run one instruction on a hart, mtime advances once per instruction of any hart. Returns
+    /// whether the hart still runs, it may stop itself through the SBI.
+    pub fn step_hart(&mut self, id: usize) -> Result<bool, Exception> {
+        self.bus.clint.tick(1);
+        let result = self.harts[id].step(&self.bus);
+        if let Some(log) = &mut self.commit_log {
+            log.record(&self.harts[id], &self.bus);
+        }
+        if self.harts[id].take_hook_stop() {
+            self.control.stop(Exit::Hook { hart: id });
+        }
+        match result {
+            Ok(()) => Ok(true),
+            Err(exception) => {
+                serve(&mut self.harts[id], &self.bus, self.sbi.as_ref(), exception)?;
+                Ok(self.runnable(id))
+            }
+        }
+    }
+
+    /// Reason to stop the guest has given, if any
+    pub fn exit_condition(&self) -> Option<Exit> {
+        exit_condition(&self.bus, self.sbi.as_ref())
+    }
+
+    /// Run until the guest exits, a fatal exception, `max_insns` instructions in total or a
+    /// pause request
+    pub fn run(&mut self, max_insns: Option<u64>) -> Exit {
+        if self.threaded && self.commit_log.is_none() {
+            return self.run_threaded(max_insns);
+        }
+
+        let mut count = 0;
+        loop {
+            if let Some(exit) = self.exit_condition() {
+                return exit;
+            }
+            if self.control.stopping() {
+                return self.control.take().unwrap_or(Exit::Paused);
+            }
+            if Some(count) == max_insns {
+                return Exit::Limit;
+            }
+            match self.run_slice(max_insns.map_or(u64::MAX, |max| max - count)) {
+                Ok(ran) => count += ran,
+                Err(exception) => return Exit::Fatal { hart: self.current, exception },
+            }
+        }
+    }
+
+    // Run the current hart for the rest of its quantum, at most `max` instructions, through
+    // translated blocks when nothing needs to see every step, see `Cpu::run_blocks`. Returns the
+    // number of instructions run.
+    fn run_slice(&mut self, max: u64) -> Result<u64, Exception> {
+        let Some(id) = self.next_hart() else {
+            return Ok(1);
+        };
+        let budget = (self.quantum - self.slice).min(max);
+        let (count, result) = self.harts[id].run_blocks(&self.bus, budget);
+        if count == 0 {
+            self.step()?;
+            return Ok(1);
+        }
+
+        self.bus.clint.tick(count);
+        let running = match result {
+            Ok(()) => true,
+            Err(exception) => {
+                serve(&mut self.harts[id], &self.bus, self.sbi.as_ref(), exception)?;
+                self.runnable(id)
+            }
+        };
+        self.slice += count;
+        if self.slice >= self.quantum || !running {
+            self.slice = 0;
+            self.current = (id + 1) % self.harts.len();
+        }
+        Ok(count)
+    }
+
+    // Run each hart on its own thread, the threads end together when one of them records a
+    // reason to stop
+    fn run_threaded(&mut self, max_insns: Option<u64>) -> Exit {
+        let bus = &self.bus;
+        let sbi = self.sbi.as_ref();
+        let control = &*self.control;
+        let quantum = self.quantum;
+        let retired = &AtomicU64::new(0);
+
+        thread::scope(|scope| {
+            for (id, hart) in self.harts.iter_mut().enumerate() {
+                scope.spawn(move || {
+                    let mut running = sbi.is_none_or(|sbi| sbi.hart_running(hart));
+                    while !control.stopping() {
+                        if !running {
+                            let sbi = sbi.unwrap();
+                            sbi.wait_for_start(id, STOPPED_HART_WAIT);
+                            running = sbi.hart_running(hart);
+                            continue;
+                        }
+
+                        let mut count = 0;
+                        while count < quantum && running {
+                            let (ran, result) = match hart.run_blocks(bus, quantum - count) {
+                                (0, _) => (1, hart.step(bus)),
+                                run => run,
+                            };
+                            count += ran;
+                            if let Err(exception) = result {
+                                if let Err(exception) = serve(hart, bus, sbi, exception) {
+                                    control.stop(Exit::Fatal { hart: id, exception });
+                                    return;
+                                }
+                                running = sbi.is_none_or(|sbi| sbi.hart_running(hart));
+                            }
+                            if hart.take_hook_stop() {
+                                control.stop(Exit::Hook { hart: id });
+                                return;
+                            }
+                            if let Some(exit) = exit_condition(bus, sbi) {
+                                control.stop(exit);
+                                return;
+                            }
+                        }
+
+                        bus.clint.tick(count);
+                        let total = retired.fetch_add(count, Ordering::Relaxed) + count;
+                        if max_insns.is_some_and(|max| total >= max) {
+                            control.stop(Exit::Limit);
+                        }
+                    }
+                });
+            }
+        });
+
+        self.control.take().unwrap_or(Exit::Paused)
+    }
+
+    /// Dump register values, with a header per hart when there are several
+    pub fn dump_registers(&self) {
+        for hart in &self.harts {
+            if self.harts.len() > 1 {
+                println!("hart {}: pc = {:#x}", hart.hartid(), hart.pc);
+            }
+            hart.dump_registers();
+        }
+    }
+}
+
+// Serve an exception a hart returned, see `Cpu::served_by_host`: ecalls to the native SBI,
+// semihosting calls and the exit syscall. The others are fatal.
+fn serve(hart: &mut Cpu, bus: &Bus, sbi: Option<&Sbi>, exception: Exception) -> Result<(), Exception> {
+    match exception {
+        Exception::EnvironmentCallFromSMode if sbi.is_some() => sbi::handle_ecall(hart, bus, sbi.unwrap()),
+        Exception::EnvironmentCallFromUMode | Exception::EnvironmentCallFromMMode => {
+            bus.request_exit(Exit::Syscall(hart.regs[10]));
+        }
+        Exception::Breakpoint(_) => {
+            if let Some(status) = semihosting::handle_call(hart, bus) {
+                bus.request_exit(Exit::Semihosting(status));
+            }
+        }
+        exception => return Err(exception),
+    }
+    Ok(())
+}
+
+// Reasons to stop the guest has given through the bus or the SBI
+fn exit_condition(bus: &Bus, sbi: Option<&Sbi>) -> Option<Exit> {
+    if let Some(exit) = bus.guest_exit() {
+        return Some(exit);
+    }
+    sbi.and_then(|sbi| sbi.stop.get()).map(|stop| Exit::Sbi(*stop))
+}
+
+#[test]
+fn test_pause_threaded() {
+    // Two harts spinning on `j .`
+    let mut machine = Machine::new(0x0000_006fu32.to_le_bytes().to_vec(), 2);
+    machine.threaded = true;
+
+    let control = machine.control();
+    let pauser = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(20));
+        control.pause();
+    });
+    assert_eq!(machine.run(None), Exit::Paused);
+    pauser.join().unwrap();
+
+    // The request is consumed, the machine runs again until the next stop
+    assert_eq!(machine.run(Some(1000)), Exit::Limit);
+    assert!(machine.harts.iter().all(|hart| hart.pc == DRAM_BASE));
+}
+
+#[test]
+fn test_builder_device() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::asm::assemble;
+
+    // A register that reads back twice what was written to it
+    struct Doubler(AtomicU64);
+    impl Device for Doubler {
+        fn load(&self, _addr: u64, _size: u64) -> Result<u64, Exception> {
+            Ok(self.0.load(Ordering::Relaxed) * 2)
+        }
+        fn store(&self, _addr: u64, _size: u64, value: u64) -> Result<(), Exception> {
+            self.0.store(value, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    let source = "
+            li t0, 0x40000000
+            li a0, 21
+            sd a0, 0(t0)
+            ld a1, 0(t0)
+        1:  j 1b
+    ";
+    let code = assemble(source, DRAM_BASE).unwrap();
+    let mut machine = Machine::builder()
+        .program(code)
+        .device(0x4000_0000, 0x1000, Box::new(Doubler(AtomicU64::new(0))))
+        .build()
+        .unwrap();
+    assert_eq!(machine.run(Some(10)), Exit::Limit);
+    assert_eq!(machine.harts[0].regs[11], 42);
+}
+
+#[test]
+fn test_build_errors() {
+    let fails = |builder: MachineBuilder| builder.build().err().unwrap();
+
+    // A program larger than DRAM, at its start or loaded further, faults at the end of DRAM
+    let program = vec![0x13; 0x1008];
+    let builder = Machine::builder().memory(DRAM_BASE, 0x1000).program(program.clone());
+    assert_eq!(fails(builder), BuildError::Load(Exception::StoreAMOAccessFault(DRAM_BASE + 0x1000)));
+    let builder = Machine::builder().memory(DRAM_BASE, 0x2000).program(program).load_address(DRAM_BASE + 0x1000);
+    assert_eq!(fails(builder), BuildError::Load(Exception::StoreAMOAccessFault(DRAM_BASE + 0x2000)));
+
+    assert!(matches!(fails(Machine::builder().harts(0)), BuildError::Config(_)));
+    assert!(matches!(fails(Machine::builder().harts(5000)), BuildError::Config(_)));
+    let config = MachineConfig { clint_base: u64::MAX - 0xf, ..MachineConfig::default() };
+    let message = "the CLINT at 0xfffffffffffffff0 ends beyond the address space";
+    assert_eq!(fails(Machine::builder().config(config)), BuildError::Config(message.to_string()));
+}
@@ -1,56 +1,591 @@
-extern crate core;
+use std::{env, io, process, thread};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::time::Duration;
 
-mod bus;
-mod cpu;
-mod dram;
-mod instruction;
-mod register;
+use rv64_emu::{asm, boot, disasm, gdb, lockstep, monitor, BootMode, BuildError, MachineConfig};
+use rv64_emu::boot::BootOptions;
+use rv64_emu::bus::DRAM_BASE;
+use rv64_emu::clint::MAX_HARTS;
+use rv64_emu::config::{parse_size, ConfigError};
+use rv64_emu::disasm::Symbols;
+use rv64_emu::elf::*;
+use rv64_emu::exception::Exception;
+use rv64_emu::finisher::FinisherStop;
+use rv64_emu::machine::*;
+use rv64_emu::sbi::SbiStop;
+use rv64_emu::trace::{CommitLog, Filter};
 
-use std::{env, io};
-use std::fs::File;
-use std::io::Read;
-use crate::bus::DRAM_BASE;
+const USAGE: &str = "\
+Usage: rv64_emu <command> [options] <program>
+
+Commands:
+  run <program>            Run a raw binary, an ELF file or an assembly source (.s)
+  test <elf>               Run a riscv-tests style ELF until it reports through HTIF
+  trace <program>          Run and write a spike-style commit log
+  debug <program>          Run under the monitor, a gdb server or in lockstep with a reference
+  resume <snapshot>        Run on from a snapshot saved with --save-snapshot, the layout and the
+                           memory come from the snapshot, the other settings from the options
+  disasm <file>            Disassemble an ELF file or a raw binary
+  help                     Print this message
+
+Machine options:
+  --config <file>          Machine configuration file
+  --set <key=value>        Override a configuration key, e.g. cpu.harts=2
+  --memory-size <size>     DRAM size, e.g. 256M
+  --load-address <addr>    Where a raw binary or an assembly source goes, the start of DRAM by default
+  --entry <addr>           Initial pc instead of the ELF entry or the load address
+  --harts <n>              Number of harts
+  --quantum <n>            Instructions a hart runs before the next one takes over
+  --threads                Run each hart on its own host thread
+  --device <name>@<addr>   Move a device: uart, clint or finisher
+  --semihosting            Serve semihosting calls
+  --max-insns <n>          Stop after n instructions
+  --timeout <seconds>      Stop after this much host time
+  --dump-registers         Print the registers when the guest exits, they are always printed
+                           when the run stops otherwise
+  --save-snapshot <file>   Save the machine when the run stops, at the instruction limit or the
+                           timeout for instance, to resume it later
+
+Boot options (run):
+  --kernel                 Boot the program as a kernel (Image, ELF or raw binary)
+  --firmware <file>        M-mode firmware such as OpenSBI fw_jump or fw_dynamic
+  --initrd <file>          Initramfs
+  --append <bootargs>      Kernel command line
+
+Test options (test):
+  --signature <file>       Dump the memory between begin_signature and end_signature
+
+Trace options (trace, or --log-commits <file> with the other commands):
+  -o, --output <file>      Commit log file, stdout by default
+  --log-pc <start:end>     Only log the instructions in this pc range
+  --log-icount <start:end> Only log this range of instructions
 
-use crate::cpu::*;
+Debug options (debug, or with the other commands):
+  --monitor                Interactive monitor, the default of debug
+  --gdb <port|socket>      gdb remote protocol server
+  --lockstep <log>         Compare with a spike or Sail commit log, then run on
+
+Disasm options:
+  --base <addr>            Address of a raw binary, the start of DRAM by default
+
+Exit status:
+  run, trace, debug        The exit status of the guest: through HTIF, the test finisher, the
+                           exit syscall (ecall with a7 = 93 and no trap handler), semihosting or
+                           an SBI system reset. 0 when a bare program jumps to address 0
+                           without taking a trap, 1 when a lockstep run diverges
+  test                     0 when the test passes, 1 when it fails, 2 when it does not report
+  64                       Invalid command line or configuration
+  66                       Unreadable or invalid input file
+  124                      Instruction limit or timeout reached
+  125                      Fatal exception, or the debugger killed the guest
+";
+
+// Stop a test that never reports through HTIF after this many instructions
+const DEFAULT_TEST_MAX_INSNS: u64 = 100_000_000;
+
+// Exit codes of the test runner, see riscv-tests
+const EXIT_PASS: i32 = 0;
+const EXIT_FAIL: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+// Exit codes of the emulator itself, above the usual guest exit codes
+const EXIT_USAGE: i32 = 64;
+const EXIT_INPUT: i32 = 66;
+const EXIT_LIMIT: i32 = 124;
+const EXIT_FATAL: i32 = 125;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Command {
+    Run,
+    Test,
+    Trace,
+    Debug,
+    Resume,
+}
+
+// Front end driving the machine instead of running it to completion
+enum Debugger {
+    Gdb(String),
+    Monitor,
+    // Compare with the commit log of a reference simulator, then run on
+    Lockstep(String),
+}
+
+// Everything the command line says about a run
+struct Options {
+    command: Command,
+    program: String,
+    config: MachineConfig,
+    load_address: Option<u64>,
+    entry: Option<u64>,
+    max_insns: Option<u64>,
+    timeout: Option<Duration>,
+    signature: Option<String>,
+    dump_registers: bool,
+    // Where the machine is saved when the run stops
+    save_snapshot: Option<String>,
+    // Commit log file, `-` for stdout
+    log: Option<String>,
+    log_filter: Filter,
+    debugger: Option<Debugger>,
+}
+
+// How a run ended
+enum Stop {
+    Guest(Exit),
+    // The debugger killed the guest
+    Killed,
+    // The lockstep run found a difference with the reference
+    Diverged,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let code = match cli(&args) {
+        Ok(code) => code,
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            eprintln!("rv64_emu: {}\nTry `rv64_emu help` for more information.", e);
+            EXIT_USAGE
+        }
+        Err(e) => {
+            eprintln!("rv64_emu: {}", e);
+            EXIT_INPUT
+        }
+    };
+    process::exit(code);
+}
+
+fn cli(args: &[String]) -> io::Result<i32> {
+    let options = match args.first().map(String::as_str) {
+        None => return Err(usage("missing command")),
+        Some("help" | "--help" | "-h") => {
+            print!("{}", USAGE);
+            return Ok(EXIT_PASS);
+        }
+        Some("disasm") => return run_disasm(&args[1..]),
+        Some("run") => parse_options(Command::Run, &args[1..])?,
+        Some("test") => parse_options(Command::Test, &args[1..])?,
+        Some("trace") => parse_options(Command::Trace, &args[1..])?,
+        Some("debug") => parse_options(Command::Debug, &args[1..])?,
+        Some("resume") => parse_options(Command::Resume, &args[1..])?,
+        // The older forms: --test <elf>, --kernel <kernel> and a lone program
+        Some("--test") => parse_options(Command::Test, &args[1..])?,
+        Some("--kernel") => {
+            let mut options = parse_options(Command::Run, &args[1..])?;
+            options.config.boot = BootMode::Kernel;
+            options
+        }
+        Some(_) => parse_options(Command::Run, args)?,
+    };
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Usage: rvemu-for-book <filename>");
+    if options.command == Command::Resume {
+        return run_snapshot(options);
     }
+    let mode = if options.command == Command::Test { BootMode::Test } else { options.config.boot };
+    match mode {
+        BootMode::Bare => run_program(options),
+        BootMode::Test => run_test(options),
+        BootMode::Kernel => run_kernel(options),
+    }
+}
+
+fn usage(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
 
-    let mut file = File::open(&args[1])?;
+fn read_file(path: &str) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
     let mut code = Vec::new();
     file.read_to_end(&mut code)?;
+    Ok(code)
+}
 
-    let mut cpu = Cpu::new(code);
+fn invalid_value(option: &str, value: &str) -> io::Error {
+    usage(&format!("invalid value for {}: {}", option, value))
+}
 
-    loop {
-        // 1. Fetch.
-        let inst = match cpu.fetch() {
-            // Break the loop if an error occurs.
-            Ok(inst) => inst,
-            Err(_) => break,
-        };
+// Decimal or 0x-prefixed hexadecimal
+fn parse_number(option: &str, value: &str) -> io::Result<u64> {
+    let number = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    number.map_err(|_| invalid_value(option, value))
+}
+
+// `start:end`, end excluded
+fn parse_range(option: &str, value: &str) -> io::Result<Range<u64>> {
+    let (start, end) = value.split_once(':').ok_or_else(|| invalid_value(option, value))?;
+    Ok(parse_number(option, start)?..parse_number(option, end)?)
+}
+
+fn parse_harts(value: &str) -> io::Result<usize> {
+    match parse_number("--harts", value)? {
+        n @ 1..=MAX_HARTS => Ok(n as usize),
+        _ => Err(usage(&format!("invalid number of harts: {}", value))),
+    }
+}
 
-        // 2. Add 4 to the program counter.
-        cpu.pc += 4;
+fn config_error(e: ConfigError) -> io::Error {
+    usage(&e.to_string())
+}
+
+// Machine configuration from the `--config` file and the `--set` overrides among `args`, the
+// other options apply on top of it
+fn configure(args: &[String]) -> io::Result<MachineConfig> {
+    let value = |i: usize| args.get(i + 1).ok_or_else(|| usage(&format!("missing value for {}", args[i])));
+    let mut config = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => MachineConfig::load(value(i)?).map_err(config_error)?,
+        None => MachineConfig::default(),
+    };
+    for (i, _) in args.iter().enumerate().filter(|(_, arg)| *arg == "--set") {
+        config.set(value(i)?).map_err(config_error)?;
+    }
+    Ok(config)
+}
 
-        // 3. Decode.
-        // 4. Execute.
-        match cpu.execute(inst) {
-            // Break the loop if an error occurs.
-            Ok(_) => {}
-            Err(_) => break,
+fn parse_options(command: Command, args: &[String]) -> io::Result<Options> {
+    let mut options = Options {
+        command,
+        program: String::new(),
+        config: configure(args)?,
+        load_address: None,
+        entry: None,
+        max_insns: if command == Command::Test { Some(DEFAULT_TEST_MAX_INSNS) } else { None },
+        timeout: None,
+        signature: None,
+        dump_registers: false,
+        save_snapshot: None,
+        log: if command == Command::Trace { Some("-".to_string()) } else { None },
+        log_filter: Filter::default(),
+        debugger: if command == Command::Debug { Some(Debugger::Monitor) } else { None },
+    };
+    let config = &mut options.config;
+    let mut program = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        // Options without a value, and the program
+        match arg.as_str() {
+            "--threads" => config.threaded = true,
+            "--semihosting" => config.semihosting = true,
+            "--dump-registers" => options.dump_registers = true,
+            "--monitor" => options.debugger = Some(Debugger::Monitor),
+            "--kernel" => config.boot = BootMode::Kernel,
+            _ if !arg.starts_with('-') && program.is_none() => {
+                program = Some(arg.clone());
+                continue;
+            }
+            _ => {}
+        }
+        if matches!(arg.as_str(), "--threads" | "--semihosting" | "--dump-registers" | "--monitor" | "--kernel") {
+            continue;
         }
 
-        // This is a workaround for avoiding an infinite loop.
-        if cpu.pc == 0 {
-            break;
+        let Some(value) = iter.next() else {
+            return Err(usage(&format!("unknown option or missing value: {}", arg)));
+        };
+        match arg.as_str() {
+            // Already applied
+            "--config" | "--set" => {}
+            "--memory-size" => config.memory_size = parse_size(value).ok_or_else(|| invalid_value(arg, value))?,
+            "--load-address" => options.load_address = Some(parse_number(arg, value)?),
+            "--entry" => options.entry = Some(parse_number(arg, value)?),
+            "--harts" => config.harts = parse_harts(value)?,
+            "--quantum" => config.quantum = parse_number(arg, value)?.max(1),
+            "--device" => {
+                let (name, addr) = value.split_once('@').ok_or_else(|| invalid_value(arg, value))?;
+                let addr = parse_number(arg, addr)?;
+                match name {
+                    "uart" => config.uart_base = addr,
+                    "clint" => config.clint_base = addr,
+                    "finisher" => config.finisher_base = addr,
+                    _ => return Err(usage(&format!("unknown device: {}", name))),
+                }
+            }
+            "--max-insns" => options.max_insns = Some(parse_number(arg, value)?),
+            "--timeout" => {
+                let seconds = value.parse::<f64>().ok().filter(|s| s.is_finite() && *s > 0.0);
+                options.timeout = Some(Duration::from_secs_f64(seconds.ok_or_else(|| invalid_value(arg, value))?));
+            }
+            "--firmware" => config.firmware = Some(value.clone()),
+            "--initrd" => config.initrd = Some(value.clone()),
+            "--append" => config.bootargs = Some(value.clone()),
+            "--signature" => options.signature = Some(value.clone()),
+            "--save-snapshot" => options.save_snapshot = Some(value.clone()),
+            "-o" | "--output" | "--log-commits" => options.log = Some(value.clone()),
+            "--log-pc" => options.log_filter.pc = Some(parse_range(arg, value)?),
+            "--log-icount" => options.log_filter.icount = Some(parse_range(arg, value)?),
+            "--gdb" => options.debugger = Some(Debugger::Gdb(value.clone())),
+            "--lockstep" => options.debugger = Some(Debugger::Lockstep(value.clone())),
+            _ => return Err(usage(&format!("unknown option: {}", arg))),
         }
     }
 
-    cpu.dump_registers();
+    let missing = if command == Command::Resume { "missing snapshot" } else { "missing program" };
+    options.program = program.ok_or_else(|| usage(missing))?;
+    options.config.validate().map_err(|e| usage(&e))?;
+    Ok(options)
+}
+
+fn load_error(e: BuildError) -> io::Error {
+    match e {
+        BuildError::Config(message) | BuildError::Memory(message) => usage(&message),
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
 
+// Set the machine up as the options say once the program is loaded
+fn prepare(machine: &mut Machine, options: &mut Options) -> io::Result<()> {
+    if let Some(entry) = options.entry {
+        machine.harts.iter_mut().for_each(|hart| hart.pc = entry);
+    }
+    let filter = std::mem::take(&mut options.log_filter);
+    match options.log.as_deref() {
+        Some("-") => machine.log_commits(CommitLog::new(Box::new(io::stdout()), filter)),
+        Some(path) => machine.log_commits(CommitLog::create(path, filter)?),
+        None => {}
+    }
     Ok(())
 }
+
+// Explain why the machine stopped unexpectedly
+fn report(machine: &Machine, exit: Exit) {
+    match exit {
+        Exit::Fatal { hart, exception } => {
+            eprintln!("fatal exception {:?} on hart {} at pc = {:#x}", exception, hart, machine.harts[hart].pc);
+        }
+        Exit::Limit => eprintln!("stopped at the instruction limit"),
+        Exit::Paused => eprintln!("stopped at the timeout"),
+        exit => eprintln!("unexpected stop: {:?}", exit),
+    }
+}
+
+// Run the machine, or let a debugger drive it. Debuggers ignore the instruction limit and the
+// timeout.
+fn run_machine(machine: &mut Machine, options: &Options, symbols: HashMap<String, u64>) -> io::Result<Stop> {
+    // The timeout pauses the machine, a pause is reported as the timeout
+    let run = |machine: &mut Machine| {
+        if let Some(timeout) = options.timeout {
+            let control = machine.control();
+            thread::spawn(move || {
+                thread::sleep(timeout);
+                control.pause();
+            });
+        }
+        Some(machine.run(options.max_insns))
+    };
+    let exit = match &options.debugger {
+        None => run(machine),
+        Some(Debugger::Gdb(address)) => gdb::serve(machine, address)?,
+        Some(Debugger::Monitor) => monitor::run(machine, symbols)?,
+        Some(Debugger::Lockstep(reference)) => {
+            if !lockstep::run(machine, reference)? {
+                return Ok(Stop::Diverged);
+            }
+            run(machine)
+        }
+    };
+    if let Some(log) = &mut machine.commit_log {
+        log.flush()?;
+    }
+    if let (Some(path), Some(_)) = (&options.save_snapshot, exit) {
+        let file = File::create(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+        machine.save_snapshot(BufWriter::new(file))?;
+    }
+    match exit {
+        Some(exit) => Ok(Stop::Guest(exit)),
+        None => {
+            eprintln!("killed by the debugger");
+            Ok(Stop::Killed)
+        }
+    }
+}
+
+// Exit code of a guest that stopped by itself, None for the other stops. A reset is reported,
+// the emulator exits instead.
+fn guest_exit_code(exit: Exit) -> Option<i32> {
+    match exit {
+        Exit::Sbi(stop @ (SbiStop::Shutdown { reason: 1.. } | SbiStop::Reboot { .. })) => {
+            eprintln!("system reset requested: {:?}", stop);
+        }
+        Exit::Finisher(FinisherStop::Reset) => eprintln!("system reset requested through the test finisher"),
+        _ => {}
+    }
+    exit.status().map(|code| code.min(255) as i32)
+}
+
+// Exit code of a run or a kernel boot, the registers are dumped when the run stops other than by
+// the guest exiting
+fn exit_code(machine: &Machine, options: &Options, stop: Stop) -> i32 {
+    let exit = match stop {
+        Stop::Guest(exit) => exit,
+        Stop::Killed => return EXIT_FATAL,
+        Stop::Diverged => return EXIT_FAIL,
+    };
+    if let Some(code) = guest_exit_code(exit) {
+        if options.dump_registers {
+            machine.dump_registers();
+        }
+        return code;
+    }
+    report(machine, exit);
+    machine.dump_registers();
+    match exit {
+        Exit::Limit | Exit::Paused => EXIT_LIMIT,
+        _ => EXIT_FATAL,
+    }
+}
+
+// Run an ELF file at its physical addresses, or a raw binary or an assembly source (.s) at the
+// load address. Bare programs end by exiting, see `guest_exit_code`, or by returning to address 0.
+fn run_program(mut options: Options) -> io::Result<i32> {
+    let code = read_file(&options.program)?;
+    let base = options.load_address.unwrap_or(options.config.memory_base);
+    let mut builder = Machine::builder().config(options.config.clone());
+    if options.load_address.is_some() {
+        builder = builder.load_address(base);
+    }
+    let mut symbols = HashMap::new();
+    let builder = if options.program.ends_with(".s") {
+        let source = String::from_utf8_lossy(&code);
+        let code = asm::assemble(&source, base)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", options.program, e)))?;
+        builder.program(code)
+    } else if is_elf(&code) {
+        let elf = Elf::parse(&code)?;
+        symbols = elf.symbols.clone();
+        builder.elf(elf)
+    } else {
+        builder.program(code)
+    };
+    let mut machine = builder.build().map_err(load_error)?;
+    prepare(&mut machine, &mut options)?;
+
+    let stop = run_machine(&mut machine, &options, symbols)?;
+    // Returning to address 0 is a jump there, a trap into a handler at 0 is a crash
+    if let Stop::Guest(Exit::Fatal { hart, exception: Exception::InstructionAccessFault(0) }) = stop {
+        if machine.harts[hart].trap.is_none() {
+            if options.dump_registers {
+                machine.dump_registers();
+            }
+            return Ok(EXIT_PASS);
+        }
+    }
+    Ok(exit_code(&machine, &options, stop))
+}
+
+// Boot a kernel, through an M-mode firmware or with the native SBI, see `boot::boot`.
+// Without a firmware the emulator stops when the kernel shuts the machine down.
+fn run_kernel(mut options: Options) -> io::Result<i32> {
+    let config = &options.config;
+    let read_path = |path: &Option<String>| path.as_deref().map(read_file).transpose();
+    let boot_options = BootOptions {
+        kernel: read_file(&options.program)?,
+        firmware: read_path(&config.firmware)?,
+        initrd: read_path(&config.initrd)?,
+        bootargs: config.bootargs.clone(),
+    };
+    let mut machine = boot::boot(&boot_options, config)?;
+    prepare(&mut machine, &mut options)?;
+
+    let kernel = &boot_options.kernel;
+    let symbols = if is_elf(kernel) { Elf::parse(kernel)?.symbols } else { HashMap::new() };
+    let stop = run_machine(&mut machine, &options, symbols)?;
+    Ok(exit_code(&machine, &options, stop))
+}
+
+// Run on from a snapshot, see `Machine::restore_snapshot`
+fn run_snapshot(mut options: Options) -> io::Result<i32> {
+    let path = &options.program;
+    let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    let mut machine = Machine::restore_snapshot(BufReader::new(file), &options.config)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+    prepare(&mut machine, &mut options)?;
+
+    let stop = run_machine(&mut machine, &options, HashMap::new())?;
+    Ok(exit_code(&machine, &options, stop))
+}
+
+// Print the disassembly of the executable segments of an ELF file, or of a raw binary loaded at
+// the start of DRAM or at --base
+fn run_disasm(args: &[String]) -> io::Result<i32> {
+    let path = args.first().ok_or_else(|| usage("missing file"))?;
+    let code = read_file(path)?;
+    let base = match args.get(1..) {
+        Some([option, value]) if option == "--base" => parse_number(option, value)?,
+        Some([]) => DRAM_BASE,
+        _ => return Err(usage(&format!("unknown options: {:?}", &args[1..]))),
+    };
+
+    if !is_elf(&code) {
+        print!("{}", disasm::listing(&code, base, None));
+        return Ok(EXIT_PASS);
+    }
+    let elf = Elf::parse(&code)?;
+    let symbols = Symbols::new(&elf.symbols);
+    for segment in elf.segments.iter().filter(|segment| segment.executable) {
+        print!("{}", disasm::listing(&segment.data, segment.addr, Some(&symbols)));
+    }
+    Ok(EXIT_PASS)
+}
+
+// Test-runner mode for riscv-tests and riscv-arch-test: run the ELF until it reports through
+// HTIF, then dump the memory between `begin_signature` and `end_signature` one 32-bit word per
+// line, the format of the reference signatures.
+fn run_test(mut options: Options) -> io::Result<i32> {
+    let elf = Elf::parse(&read_file(&options.program)?)?;
+    if elf.symbol("tohost").is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "no `tohost` symbol in the test ELF"));
+    }
+
+    let mut machine = Machine::builder()
+        .config(options.config.clone())
+        .elf(elf.clone())
+        .build()
+        .map_err(load_error)?;
+    prepare(&mut machine, &mut options)?;
+
+    let exit_code = match run_machine(&mut machine, &options, elf.symbols.clone())? {
+        Stop::Guest(Exit::Htif(code)) => Some(code),
+        Stop::Guest(exit) => {
+            report(&machine, exit);
+            None
+        }
+        Stop::Killed => None,
+        Stop::Diverged => return Ok(EXIT_FAIL),
+    };
+
+    if let Some(path) = &options.signature {
+        let begin = elf.symbol("begin_signature");
+        let end = elf.symbol("end_signature");
+        let (begin, end) = begin.zip(end).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "no `begin_signature`/`end_signature` symbols")
+        })?;
+
+        let mut file = File::create(path)?;
+        for addr in (begin..end).step_by(4) {
+            let word = machine.bus.load(addr, 32).unwrap_or(0);
+            writeln!(file, "{:08x}", word)?;
+        }
+    }
+
+    if exit_code.is_some() && options.dump_registers {
+        machine.dump_registers();
+    }
+    // riscv-tests pass the number of the failed test case, only report it
+    Ok(match exit_code {
+        Some(0) => EXIT_PASS,
+        Some(code) => {
+            eprintln!("test {} failed", code);
+            EXIT_FAIL
+        }
+        None => {
+            machine.dump_registers();
+            EXIT_ERROR
+        }
+    })
+}
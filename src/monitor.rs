@@ -0,0 +1,401 @@
+// Interactive monitor: a command console on stdin that runs, stops and inspects the machine
+// without an external debugger. The console is entered at startup, on Ctrl-C, breakpoints,
+// watchpoints and, unless disabled, on every trap a hart takes.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::bus::*;
+use crate::csr::*;
+use crate::disasm::*;
+use crate::machine::*;
+use crate::register::ABI_NAMES;
+
+// Instructions shown by `dis` without a count, half of them before pc
+const DISASSEMBLE_COUNT: u64 = 8;
+
+// Bytes shown by `x` without a count
+const EXAMINE_COUNT: u64 = 64;
+
+const HELP: &str = "\
+step [n]                  run n instructions, 1 by default
+continue [addr]           run until a stop, or until the current hart reaches addr
+break [addr]              set a breakpoint, list breakpoints and watchpoints without addr
+delete <addr>             remove the breakpoint or the watchpoints at addr
+watch|rwatch|awatch <addr> [len]
+                          stop on writes, reads or both to len bytes at addr, 8 by default
+regs                      dump the registers of the current hart
+print <reg>               print a register: x0-x31, an ABI name or pc
+set <reg> <value>         write a register
+x <addr> [n]              examine n bytes of memory
+write <addr> <value> [n]  write a value of n bytes, 8 by default, to memory
+dis [addr] [n]            disassemble n instructions around pc, or from addr
+csr [name]                show the CSRs of the current hart, or one of them
+hart <n>                  select the hart the commands apply to
+trap on|off               stop on traps or not
+quit                      kill the guest and exit
+Addresses and values are numbers, 0x-prefixed in hex, or symbols.";
+
+// Set by the SIGINT handler, the run loop polls it between instructions
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+const SIGINT: i32 = 2;
+
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+extern "C" fn interrupt(_: i32) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+struct Monitor<'a> {
+    machine: &'a mut Machine,
+    symbols: HashMap<String, u64>,
+    sorted_symbols: Symbols,
+    breakpoints: Vec<u64>,
+    hart: usize,
+    stop_on_traps: bool,
+}
+
+// Run the console until the guest exits or the user quits. Returns why the guest exited, None
+// when the user killed it.
+pub fn run(machine: &mut Machine, symbols: HashMap<String, u64>) -> io::Result<Option<Exit>> {
+    // Ctrl-C stops the guest instead of the emulator
+    unsafe {
+        signal(SIGINT, interrupt);
+    }
+    machine.threaded = false;
+    let mut monitor = Monitor {
+        machine,
+        sorted_symbols: Symbols::new(&symbols),
+        symbols,
+        breakpoints: Vec::new(),
+        hart: 0,
+        stop_on_traps: true,
+    };
+    monitor.show_pc();
+
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("(rvemu) ");
+        io::stdout().flush()?;
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            continue;
+        };
+        match monitor.command(command, args) {
+            Ok(Some(exit)) => return Ok(Some(exit)),
+            Ok(None) => {}
+            Err(Quit) => return Ok(None),
+        }
+    }
+}
+
+// `quit` was entered
+struct Quit;
+
+impl Monitor<'_> {
+    // Run a command, Some(exit) when the guest exited
+    fn command(&mut self, command: &str, args: &[&str]) -> Result<Option<Exit>, Quit> {
+        let result = match command {
+            "s" | "step" => match args.first().map(|n| self.value(n)).unwrap_or(Ok(1)) {
+                Ok(count) => return Ok(self.resume(Some(count), None)),
+                Err(e) => Err(e),
+            },
+            "c" | "continue" => match args.first().map(|addr| self.value(addr)).transpose() {
+                Ok(until) => return Ok(self.resume(None, until)),
+                Err(e) => Err(e),
+            },
+            "b" | "break" => self.set_breakpoint(args),
+            "d" | "delete" => self.delete(args),
+            "watch" => self.watch(args, WatchKind::Write),
+            "rwatch" => self.watch(args, WatchKind::Read),
+            "awatch" => self.watch(args, WatchKind::Access),
+            "r" | "regs" => {
+                self.machine.harts[self.hart].dump_registers();
+                Ok(())
+            }
+            "p" | "print" => self.print(args),
+            "set" => self.set(args),
+            "x" => self.examine(args),
+            "w" | "write" => self.write(args),
+            "dis" => self.disassemble(args),
+            "csr" => self.csr(args),
+            "hart" => self.select(args),
+            "trap" => match args.first() {
+                Some(&"on") | Some(&"off") => {
+                    self.stop_on_traps = args[0] == "on";
+                    Ok(())
+                }
+                _ => Err("usage: trap on|off".to_string()),
+            },
+            "h" | "help" => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            "q" | "quit" => return Err(Quit),
+            _ => Err(format!("unknown command: {}, try help", command)),
+        };
+        if let Err(message) = result {
+            println!("{}", message);
+        }
+        Ok(None)
+    }
+
+    // A number, decimal or 0x-prefixed, or a symbol
+    fn value(&self, word: &str) -> Result<u64, String> {
+        if let Some(addr) = self.symbols.get(word) {
+            return Ok(*addr);
+        }
+        let number = match word.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => word.parse(),
+        };
+        number.map_err(|_| format!("not a number or a symbol: {}", word))
+    }
+
+    // Index of a register, 32 for pc
+    fn register(name: &str) -> Result<usize, String> {
+        if name == "pc" {
+            return Ok(32);
+        }
+        if let Some(i) = ABI_NAMES.iter().position(|abi| *abi == name) {
+            return Ok(i);
+        }
+        match name.strip_prefix('x').and_then(|i| i.parse().ok()) {
+            Some(i @ 0..32) => Ok(i),
+            // s0 is also called fp
+            _ if name == "fp" => Ok(8),
+            _ => Err(format!("unknown register: {}", name)),
+        }
+    }
+
+    fn arg<'w>(args: &[&'w str], i: usize, usage: &str) -> Result<&'w str, String> {
+        args.get(i).copied().ok_or_else(|| format!("usage: {}", usage))
+    }
+
+    // Run `count` instructions, or until the current hart reaches `until`, stopping early on
+    // breakpoints, watchpoints, traps and Ctrl-C. Some(exit) when the guest exited.
+    fn resume(&mut self, count: Option<u64>, until: Option<u64>) -> Option<Exit> {
+        let _ = self.machine.bus.take_watch_hit();
+        for hart in &mut self.machine.harts {
+            hart.trap = None;
+        }
+        INTERRUPTED.store(false, Ordering::Relaxed);
+
+        let mut executed = 0;
+        loop {
+            if Some(executed) == count {
+                break;
+            }
+            let hart = self.machine.next_hart();
+            // The instruction at the breakpoint the harts stopped on runs on resume
+            if let Some(id) = hart.filter(|_| executed > 0) {
+                let pc = self.machine.harts[id].pc;
+                if self.breakpoints.contains(&pc) {
+                    println!("breakpoint on hart {} at {:#x}", id, pc);
+                    self.hart = id;
+                    break;
+                }
+                if id == self.hart && until == Some(pc) {
+                    break;
+                }
+            }
+            if INTERRUPTED.swap(false, Ordering::Relaxed) {
+                println!("interrupted");
+                break;
+            }
+
+            match self.machine.run(Some(1)) {
+                Exit::Limit => {}
+                Exit::Fatal { hart, exception } => {
+                    println!("fatal exception {:?} on hart {}", exception, hart);
+                    self.hart = hart;
+                    break;
+                }
+                Exit::Paused => break,
+                exit => {
+                    println!("guest exited: {:?}", exit);
+                    return Some(exit);
+                }
+            }
+            executed += 1;
+
+            let Some(id) = hart else {
+                continue;
+            };
+            if let Some(watchpoint) = self.machine.bus.take_watch_hit() {
+                println!("watchpoint on hart {}: {:?} at {:#x}", id, watchpoint.kind, watchpoint.addr);
+                self.hart = id;
+                break;
+            }
+            if let Some(cause) = self.machine.harts[id].trap.take().filter(|_| self.stop_on_traps) {
+                let kind = if cause >> 63 == 1 { "interrupt" } else { "exception" };
+                println!("trap on hart {}: {} {}", id, kind, cause & !(1 << 63));
+                self.hart = id;
+                break;
+            }
+        }
+        self.show_pc();
+        None
+    }
+
+    // Current hart, pc and the instruction there
+    fn show_pc(&self) {
+        let pc = self.machine.harts[self.hart].pc;
+        println!("hart {} {}", self.hart, self.instruction(pc));
+    }
+
+    fn instruction(&self, addr: u64) -> String {
+        let mut inst = [0; 4];
+        match self.machine.bus.peek_bytes(addr, &mut inst) {
+            Ok(()) => {
+                let inst = u32::from_le_bytes(inst);
+                format!("{:#x}: {:08x}  {}", addr, inst, disassemble(inst, addr, Some(&self.sorted_symbols)))
+            }
+            Err(_) => format!("{:#x}: cannot fetch", addr),
+        }
+    }
+
+    fn set_breakpoint(&mut self, args: &[&str]) -> Result<(), String> {
+        let Some(addr) = args.first() else {
+            for addr in &self.breakpoints {
+                println!("breakpoint at {:#x}", addr);
+            }
+            for watchpoint in &self.machine.bus.watchpoints {
+                println!("{:?} watchpoint at {:#x}, {} bytes", watchpoint.kind, watchpoint.addr, watchpoint.len);
+            }
+            return Ok(());
+        };
+        let addr = self.value(addr)?;
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, args: &[&str]) -> Result<(), String> {
+        let addr = self.value(Self::arg(args, 0, "delete <addr>")?)?;
+        self.breakpoints.retain(|a| *a != addr);
+        self.machine.bus.watchpoints.retain(|w| w.addr != addr);
+        Ok(())
+    }
+
+    fn watch(&mut self, args: &[&str], kind: WatchKind) -> Result<(), String> {
+        let addr = self.value(Self::arg(args, 0, "watch <addr> [len]")?)?;
+        let len = args.get(1).map(|len| self.value(len)).unwrap_or(Ok(8))?;
+        self.machine.bus.watchpoints.push(Watchpoint { addr, len, kind });
+        Ok(())
+    }
+
+    fn print(&self, args: &[&str]) -> Result<(), String> {
+        let name = Self::arg(args, 0, "print <reg>")?;
+        let hart = &self.machine.harts[self.hart];
+        let value = match Self::register(name)? {
+            32 => hart.pc,
+            i => hart.regs[i],
+        };
+        println!("{} = {:#x} ({})", name, value, value as i64);
+        Ok(())
+    }
+
+    fn set(&mut self, args: &[&str]) -> Result<(), String> {
+        let usage = "set <reg> <value>";
+        let register = Self::register(Self::arg(args, 0, usage)?)?;
+        let value = self.value(Self::arg(args, 1, usage)?)?;
+        let hart = &mut self.machine.harts[self.hart];
+        match register {
+            0 => {}
+            32 => hart.pc = value,
+            i => hart.regs[i] = value,
+        }
+        Ok(())
+    }
+
+    // Hex dump, 16 bytes per line
+    fn examine(&self, args: &[&str]) -> Result<(), String> {
+        let addr = self.value(Self::arg(args, 0, "x <addr> [n]")?)?;
+        let count = args.get(1).map(|n| self.value(n)).unwrap_or(Ok(EXAMINE_COUNT))?;
+        for line in (0..count).step_by(16) {
+            let mut text = format!("{:#x}:", addr.wrapping_add(line));
+            for i in line..count.min(line + 16) {
+                let mut byte = [0];
+                match self.machine.bus.peek_bytes(addr.wrapping_add(i), &mut byte) {
+                    Ok(()) => text += &format!(" {:02x}", byte[0]),
+                    Err(_) => text += " ??",
+                }
+            }
+            println!("{}", text);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, args: &[&str]) -> Result<(), String> {
+        let usage = "write <addr> <value> [n]";
+        let addr = self.value(Self::arg(args, 0, usage)?)?;
+        let value = self.value(Self::arg(args, 1, usage)?)?;
+        let size = match args.get(2).map(|n| self.value(n)).unwrap_or(Ok(8))? {
+            size @ (1 | 2 | 4 | 8) => size * 8,
+            _ => return Err("the size is 1, 2, 4 or 8 bytes".to_string()),
+        };
+        let result = self.machine.bus.store(addr, size, value);
+        let _ = self.machine.bus.take_watch_hit();
+        result.map_err(|e| format!("cannot write: {:?}", e))
+    }
+
+    fn disassemble(&self, args: &[&str]) -> Result<(), String> {
+        let pc = self.machine.harts[self.hart].pc;
+        let start = match args.first() {
+            Some(addr) => self.value(addr)?,
+            None => pc.saturating_sub(DISASSEMBLE_COUNT / 2 * 4),
+        };
+        let count = args.get(1).map(|n| self.value(n)).unwrap_or(Ok(DISASSEMBLE_COUNT))?;
+        for i in 0..count {
+            let addr = start.wrapping_add(4 * i);
+            let marker = if addr == pc { "=>" } else { "  " };
+            println!("{} {}", marker, self.instruction(addr));
+        }
+        Ok(())
+    }
+
+    fn csr(&self, args: &[&str]) -> Result<(), String> {
+        let csr = &self.machine.harts[self.hart].csr;
+        let show = |name: &str, value: u64| println!("{:<10} = {:#x}", name, value);
+        match args.first() {
+            Some(&"time") => show("time", self.machine.bus.clint.mtime()),
+            Some(name) => {
+                let (addr, name) = CSR_NAMES
+                    .iter()
+                    .find(|(_, csr)| csr == name)
+                    .ok_or_else(|| format!("unknown CSR: {}", name))?;
+                show(name, csr.load(*addr));
+            }
+            None => {
+                for (addr, name) in CSR_NAMES {
+                    show(name, csr.load(addr));
+                }
+                show("time", self.machine.bus.clint.mtime());
+            }
+        }
+        Ok(())
+    }
+
+    fn select(&mut self, args: &[&str]) -> Result<(), String> {
+        let hart = self.value(Self::arg(args, 0, "hart <n>")?)? as usize;
+        if hart >= self.machine.harts.len() {
+            return Err(format!("there are {} harts", self.machine.harts.len()));
+        }
+        self.hart = hart;
+        self.show_pc();
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
+use std::fmt;
+
 #[derive(Copy, Clone, PartialEq, Debug)]
-// Registers in RISC-V
+/// Registers in RISC-V
 pub enum Register {
     X0,
     X1,
@@ -37,10 +39,27 @@ pub enum Register {
     ICount,
 }
 
+/// ABI names of x0-x31
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+// ABI name, as in disassembly
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::PC => write!(f, "pc"),
+            Register::ICount => write!(f, "icount"),
+            register => write!(f, "{}", ABI_NAMES[*register as usize]),
+        }
+    }
+}
+
 // Convert Register to usize
 impl From<Register> for usize {
     fn from(register: Register) -> Self {
-        return register as usize;
+        register as usize
     }
 }
 
@@ -0,0 +1,385 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::cpu::*;
+use crate::csr::*;
+use crate::interrupt::Interrupt;
+use crate::bus::Bus;
+use crate::dram::PAGE_SIZE;
+use crate::machine::Machine;
+
+// Native implementation of the RISC-V Supervisor Binary Interface v2.0, used instead of an
+// M-mode firmware. An ecall from S-mode puts the extension id in a7, the function id in a6 and
+// the arguments in a0-a5, the error code is returned in a0 and the value in a1.
+
+// Specification version 2.0: major in bits [30:24], minor in bits [23:0]
+const SPEC_VERSION: u64 = 2 << 24;
+
+// Implementation id, outside of the range registered by the specification
+const IMPL_ID: u64 = 0x5256_3634;
+const IMPL_VERSION: u64 = 1;
+
+// Extension ids
+const EXT_LEGACY_SET_TIMER: u64 = 0x00;
+const EXT_LEGACY_PUTCHAR: u64 = 0x01;
+const EXT_LEGACY_GETCHAR: u64 = 0x02;
+const EXT_LEGACY_SHUTDOWN: u64 = 0x08;
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4d45;
+const EXT_IPI: u64 = 0x73_5049;
+const EXT_RFENCE: u64 = 0x5246_4e43;
+const EXT_HSM: u64 = 0x48_534d;
+const EXT_SRST: u64 = 0x5352_5354;
+const EXT_DBCN: u64 = 0x4442_434e;
+
+// Error codes
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_FAILED: i64 = -1;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+// HSM hart states
+const HSM_STARTED: u64 = 0;
+const HSM_STOPPED: u64 = 1;
+const HSM_START_PENDING: u64 = 2;
+
+// SRST reset types
+const SRST_SHUTDOWN: u64 = 0;
+const SRST_COLD_REBOOT: u64 = 1;
+const SRST_WARM_REBOOT: u64 = 2;
+
+// Exceptions and interrupts handed straight to S-mode, like OpenSBI does: everything except
+// the ecalls from S/M-mode, and the supervisor software, timer and external interrupts
+const MEDELEG_VALUE: u64 = 0xb1ff;
+const MIDELEG_VALUE: u64 = (1 << 1) | (1 << 5) | (1 << 9);
+
+// Request to stop the machine through SRST or the legacy shutdown, or HSM hart_stop of the last
+// running hart
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SbiStop {
+    Shutdown { reason: u64 },
+    Reboot { reason: u64 },
+    HartStop,
+}
+
+// HSM state of a hart, a started hart runs, a stopped one waits for hart_start
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending { start_addr: u64, opaque: u64 },
+}
+
+// SBI state, shared by the harts when they run on their own threads
+pub struct Sbi {
+    // The first request to stop the machine
+    pub stop: OnceLock<SbiStop>,
+    harts: Mutex<Vec<HartState>>,
+    start: Condvar,
+}
+
+impl Sbi {
+    // Hart 0 boots, the others wait for hart_start
+    pub fn new(harts: usize) -> Self {
+        let mut states = vec![HartState::Stopped; harts];
+        states[0] = HartState::Started;
+        Self {
+            stop: OnceLock::new(),
+            harts: Mutex::new(states),
+            start: Condvar::new(),
+        }
+    }
+
+    // Enter the hart as requested by a pending hart_start: S-mode at start_addr with a0 = hartid,
+    // a1 = opaque and translation and interrupts disabled. Returns whether the hart runs.
+    pub fn hart_running(&self, cpu: &mut Cpu) -> bool {
+        let hartid = cpu.hartid();
+        let mut harts = self.harts.lock().unwrap();
+        match harts[hartid] {
+            HartState::Started => true,
+            HartState::Stopped => false,
+            HartState::StartPending { start_addr, opaque } => {
+                cpu.mode = Mode::Supervisor;
+                cpu.pc = start_addr;
+                cpu.regs[10] = hartid as u64;
+                cpu.regs[11] = opaque;
+                cpu.csr.store(SATP, 0);
+                cpu.csr.store(SSTATUS, cpu.csr.load(SSTATUS) & !MSTATUS_SIE);
+                harts[hartid] = HartState::Started;
+                true
+            }
+        }
+    }
+
+    // HSM state of every hart, for snapshots
+    pub(crate) fn hart_states(&self) -> Vec<HartState> {
+        self.harts.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set_hart_states(&self, states: Vec<HartState>) {
+        *self.harts.lock().unwrap() = states;
+    }
+
+    // Block a stopped hart until hart_start is called for it or the timeout expires
+    pub fn wait_for_start(&self, hart: usize, timeout: Duration) {
+        let harts = self.harts.lock().unwrap();
+        if harts[hart] == HartState::Stopped {
+            let _ = self.start.wait_timeout(harts, timeout).unwrap();
+        }
+    }
+}
+
+// Jump to an S-mode kernel like a firmware would: hart 0 starts with a0 = hartid and
+// a1 = device tree address, the other harts wait for HSM hart_start
+pub fn boot(machine: &mut Machine, entry: u64, dtb_addr: u64) {
+    machine.sbi = Some(Sbi::new(machine.harts.len()));
+    for hart in &mut machine.harts {
+        hart.sbi = true;
+        hart.csr.store(MEDELEG, MEDELEG_VALUE);
+        hart.csr.store(MIDELEG, MIDELEG_VALUE);
+        hart.csr.store(MCOUNTEREN, 0b111);
+        hart.mode = Mode::Supervisor;
+    }
+    let hart = &mut machine.harts[0];
+    hart.pc = entry;
+    hart.regs[10] = hart.csr.load(MHARTID);
+    hart.regs[11] = dtb_addr;
+}
+
+// Serve an ecall from S-mode
+pub fn handle_ecall(cpu: &mut Cpu, bus: &Bus, sbi: &Sbi) {
+    let eid = cpu.regs[17];
+    let fid = cpu.regs[16];
+    let args = [cpu.regs[10], cpu.regs[11], cpu.regs[12], cpu.regs[13], cpu.regs[14], cpu.regs[15]];
+
+    // Legacy extensions return a single value in a0 and leave a1 alone
+    let legacy = match eid {
+        EXT_LEGACY_SET_TIMER => Some(set_timer(cpu, bus, args[0])),
+        EXT_LEGACY_PUTCHAR => {
+            bus.uart.write_byte(args[0] as u8);
+            Some(0)
+        }
+        EXT_LEGACY_GETCHAR => Some(bus.uart.read_byte().map_or(-1, |c| c as i64)),
+        EXT_LEGACY_SHUTDOWN => {
+            stop(sbi, SbiStop::Shutdown { reason: 0 });
+            Some(0)
+        }
+        _ => None,
+    };
+    if let Some(value) = legacy {
+        cpu.regs[10] = value as u64;
+        return;
+    }
+
+    let (error, value) = match eid {
+        EXT_BASE => base(cpu, fid, args[0]),
+        EXT_TIME if fid == 0 => (set_timer(cpu, bus, args[0]), 0),
+        EXT_IPI if fid == 0 => send_ipi(bus, args[0], args[1]),
+        // Remote FENCE.I is a no-op, a store to code bumps the generation of its page (see
+        // `Bus::watch_code`) so the other harts decode it again; there is no TLB for SFENCE.VMA
+        EXT_RFENCE if fid <= 6 => (SBI_SUCCESS, 0),
+        EXT_HSM => hsm(cpu, sbi, fid, args),
+        EXT_SRST if fid == 0 => system_reset(sbi, args[0], args[1]),
+        EXT_DBCN => debug_console(bus, fid, args),
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    };
+    // hart_stop does not return, the registers of a stopped hart are set by hart_start
+    cpu.regs[10] = error as u64;
+    cpu.regs[11] = value;
+}
+
+fn stop(sbi: &Sbi, request: SbiStop) {
+    let _ = sbi.stop.set(request);
+}
+
+fn is_supported(eid: u64) -> bool {
+    matches!(
+        eid,
+        EXT_LEGACY_SET_TIMER | EXT_LEGACY_PUTCHAR | EXT_LEGACY_GETCHAR | EXT_LEGACY_SHUTDOWN
+            | EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE | EXT_HSM | EXT_SRST | EXT_DBCN
+    )
+}
+
+fn base(cpu: &Cpu, fid: u64, arg: u64) -> (i64, u64) {
+    match fid {
+        0 => (SBI_SUCCESS, SPEC_VERSION),
+        1 => (SBI_SUCCESS, IMPL_ID),
+        2 => (SBI_SUCCESS, IMPL_VERSION),
+        3 => (SBI_SUCCESS, is_supported(arg) as u64),
+        4 => (SBI_SUCCESS, cpu.csr.load(MVENDORID)),
+        5 => (SBI_SUCCESS, cpu.csr.load(MARCHID)),
+        6 => (SBI_SUCCESS, cpu.csr.load(MIMPID)),
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+// Program the next timer event of the calling hart, its pending timer interrupt is cleared
+// until it fires
+fn set_timer(cpu: &mut Cpu, bus: &Bus, stime_value: u64) -> i64 {
+    let hart = cpu.hartid();
+    bus.clint.mtimecmp[hart].store(stime_value, Ordering::Relaxed);
+    cpu.csr.set_pending(Interrupt::SupervisorTimer.mask(), bus.clint.timer_pending(hart));
+    SBI_SUCCESS
+}
+
+// A hart mask selects harts hart_mask_base + i for each set bit i, a base of -1 means all harts
+fn hart_selected(hartid: u64, hart_mask: u64, hart_mask_base: u64) -> bool {
+    if hart_mask_base == u64::MAX {
+        return true;
+    }
+    hartid >= hart_mask_base && hartid - hart_mask_base < 64 && (hart_mask >> (hartid - hart_mask_base)) & 1 == 1
+}
+
+// Raise the supervisor software interrupt on the selected harts, each hart moves it to its sip
+// before its next instruction
+fn send_ipi(bus: &Bus, hart_mask: u64, hart_mask_base: u64) -> (i64, u64) {
+    // Every hart in the mask must exist
+    let count = bus.clint.ssip.len() as u64;
+    if hart_mask_base != u64::MAX
+        && (hart_mask_base >= count || hart_mask.checked_shr((count - hart_mask_base) as u32).unwrap_or(0) != 0)
+    {
+        return (SBI_ERR_INVALID_PARAM, 0);
+    }
+    for (hartid, ssip) in bus.clint.ssip.iter().enumerate() {
+        if hart_selected(hartid as u64, hart_mask, hart_mask_base) {
+            ssip.store(true, Ordering::Release);
+        }
+    }
+    (SBI_SUCCESS, 0)
+}
+
+// Hart state management, see chapter 9 of the SBI specification. A started hart enters the
+// kernel through `Sbi::hart_running` on its own thread.
+fn hsm(cpu: &Cpu, sbi: &Sbi, fid: u64, args: [u64; 6]) -> (i64, u64) {
+    let hartid = args[0] as usize;
+    let mut harts = sbi.harts.lock().unwrap();
+    match fid {
+        // hart_start(hartid, start_addr, opaque)
+        0 => match harts.get(hartid) {
+            None => (SBI_ERR_INVALID_PARAM, 0),
+            Some(HartState::Stopped) => {
+                harts[hartid] = HartState::StartPending { start_addr: args[1], opaque: args[2] };
+                sbi.start.notify_all();
+                (SBI_SUCCESS, 0)
+            }
+            Some(_) => (SBI_ERR_ALREADY_AVAILABLE, 0),
+        },
+        // hart_stop does not return on success, the machine stops with its last hart
+        1 => {
+            harts[cpu.hartid()] = HartState::Stopped;
+            if harts.iter().all(|state| *state == HartState::Stopped) {
+                stop(sbi, SbiStop::HartStop);
+            }
+            (SBI_SUCCESS, 0)
+        }
+        2 => match harts.get(hartid) {
+            Some(HartState::Started) => (SBI_SUCCESS, HSM_STARTED),
+            Some(HartState::Stopped) => (SBI_SUCCESS, HSM_STOPPED),
+            Some(HartState::StartPending { .. }) => (SBI_SUCCESS, HSM_START_PENDING),
+            None => (SBI_ERR_INVALID_PARAM, 0),
+        },
+        // hart_suspend: a retentive suspend resumes at once, an interrupt is already due or will be
+        3 => (SBI_SUCCESS, 0),
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+fn system_reset(sbi: &Sbi, reset_type: u64, reason: u64) -> (i64, u64) {
+    match reset_type {
+        SRST_SHUTDOWN => stop(sbi, SbiStop::Shutdown { reason }),
+        SRST_COLD_REBOOT | SRST_WARM_REBOOT => stop(sbi, SbiStop::Reboot { reason }),
+        _ => return (SBI_ERR_INVALID_PARAM, 0),
+    }
+    (SBI_SUCCESS, 0)
+}
+
+// Debug console: write and read a buffer in physical memory, or write a single byte
+fn debug_console(bus: &Bus, fid: u64, args: [u64; 6]) -> (i64, u64) {
+    let (len, addr) = (args[0], args[1]);
+    match fid {
+        // A page at a time, the length comes from the guest
+        0 => {
+            if len > bus.dram_size() {
+                return (SBI_ERR_INVALID_PARAM, 0);
+            }
+            let mut buffer = [0; PAGE_SIZE as usize];
+            let mut written = 0;
+            while written < len {
+                let bytes = &mut buffer[..(len - written).min(PAGE_SIZE) as usize];
+                if bus.read_bytes(addr.wrapping_add(written), bytes).is_err() {
+                    return (SBI_ERR_FAILED, written);
+                }
+                bus.uart.write_bytes(bytes);
+                written += bytes.len() as u64;
+            }
+            (SBI_SUCCESS, len)
+        }
+        1 => {
+            let mut count = 0;
+            while count < len {
+                let byte = match bus.uart.read_byte() {
+                    Some(byte) => byte,
+                    None => break,
+                };
+                if bus.store(addr.wrapping_add(count), 8, byte as u64).is_err() {
+                    return (SBI_ERR_FAILED, 0);
+                }
+                count += 1;
+            }
+            (SBI_SUCCESS, count)
+        }
+        2 => {
+            bus.uart.write_byte(args[0] as u8);
+            (SBI_SUCCESS, 0)
+        }
+        _ => (SBI_ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+#[test]
+fn test_debug_console_write_bounds() {
+    use crate::asm::assemble;
+    use crate::bus::DRAM_BASE;
+    use crate::machine::Exit;
+
+    // Write lengths beyond memory, then from an address outside of it, logging a0 and a1
+    let source = "
+            li s0, 0x80001000
+            li a0, -1
+            li a1, 0x80000000
+            jal write
+            li a0, 0x10001
+            li a1, 0x80000000
+            jal write
+            li a0, 8
+            li a1, 0x1000
+            jal write
+            li a0, 0
+            li a1, 0x1000
+            jal write
+        1:  j 1b
+
+        write:
+            li a7, 0x4442434e
+            li a6, 0
+            ecall
+            sd a0, 0(s0)
+            sd a1, 8(s0)
+            addi s0, s0, 16
+            ret
+    ";
+    let code = assemble(source, DRAM_BASE).unwrap();
+    let mut machine = Machine::builder().memory(DRAM_BASE, 0x10000).program(code).build().unwrap();
+    boot(&mut machine, DRAM_BASE, 0);
+    assert_eq!(machine.run(Some(100)), Exit::Limit);
+    let log: Vec<(i64, u64)> = (0..4)
+        .map(|i| {
+            let entry = DRAM_BASE + 0x1000 + 16 * i;
+            (machine.bus.load(entry, 64).unwrap() as i64, machine.bus.load(entry + 8, 64).unwrap())
+        })
+        .collect();
+    let expected = [(SBI_ERR_INVALID_PARAM, 0), (SBI_ERR_INVALID_PARAM, 0), (SBI_ERR_FAILED, 0), (SBI_SUCCESS, 0)];
+    assert_eq!(log, expected);
+}
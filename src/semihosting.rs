@@ -0,0 +1,65 @@
+use std::io::Write;
+
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+
+// RISC-V semihosting, see the RISC-V Semihosting specification: an ebreak between
+// `slli x0, x0, 0x1f` and `srai x0, x0, 7` asks the host for a service. The operation is in a0,
+// its parameter in a1, the result goes to a0. The operations are those of Arm semihosting,
+// only the console output and the exit are served.
+const SLLI_X0_1F: u64 = 0x01f0_1013;
+const EBREAK: u64 = 0x0010_0073;
+const SRAI_X0_7: u64 = 0x4070_5013;
+
+// Operation numbers
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+// Reason of SYS_EXIT for a normal exit, the subcode is the exit status then
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x2_0026;
+
+// Longest string SYS_WRITE0 prints, in case the guest forgot the terminating NUL
+const MAX_STRING: u64 = 0x1_0000;
+
+/// Whether the ebreak at `addr` is a semihosting call
+pub fn is_call(bus: &Bus, addr: u64) -> bool {
+    let word = |addr: u64| bus.fetch(addr).ok();
+    addr.checked_sub(4).and_then(word) == Some(SLLI_X0_1F)
+        && word(addr) == Some(EBREAK)
+        && addr.checked_add(4).and_then(word) == Some(SRAI_X0_7)
+}
+
+/// Serve the semihosting call the hart just made, returns the exit status when the guest exits
+pub fn handle_call(cpu: &mut Cpu, bus: &Bus) -> Option<u64> {
+    let (operation, parameter) = (cpu.regs[10], cpu.regs[11]);
+    let byte = |addr: u64| bus.read(addr, 8).ok().map(|b| b as u8);
+    let mut out = std::io::stdout();
+
+    cpu.regs[10] = match operation {
+        SYS_WRITEC => {
+            if let Some(c) = byte(parameter) {
+                let _ = out.write_all(&[c]);
+            }
+            0
+        }
+        SYS_WRITE0 => {
+            // What could be read of a string running into a hole of the memory map
+            let bytes = bus.read_cstring(parameter, MAX_STRING).unwrap_or_else(|_| {
+                (0..MAX_STRING).map_while(|i| byte(parameter + i).filter(|&c| c != 0)).collect()
+            });
+            let _ = out.write_all(&bytes);
+            0
+        }
+        // The parameter block of RV64 holds the reason and the subcode
+        SYS_EXIT | SYS_EXIT_EXTENDED => {
+            let reason = bus.read(parameter, 64).unwrap_or(0);
+            let subcode = bus.read(parameter + 8, 64).unwrap_or(0);
+            let _ = out.flush();
+            return Some(if reason == ADP_STOPPED_APPLICATION_EXIT { subcode } else { 1 });
+        }
+        _ => u64::MAX,
+    };
+    None
+}
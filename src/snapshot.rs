@@ -0,0 +1,388 @@
+// Machine snapshots: everything a machine needs to run on, saved to a file and restored into a
+// new machine. All numbers are little-endian:
+//
+//     magic "RV64SNAP", version (u32)
+//     layout: memory base and size, harts, CLINT, UART and test finisher addresses (u64)
+//     HTIF: attached (u8), tohost, fromhost attached (u8), fromhost
+//     native SBI: present (u8), then the HSM state of each hart (u8) with its start address and
+//         opaque value (u64)
+//     scheduler: current hart, instructions in its slice (u64)
+//     each hart: pc (u64), mode, SBI, semihosting (u8), x0-x31 (u64), the number of CSRs other
+//         than 0 (u32), each as its address (u16) and value (u64)
+//     CLINT: mtime (u64), then msip, mtimecmp (u64) and the pending SSIP (u8) of each hart
+//     UART: the 8 registers (u8)
+//     DRAM: the pages holding anything but zeros, each as its index (u64), the length of its
+//         PackBits data (u32) and the data, up to the index u64::MAX
+//
+// What the host sets up rather than the guest is not kept: the settings outside of the layout
+// come from the configuration of the new machine, hooks, commit logs, watchpoints and devices
+// attached by embedders are set up again. LR reservations are dropped, an SC right after a
+// restore fails like a spurious failure and the guest retries. Caches of decoded and translated
+// instructions are filled again.
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::Ordering;
+
+use crate::clint::MAX_HARTS;
+use crate::config::MachineConfig;
+use crate::cpu::Mode;
+use crate::dram::PAGE_SIZE;
+use crate::machine::Machine;
+use crate::sbi::{HartState, Sbi};
+
+const MAGIC: &[u8; 8] = b"RV64SNAP";
+
+/// Version of the snapshot format, snapshots of another version are refused
+pub const VERSION: u32 = 1;
+
+// Index ending the DRAM pages
+const END_OF_PAGES: u64 = u64::MAX;
+
+// Longest PackBits data of a page: a header for every 128 bytes copied as they are
+const MAX_PACKED_PAGE: u64 = PAGE_SIZE + PAGE_SIZE / 128;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct Output<W: Write>(W);
+
+impl<W: Write> Output<W> {
+    fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.0.write_all(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> io::Result<()> {
+        self.0.write_all(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.0.write_all(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.0.write_all(&value.to_le_bytes())
+    }
+}
+
+struct Input<R: Read>(R);
+
+impl<R: Read> Input<R> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.0.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(invalid(format!("invalid flag in the snapshot: {}", value))),
+        }
+    }
+}
+
+// PackBits: a header n below 128 is followed by n + 1 bytes copied as they are, a header above
+// 128 by one byte repeated 257 - n times
+fn pack(data: &[u8], out: &mut Vec<u8>) {
+    let run_at = |i: usize| data[i..].iter().take(128).take_while(|&&byte| byte == data[i]).count();
+    let mut i = 0;
+    while i < data.len() {
+        let run = run_at(i);
+        if run >= 3 {
+            out.extend_from_slice(&[(257 - run) as u8, data[i]]);
+            i += run;
+            continue;
+        }
+        // Bytes as they are up to the next run worth a header
+        let start = i;
+        while i < data.len() && i - start < 128 && (i == start || run_at(i) < 3) {
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&data[start..i]);
+    }
+}
+
+// Unpack `data` into `out`, which cannot grow beyond `limit` bytes
+fn unpack(data: &[u8], out: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+    let truncated = || invalid("truncated page in the snapshot".to_string());
+    let mut i = 0;
+    while i < data.len() {
+        let header = data[i] as usize;
+        i += 1;
+        let len = match header {
+            0..=127 => header + 1,
+            128 => 0,
+            _ => 257 - header,
+        };
+        if out.len() + len > limit {
+            return Err(invalid("page too large in the snapshot".to_string()));
+        }
+        match header {
+            0..=127 => {
+                out.extend_from_slice(data.get(i..i + len).ok_or_else(truncated)?);
+                i += len;
+            }
+            128 => {}
+            _ => {
+                let byte = *data.get(i).ok_or_else(truncated)?;
+                out.resize(out.len() + len, byte);
+                i += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Machine {
+    /// Write everything the machine needs to run on to `out`, see `restore_snapshot`. The
+    /// machine must not be running.
+    pub fn save_snapshot(&self, out: impl Write) -> io::Result<()> {
+        let mut out = Output(out);
+        let bus = &self.bus;
+        out.0.write_all(MAGIC)?;
+        out.u32(VERSION)?;
+
+        let clint = &bus.clint;
+        for value in [bus.dram_base(), bus.dram_size(), self.harts.len() as u64, clint.base, bus.uart.base, bus.finisher.base] {
+            out.u64(value)?;
+        }
+        let htif = bus.htif_addresses();
+        out.u8(htif.is_some() as u8)?;
+        let (tohost, fromhost) = htif.unwrap_or((0, None));
+        out.u64(tohost)?;
+        out.u8(fromhost.is_some() as u8)?;
+        out.u64(fromhost.unwrap_or(0))?;
+
+        out.u8(self.sbi.is_some() as u8)?;
+        for state in self.sbi.iter().flat_map(Sbi::hart_states) {
+            let (tag, start_addr, opaque) = match state {
+                HartState::Started => (0, 0, 0),
+                HartState::Stopped => (1, 0, 0),
+                HartState::StartPending { start_addr, opaque } => (2, start_addr, opaque),
+            };
+            out.u8(tag)?;
+            out.u64(start_addr)?;
+            out.u64(opaque)?;
+        }
+        out.u64(self.current as u64)?;
+        out.u64(self.slice)?;
+
+        for hart in &self.harts {
+            out.u64(hart.pc)?;
+            out.u8(hart.mode as u8)?;
+            out.u8(hart.sbi as u8)?;
+            out.u8(hart.semihosting as u8)?;
+            for reg in hart.regs {
+                out.u64(reg)?;
+            }
+            let csrs = hart.csr.raw();
+            out.u32(csrs.iter().filter(|&&value| value != 0).count() as u32)?;
+            for (addr, &value) in csrs.iter().enumerate().filter(|(_, &value)| value != 0) {
+                out.u16(addr as u16)?;
+                out.u64(value)?;
+            }
+        }
+
+        out.u64(clint.mtime())?;
+        for hart in 0..self.harts.len() {
+            out.u64(clint.msip[hart].load(Ordering::Relaxed))?;
+            out.u64(clint.mtimecmp[hart].load(Ordering::Relaxed))?;
+            out.u8(clint.ssip[hart].load(Ordering::Relaxed) as u8)?;
+        }
+        out.0.write_all(&bus.uart.registers())?;
+
+        // Pages never written are zeros and are left out
+        let dram = &bus.dram;
+        let mut page = vec![0; PAGE_SIZE as usize];
+        let mut packed = Vec::new();
+        for index in 0..dram.size().div_ceil(PAGE_SIZE) {
+            let offset = index * PAGE_SIZE;
+            let page = &mut page[..PAGE_SIZE.min(dram.size() - offset) as usize];
+            dram.read_bytes(dram.base() + offset, page).unwrap();
+            if page.iter().all(|&byte| byte == 0) {
+                continue;
+            }
+            packed.clear();
+            pack(page, &mut packed);
+            out.u64(index)?;
+            out.u32(packed.len() as u32)?;
+            out.0.write_all(&packed)?;
+        }
+        out.u64(END_OF_PAGES)?;
+        out.0.flush()
+    }
+
+    /// Machine saved by `save_snapshot`. Its layout, memory and devices come from the snapshot,
+    /// the other settings from `config`, like the quantum, the threads and the misaligned
+    /// access policy. Hooks, commit logs and the devices of `MachineBuilder::device` are set up
+    /// again on the new machine.
+    pub fn restore_snapshot(input: impl Read, config: &MachineConfig) -> io::Result<Machine> {
+        Self::read_snapshot(&mut Input(input), config).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("truncated snapshot".to_string()),
+            _ => e,
+        })
+    }
+
+    fn read_snapshot(input: &mut Input<impl Read>, config: &MachineConfig) -> io::Result<Machine> {
+        if &input.bytes::<8>()? != MAGIC {
+            return Err(invalid("not a snapshot".to_string()));
+        }
+        let version = input.u32()?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported snapshot version {}, this emulator reads version {}", version, VERSION)));
+        }
+
+        let mut config = config.clone();
+        config.memory_base = input.u64()?;
+        config.memory_size = input.u64()?;
+        let harts = input.u64()?;
+        if !(1..=MAX_HARTS).contains(&harts) {
+            return Err(invalid(format!("invalid number of harts in the snapshot: {}", harts)));
+        }
+        config.harts = harts as usize;
+        config.clint_base = input.u64()?;
+        config.uart_base = input.u64()?;
+        config.finisher_base = input.u64()?;
+        config.validate().map_err(invalid)?;
+        let mut machine = Machine::with_config(&config, Vec::new()).map_err(|e| invalid(e.to_string()))?;
+
+        let htif = input.bool()?;
+        let tohost = input.u64()?;
+        let fromhost = input.bool()?;
+        let fromhost = Some(input.u64()?).filter(|_| fromhost);
+        if htif {
+            machine.bus.attach_htif(tohost, fromhost);
+        }
+
+        if input.bool()? {
+            let mut states = Vec::new();
+            for _ in 0..harts {
+                let (tag, start_addr, opaque) = (input.u8()?, input.u64()?, input.u64()?);
+                states.push(match tag {
+                    0 => HartState::Started,
+                    1 => HartState::Stopped,
+                    2 => HartState::StartPending { start_addr, opaque },
+                    _ => return Err(invalid(format!("invalid hart state in the snapshot: {}", tag))),
+                });
+            }
+            let sbi = Sbi::new(config.harts);
+            sbi.set_hart_states(states);
+            machine.sbi = Some(sbi);
+        }
+        let current = input.u64()?;
+        if current >= harts {
+            return Err(invalid(format!("invalid current hart in the snapshot: {}", current)));
+        }
+        machine.current = current as usize;
+        machine.slice = input.u64()?;
+
+        for hart in &mut machine.harts {
+            hart.pc = input.u64()?;
+            hart.mode = match input.u8()? {
+                0b00 => Mode::User,
+                0b01 => Mode::Supervisor,
+                0b11 => Mode::Machine,
+                mode => return Err(invalid(format!("invalid privilege mode in the snapshot: {}", mode))),
+            };
+            hart.sbi = input.bool()?;
+            hart.semihosting = input.bool()?;
+            for reg in &mut hart.regs {
+                *reg = input.u64()?;
+            }
+            let csrs = hart.csr.raw_mut();
+            csrs.fill(0);
+            for _ in 0..input.u32()? {
+                let addr = input.u16()? as usize;
+                let value = input.u64()?;
+                *csrs.get_mut(addr).ok_or_else(|| invalid(format!("invalid CSR in the snapshot: {:#x}", addr)))? = value;
+            }
+        }
+
+        let clint = &machine.bus.clint;
+        clint.mtime.store(input.u64()?, Ordering::Relaxed);
+        for hart in 0..config.harts {
+            clint.msip[hart].store(input.u64()?, Ordering::Relaxed);
+            clint.mtimecmp[hart].store(input.u64()?, Ordering::Relaxed);
+            clint.ssip[hart].store(input.bool()?, Ordering::Relaxed);
+        }
+        machine.bus.uart.set_registers(input.bytes()?);
+
+        let dram = &machine.bus.dram;
+        let pages = dram.size().div_ceil(PAGE_SIZE);
+        let (mut packed, mut page) = (Vec::new(), Vec::new());
+        loop {
+            let index = input.u64()?;
+            if index == END_OF_PAGES {
+                break;
+            }
+            if index >= pages {
+                return Err(invalid(format!("page {} beyond the memory in the snapshot", index)));
+            }
+            let len = input.u32()? as u64;
+            if len > MAX_PACKED_PAGE {
+                return Err(invalid(format!("page {} too large in the snapshot", index)));
+            }
+            packed.resize(len as usize, 0);
+            input.0.read_exact(&mut packed)?;
+            let offset = index * PAGE_SIZE;
+            let size = PAGE_SIZE.min(dram.size() - offset);
+            page.clear();
+            unpack(&packed, &mut page, size as usize)?;
+            if page.len() as u64 != size {
+                return Err(invalid(format!("page {} of the wrong size in the snapshot", index)));
+            }
+            dram.write_bytes(dram.base() + offset, &page).unwrap();
+        }
+        Ok(machine)
+    }
+}
+
+#[test]
+fn test_pack() {
+    let mut data = vec![0; 300];
+    data.extend_from_slice(b"abcdd");
+    data.extend((0..=255).cycle().take(700));
+    data.extend_from_slice(&[7, 7, 7, 1, 7, 7]);
+    for len in [0, 1, 2, 3, 130, 303, data.len()] {
+        let mut packed = Vec::new();
+        pack(&data[..len], &mut packed);
+        let mut unpacked = Vec::new();
+        unpack(&packed, &mut unpacked, len).unwrap();
+        assert_eq!(unpacked, &data[..len], "{} bytes", len);
+    }
+
+    // Runs take two bytes per 128
+    let mut packed = Vec::new();
+    pack(&[0; PAGE_SIZE as usize], &mut packed);
+    assert_eq!(packed.len(), 2 * PAGE_SIZE.div_ceil(128) as usize);
+    assert!(unpack(&[5, 1, 2], &mut Vec::new(), 100).is_err());
+    // Bytes that are all different take the most room
+    let data: Vec<u8> = (0..PAGE_SIZE).map(|i| (i % 2 * 255 + i % 7) as u8).collect();
+    packed.clear();
+    pack(&data, &mut packed);
+    assert_eq!(packed.len() as u64, MAX_PACKED_PAGE);
+
+    // The output stops at the limit, whatever the data says
+    let mut unpacked = Vec::new();
+    assert!(unpack(&[0x81, 1].repeat(1000), &mut unpacked, PAGE_SIZE as usize).is_err());
+    assert!(unpacked.len() as u64 <= PAGE_SIZE);
+}
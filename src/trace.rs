@@ -0,0 +1,208 @@
+// Commit log in the format of `spike -l --log-commits`: for each step, the disassembly line, then
+// either the trap it took or the registers, CSRs and memory it wrote. The reference simulator's
+// log can be diffed against it line by line.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+
+use crate::bus::Bus;
+use crate::cpu::{Cpu, Retired};
+use crate::csr::*;
+use crate::disasm::disassemble;
+use crate::exception::Exception;
+use crate::instruction::decode;
+use crate::instruction::Instruction::*;
+
+/// Steps to log, by pc and by their index among all the steps of the machine
+#[derive(Default)]
+pub struct Filter {
+    pub pc: Option<Range<u64>>,
+    pub icount: Option<Range<u64>>,
+}
+
+pub struct CommitLog {
+    out: BufWriter<Box<dyn Write + Send>>,
+    filter: Filter,
+    count: u64,
+    // First write error, reported by `flush`
+    error: Option<io::Error>,
+}
+
+// Trap names as spike prints them, and whether it prints tval
+fn trap_name(exception: &Exception) -> (&'static str, bool) {
+    match exception {
+        Exception::InstructionAddressMisaligned(_) => ("trap_instruction_address_misaligned", true),
+        Exception::InstructionAccessFault(_) => ("trap_instruction_access_fault", true),
+        Exception::IllegalInstruction(_) => ("trap_illegal_instruction", true),
+        Exception::Breakpoint(_) => ("trap_breakpoint", true),
+        Exception::LoadAddressMisaligned(_) => ("trap_load_address_misaligned", true),
+        Exception::LoadAccessFault(_) => ("trap_load_access_fault", true),
+        Exception::StoreAMOAddressMisaligned(_) => ("trap_store_address_misaligned", true),
+        Exception::StoreAMOAccessFault(_) => ("trap_store_access_fault", true),
+        Exception::EnvironmentCallFromUMode => ("trap_user_ecall", false),
+        Exception::EnvironmentCallFromSMode => ("trap_supervisor_ecall", false),
+        Exception::EnvironmentCallFromMMode => ("trap_machine_ecall", false),
+    }
+}
+
+fn csr_name(csr: u16) -> String {
+    match CSR_NAMES.iter().find(|(addr, _)| *addr == csr) {
+        Some((_, name)) => name.to_string(),
+        None => format!("{:#x}", csr),
+    }
+}
+
+// Writes of a retired instruction: rd, a CSR, the address and size of a load, and the address,
+// size and value of a store
+#[derive(Default)]
+pub(crate) struct Writes {
+    pub rd: Option<usize>,
+    pub csr: Option<u16>,
+    pub load: Option<(u64, u64)>,
+    pub store: Option<(u64, u64, u64)>,
+}
+
+// What the instruction `inst` did, from the registers before and the hart after it retired
+pub(crate) fn writes(inst: u32, before: &[u64; 32], cpu: &Cpu, bus: &Bus) -> Writes {
+    let reg = |r| before[usize::from(r)];
+    let address = |rs1, imm: i32| reg(rs1).wrapping_add(imm as i64 as u64);
+    let mask = |value: u64, size: u64| if size == 64 { value } else { value & ((1 << size) - 1) };
+
+    let (rd, load_size, store_size) = match decode(inst) {
+        Lb { rd, rs1, imm } | Lbu { rd, rs1, imm } => (rd, Some((address(rs1, imm), 8)), None),
+        Lh { rd, rs1, imm } | Lhu { rd, rs1, imm } => (rd, Some((address(rs1, imm), 16)), None),
+        Lw { rd, rs1, imm } | Lwu { rd, rs1, imm } => (rd, Some((address(rs1, imm), 32)), None),
+        Ld { rd, rs1, imm } => (rd, Some((address(rs1, imm), 64)), None),
+        Sb { rs1, rs2, imm } => return store(address(rs1, imm), 8, mask(reg(rs2), 8)),
+        Sh { rs1, rs2, imm } => return store(address(rs1, imm), 16, mask(reg(rs2), 16)),
+        Sw { rs1, rs2, imm } => return store(address(rs1, imm), 32, mask(reg(rs2), 32)),
+        Sd { rs1, rs2, imm } => return store(address(rs1, imm), 64, reg(rs2)),
+        Addi { rd, .. } | Slti { rd, .. } | Sltiu { rd, .. } | Xori { rd, .. } | Ori { rd, .. }
+        | Andi { rd, .. } | Addiw { rd, .. } | Slli { rd, .. } | Srli { rd, .. } | Srai { rd, .. }
+        | Slliw { rd, .. } | Srliw { rd, .. } | Sraiw { rd, .. } | Add { rd, .. } | Sub { rd, .. }
+        | Sll { rd, .. } | Slt { rd, .. } | Sltu { rd, .. } | Xor { rd, .. } | Srl { rd, .. }
+        | Sra { rd, .. } | Or { rd, .. } | And { rd, .. } | Addw { rd, .. } | Subw { rd, .. }
+        | Sllw { rd, .. } | Srlw { rd, .. } | Sraw { rd, .. } | Lui { rd, .. } | Auipc { rd, .. }
+        | Jal { rd, .. } | Jalr { rd, .. } => (rd, None, None),
+        Csrrw { rd, csr, .. } | Csrrwi { rd, csr, .. } => {
+            return Writes { rd: Some(usize::from(rd)), csr: Some(csr), ..Default::default() };
+        }
+        Csrrs { rd, rs1, csr } | Csrrc { rd, rs1, csr } => {
+            let csr = (usize::from(rs1) != 0).then_some(csr);
+            return Writes { rd: Some(usize::from(rd)), csr, ..Default::default() };
+        }
+        Csrrsi { rd, uimm, csr } | Csrrci { rd, uimm, csr } => {
+            let csr = (uimm != 0).then_some(csr);
+            return Writes { rd: Some(usize::from(rd)), csr, ..Default::default() };
+        }
+        Sret | Mret => return Writes { csr: Some(MSTATUS), ..Default::default() },
+        LrW { rd, rs1 } => (rd, Some((reg(rs1), 32)), None),
+        LrD { rd, rs1 } => (rd, Some((reg(rs1), 64)), None),
+        ScW { rd, rs1, rs2 } => return store_conditional(usize::from(rd), reg(rs1), 32, mask(reg(rs2), 32), cpu),
+        ScD { rd, rs1, rs2 } => return store_conditional(usize::from(rd), reg(rs1), 64, reg(rs2), cpu),
+        AmoswapW { rd, rs1, .. } | AmoaddW { rd, rs1, .. } | AmoxorW { rd, rs1, .. }
+        | AmoandW { rd, rs1, .. } | AmoorW { rd, rs1, .. } | AmominW { rd, rs1, .. }
+        | AmomaxW { rd, rs1, .. } | AmominuW { rd, rs1, .. } | AmomaxuW { rd, rs1, .. } => {
+            (rd, Some((reg(rs1), 32)), Some(32))
+        }
+        AmoswapD { rd, rs1, .. } | AmoaddD { rd, rs1, .. } | AmoxorD { rd, rs1, .. }
+        | AmoandD { rd, rs1, .. } | AmoorD { rd, rs1, .. } | AmominD { rd, rs1, .. }
+        | AmomaxD { rd, rs1, .. } | AmominuD { rd, rs1, .. } | AmomaxuD { rd, rs1, .. } => {
+            (rd, Some((reg(rs1), 64)), Some(64))
+        }
+        Beq { .. } | Bne { .. } | Blt { .. } | Bge { .. } | Bltu { .. } | Bgeu { .. } | Fence { .. }
+        | FenceI | Ecall | Ebreak | Wfi | SfenceVma { .. } | Undefined => return Writes::default(),
+    };
+
+    // AMOs store what they computed, read it back
+    let store = store_size.zip(load_size).map(|(size, (addr, _))| (addr, size, bus.read(addr, size).unwrap_or(0)));
+    Writes { rd: Some(usize::from(rd)), load: load_size, store, ..Default::default() }
+}
+
+fn store(addr: u64, size: u64, value: u64) -> Writes {
+    Writes { store: Some((addr, size, value)), ..Default::default() }
+}
+
+// rd is 0 when the store happened
+fn store_conditional(rd: usize, addr: u64, size: u64, value: u64, cpu: &Cpu) -> Writes {
+    let store = (cpu.regs[rd] == 0).then_some((addr, size, value));
+    Writes { rd: Some(rd), store, ..Default::default() }
+}
+
+impl CommitLog {
+    pub fn create(path: &str, filter: Filter) -> io::Result<Self> {
+        Ok(Self::new(Box::new(File::create(path)?), filter))
+    }
+
+    /// Log to `out`, stdout for instance
+    pub fn new(out: Box<dyn Write + Send>, filter: Filter) -> Self {
+        Self { out: BufWriter::new(out), filter, count: 0, error: None }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.out.flush(),
+        }
+    }
+
+    /// Log the last step of `cpu`, which must have recording enabled
+    pub fn record(&mut self, cpu: &Cpu, bus: &Bus) {
+        let retired = cpu.retired.as_ref().expect("the hart does not record its steps");
+        let index = self.count;
+        self.count += 1;
+        if self.filter.pc.as_ref().is_some_and(|range| !range.contains(&retired.pc))
+            || self.filter.icount.as_ref().is_some_and(|range| !range.contains(&index))
+        {
+            return;
+        }
+        if let Err(error) = self.write(cpu, bus, retired) {
+            self.error.get_or_insert(error);
+        }
+    }
+
+    fn write(&mut self, cpu: &Cpu, bus: &Bus, retired: &Retired) -> io::Result<()> {
+        let core = format!("core {:>3}:", cpu.hartid());
+        if let Some((cause, epc)) = retired.interrupt {
+            writeln!(self.out, "{} exception interrupt #{}, epc {:#018x}", core, cause & !(1 << 63), epc)?;
+        }
+
+        let Some(inst) = retired.inst else {
+            // Nothing was fetched, only the trap is logged
+            return self.trap(&core, retired);
+        };
+        writeln!(self.out, "{} {:#018x} ({:#010x}) {}", core, retired.pc, inst, disassemble(inst, retired.pc, None))?;
+        if retired.exception.is_some() {
+            return self.trap(&core, retired);
+        }
+
+        write!(self.out, "{} {} {:#018x} ({:#010x})", core, retired.mode as u8, retired.pc, inst)?;
+        let writes = writes(inst, &retired.regs, cpu, bus);
+        if let Some(rd) = writes.rd.filter(|rd| *rd != 0) {
+            write!(self.out, " x{:<2} {:#018x}", rd, cpu.regs[rd])?;
+        }
+        if let Some(csr) = writes.csr {
+            write!(self.out, " c{}_{} {:#018x}", csr, csr_name(csr), cpu.csr.load(csr))?;
+        }
+        if let Some((addr, _)) = writes.load {
+            write!(self.out, " mem {:#018x}", addr)?;
+        }
+        if let Some((addr, size, value)) = writes.store {
+            write!(self.out, " mem {:#018x} 0x{:0width$x}", addr, value, width = size as usize / 4)?;
+        }
+        writeln!(self.out)
+    }
+
+    fn trap(&mut self, core: &str, retired: &Retired) -> io::Result<()> {
+        let Some(exception) = retired.exception else {
+            return Ok(());
+        };
+        let (name, tval) = trap_name(&exception);
+        writeln!(self.out, "{} exception {}, epc {:#018x}", core, name, retired.pc)?;
+        if tval {
+            writeln!(self.out, "{}           tval {:#018x}", core, exception.value())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use crate::bus::Device;
+use crate::exception::Exception;
+
+// 16550 compatible UART, same default address as QEMU virt
+pub const UART_BASE: u64 = 0x1000_0000;
+pub const UART_SIZE: u64 = 0x100;
+
+// Register offsets, see the TI PC16550D datasheet
+const RBR_THR: u64 = 0;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const LSR: u64 = 5;
+
+// Divisor latch access bit in LCR, offsets 0 and 1 are the baud rate divisor while it is set
+const LCR_DLAB: u8 = 1 << 7;
+
+// Interrupt identification: no interrupt pending, FIFOs enabled
+const IIR_NO_INTERRUPT: u8 = 0xc1;
+
+// Line status bits: data ready, transmitter holding register empty, transmitter empty
+const LSR_DR: u8 = 1 << 0;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+// Uart
+pub struct Uart {
+    pub base: u64,
+    // Registers other than RBR/THR and LSR, kept so that drivers read back what they wrote
+    regs: [AtomicU8; 8],
+    // Bytes read from stdin, the reader thread starts on the first access to the receiver
+    rx: OnceLock<Arc<Mutex<VecDeque<u8>>>>,
+}
+
+impl Uart {
+    pub fn new(base: u64) -> Self {
+        Self {
+            base,
+            regs: Default::default(),
+            rx: OnceLock::new(),
+        }
+    }
+
+    fn rx(&self) -> &Arc<Mutex<VecDeque<u8>>> {
+        self.rx.get_or_init(|| {
+            let rx = Arc::new(Mutex::new(VecDeque::new()));
+            let input = rx.clone();
+            thread::spawn(move || {
+                let mut byte = [0];
+                while let Ok(1) = io::stdin().read(&mut byte) {
+                    input.lock().unwrap().push_back(byte[0]);
+                }
+            });
+            rx
+        })
+    }
+
+    // Send bytes to the host console
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(bytes);
+        let _ = stdout.flush();
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        self.write_bytes(&[byte]);
+    }
+
+    // Take a byte from the host console, if one has arrived
+    pub fn read_byte(&self) -> Option<u8> {
+        self.rx().lock().unwrap().pop_front()
+    }
+
+    // Register values as kept, for snapshots
+    pub(crate) fn registers(&self) -> [u8; 8] {
+        self.regs.each_ref().map(|reg| reg.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_registers(&self, values: [u8; 8]) {
+        for (reg, value) in self.regs.iter().zip(values) {
+            reg.store(value, Ordering::Relaxed);
+        }
+    }
+
+    fn reg(&self, offset: u64) -> u8 {
+        self.regs[offset as usize].load(Ordering::Relaxed)
+    }
+}
+
+impl Device for Uart {
+    fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if size != 8 {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        let value = match (addr - self.base) & 0x7 {
+            RBR_THR if self.reg(LCR) & LCR_DLAB == 0 => self.read_byte().unwrap_or(0),
+            IIR_FCR => IIR_NO_INTERRUPT,
+            LSR => {
+                let ready = if self.rx().lock().unwrap().is_empty() { 0 } else { LSR_DR };
+                ready | LSR_THRE | LSR_TEMT
+            }
+            offset => self.reg(offset),
+        };
+        Ok(value as u64)
+    }
+
+    fn store(&self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 8 {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        match (addr - self.base) & 0x7 {
+            RBR_THR if self.reg(LCR) & LCR_DLAB == 0 => self.write_byte(value as u8),
+            LSR => {}
+            offset => self.regs[offset as usize].store(value as u8, Ordering::Relaxed),
+        }
+        Ok(())
+    }
+}
+
+impl Default for Uart {
+    fn default() -> Self {
+        Self::new(UART_BASE)
+    }
+}
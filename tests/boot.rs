@@ -0,0 +1,63 @@
+// Integration tests for the `--kernel` boot flow
+
+use std::fs;
+
+mod common;
+use common::{fixture, run, temp_file};
+
+#[test]
+fn sbi_hello() {
+    let output = run(&["--kernel", &fixture("sbi_hello.elf")]);
+
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Hello !\n");
+}
+
+#[test]
+fn image_with_initrd_and_bootargs() {
+    let initrd = temp_file("initrd", vec![0x5a; 10000]);
+
+    let output = run(&[
+        "--kernel",
+        &fixture("Image"),
+        "--initrd",
+        &initrd,
+        "--append",
+        "console=ttyS0 rdinit=/init",
+    ]);
+    fs::remove_file(&initrd).unwrap();
+
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Image\n");
+}
+
+#[test]
+fn smp_hart_start_and_ipi() {
+    let kernel = fixture("smp.elf");
+    for threads in [&[][..], &["--threads"]] {
+        let output = run(&[&["--kernel", &kernel, "--harts", "2", "--quantum", "7"], threads].concat());
+
+        assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "SMP ok\n");
+    }
+
+    // hart_start fails without a second hart
+    let output = run(&["--kernel", &kernel]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn kernels_that_do_not_fit() {
+    // No room for the device tree at all, then a kernel running into it
+    let small = temp_file("small_kernel.bin", vec![0x13; 16]);
+    let output = run(&["--kernel", &small, "--set", "memory.size=16K", "--set", "memory.base=0"]);
+    assert_eq!(output.status.code(), Some(64));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("leave no room for the device tree"));
+
+    let large = temp_file("large_kernel.bin", vec![0x13; 100 * 1024]);
+    let output = run(&["--kernel", &large, "--set", "memory.size=128K"]);
+    assert_eq!(output.status.code(), Some(64));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("above the device tree"));
+    fs::remove_file(small).unwrap();
+    fs::remove_file(large).unwrap();
+}
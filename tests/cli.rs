@@ -0,0 +1,174 @@
+// Integration tests for the subcommands, the options and the exit codes of the command line
+
+use std::fs;
+
+mod common;
+use common::{fixture, run, stdout, temp_file};
+
+// Assembly source in a temporary file named after the test
+fn source(name: &str, text: &str) -> String {
+    temp_file(&format!("cli_{}.s", name), text)
+}
+
+#[test]
+fn help_and_usage_errors() {
+    let output = run(&["help"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(&output).starts_with("Usage: rv64_emu <command>"));
+
+    let output = run(&["run", "--frobnicate", "1", &fixture("htif_pass.elf")]);
+    assert_eq!(output.status.code(), Some(64));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown option: --frobnicate"));
+    assert_eq!(run(&["run"]).status.code(), Some(64));
+    assert_eq!(run(&["run", "--device", "plic@0x0c000000", &fixture("htif_pass.elf")]).status.code(), Some(64));
+    assert_eq!(run(&["run", "/nonexistent/program.elf"]).status.code(), Some(66));
+
+    // Layouts that do not fit in the address space, and programs that do not fit in memory
+    let output = run(&["run", "--device", "clint@0xfffffffffffffff0", &fixture("htif_pass.elf")]);
+    assert_eq!(output.status.code(), Some(64));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("ends beyond the address space"));
+    let output = run(&["run", "--memory-size", "0", &fixture("htif_pass.elf")]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("the memory size must not be zero"));
+    let output = run(&["run", "--memory-size", "0x10000000000000", &fixture("htif_pass.elf")]);
+    assert_eq!(output.status.code(), Some(64));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("the memory size must be at most"));
+    let program = temp_file("cli_large.bin", vec![0x13; 10000]);
+    let output = run(&["run", "--memory-size", "4K", &program]);
+    fs::remove_file(&program).unwrap();
+    assert_eq!(output.status.code(), Some(66));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot load the program"));
+}
+
+#[test]
+fn guest_exit_codes() {
+    // The HTIF exit code of the guest
+    assert_eq!(run(&["run", &fixture("htif_pass.elf")]).status.code(), Some(0));
+    assert_eq!(run(&["test", &fixture("htif_pass.elf")]).status.code(), Some(0));
+
+    let spin = source("spin", "1: j 1b\n");
+    let output = run(&["run", "--max-insns", "1000", &spin]);
+    assert_eq!(output.status.code(), Some(124));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("stopped at the instruction limit"));
+    assert_eq!(run(&["run", "--timeout", "0.2", &spin]).status.code(), Some(124));
+    fs::remove_file(spin).unwrap();
+
+    // Fetching from nowhere is fatal
+    let fatal = source("fatal", "li t0, 0x1000\njr t0\n");
+    let output = run(&["run", &fatal]);
+    fs::remove_file(&fatal).unwrap();
+    assert_eq!(output.status.code(), Some(125));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("InstructionAccessFault(4096)"));
+
+    // Returning to address 0 passes, trapping there does not, in either form of the command line
+    let illegal = temp_file("cli_illegal.bin", 0xffff_ffffu32.to_le_bytes());
+    assert_eq!(run(&["run", &illegal]).status.code(), Some(125));
+    assert_eq!(run(&[&illegal]).status.code(), Some(125));
+    fs::remove_file(&illegal).unwrap();
+    let null = source("null", "li t0, 0\njr t0\n");
+    assert_eq!(run(&["run", &null]).status.code(), Some(0));
+    assert_eq!(run(&[&null]).status.code(), Some(0));
+    fs::remove_file(&null).unwrap();
+}
+
+#[test]
+fn guest_termination_paths() {
+    let status = |name: &str, text: &str, args: &[&str]| {
+        let program = source(name, text);
+        let mut args = args.to_vec();
+        args.push(&program);
+        let output = run(&args);
+        fs::remove_file(&program).unwrap();
+        (output.status.code(), stdout(&output))
+    };
+
+    // The exit syscall without a trap handler, the registers are only dumped on request
+    let exit = "li a0, 3\nli a7, 93\necall\n";
+    assert_eq!(status("syscall", exit, &["run"]), (Some(3), String::new()));
+    let (code, dump) = status("syscall_dump", exit, &["run", "--dump-registers"]);
+    assert_eq!(code, Some(3));
+    assert!(dump.contains("x17( a7 )=              0x5d"), "{}", dump);
+
+    // The test finisher: pass, fail with a code, and poweroff as Linux does it
+    let finisher = |value: &str| format!("li t0, 0x100000\nli t1, {}\nsw t1, 0(t0)\n1: j 1b\n", value);
+    assert_eq!(status("pass", &finisher("0x5555"), &["run"]).0, Some(0));
+    assert_eq!(status("fail", &finisher("0x73333"), &["run"]).0, Some(7));
+    let moved = finisher("0x43333").replace("0x100000", "0x4000000");
+    assert_eq!(status("moved", &moved, &["run", "--device", "finisher@0x4000000"]).0, Some(4));
+
+    // Semihosting prints and exits with the subcode of SYS_EXIT
+    let semihosting = "
+            li a0, 4
+            la a1, message
+            slli zero, zero, 0x1f
+            ebreak
+            srai zero, zero, 7
+            li a0, 0x18
+            la a1, block
+            slli zero, zero, 0x1f
+            ebreak
+            srai zero, zero, 7
+        message:
+            .byte 0x68, 0x69, 0x0a, 0
+            .align 3
+        block:
+            .dword 0x20026, 9
+    ";
+    assert_eq!(status("semihosting", semihosting, &["run", "--semihosting"]), (Some(9), "hi\n".to_string()));
+    // Without it the ebreak traps to address 0, which is a crash and not a return to 0
+    assert_eq!(status("breakpoint", semihosting, &["run"]).0, Some(125));
+}
+
+#[test]
+fn load_address_entry_and_memory_size() {
+    // Loaded 1 MiB into DRAM, entered at its second instruction, with 2 MiB of memory
+    let program = source("layout", "li a0, 1\nmv a1, sp\nauipc a2, 0\njr zero\n");
+    let output = run(&[
+        "run",
+        "--load-address",
+        "0x80100000",
+        "--entry",
+        "0x80100004",
+        "--memory-size",
+        "2M",
+        "--dump-registers",
+        &program,
+    ]);
+    fs::remove_file(&program).unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("x10( a0 )=               0x0"), "{}", stdout);
+    assert!(stdout.contains("x11( a1 )=        0x80200000"), "{}", stdout);
+    assert!(stdout.contains("x12( a2 )=        0x80100008"), "{}", stdout);
+}
+
+#[test]
+fn trace_to_stdout() {
+    let output = run(&["trace", "--log-icount", "0:1", &fixture("htif_pass.elf")]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = stdout(&output);
+    assert!(stdout.starts_with("core   0: 0x0000000080000000"), "{}", stdout);
+    assert_eq!(stdout.lines().filter(|line| line.starts_with("core")).count(), 2, "{}", stdout);
+}
+
+#[test]
+fn save_and_resume_snapshots() {
+    let program = source("snapshot", "li t0, 1000\n1: addi a0, a0, 1\nbne a0, t0, 1b\nli a0, 42\nli a7, 93\necall\n");
+    let snapshot = std::env::temp_dir().join(format!("rv64_emu_cli_{}_snapshot.snap", std::process::id()));
+    let (program, snapshot) = (program.as_str(), snapshot.to_str().unwrap());
+
+    // Stopped halfway, then saved twice more before it ends
+    let output = run(&["run", "--memory-size", "2M", "--max-insns", "500", "--save-snapshot", snapshot, program]);
+    assert_eq!(output.status.code(), Some(124));
+    let output = run(&["resume", "--max-insns", "500", "--save-snapshot", snapshot, snapshot]);
+    assert_eq!(output.status.code(), Some(124));
+    let output = run(&["resume", "--dump-registers", snapshot]);
+    assert_eq!(output.status.code(), Some(42));
+    assert!(stdout(&output).contains("x02( sp )=        0x80200000"), "{}", stdout(&output));
+
+    assert_eq!(run(&["resume"]).status.code(), Some(64));
+    assert_eq!(run(&["resume", program]).status.code(), Some(66));
+    fs::remove_file(program).unwrap();
+    fs::remove_file(snapshot).unwrap();
+    assert_eq!(run(&["resume", snapshot]).status.code(), Some(66));
+}
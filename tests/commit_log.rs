@@ -0,0 +1,55 @@
+// Integration test for the spike-style commit log
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn log_commits(name: &str, filters: &[&str]) -> Vec<String> {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/htif_pass.elf");
+    let log = std::env::temp_dir().join(format!("rv64_emu_{}_{}.log", name, std::process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_rv64_emu"))
+        .arg("--test")
+        .arg(fixture)
+        .arg("--log-commits")
+        .arg(&log)
+        .args(filters)
+        .status()
+        .expect("failed to run the emulator");
+    assert_eq!(status.code(), Some(0));
+
+    let lines = fs::read_to_string(&log).unwrap().lines().map(String::from).collect();
+    fs::remove_file(log).unwrap();
+    lines
+}
+
+#[test]
+fn register_csr_and_memory_writes() {
+    let log = log_commits("writes", &[]);
+    let expected = [
+        "core   0: 0x0000000080000030 (0x00000297) auipc t0,0x0",
+        "core   0: 3 0x0000000080000030 (0x00000297) x5  0x0000000080000030",
+        "core   0: 3 0x0000000080000038 (0x30529073) c773_mtvec 0x0000000080000004",
+        "core   0: exception trap_illegal_instruction, epc 0x0000000080000040",
+        "core   0:           tval 0x00000000ffffffff",
+        "core   0: 0 0x0000000080000064 (0x00500513) x10 0x0000000000000005",
+        "core   0: 0 0x000000008000007c (0x00a2b023) mem 0x0000000080001010 0x0000000000000008",
+        "core   0: 0 0x0000000080000088 (0x00c2a423) mem 0x0000000080001018 0x12345678",
+        "core   0: exception trap_user_ecall, epc 0x0000000080000090",
+    ];
+    for line in expected {
+        assert!(log.iter().any(|l| l == line), "missing line: {}", line);
+    }
+}
+
+#[test]
+fn pc_and_icount_filters() {
+    // The user-mode code only
+    let log = log_commits("pc", &["--log-pc", "0x80000064:0x80000094"]);
+    assert_eq!(log.first().unwrap(), "core   0: 0x0000000080000064 (0x00500513) li a0,5");
+    assert!(log.iter().all(|l| !l.contains("(0x34202f73)")));
+
+    // The jump to the reset code and the auipc after it
+    let log = log_commits("icount", &["--log-icount", "0:2"]);
+    assert_eq!(log.len(), 4);
+    assert_eq!(log[2], "core   0: 0x0000000080000030 (0x00000297) auipc t0,0x0");
+}
@@ -0,0 +1,25 @@
+// Helpers shared by the integration tests that run the emulator binary
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+pub fn fixture(name: &str) -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name).to_str().unwrap().to_string()
+}
+
+// Write `contents` to a temporary file named after the test, returns its path
+pub fn temp_file(name: &str, contents: impl AsRef<[u8]>) -> String {
+    let path = std::env::temp_dir().join(format!("rv64_emu_{}_{}", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+pub fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rv64_emu")).args(args).output().expect("failed to run the emulator")
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
@@ -0,0 +1,36 @@
+// Integration test for machine configuration files and --set overrides
+
+use std::fs;
+
+mod common;
+use common::{fixture, run, temp_file};
+
+#[test]
+fn memory_layout_from_a_file() {
+    let config = temp_file("layout.toml", "[memory]\nbase = 0x4000_0000\nsize = \"1M\"\n");
+    // sp starts at the end of memory, the program is placed at its start, then jump to 0 to stop
+    let program = temp_file("layout.s", "mv a0, sp\nauipc a1, 0\njr zero\n");
+    let output = run(&["--config", &config, "--dump-registers", &program]);
+    fs::remove_file(config).unwrap();
+    fs::remove_file(program).unwrap();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("x10( a0 )=        0x40100000"), "{}", stdout);
+    assert!(stdout.contains("x11( a1 )=        0x40000004"), "{}", stdout);
+}
+
+#[test]
+fn boot_mode_and_overrides() {
+    let config = temp_file("test.toml", "[boot]\nmode = \"test\"\n\n[cpu]\nharts = 2\n");
+    assert_eq!(run(&["--config", &config, &fixture("htif_pass.elf")]).status.code(), Some(0));
+
+    // Without the A extension the atomics are illegal instructions
+    let output = run(&["--config", &config, "--set", "cpu.isa=rv64isu", &fixture("atomics.elf")]);
+    assert_ne!(output.status.code(), Some(0));
+
+    let output = run(&["--test", &fixture("htif_pass.elf"), "--set", "devices.uart=0x8000_0000"]);
+    assert_eq!(output.status.code(), Some(64));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("overlaps the UART"));
+    fs::remove_file(config).unwrap();
+}
@@ -0,0 +1,20 @@
+// Integration test for the `disasm` subcommand
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn disasm_elf_with_symbols() {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/htif_pass.elf");
+    let output = Command::new(env!("CARGO_BIN_EXE_rv64_emu"))
+        .arg("disasm")
+        .arg(fixture)
+        .output()
+        .expect("failed to run the emulator");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout.contains("0000000080000064 <user>:\n80000064: 00500513           li a0,5\n"), "{}", stdout);
+    assert!(stdout.contains("80000070: 02b51263           bne a0,a1,0x80000094 <fail>\n"), "{}", stdout);
+    assert!(stdout.contains("8000003c: f1402573           csrr a0,mhartid\n"), "{}", stdout);
+}
@@ -0,0 +1,37 @@
+# Minimal RISC-V Linux `Image` for the boot flow test: the 64-byte header with text_offset
+# 0x200000, then code that checks it runs at 0x80200000, prints through the SBI debug console
+# and shuts down through SRST, with reason 1 if it was loaded elsewhere.
+.option norelax
+.text
+.globl _start
+_start:
+  j entry
+  .word 0
+  .dword 0x200000
+  .dword 0x1000
+  .dword 0
+  .word 2
+  .word 0
+  .dword 0
+  .ascii "RISCV\0\0\0"
+  .ascii "RSC\x05"
+  .word 0
+entry:
+  la t0, hello
+  auipc t1, 0
+  li a7, 0x4442434E
+  li a6, 0
+  li a0, 6
+  mv a1, t0
+  li a2, 0
+  ecall
+  li a7, 0x53525354
+  li a6, 0
+  li a0, 0
+  li a1, 0
+  li t0, 0x80200048
+  beq t1, t0, 1f
+  li a1, 1
+1: ecall
+hello: .ascii "Image\n"
+image_end:
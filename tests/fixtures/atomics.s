@@ -0,0 +1,53 @@
+# Four harts increment shared counters, one with amoadd.d and one with an lr.w/sc.w loop,
+# linked at 0x80000000. Hart 0 waits for the others and reports through HTIF whether no
+# increment was lost: test 2 failed for the AMO counter, test 3 for the LR/SC one.
+.option norelax
+.equ HARTS, 4
+.equ COUNT, 20000
+.text
+.globl _start
+_start:
+  la s0, amo_counter
+  la s1, lrsc_counter
+  la s2, done
+  li s3, COUNT
+  li t2, 1
+1:
+  amoadd.d zero, t2, (s0)
+2:
+  lr.w t0, (s1)
+  addi t0, t0, 1
+  sc.w t1, t0, (s1)
+  bnez t1, 2b
+  addi s3, s3, -1
+  bnez s3, 1b
+  amoadd.w zero, t2, (s2)
+  csrr t0, mhartid
+  bnez t0, park
+3:
+  lw t0, (s2)
+  li t1, HARTS
+  bne t0, t1, 3b
+  li t1, HARTS * COUNT
+  li gp, (2 << 1) | 1
+  ld t0, (s0)
+  bne t0, t1, report
+  li gp, (3 << 1) | 1
+  lw t0, (s1)
+  bne t0, t1, report
+  li gp, 1
+report:
+  la t0, tohost
+  sd gp, 0(t0)
+park:
+  wfi
+  j park
+.align 6
+amo_counter: .dword 0
+.align 6
+lrsc_counter: .dword 0
+.align 6
+done: .dword 0
+.align 12
+tohost: .dword 0
+fromhost: .dword 0
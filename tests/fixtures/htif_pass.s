@@ -0,0 +1,51 @@
+# Minimal riscv-tests style program for the test-runner mode, linked at 0x80000000.
+# It installs a trap handler, skips an illegal instruction, drops to user mode, stores a
+# signature and reports success through HTIF from the ecall handler.
+.option norelax
+.text
+.globl _start
+_start:
+  j reset
+trap:
+  csrr t5, mcause
+  li t6, 8
+  beq t5, t6, ecall_h
+  csrr t5, mepc
+  addi t5, t5, 4
+  csrw mepc, t5
+  mret
+ecall_h:
+  la t5, tohost
+  sw gp, 0(t5)
+1: j 1b
+reset:
+  la t0, trap
+  csrw mtvec, t0
+  csrr a0, mhartid
+  .word 0xffffffff
+  li gp, 0
+  la t0, user
+  csrw mepc, t0
+  li t0, 0x1800
+  csrc mstatus, t0
+  mret
+user:
+  li a0, 5
+  addi a0, a0, 3
+  li a1, 8
+  bne a0, a1, fail
+  la t0, begin_signature
+  sd a0, 0(t0)
+  li a2, 0x12345678
+  sw a2, 8(t0)
+  li gp, 1
+  ecall
+fail:
+  li gp, (2 << 1) | 1
+  ecall
+.align 12
+tohost: .dword 0
+fromhost: .dword 0
+.align 4
+begin_signature: .fill 4, 4, 0xdeadbeef
+end_signature:
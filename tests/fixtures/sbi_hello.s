@@ -0,0 +1,60 @@
+# S-mode kernel for the firmware-less boot, linked at 0x80000000. It prints through the debug
+# console and the legacy putchar, checks the SBI version and the device tree magic passed in a1,
+# waits for a timer interrupt and shuts down through SRST, with reason 1 if a check failed.
+.option norelax
+.text
+.globl _start
+_start:
+  mv s0, a0
+  mv s1, a1
+  la t0, handler
+  csrw stvec, t0
+  # DBCN write
+  li a7, 0x4442434E
+  li a6, 0
+  li a0, 6
+  la a1, msg
+  li a2, 0
+  ecall
+  # legacy putchar '!'
+  li a7, 1
+  li a0, 33
+  ecall
+  li a7, 1
+  li a0, 10
+  ecall
+  # base spec version -> s2
+  li a7, 0x10
+  li a6, 0
+  ecall
+  mv s2, a1
+  # fdt magic -> s3
+  lwu s3, 0(s1)
+  # set timer now+100
+  rdtime t0
+  addi a0, t0, 100
+  li a7, 0x54494D45
+  li a6, 0
+  ecall
+  li t0, 0x20
+  csrs sie, t0
+  csrsi sstatus, 2
+1: wfi
+  j 1b
+handler:
+  csrr s4, scause
+  li a7, 0x53525354
+  li a6, 0
+  li a0, 0
+  li a1, 1
+  li t0, 0x8000000000000005
+  bne s4, t0, 3f
+  li t0, 0xedfe0dd0
+  bne s3, t0, 3f
+  li t0, 0x2000000
+  bne s2, t0, 3f
+  li a1, 0
+3:
+  ecall
+2: j 2b
+msg: .ascii "Hello "
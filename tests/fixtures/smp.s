@@ -0,0 +1,85 @@
+# S-mode kernel for the firmware-less boot with two harts, linked at 0x80000000. Hart 0 starts
+# hart 1 through SBI HSM, sends it an IPI once it is waiting for interrupts, waits for it to
+# stop and shuts down through SRST, with reason 1 if a check failed.
+.option norelax
+.text
+.globl _start
+_start:
+  # hart_start(1, secondary, opaque)
+  li a7, 0x48534D
+  li a6, 0
+  li a0, 1
+  la a1, secondary
+  li a2, 0x1234
+  ecall
+  bnez a0, fail
+  # wait for hart 1 to enable its software interrupt
+  la s0, flag
+1: ld t0, 0(s0)
+  beqz t0, 1b
+  # send_ipi(1 << 1, 0)
+  li a7, 0x735049
+  li a6, 0
+  li a0, 2
+  li a1, 0
+  ecall
+  bnez a0, fail
+  # hart_get_status(1) until it is stopped
+2: li a7, 0x48534D
+  li a6, 2
+  li a0, 1
+  ecall
+  li t0, 1
+  bne a1, t0, 2b
+  ld t0, 0(s0)
+  li t1, 2
+  bne t0, t1, fail
+  # DBCN write
+  li a7, 0x4442434E
+  li a6, 0
+  li a0, 7
+  la a1, msg
+  li a2, 0
+  ecall
+  li a1, 0
+  j reset
+fail:
+  li a1, 1
+reset:
+  li a7, 0x53525354
+  li a6, 0
+  li a0, 0
+  ecall
+3: j 3b
+
+secondary:
+  # a0 = hartid, a1 = opaque
+  li t0, 1
+  bne a0, t0, fail
+  li t0, 0x1234
+  bne a1, t0, fail
+  la t0, handler
+  csrw stvec, t0
+  csrsi sie, 2
+  csrsi sstatus, 2
+  la t0, flag
+  li t1, 1
+  sd t1, 0(t0)
+4: wfi
+  j 4b
+handler:
+  csrr t0, scause
+  li t1, 0x8000000000000001
+  bne t0, t1, fail
+  csrci sip, 2
+  la t0, flag
+  li t1, 2
+  sd t1, 0(t0)
+  # hart_stop
+  li a7, 0x48534D
+  li a6, 1
+  ecall
+  j fail
+.balign 8
+msg: .ascii "SMP ok\n\0"
+flag: .dword 0
@@ -0,0 +1,31 @@
+# 32-bit (W) shifts and arithmetic, the results are written to the signature and the test
+# passes through HTIF. wops.log is its commit log in the spike format, the values checked by hand.
+.option norelax
+.text
+.globl _start
+_start:
+  li a0, -2147483647
+  srliw a1, a0, 4
+  sraiw a2, a0, 4
+  li t0, 36
+  sraw a3, a0, t0
+  srlw a4, a0, t0
+  sllw a5, a0, t0
+  li t1, 0x7fffffff
+  addw a6, t1, t1
+  subw a7, zero, t1
+  slliw s2, t1, 1
+  la t0, begin_signature
+  sd a1, 0(t0)
+  sd a2, 8(t0)
+  sd a3, 16(t0)
+  li gp, 1
+  la t5, tohost
+  sw gp, 0(t5)
+1: j 1b
+.align 12
+tohost: .dword 0
+fromhost: .dword 0
+.align 4
+begin_signature: .fill 6, 4, 0
+end_signature:
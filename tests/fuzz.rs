@@ -0,0 +1,159 @@
+// Random addresses never panic the host: outside of DRAM and the devices, every access is an
+// access fault, returned by the bus and taken by the guest
+
+use rv64_emu::clint::{CLINT_BASE, CLINT_SIZE};
+use rv64_emu::finisher::{FINISHER_BASE, FINISHER_SIZE};
+use rv64_emu::uart::{UART_BASE, UART_SIZE};
+use rv64_emu::{assemble, Bus, Exception, Exit, Machine, MachineConfig, DRAM_BASE};
+
+const MEMORY: u64 = 0x10000;
+
+// xorshift64*, good enough to pick addresses
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // Mostly around the ends of DRAM and of the address space, where the off-by-ones are
+    fn address(&mut self) -> u64 {
+        let edges = [DRAM_BASE, DRAM_BASE + MEMORY, 0, u64::MAX, 1 << 32, 1 << 63];
+        match self.next() % 4 {
+            0 => self.next(),
+            _ => edges[(self.next() % edges.len() as u64) as usize].wrapping_add(self.next() % 32).wrapping_sub(16),
+        }
+    }
+}
+
+fn in_dram(addr: u64, len: u64) -> bool {
+    addr >= DRAM_BASE && addr - DRAM_BASE <= MEMORY && len <= MEMORY - (addr - DRAM_BASE)
+}
+
+fn on_device(addr: u64, len: u64) -> bool {
+    [(CLINT_BASE, CLINT_SIZE), (UART_BASE, UART_SIZE), (FINISHER_BASE, FINISHER_SIZE)]
+        .iter()
+        .any(|&(base, size)| addr < base + size && base < addr.saturating_add(len))
+}
+
+#[test]
+fn random_bus_accesses() {
+    let config = MachineConfig { memory_size: MEMORY, ..MachineConfig::default() };
+    let bus = Bus::new(&config, Vec::new()).unwrap();
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut buffer = [0; 64];
+
+    for _ in 0..100_000 {
+        let addr = rng.address();
+        let size = 8 << (rng.next() % 4);
+        if on_device(addr, size / 8) {
+            continue;
+        }
+        let inside = in_dram(addr, size / 8);
+        let check = |result: Result<(), Exception>, fault: Exception, access: &str| {
+            let expected = if inside { Ok(()) } else { Err(fault) };
+            assert_eq!(result, expected, "{} of {} bits at {:#x}", access, size, addr);
+        };
+        check(bus.load(addr, size).map(|_| ()), Exception::LoadAccessFault(addr), "load");
+        check(bus.store(addr, size, rng.next()), Exception::StoreAMOAccessFault(addr), "store");
+        assert_eq!(bus.fetch(addr).is_ok(), in_dram(addr, 4), "fetch at {:#x}", addr);
+
+        // Atomics raise address-misaligned exceptions first, and fault on bytes and halves
+        let expected = |misaligned: fn(u64) -> Exception, fault: fn(u64) -> Exception| {
+            if !addr.is_multiple_of(size / 8) {
+                Err(misaligned(addr))
+            } else if inside && size >= 32 {
+                Ok(())
+            } else {
+                Err(fault(addr))
+            }
+        };
+        let amo = bus.fetch_update(addr, size, |value| value.wrapping_add(1)).map(|_| ());
+        let (load, store) = (Exception::LoadAddressMisaligned, Exception::StoreAMOAddressMisaligned);
+        assert_eq!(amo, expected(store, Exception::StoreAMOAccessFault), "AMO of {} bits at {:#x}", size, addr);
+        let lr = bus.load_reserved(0, addr, size).map(|_| ());
+        assert_eq!(lr, expected(load, Exception::LoadAccessFault), "LR of {} bits at {:#x}", size, addr);
+        let sc = bus.store_conditional(0, addr, size, 0, 0).map(|_| ());
+        assert_eq!(sc, expected(store, Exception::StoreAMOAccessFault), "SC of {} bits at {:#x}", size, addr);
+
+        // Bulk accesses fault at the first byte outside of DRAM
+        let len = rng.next() % buffer.len() as u64;
+        if on_device(addr, len) || addr.checked_add(len).is_none() {
+            continue;
+        }
+        let first_outside = if in_dram(addr, 0) { DRAM_BASE + MEMORY } else { addr };
+        let expected = |fault: fn(u64) -> Exception| {
+            if len == 0 || in_dram(addr, len) { Ok(()) } else { Err(fault(first_outside)) }
+        };
+        let buffer = &mut buffer[..len as usize];
+        assert_eq!(bus.read_bytes(addr, buffer), expected(Exception::LoadAccessFault), "read of {} at {:#x}", len, addr);
+        assert_eq!(bus.write_bytes(addr, buffer), expected(Exception::StoreAMOAccessFault), "write of {} at {:#x}", len, addr);
+        assert_eq!(bus.memset(addr, 0, len), expected(Exception::StoreAMOAccessFault), "memset of {} at {:#x}", len, addr);
+    }
+}
+
+#[test]
+fn random_guest_accesses() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut source = String::from("
+            la t0, trap
+            csrw mtvec, t0
+            li s1, 0x80008000
+    ");
+    // Faults the handler should log, DRAM loads are fine
+    let mut expected = Vec::new();
+    while expected.len() < 300 {
+        let addr = rng.address() & !7;
+        if on_device(addr, 8) {
+            continue;
+        }
+        let inside = in_dram(addr, 8);
+        let (op, cause) = match rng.next() % 4 {
+            0 => ("ld t1, 0(t0)", 5),
+            _ if inside => continue,
+            1 => ("sd t1, 0(t0)", 7),
+            2 => ("amoadd.d t1, t2, (t0)", 7),
+            _ => ("jalr ra, 0(t0)", 1),
+        };
+        source += &format!("li t0, {:#x}\n{}\n", addr, op);
+        if !inside {
+            expected.push((cause, addr));
+        }
+    }
+    source += "
+            li s2, 1
+        1:  j 1b
+
+        # Log the cause and the address, then go on after the access or the jump
+        trap:
+            csrr t3, mcause
+            csrr t4, mtval
+            sd t3, 0(s1)
+            sd t4, 8(s1)
+            addi s1, s1, 16
+            csrr t5, mepc
+            addi t5, t5, 4
+            li t6, 1
+            bne t3, t6, 2f
+            mv t5, ra
+        2:  csrw mepc, t5
+            mret
+    ";
+
+    let code = assemble(&source, DRAM_BASE).unwrap();
+    let mut machine = Machine::builder().memory(DRAM_BASE, MEMORY).program(code).build().unwrap();
+    assert_eq!(machine.run(Some(100_000)), Exit::Limit);
+    assert_eq!(machine.harts[0].regs[18], 1, "the program did not reach its end");
+    let log = 0x8000_8000;
+    let logged = (machine.harts[0].regs[9] - log) / 16;
+    let faults: Vec<(u64, u64)> = (0..logged)
+        .map(|i| {
+            let entry = log + 16 * i;
+            (machine.bus.load(entry, 64).unwrap(), machine.bus.load(entry + 8, 64).unwrap())
+        })
+        .collect();
+    assert_eq!(faults, expected);
+}
@@ -0,0 +1,91 @@
+// Integration test for the GDB stub, driven by a minimal remote protocol client
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// Addresses in htif_pass.elf: the user-mode code and the signature it writes
+const USER: u64 = 0x8000_0064;
+const BEGIN_SIGNATURE: u64 = 0x8000_1010;
+
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    // Send a packet and return the reply
+    fn request(&mut self, data: &str) -> String {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, sum).unwrap();
+
+        let mut bytes = self.reader.by_ref().bytes().map(Result::unwrap);
+        let mut reply = Vec::new();
+        // Skip the acknowledgment
+        bytes.by_ref().find(|&b| b == b'$').unwrap();
+        for byte in bytes.by_ref() {
+            if byte == b'#' {
+                break;
+            }
+            reply.push(byte);
+        }
+        let _checksum: Vec<u8> = bytes.take(2).collect();
+        String::from_utf8(reply).unwrap()
+    }
+}
+
+fn le_hex(value: u64) -> String {
+    value.to_le_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn breakpoint_step_and_watchpoint() {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/htif_pass.elf");
+    let mut emulator = Command::new(env!("CARGO_BIN_EXE_rv64_emu"))
+        .arg("--test")
+        .arg(fixture)
+        .args(["--gdb", "0"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run the emulator");
+
+    // "waiting for GDB on 127.0.0.1:<port>", the pipe stays open for the later messages
+    let mut stderr = BufReader::new(emulator.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    let address = line.trim().rsplit(' ').next().unwrap();
+    let stream = TcpStream::connect(address).unwrap();
+    let mut gdb = Client { reader: BufReader::new(stream.try_clone().unwrap()), stream };
+
+    assert!(gdb.request("qSupported:swbreak+").contains("qXfer:features:read+"));
+    assert!(gdb.request("qXfer:features:read:target.xml:0,2000").contains("org.gnu.gdb.riscv.csr"));
+    assert!(gdb.request("qXfer:features:read:target.xml:10,ffffffffffffffff").starts_with('l'));
+    // Past the CSRs and the privilege mode, not mstatus again
+    assert_eq!(gdb.request("p10341"), "xxxxxxxxxxxxxxxx");
+    assert_eq!(gdb.request("P10341=0000000000000000"), "E01");
+    // Only DRAM is read, a read of the UART would take its received bytes
+    assert_eq!(gdb.request("m10000000,8"), "E01");
+    assert_eq!(gdb.request("qfThreadInfo"), "m1");
+    assert_eq!(gdb.request("?"), "T05thread:1;");
+    assert!(gdb.request("g").ends_with(&le_hex(0x8000_0000)));
+
+    assert_eq!(gdb.request(&format!("Z0,{:x},4", USER)), "OK");
+    assert_eq!(gdb.request("c"), "T05thread:1;swbreak:;");
+    assert_eq!(gdb.request("p20"), le_hex(USER));
+    // Machine mode is left through mret
+    assert_eq!(gdb.request("p1041"), le_hex(0));
+    assert_eq!(gdb.request(&format!("z0,{:x},4", USER)), "OK");
+
+    assert_eq!(gdb.request("vCont;s:1"), "T05thread:1;");
+    assert_eq!(gdb.request("pa"), le_hex(5));
+    assert_eq!(gdb.request("Pa=0500000000000000"), "OK");
+
+    assert_eq!(gdb.request(&format!("Z2,{:x},8", BEGIN_SIGNATURE)), "OK");
+    assert_eq!(gdb.request("c"), format!("T05thread:1;watch:{:x};", BEGIN_SIGNATURE));
+    assert_eq!(gdb.request(&format!("m{:x},8", BEGIN_SIGNATURE)), le_hex(8));
+    assert_eq!(gdb.request(&format!("z2,{:x},8", BEGIN_SIGNATURE)), "OK");
+
+    assert_eq!(gdb.request("c"), "W00");
+    assert_eq!(emulator.wait().unwrap().code(), Some(0));
+}
@@ -0,0 +1,43 @@
+// Integration test for the lockstep run against a reference commit log
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn lockstep(reference: &Path) -> Output {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/wops.elf");
+    Command::new(env!("CARGO_BIN_EXE_rv64_emu"))
+        .arg("--test")
+        .arg(fixture)
+        .arg("--lockstep")
+        .arg(reference)
+        .output()
+        .expect("failed to run the emulator")
+}
+
+#[test]
+fn w_ops_match_the_reference() {
+    let reference = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/wops.log");
+    let output = lockstep(&reference);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(stderr.contains("lockstep: 22 instructions match the reference"), "{}", stderr);
+}
+
+#[test]
+fn divergence_is_reported() {
+    // A reference where srliw sign-extends its result
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/wops.log");
+    let log = fs::read_to_string(fixture).unwrap().replace("x11 0x0000000008000000", "x11 0xfffffffff8000000");
+    let reference = env::temp_dir().join(format!("rv64_emu_lockstep_{}.log", std::process::id()));
+    fs::write(&reference, log).unwrap();
+
+    let output = lockstep(&reference);
+    fs::remove_file(&reference).unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr.contains("lockstep: diverged at instruction 2 on hart 0"), "{}", stderr);
+    assert!(stderr.contains("  reference: 0x0000000080000008 (0x0045559b) srliw a1,a0,4 x11 0xfffffffff8000000"), "{}", stderr);
+    assert!(stderr.contains("  emulator:  0x0000000080000008 (0x0045559b) srliw a1,a0,4 x11 0x0000000008000000"), "{}", stderr);
+}
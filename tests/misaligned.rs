@@ -0,0 +1,125 @@
+// Misaligned accesses under each policy: the trap handler sees the exception with the address in
+// mtval and the instruction in mepc, or the access happens
+
+use rv64_emu::{assemble, decode, Exit, Instruction, Machine, MachineConfig, Misaligned, DRAM_BASE};
+
+const LOG: u64 = 0x8000_8000;
+
+// Every pass logs its traps over those of the last one, the blocks are compiled after a few
+const SOURCE: &str = "
+        la t0, trap
+        csrw mtvec, t0
+        la s0, data
+        li s2, 20
+    loop:
+        li s1, 0x80008000
+        lw t1, 2(s0)
+        sw t1, 6(s0)
+        addi t2, s0, 2
+        amoadd.w t3, t1, (t2)
+        addi t2, s0, 6
+        amoadd.w t3, t1, (t2)
+        jal ra, target
+        la t5, target
+        jalr ra, 0(t5)
+        addi s2, s2, -1
+        bnez s2, loop
+        li s3, 1
+    1:  j 1b
+
+    # Log the cause, the address and the instruction, then skip it
+    trap:
+        csrr t4, mcause
+        sd t4, 0(s1)
+        csrr t4, mtval
+        sd t4, 8(s1)
+        csrr t4, mepc
+        sd t4, 16(s1)
+        addi t4, t4, 4
+        csrw mepc, t4
+        addi s1, s1, 24
+        mret
+
+        .half 0
+    target:
+        addi s4, s4, 1
+        jr ra
+        .half 0
+
+        .align 3
+    data:
+        .dword 0x0807060504030201
+        .dword 0x100f0e0d0c0b0a09
+";
+
+// Run with `policy` for every kind of access, with or without the blocks, returns the machine
+// and the logged traps as cause, address and the instruction that trapped
+fn run(policy: Misaligned, blocks: bool) -> (Machine, Vec<(u64, u64, Instruction)>) {
+    let mut config = MachineConfig { memory_size: 0x10000, ..MachineConfig::default() };
+    for kind in ["fetch", "load", "store", "amo"] {
+        let value = if policy == Misaligned::Trap { "trap" } else { "emulate" };
+        config.set(&format!("misaligned.{}={}", kind, value)).unwrap();
+    }
+    let code = assemble(SOURCE, DRAM_BASE).unwrap();
+    let mut machine = Machine::builder().config(config).program(code).build().unwrap();
+    machine.harts[0].blocks.enabled = blocks;
+    assert_eq!(machine.run(Some(10_000)), Exit::Limit);
+    assert_eq!(machine.harts[0].regs[19], 1, "the program did not reach its end");
+
+    let load = |addr: u64| machine.bus.load(addr, 64).unwrap();
+    let traps = (LOG..machine.harts[0].regs[9])
+        .step_by(24)
+        .map(|entry| (load(entry), load(entry + 8), decode(machine.bus.load(load(entry + 16), 32).unwrap() as u32)))
+        .collect();
+    (machine, traps)
+}
+
+#[test]
+fn misaligned_accesses_trap() {
+    for blocks in [false, true] {
+        let (machine, traps) = run(Misaligned::Trap, blocks);
+        let regs = &machine.harts[0].regs;
+        let (data, target) = (regs[8], regs[30]);
+        assert!(!target.is_multiple_of(4));
+        let expected = vec![
+            (4, data + 2, "lw"),
+            (6, data + 6, "sw"),
+            (6, data + 2, "amoadd.w"),
+            (6, data + 6, "amoadd.w"),
+            (0, target, "jal"),
+            (0, target, "jalr"),
+        ];
+        let traps: Vec<_> = traps.iter().map(|(cause, tval, inst)| (*cause, *tval, mnemonic(inst))).collect();
+        assert_eq!(traps, expected, "blocks: {}", blocks);
+        // Nothing happened: rd kept its value, the jumps did not go
+        assert_eq!((regs[6], regs[20]), (0, 0));
+        assert_eq!(machine.bus.load(data, 64), Ok(0x0807_0605_0403_0201));
+    }
+}
+
+#[test]
+fn misaligned_accesses_emulated() {
+    let (steps, traps) = run(Misaligned::Emulate, false);
+    let data = steps.harts[0].regs[8];
+    // Only the AMO crossing two words cannot be done atomically
+    let traps: Vec<_> = traps.iter().map(|(cause, tval, inst)| (*cause, *tval, mnemonic(inst))).collect();
+    assert_eq!(traps, vec![(7, data + 6, "amoadd.w")]);
+    assert_eq!(steps.harts[0].regs[20], 40);
+
+    let (blocks, _) = run(Misaligned::Emulate, true);
+    assert_eq!(blocks.harts[0].regs, steps.harts[0].regs);
+    for addr in [data, data + 8] {
+        assert_eq!(blocks.bus.load(addr, 64), steps.bus.load(addr, 64));
+    }
+}
+
+fn mnemonic(inst: &Instruction) -> &'static str {
+    match inst {
+        Instruction::Lw { .. } => "lw",
+        Instruction::Sw { .. } => "sw",
+        Instruction::AmoaddW { .. } => "amoadd.w",
+        Instruction::Jal { .. } => "jal",
+        Instruction::Jalr { .. } => "jalr",
+        _ => "other",
+    }
+}
@@ -0,0 +1,62 @@
+// Integration test for the monitor console, driven through stdin
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+fn run(script: &str) -> Output {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/htif_pass.elf");
+    let mut emulator = Command::new(env!("CARGO_BIN_EXE_rv64_emu"))
+        .arg("--test")
+        .arg(fixture)
+        .arg("--monitor")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run the emulator");
+    emulator.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+    emulator.wait_with_output().unwrap()
+}
+
+#[test]
+fn breakpoints_traps_and_memory() {
+    let output = run("break user\n\
+                      continue\n\
+                      continue\n\
+                      print pc\n\
+                      step 2\n\
+                      print a0\n\
+                      watch begin_signature\n\
+                      continue\n\
+                      x begin_signature 4\n\
+                      csr mcause\n\
+                      trap off\n\
+                      continue\n");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    // The illegal instruction traps before the breakpoint is reached
+    assert!(stdout.contains("trap on hart 0: exception 2"), "{}", stdout);
+    assert!(stdout.contains("breakpoint on hart 0 at 0x80000064"), "{}", stdout);
+    assert!(stdout.contains("pc = 0x80000064"), "{}", stdout);
+    assert!(stdout.contains("a0 = 0x8 (8)"), "{}", stdout);
+    assert!(stdout.contains("watchpoint on hart 0: Write at 0x80001010"), "{}", stdout);
+    assert!(stdout.contains("0x80001010: 08 00 00 00"), "{}", stdout);
+    assert!(stdout.contains("mcause     = 0x2"), "{}", stdout);
+    assert!(stdout.contains("guest exited: Htif(0)"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn quit_kills_the_guest() {
+    let output = run("step\nquit\n");
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "killed by the debugger\n");
+
+    // Devices are not read, and the disassembly wraps around the address space
+    let output = run("x 0x10000000 2\ndis 0xfffffffffffffffc 2\nquit\n");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("0x10000000: ?? ??"), "{}", stdout);
+    assert!(stdout.contains("0x0: cannot fetch"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(2));
+}
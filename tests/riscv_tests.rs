@@ -0,0 +1,96 @@
+// Integration tests for the `--test` runner mode.
+//
+// `riscv_tests_dir` runs a directory of prebuilt riscv-tests binaries, e.g. `isa/` of a
+// riscv-tests build, and reports one line per test. Point RISCV_TESTS_DIR at the directory
+// to enable it, RISCV_TESTS_FILTER optionally keeps only the tests whose name contains it:
+//
+//     RISCV_TESTS_DIR=~/riscv-tests/isa cargo test --test riscv_tests -- --nocapture
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+// Physical-memory test suites for the user-level ISA the harts implement
+const SUITES: [&str; 2] = ["rv64ui-p-", "rv64ua-p-"];
+
+// Suites of the extensions the harts do not implement, reported as unsupported without running
+const UNSUPPORTED: [&str; 4] = ["rv64um-p-", "rv64uf-p-", "rv64ud-p-", "rv64uc-p-"];
+
+fn run(elf: &Path, extra: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_rv64_emu"))
+        .arg("--test")
+        .arg(elf)
+        .args(extra)
+        .output()
+        .expect("failed to run the emulator")
+}
+
+#[test]
+fn htif_pass_and_signature() {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/htif_pass.elf");
+    let signature = env::temp_dir().join(format!("rv64_emu_signature_{}", std::process::id()));
+
+    let output = run(&fixture, &["--signature", signature.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let dump = fs::read_to_string(&signature).unwrap();
+    fs::remove_file(&signature).unwrap();
+    assert_eq!(dump, "00000008\n00000000\n12345678\ndeadbeef\n");
+}
+
+#[test]
+fn atomics_across_harts() {
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/atomics.elf");
+    for extra in [&["--harts", "4"][..], &["--harts", "4", "--threads"], &["--harts", "4", "--threads", "--quantum", "1"]] {
+        let output = run(&fixture, extra);
+        assert_eq!(output.status.code(), Some(0), "{:?}: {}", extra, String::from_utf8_lossy(&output.stderr));
+    }
+}
+
+#[test]
+fn riscv_tests_dir() {
+    let dir = match env::var("RISCV_TESTS_DIR") {
+        Ok(dir) => dir,
+        Err(_) => {
+            eprintln!("RISCV_TESTS_DIR is not set, skipping riscv-tests");
+            return;
+        }
+    };
+    let filter = env::var("RISCV_TESTS_FILTER").unwrap_or_default();
+
+    // The suites ship `.dump` disassembly next to the binaries, only run the extension-less files
+    let mut tests: Vec<_> = fs::read_dir(&dir)
+        .expect("cannot read RISCV_TESTS_DIR")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            path.extension().is_none()
+                && SUITES.iter().chain(&UNSUPPORTED).any(|suite| name.starts_with(suite))
+                && name.contains(filter.as_str())
+        })
+        .collect();
+    tests.sort();
+
+    let (mut failed, mut unsupported) = (Vec::new(), 0);
+    for test in &tests {
+        let name = test.file_name().unwrap().to_string_lossy().into_owned();
+        if UNSUPPORTED.iter().any(|suite| name.starts_with(suite)) {
+            println!("UNSUPPORTED {}", name);
+            unsupported += 1;
+            continue;
+        }
+        let output = run(test, &[]);
+        if output.status.success() {
+            println!("PASS {}", name);
+        } else {
+            let reason = String::from_utf8_lossy(&output.stderr);
+            println!("FAIL {} ({})", name, reason.lines().next().unwrap_or("no output"));
+            failed.push(name);
+        }
+    }
+
+    let passed = tests.len() - failed.len() - unsupported;
+    println!("{} passed, {} failed, {} unsupported", passed, failed.len(), unsupported);
+    assert!(failed.is_empty(), "failed tests: {:?}", failed);
+}
@@ -0,0 +1,153 @@
+// A machine restored from a snapshot runs on exactly like the machine it was saved from
+
+use std::io::ErrorKind;
+
+use rv64_emu::csr::{MCAUSE, MEPC, MIE, MIP, MSCRATCH, MSTATUS, MTVEC};
+use rv64_emu::uart::UART_BASE;
+use rv64_emu::{assemble, Exit, Machine, MachineConfig, DRAM_BASE};
+
+const MEMORY: u64 = 32 * 1024 * 1024;
+
+// Both harts add to a shared counter and fill their own pages far apart, hart 0 takes timer
+// interrupts and counts them in mscratch
+const SOURCE: &str = "
+        la t0, trap
+        csrw mtvec, t0
+        li t0, 0x10000000
+        li t1, 3
+        sb t1, 3(t0)
+        csrr s0, mhartid
+        li s1, 0x80100000
+        slli t0, s0, 24
+        add s2, s1, t0
+        addi s2, s2, 64
+        bnez s0, loop
+        li t0, 0x2004000
+        li t1, 500
+        sd t1, 0(t0)
+        li t0, 0x80
+        csrw mie, t0
+        csrsi mstatus, 8
+    loop:
+        li t0, 1
+        amoadd.d zero, t0, (s1)
+        sd s3, 0(s2)
+        addi s3, s3, 7
+        addi s2, s2, 8
+        j loop
+
+    # Push mtimecmp 500 ticks further
+    trap:
+        csrr t2, mscratch
+        addi t2, t2, 1
+        csrw mscratch, t2
+        li t2, 0x2004000
+        ld t3, 0(t2)
+        addi t3, t3, 500
+        sd t3, 0(t2)
+        mret
+";
+
+fn machine() -> Machine {
+    let config = MachineConfig { memory_size: MEMORY, harts: 2, quantum: 37, ..MachineConfig::default() };
+    let code = assemble(SOURCE, DRAM_BASE).unwrap();
+    Machine::builder().config(config).program(code).build().unwrap()
+}
+
+fn assert_same(restored: &Machine, original: &Machine) {
+    for (hart, other) in restored.harts.iter().zip(&original.harts) {
+        assert_eq!((hart.pc, hart.mode, hart.regs), (other.pc, other.mode, other.regs));
+        for csr in [MSTATUS, MTVEC, MIE, MIP, MSCRATCH, MEPC, MCAUSE] {
+            assert_eq!(hart.csr.load(csr), other.csr.load(csr), "CSR {:#x}", csr);
+        }
+    }
+    assert_eq!(restored.bus.clint.mtime(), original.bus.clint.mtime());
+    let mut memory = vec![0; MEMORY as usize];
+    let mut other = vec![0; MEMORY as usize];
+    restored.bus.read_bytes(DRAM_BASE, &mut memory).unwrap();
+    original.bus.read_bytes(DRAM_BASE, &mut other).unwrap();
+    assert!(memory == other, "the memory differs");
+}
+
+#[test]
+fn snapshot_resumes_the_run() {
+    for blocks in [false, true] {
+        let mut original = machine();
+        let mut uninterrupted = machine();
+        for machine in [&mut original, &mut uninterrupted] {
+            machine.harts.iter_mut().for_each(|hart| hart.blocks.enabled = blocks);
+        }
+        assert_eq!(original.run(Some(20_001)), Exit::Limit);
+
+        let mut snapshot = Vec::new();
+        original.save_snapshot(&mut snapshot).unwrap();
+        // Only the pages written are kept, mostly as they are
+        assert!(snapshot.len() < 64 * 1024, "{} bytes", snapshot.len());
+
+        // The layout comes from the snapshot, not from the configuration
+        let mut restored = Machine::restore_snapshot(snapshot.as_slice(), &MachineConfig::default()).unwrap();
+        restored.harts.iter_mut().for_each(|hart| hart.blocks.enabled = blocks);
+        assert_eq!(restored.harts.len(), 2);
+        assert_eq!(restored.bus.dram_size(), MEMORY);
+        assert_same(&restored, &original);
+        assert_eq!(restored.bus.load(UART_BASE + 3, 8), Ok(3));
+
+        // Same quantum as the original, the harts take turns at the same points
+        restored.quantum = 37;
+        assert_eq!(restored.run(Some(30_000)), Exit::Limit);
+        assert_eq!(uninterrupted.run(Some(50_001)), Exit::Limit);
+        assert_same(&restored, &uninterrupted);
+        assert!(restored.harts[0].csr.load(MSCRATCH) > 10, "no timer interrupt was taken");
+    }
+}
+
+#[test]
+fn invalid_snapshots() {
+    let mut snapshot = Vec::new();
+    machine().save_snapshot(&mut snapshot).unwrap();
+    let restore = |bytes: &[u8]| Machine::restore_snapshot(bytes, &MachineConfig::default()).err().unwrap().to_string();
+
+    assert_eq!(restore(b"RV64SNAQ"), "not a snapshot");
+    let mut other_version = snapshot.clone();
+    other_version[8] = 2;
+    assert_eq!(restore(&other_version), "unsupported snapshot version 2, this emulator reads version 1");
+    assert_eq!(restore(&snapshot[..snapshot.len() - 3]), "truncated snapshot");
+    let error = Machine::restore_snapshot(&snapshot[..100], &MachineConfig::default()).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+    // More memory than the host can map, the size follows the memory base
+    let mut huge = snapshot[..60].to_vec();
+    huge[20..28].copy_from_slice(&(1u64 << 52).to_le_bytes());
+    let error = Machine::restore_snapshot(huge.as_slice(), &MachineConfig::default()).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(error.to_string().starts_with("the memory size must be at most"), "{}", error);
+}
+
+#[test]
+fn corrupted_snapshots() {
+    let mut snapshot = Vec::new();
+    machine().save_snapshot(&mut snapshot).unwrap();
+    let restore = |bytes: &[u8]| Machine::restore_snapshot(bytes, &MachineConfig::default()).err().unwrap().to_string();
+    // Magic and version, the layout, HTIF and the SBI flag come before the scheduler, then the
+    // pc of the first hart and its mode
+    let current = 12 + 6 * 8 + 18 + 1;
+    let mode = current + 16 + 8;
+
+    let mut corrupted = snapshot.clone();
+    corrupted[current] = 2;
+    assert_eq!(restore(&corrupted), "invalid current hart in the snapshot: 2");
+    let mut corrupted = snapshot.clone();
+    corrupted[mode] = 2;
+    assert_eq!(restore(&corrupted), "invalid privilege mode in the snapshot: 2");
+
+    // The only page is the program, its length is read before the data
+    let page = (0..snapshot.len() - 20)
+        .find(|&i| {
+            let len = u32::from_le_bytes(snapshot[i + 8..i + 12].try_into().unwrap()) as usize;
+            snapshot[i..i + 8] == [0; 8] && i + 12 + len + 8 == snapshot.len()
+        })
+        .unwrap();
+    let mut corrupted = snapshot.clone();
+    corrupted[page + 8..page + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(restore(&corrupted), "page 0 too large in the snapshot");
+}